@@ -24,7 +24,7 @@ pub fn set_bench(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let tmp_dir = tempdir().unwrap();
-                (Sled::new(sled::open(&tmp_dir).unwrap()), tmp_dir)
+                (Sled::new(sled::open(&tmp_dir).unwrap()).unwrap(), tmp_dir)
             },
             |(mut db, tmp_dir)| {
                 for i in 1..(1 << 12) {
@@ -58,7 +58,7 @@ pub fn get_bench(c: &mut Criterion) {
     for i in &vec![8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = tempdir().unwrap();
-            let mut db = Sled::new(sled::open(&temp_dir).unwrap());
+            let mut db = Sled::new(sled::open(&temp_dir).unwrap()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(&format!("key{}", key_i), "value")
                     .unwrap();
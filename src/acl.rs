@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use crate::{tools, KvError, Request, Result};
+
+/// The user new connections are logged in as, and `AUTH <password>` authenticates.
+pub const DEFAULT_USER: &str = "default";
+
+const READ_COMMANDS: &[&str] = &["get", "mget", "scan", "dbsize", "watch", "unwatch"];
+const WRITE_COMMANDS: &[&str] = &["set", "rm", "mset", "mdel"];
+const ADMIN_COMMANDS: &[&str] = &["backup", "info", "slowlog", "acl", "changes", "sync", "role", "cluster"];
+const PUBSUB_COMMANDS: &[&str] = &["subscribe", "unsubscribe", "psubscribe", "punsubscribe", "publish"];
+
+/// A named user, see `Acl::set_user` for the rules that build it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    passwords: Vec<String>,
+    commands: BTreeSet<&'static str>,
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    fn apply(&mut self, rule: &str) -> Result<()> {
+        let invalid = || KvError::Message(format!("invalid ACL rule '{}'", rule));
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_owned()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" | "+@all" => self.commands = all_commands().collect(),
+            "nocommands" | "-@all" => self.commands.clear(),
+            "reset" => *self = User::default(),
+            lower => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.nopass = false;
+                    if !self.passwords.iter().any(|p| p == password) {
+                        self.passwords.push(password.to_owned());
+                    }
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.passwords.retain(|p| p != password);
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.key_patterns.push(pattern.to_owned());
+                } else if let Some(category) = lower.strip_prefix("+@") {
+                    self.commands.extend(category_commands(category).ok_or_else(invalid)?);
+                } else if let Some(category) = lower.strip_prefix("-@") {
+                    for command in category_commands(category).ok_or_else(invalid)? {
+                        self.commands.remove(command);
+                    }
+                } else if let Some(command) = lower.strip_prefix('+') {
+                    self.commands.insert(find_command(command).ok_or_else(invalid)?);
+                } else if let Some(command) = lower.strip_prefix('-') {
+                    self.commands.remove(find_command(command).ok_or_else(invalid)?);
+                } else {
+                    return Err(invalid());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn allows_key(&self, key: &str) -> bool {
+        self.key_patterns.iter().any(|pattern| tools::glob_match(pattern, key))
+    }
+
+    /// Rules that rebuild this user, except passwords which are never listed.
+    fn describe(&self) -> String {
+        let mut rules = vec![if self.enabled { "on" } else { "off" }.to_owned()];
+        if self.nopass {
+            rules.push("nopass".to_owned());
+        }
+        if self.commands.len() == all_commands().count() {
+            rules.push("+@all".to_owned());
+        } else {
+            rules.push("-@all".to_owned());
+            rules.extend(self.commands.iter().map(|command| format!("+{}", command)));
+        }
+        rules.extend(self.key_patterns.iter().map(|pattern| format!("~{}", pattern)));
+        rules.join(" ")
+    }
+}
+
+fn all_commands() -> impl Iterator<Item = &'static str> {
+    READ_COMMANDS.iter().chain(WRITE_COMMANDS).chain(ADMIN_COMMANDS).chain(PUBSUB_COMMANDS).copied()
+}
+
+fn category_commands(category: &str) -> Option<&'static [&'static str]> {
+    match category {
+        "read" => Some(READ_COMMANDS),
+        "write" => Some(WRITE_COMMANDS),
+        "admin" => Some(ADMIN_COMMANDS),
+        "pubsub" => Some(PUBSUB_COMMANDS),
+        _ => None,
+    }
+}
+
+fn find_command(name: &str) -> Option<&'static str> {
+    all_commands().find(|command| *command == name)
+}
+
+/// Why a request is rejected, displayed as a `NOPERM` error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Denied {
+    /// The user is disabled or deleted since the connection authenticated.
+    User,
+    Command(&'static str),
+    Key,
+}
+
+impl Display for Denied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denied::User => write!(f, "NOPERM this user is disabled or no longer exists"),
+            Denied::Command(name) => write!(f, "NOPERM this user has no permissions to run the '{}' command", name),
+            Denied::Key => write!(f, "NOPERM this user has no permissions to access one of the keys used as arguments"),
+        }
+    }
+}
+
+/// Users allowed to connect, with the commands and keys each of them may access.
+///
+/// A user is built from Redis-like rules:
+/// * `on`, `off` enable or disable the user
+/// * `>password`, `<password` add or remove a password, `nopass` allows any password,
+///   `resetpass` removes all passwords and `nopass`
+/// * `+command`, `-command` allow or disallow a command, `+@read`, `+@write`, `+@admin`,
+///   `+@pubsub`, `+@all` and their `-@` forms do the same for a category
+/// * `~pattern` allows keys matching a glob pattern, `allkeys` is `~*`, `resetkeys` clears them
+/// * `reset` clears everything, leaving a disabled user that can do nothing
+///
+/// `SCAN` and `WATCH` take any prefix, but only keys matching the key patterns are returned or sent.
+/// `AUTH`, `PING`, `ACL WHOAMI` and `ASKING` are always allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    users: BTreeMap<String, User>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acl {
+    /// Only the default user, which needs no password and may run everything.
+    pub fn new() -> Self {
+        let mut acl = Self { users: BTreeMap::new() };
+        acl.set_user(DEFAULT_USER, &["on", "nopass", "+@all", "allkeys"])
+            .expect("default user rules are valid");
+        acl
+    }
+
+    /// Load users from a file of `user <name> <rules...>` lines, `#` starts a comment.
+    /// Each user starts from `reset`, the default user is kept as is unless the file defines it.
+    /// # Examples
+    /// ```rust
+    /// use std::fs;
+    /// use tempfile::TempDir;
+    /// use kvs::acl::Acl;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let path = temp_dir.path().join("users.acl");
+    /// fs::write(&path, "user reader on >secret +@read ~app:*\n").unwrap();
+    /// let acl = Acl::load(&path).unwrap();
+    /// assert!(acl.authenticate("reader", "secret"));
+    /// ```
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut acl = Self::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let name = match (words.next(), words.next()) {
+                (Some("user"), Some(name)) => name,
+                _ => return Err(KvError::Message(format!("invalid ACL line: {}", line))),
+            };
+            let rules: Vec<&str> = words.collect();
+            acl.users.remove(name);
+            acl.set_user(name, &rules)?;
+        }
+        Ok(acl)
+    }
+
+    /// Create or modify a user by applying `rules` in order, a new user starts disabled
+    /// with no permissions. Nothing changes if a rule is invalid.
+    pub fn set_user<S: AsRef<str>>(&mut self, name: &str, rules: &[S]) -> Result<()> {
+        let mut user = self.users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            user.apply(rule.as_ref())?;
+        }
+        self.users.insert(name.to_owned(), user);
+        Ok(())
+    }
+
+    /// Remove users, return how many of them existed. The default user can not be removed.
+    pub fn del_user<S: AsRef<str>>(&mut self, names: &[S]) -> Result<usize> {
+        if names.iter().any(|name| name.as_ref() == DEFAULT_USER) {
+            return Err(KvError::Message("the 'default' user cannot be removed".to_owned()));
+        }
+        Ok(names.iter().filter(|name| self.users.remove(name.as_ref()).is_some()).count())
+    }
+
+    /// One `user <name> <rules...>` line per user, without passwords.
+    pub fn list(&self) -> Vec<String> {
+        self.users.iter().map(|(name, user)| format!("user {} {}", name, user.describe())).collect()
+    }
+
+    /// Check a password in constant time, any password is accepted for `nopass` users.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        match self.users.get(name) {
+            Some(user) if user.enabled => user.nopass || user.passwords.iter()
+                .fold(false, |ok, p| tools::constant_time_eq(password.as_bytes(), p.as_bytes()) | ok),
+            _ => false,
+        }
+    }
+
+    /// Whether new connections are logged in as the default user without `AUTH`.
+    pub fn default_user_open(&self) -> bool {
+        self.users.get(DEFAULT_USER).is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    /// Check that `name` may run `request` on all of its keys.
+    pub fn check(&self, name: &str, request: &Request) -> std::result::Result<(), Denied> {
+        if matches!(request, Request::Auth { .. } | Request::Ping { .. } | Request::AclWhoAmI | Request::Asking) {
+            return Ok(());
+        }
+        let user = match self.users.get(name) {
+            Some(user) if user.enabled => user,
+            _ => return Err(Denied::User),
+        };
+        if !user.commands.contains(request.name()) {
+            return Err(Denied::Command(request.name()));
+        }
+        if !request.keys().iter().all(|key| user.allows_key(key)) {
+            return Err(Denied::Key);
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is enabled and may access `key`, used to filter the keys found by `SCAN` and `WATCH`.
+    pub fn allows_key(&self, name: &str, key: &str) -> bool {
+        self.users.get(name).is_some_and(|user| user.enabled && user.allows_key(key))
+    }
+}
+
+#[cfg(test)]
+mod acl_tests {
+    use crate::Request;
+    use super::{Acl, Denied};
+
+    // Should restrict commands by category and keys by pattern
+    #[test]
+    fn check_permissions() {
+        let mut acl = Acl::new();
+        acl.set_user("reader", &["on", ">secret", "+@read", "~app:*"]).unwrap();
+        assert!(acl.check("default", &Request::set("any", "value")).is_ok());
+        assert!(acl.check("reader", &Request::get("app:1")).is_ok());
+        assert_eq!(acl.check("reader", &Request::get("other")), Err(Denied::Key));
+        assert_eq!(acl.check("reader", &Request::mget(&["app:1", "other"])), Err(Denied::Key));
+        assert_eq!(acl.check("reader", &Request::set("app:1", "value")), Err(Denied::Command("set")));
+        assert!(acl.check("reader", &Request::AclWhoAmI).is_ok());
+        // any prefix may be scanned, the keys found are filtered with allows_key
+        assert!(acl.check("reader", &Request::scan("ap")).is_ok());
+        assert!(acl.allows_key("reader", "app:1"));
+        assert!(!acl.allows_key("reader", "apple"));
+
+        acl.set_user("reader", &["off"]).unwrap();
+        assert!(!acl.allows_key("reader", "app:1"));
+        assert_eq!(acl.check("reader", &Request::get("app:1")), Err(Denied::User));
+        assert_eq!(acl.check("nobody", &Request::get("app:1")), Err(Denied::User));
+    }
+
+    // Should authenticate enabled users with any of their passwords
+    #[test]
+    fn authenticate_users() {
+        let mut acl = Acl::new();
+        acl.set_user("alice", &["on", ">one", ">two"]).unwrap();
+        assert!(acl.authenticate("alice", "one"));
+        assert!(acl.authenticate("alice", "two"));
+        assert!(!acl.authenticate("alice", "three"));
+        acl.set_user("alice", &["<one"]).unwrap();
+        assert!(!acl.authenticate("alice", "one"));
+        assert!(acl.default_user_open());
+        acl.set_user("default", &["resetpass", ">admin"]).unwrap();
+        assert!(!acl.default_user_open());
+        assert!(acl.authenticate("default", "admin"));
+    }
+
+    // Should keep the user unchanged on invalid rules and never list passwords
+    #[test]
+    fn manage_users() {
+        let mut acl = Acl::new();
+        assert!(acl.set_user("bob", &["on", "+flushall"]).is_err());
+        assert!(acl.set_user("bob", &["on", ">pw", "-@all", "+get", "+set", "~*"]).is_ok());
+        assert_eq!(acl.list(), vec![
+            "user bob on -@all +get +set ~*".to_owned(),
+            "user default on nopass +@all ~*".to_owned(),
+        ]);
+        assert!(acl.del_user(&["default"]).is_err());
+        assert_eq!(acl.del_user(&["bob", "nobody"]).unwrap(), 1);
+    }
+}
@@ -1,23 +1,106 @@
 #![feature(let_chains)]
 #![feature(is_some_and)]
 
-use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Result};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use kvs::engine::Event;
+use kvs::replication::Role;
+use kvs::tools::split_args;
+use kvs::{KvError, KvsClient, Request, Result, Subscription};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::string::String;
 
 const DEFAULT_PORT: u16 = 4000;
+/// Kept in the home directory.
+const HISTORY_FILE: &str = ".kvscli_history";
+/// Commands of `--pipe` or `--file` sent at most before reading their replies.
+const PIPELINE_BATCH: usize = 1000;
+/// Exit code of `--output json|raw` when the key of `get` or `rm` is missing.
+const EXIT_NOT_FOUND: i32 = 1;
+/// Exit code of `--output json|raw` on errors, also used by clap for invalid arguments.
+const EXIT_ERROR: i32 = 2;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[arg(short, long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..))]
     port: Option<u16>,
+    #[arg(short = 'a', long, value_name = "PASSWORD", help = "Authenticate to a server started with --requirepass")]
+    password: Option<String>,
+    #[arg(long, value_name = "USER", requires = "password", help = "Authenticate as a user defined by the server ACL")]
+    user: Option<String>,
+    #[arg(short = 's', long, value_name = "PATH", conflicts_with_all = ["port", "tls_ca"], help = "Connect to a unix socket instead of TCP")]
+    unix_socket: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Connect with TLS, trusting the CA certificates of this PEM file")]
+    tls_ca: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires_all = ["tls_ca", "tls_key"], help = "Client certificate for servers verifying clients")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "tls_cert", help = "Private key of the client certificate")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, value_name = "NAME", default_value = "localhost", help = "Name checked against the server certificate")]
+    tls_server_name: String,
+    #[arg(long, group = "input", help = "Run the commands of stdin, one per line, pipelined over one connection")]
+    pipe: bool,
+    #[arg(long, value_name = "FILE", group = "input", help = "Run the commands of a file, one per line, pipelined over one connection")]
+    file: Option<PathBuf>,
+    #[arg(long, requires = "input", help = "Stop at the first failed command of --pipe or --file, sending commands one at a time")]
+    stop_on_error: bool,
+    #[arg(short, long, value_enum, default_value_t = Output::Text, conflicts_with = "input", help = "Format of the reply of a command",
+        long_help = "Format of the reply of a command. With json and raw, the exit code is 0 on success, 1 if the key of get \
+            or rm is missing and 2 on errors. text exits with 0 for a missing key and 1 on errors, as it always did, so \
+            existing scripts checking for errors only keep working; use json or raw to tell a missing key by the exit code")]
+    output: Output,
+    #[command(subcommand)]
+    command: Option<Commands>
+}
+
+/// Formats of `--output`. `json` and `raw` exit with `EXIT_NOT_FOUND` if the key of `get` or
+/// `rm` is missing and `EXIT_ERROR` on errors. `text` keeps exiting with 0 for a missing key
+/// and 1 on errors, see the help of `--output`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    /// Plain lines, an empty line for a missing value, exits with 0 for a missing key
+    Text,
+    /// A JSON document, or a JSON line per message of streaming commands
+    Json,
+    /// Values as stored, without a trailing newline, other replies like text
+    Raw
+}
+
+/// A key of `get`, `rm` or `mget` in JSON output.
+#[derive(Serialize)]
+struct Lookup<'a> {
+    key: &'a str,
+    found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>
+}
+
+/// Commands of the interactive shell, left with `quit` or `exit`.
+#[derive(Parser)]
+#[command(name = "", no_binary_name = true)]
+struct Line {
     #[command(subcommand)]
     command: Commands
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    #[command(about = "Start an interactive shell, the default without a subcommand", long_about = None)]
+    Repl,
     #[command(about = "Demo program that demonstrates the usage of \"KvStore\" core", long_about = None)]
     Get {
         key: String,
@@ -31,27 +114,789 @@ enum Commands {
     #[command(name="rm")]
     Remove {
         key: String
+    },
+    #[command(about = "Get values of several keys, print one line per key", long_about = None)]
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>
+    },
+    #[command(about = "Set several key-value pairs atomically", long_about = None)]
+    Mset {
+        #[arg(required = true, value_name = "KEY VALUE")]
+        pairs: Vec<String>
+    },
+    #[command(about = "Remove several keys, print the number of removed keys", long_about = None)]
+    Mdel {
+        #[arg(required = true)]
+        keys: Vec<String>
+    },
+    #[command(about = "Print \"<key> <value>\" for every pair whose key starts with PREFIX, sorted by key", long_about = None)]
+    Scan {
+        #[arg(default_value = "")]
+        prefix: String
+    },
+    #[command(about = "Write an online backup of the server store into a directory on the server host", long_about = None)]
+    Backup {
+        dir: String
+    },
+    #[command(about = "Print server information, optionally only one section", long_about = None)]
+    Info {
+        section: Option<String>
+    },
+    #[command(about = "Print the number of keys in kv store", long_about = None)]
+    Dbsize,
+    #[command(about = "Print the replication role of the server", long_about = None)]
+    Role,
+    #[command(about = "Inspect the hash slots of a cluster", long_about = None)]
+    Cluster {
+        #[command(subcommand)]
+        command: ClusterCommands
+    },
+    #[command(about = "Inspect commands that exceeded the slowlog threshold", long_about = None)]
+    Slowlog {
+        #[command(subcommand)]
+        command: SlowlogCommands
+    },
+    #[command(about = "Manage users allowed to access the server", long_about = None)]
+    Acl {
+        #[command(subcommand)]
+        command: AclCommands
+    },
+    #[command(about = "Publish a message, print the number of subscriptions it is sent to", long_about = None)]
+    Publish {
+        channel: String,
+        message: String
+    },
+    #[command(about = "Print \"<channel> <message>\" for each message published to channels until interrupted", long_about = None)]
+    Subscribe {
+        #[arg(required = true)]
+        channels: Vec<String>
+    },
+    #[command(about = "Print \"<channel> <message>\" for each message published to channels matching glob patterns", long_about = None)]
+    Psubscribe {
+        #[arg(required = true)]
+        patterns: Vec<String>
+    },
+    #[command(about = "Print \"set <key> <value>\" or \"remove <key>\" for each change of keys with given prefixes until interrupted", long_about = None)]
+    Watch {
+        #[arg(required = true)]
+        prefixes: Vec<String>
+    },
+    #[command(about = "Print \"<seq> set <key> <value>\" or \"<seq> remove <key>\" for each mutation after sequence number SINCE until interrupted", long_about = None)]
+    Changes {
+        #[arg(default_value_t = 0)]
+        since: u64
     }
 }
 
+#[derive(Subcommand)]
+enum SlowlogCommands {
+    #[command(about = "Print the latest slow commands, newest first", long_about = None)]
+    Get {
+        count: Option<usize>
+    },
+    #[command(about = "Print the number of entries in slowlog", long_about = None)]
+    Len,
+    #[command(about = "Remove all entries from slowlog", long_about = None)]
+    Reset
+}
+
+#[derive(Subcommand)]
+enum ClusterCommands {
+    #[command(about = "Print \"<start>-<end> <node>\" for the slots owned by each node", long_about = None)]
+    Slots,
+    #[command(about = "Print each node with its slots and the slots migrating from or to the server", long_about = None)]
+    Nodes,
+    #[command(about = "Print the slot of a key", long_about = None)]
+    Keyslot {
+        key: String
+    },
+    #[command(about = "Print the number of keys of a slot stored on the server", long_about = None)]
+    Countkeysinslot {
+        slot: u16
+    }
+}
+
+#[derive(Subcommand)]
+enum AclCommands {
+    #[command(about = "Create or modify a user with rules like on, >password, +@read, ~prefix:*", long_about = None)]
+    Setuser {
+        username: String,
+        #[arg(allow_hyphen_values = true)]
+        rules: Vec<String>
+    },
+    #[command(about = "Remove users, print the number of removed users", long_about = None)]
+    Deluser {
+        #[arg(required = true)]
+        usernames: Vec<String>
+    },
+    #[command(about = "Print the rules of each user", long_about = None)]
+    List,
+    #[command(about = "Print the user of the connection", long_about = None)]
+    Whoami
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let port = cli.port.unwrap_or_else(|| DEFAULT_PORT);
-    let mut client = KvsClient::connect("127.0.0.1:".to_owned() + &port.to_string())?;
-    match &cli.command {
-        Commands::Set { key, value } => client.set(key, value)?,
-        Commands::Get { key } => {
-            match client.get(key)? {
-                Some(value) => println!("{value}"),
-                None => println!()
+    if cli.output == Output::Text {
+        return run(&cli);
+    }
+    // scripts tell errors from missing keys by the exit code
+    if let Err(err) = run(&cli) {
+        match cli.output {
+            Output::Json => println!("{}", json!({ "error": err.to_string() })),
+            _ => eprintln!("Error: {err}")
+        }
+        exit(EXIT_ERROR);
+    }
+    Ok(())
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    let input: Option<Box<dyn BufRead>> = match (&cli.file, &cli.command) {
+        (_, Some(_)) if cli.pipe || cli.file.is_some() => {
+            return Err(KvError::Message("--pipe and --file run commands of the input, not a command argument".to_owned()))
+        }
+        (Some(path), _) => Some(Box::new(BufReader::new(File::open(path)?))),
+        (None, _) if cli.pipe => Some(Box::new(io::stdin().lock())),
+        (None, _) => None
+    };
+    if cli.output != Output::Text && matches!(cli.command, None | Some(Commands::Repl)) {
+        return Err(KvError::Message("--output formats the reply of a command, not the interactive shell".to_owned()));
+    }
+    let mut client = connect(cli)?;
+    if let Some(input) = input {
+        if !run_batch(&mut client, input, cli.stop_on_error)? {
+            exit(1);
+        }
+        return Ok(());
+    }
+    let found = match &cli.command {
+        None | Some(Commands::Repl) => return repl(cli, client),
+        Some(command) if is_stream(command) => return stream(client, command, cli.output),
+        Some(command) => match cli.output {
+            Output::Text => {
+                print_plain(&execute(&mut client, command)?);
+                true
             }
+            Output::Json => {
+                let (reply, found) = json_reply(&mut client, command)?;
+                println!("{reply}");
+                found
+            }
+            Output::Raw => print_raw(&mut client, command)?
+        }
+    };
+    if !found {
+        exit(EXIT_NOT_FOUND);
+    }
+    Ok(())
+}
+
+fn connect(cli: &Cli) -> Result<KvsClient> {
+    let mut client = if let Some(path) = &cli.unix_socket {
+        connect_unix(path)?
+    } else if let Some(ca) = &cli.tls_ca {
+        let identity = cli.tls_cert.as_deref().zip(cli.tls_key.as_deref());
+        KvsClient::connect_tls(address(cli), &cli.tls_server_name, kvs::tls::client_config(ca, identity)?)?
+    } else {
+        KvsClient::connect(address(cli))?
+    };
+    match (&cli.user, &cli.password) {
+        (Some(user), Some(password)) => client.auth_user(user, password)?,
+        (_, Some(password)) => client.auth(password)?,
+        (_, None) => {}
+    }
+    Ok(client)
+}
+
+fn address(cli: &Cli) -> String {
+    "127.0.0.1:".to_owned() + &cli.port.unwrap_or(DEFAULT_PORT).to_string()
+}
+
+/// Reply of a command, printed plainly for a single command or like `redis-cli` in the shell.
+enum Reply {
+    Done,
+    Nil,
+    Integer(u64),
+    /// A value stored by a client.
+    Value(String),
+    /// Text made up by the server or the client.
+    Text(String),
+    List(Vec<Reply>)
+}
+
+fn execute(client: &mut KvsClient, command: &Commands) -> Result<Reply> {
+    let reply = match command {
+        Commands::Set { key, value } => {
+            client.set(key, value)?;
+            Reply::Done
+        }
+        Commands::Get { key } => client.get(key)?.map_or(Reply::Nil, Reply::Value),
+        Commands::Remove { key } => Reply::Text(client.rm(key)?.unwrap_or_else(|| "Key not found".to_owned())),
+        Commands::Mget { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            Reply::List(client.mget(&keys)?.into_iter().map(|value| value.map_or(Reply::Nil, Reply::Value)).collect())
+        },
+        Commands::Mset { pairs } => {
+            if pairs.len() % 2 != 0 {
+                return Err(KvError::MissingArguments);
+            }
+            let pairs: Vec<(&str, &str)> = pairs.chunks(2).map(|kv| (kv[0].as_str(), kv[1].as_str())).collect();
+            client.mset(&pairs)?;
+            Reply::Done
+        },
+        Commands::Mdel { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            Reply::Integer(client.mdel(&keys)? as u64)
+        },
+        Commands::Scan { prefix } => {
+            Reply::List(client.scan(prefix)?.into_iter().map(|(key, value)| Reply::Text(format!("{key} {value}"))).collect())
+        },
+        Commands::Backup { dir } => {
+            client.backup(dir)?;
+            Reply::Done
+        }
+        Commands::Info { section } => Reply::Text(client.info(section.as_deref())?.replace("\r\n", "\n")),
+        Commands::Dbsize => Reply::Integer(client.dbsize()?),
+        Commands::Role => match client.role()? {
+            Role::Leader { seq, followers } => {
+                let followers = followers.into_iter().map(|(addr, seq)| Reply::Text(format!("{addr} {seq}")));
+                Reply::List([Reply::Text(format!("leader {seq}"))].into_iter().chain(followers).collect())
+            }
+            Role::Follower { leader, status, seq } => Reply::Text(format!("follower {leader} {} {seq}", status.as_str()))
+        },
+        Commands::Cluster { command } => match command {
+            ClusterCommands::Slots => Reply::List(client.cluster_slots()?.into_iter()
+                .map(|range| Reply::Text(format!("{}-{} {}", range.start, range.end, range.node)))
+                .collect()),
+            ClusterCommands::Nodes => Reply::Text(client.cluster_nodes()?),
+            ClusterCommands::Keyslot { key } => Reply::Integer(client.cluster_keyslot(key)?.into()),
+            ClusterCommands::Countkeysinslot { slot } => Reply::Integer(client.cluster_countkeysinslot(*slot)?)
+        },
+        Commands::Slowlog { command } => match command {
+            SlowlogCommands::Get { count } => Reply::List(client.slowlog_get(*count)?.into_iter()
+                .map(|entry| Reply::Text(format!(
+                    "{} {} {}us {} {}",
+                    entry.id, entry.timestamp, entry.duration.as_micros(), entry.client, entry.args.join(" ")
+                )))
+                .collect()),
+            SlowlogCommands::Len => Reply::Integer(client.slowlog_len()?),
+            SlowlogCommands::Reset => {
+                client.slowlog_reset()?;
+                Reply::Done
+            }
+        },
+        Commands::Acl { command } => match command {
+            AclCommands::Setuser { username, rules } => {
+                let rules: Vec<&str> = rules.iter().map(String::as_str).collect();
+                client.acl_setuser(username, &rules)?;
+                Reply::Done
+            }
+            AclCommands::Deluser { usernames } => {
+                let usernames: Vec<&str> = usernames.iter().map(String::as_str).collect();
+                Reply::Integer(client.acl_deluser(&usernames)? as u64)
+            }
+            AclCommands::List => Reply::List(client.acl_list()?.into_iter().map(Reply::Text).collect()),
+            AclCommands::Whoami => Reply::Text(client.acl_whoami()?)
         },
+        Commands::Publish { channel, message } => Reply::Integer(client.publish(channel, message)?),
+        Commands::Repl => return Err(KvError::Message("already in the interactive shell".to_owned())),
+        Commands::Subscribe { .. } | Commands::Psubscribe { .. } | Commands::Watch { .. } | Commands::Changes { .. } => {
+            unreachable!("streaming commands are run by `stream`")
+        }
+    };
+    Ok(reply)
+}
+
+fn is_stream(command: &Commands) -> bool {
+    matches!(command, Commands::Subscribe { .. } | Commands::Psubscribe { .. } | Commands::Watch { .. } | Commands::Changes { .. })
+}
+
+/// Print messages or changes as they arrive, which takes over the connection until it fails.
+fn stream(client: KvsClient, command: &Commands, output: Output) -> Result<()> {
+    match command {
+        Commands::Subscribe { channels } => {
+            let channels: Vec<&str> = channels.iter().map(String::as_str).collect();
+            print_messages(client.subscribe(&channels)?, output)
+        }
+        Commands::Psubscribe { patterns } => {
+            let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+            print_messages(client.psubscribe(&patterns)?, output)
+        }
+        Commands::Watch { prefixes } => {
+            let prefixes: Vec<&str> = prefixes.iter().map(String::as_str).collect();
+            for event in client.watch(&prefixes)? {
+                print_event(None, event?, output);
+            }
+            Ok(())
+        }
+        Commands::Changes { since } => {
+            for change in client.changes(*since)? {
+                let change = change?;
+                print_event(Some(change.seq), change.event, output);
+            }
+            Ok(())
+        }
+        _ => unreachable!("not a streaming command")
+    }
+}
+
+/// Print a change of `watch`, or of `changes` with its sequence number.
+fn print_event(seq: Option<u64>, event: Event, output: Output) {
+    if output == Output::Json {
+        let mut line = match event {
+            Event::Set { key, value } => json!({ "event": "set", "key": key, "value": value }),
+            Event::Remove { key } => json!({ "event": "remove", "key": key })
+        };
+        if let Some(seq) = seq {
+            line["seq"] = json!(seq);
+        }
+        println!("{line}");
+        return;
+    }
+    let seq = seq.map(|seq| format!("{seq} ")).unwrap_or_default();
+    match event {
+        Event::Set { key, value } => println!("{seq}set {key} {value}"),
+        Event::Remove { key } => println!("{seq}remove {key}")
+    }
+}
+
+/// Print the reply of a command with values as stored and return whether the key of `get` or
+/// `rm` was found.
+fn print_raw(client: &mut KvsClient, command: &Commands) -> Result<bool> {
+    match command {
+        Commands::Get { key } => match client.get_bytes(key)? {
+            Some(value) => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&value)?;
+                stdout.flush()?;
+                Ok(true)
+            }
+            None => Ok(false)
+        },
+        Commands::Remove { key } => match client.rm(key)? {
+            Some(msg) => {
+                println!("{msg}");
+                Ok(true)
+            }
+            None => Ok(false)
+        },
+        command => {
+            print_plain(&execute(client, command)?);
+            Ok(true)
+        }
+    }
+}
+
+/// Reply of a command as a JSON document, and whether the key of `get` or `rm` was found.
+fn json_reply(client: &mut KvsClient, command: &Commands) -> Result<(String, bool)> {
+    let reply = match command {
+        Commands::Get { key } => {
+            let value = client.get(key)?;
+            let found = value.is_some();
+            return Ok((serde_json::to_string(&Lookup { key, found, value })?, found));
+        }
         Commands::Remove { key } => {
-            match client.rm(key)? {
-                Some(msg) => println!("{msg}"),
-                None => println!("Key not found")
+            let found = client.rm(key)?.is_some();
+            return Ok((serde_json::to_string(&Lookup { key, found, value: None })?, found));
+        }
+        Commands::Mget { keys } => {
+            let names: Vec<&str> = keys.iter().map(String::as_str).collect();
+            let lookups: Vec<Lookup> = names.iter().zip(client.mget(&names)?)
+                .map(|(key, value)| Lookup { key, found: value.is_some(), value })
+                .collect();
+            return Ok((serde_json::to_string(&lookups)?, true));
+        }
+        Commands::Mdel { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            json!({ "removed": client.mdel(&keys)? })
+        }
+        Commands::Scan { prefix } => {
+            let pairs: Vec<_> = client.scan(prefix)?.into_iter().map(|(key, value)| json!({ "key": key, "value": value })).collect();
+            json!(pairs)
+        }
+        Commands::Info { section } => info_json(&client.info(section.as_deref())?),
+        Commands::Dbsize => json!({ "keys": client.dbsize()? }),
+        Commands::Role => match client.role()? {
+            Role::Leader { seq, followers } => {
+                let followers: Vec<_> = followers.into_iter().map(|(addr, seq)| json!({ "addr": addr, "seq": seq })).collect();
+                json!({ "role": "leader", "seq": seq, "followers": followers })
+            }
+            Role::Follower { leader, status, seq } => {
+                json!({ "role": "follower", "leader": leader, "status": status.as_str(), "seq": seq })
+            }
+        },
+        Commands::Cluster { command } => match command {
+            ClusterCommands::Slots => {
+                let ranges: Vec<_> = client.cluster_slots()?.into_iter()
+                    .map(|range| json!({ "start": range.start, "end": range.end, "node": range.node }))
+                    .collect();
+                json!(ranges)
+            }
+            ClusterCommands::Nodes => json!(client.cluster_nodes()?.lines().collect::<Vec<_>>()),
+            ClusterCommands::Keyslot { key } => json!({ "slot": client.cluster_keyslot(key)? }),
+            ClusterCommands::Countkeysinslot { slot } => json!({ "keys": client.cluster_countkeysinslot(*slot)? })
+        },
+        Commands::Slowlog { command } => match command {
+            SlowlogCommands::Get { count } => {
+                let entries: Vec<_> = client.slowlog_get(*count)?.into_iter()
+                    .map(|entry| json!({
+                        "id": entry.id,
+                        "timestamp": entry.timestamp,
+                        "duration_us": entry.duration.as_micros() as u64,
+                        "client": entry.client,
+                        "args": entry.args
+                    }))
+                    .collect();
+                json!(entries)
+            }
+            SlowlogCommands::Len => json!({ "len": client.slowlog_len()? }),
+            SlowlogCommands::Reset => {
+                client.slowlog_reset()?;
+                json!({ "ok": true })
             }
+        },
+        Commands::Acl { command } => match command {
+            AclCommands::Deluser { usernames } => {
+                let usernames: Vec<&str> = usernames.iter().map(String::as_str).collect();
+                json!({ "removed": client.acl_deluser(&usernames)? })
+            }
+            AclCommands::List => json!(client.acl_list()?),
+            AclCommands::Whoami => json!({ "user": client.acl_whoami()? }),
+            AclCommands::Setuser { username, rules } => {
+                let rules: Vec<&str> = rules.iter().map(String::as_str).collect();
+                client.acl_setuser(username, &rules)?;
+                json!({ "ok": true })
+            }
+        },
+        Commands::Publish { channel, message } => json!({ "receivers": client.publish(channel, message)? }),
+        // the reply only tells that the command succeeded
+        command => {
+            execute(client, command)?;
+            json!({ "ok": true })
         }
     };
+    Ok((reply.to_string(), true))
+}
+
+/// `info` as an object of sections, each an object of its fields.
+fn info_json(info: &str) -> serde_json::Value {
+    let mut sections = json!({});
+    let mut section = String::new();
+    for line in info.lines() {
+        if let Some(name) = line.strip_prefix("# ") {
+            section = name.to_lowercase();
+            sections[&section] = json!({});
+        } else if let Some((field, value)) = line.split_once(':') {
+            sections[&section][field] = json!(value);
+        }
+    }
+    sections
+}
+
+/// Print a reply the way single commands always did, a list item per line and nil as an empty line.
+fn print_plain(reply: &Reply) {
+    match reply {
+        Reply::Done => {}
+        Reply::Nil => println!(),
+        Reply::Integer(n) => println!("{n}"),
+        Reply::Value(value) => println!("{value}"),
+        Reply::Text(text) if text.is_empty() || text.ends_with('\n') => print!("{text}"),
+        Reply::Text(text) => println!("{text}"),
+        Reply::List(items) => items.iter().for_each(print_plain)
+    }
+}
+
+/// Format a reply like `redis-cli`, with quoted values, `(nil)`, `(integer) N` and numbered items.
+fn pretty(reply: &Reply) -> String {
+    match reply {
+        Reply::Done => "OK".to_owned(),
+        Reply::Nil => "(nil)".to_owned(),
+        Reply::Integer(n) => format!("(integer) {n}"),
+        Reply::Value(value) => format!("{value:?}"),
+        Reply::Text(text) => text.trim_end().to_owned(),
+        Reply::List(items) if items.is_empty() => "(empty array)".to_owned(),
+        Reply::List(items) => {
+            let width = items.len().to_string().len();
+            items.iter().enumerate()
+                .map(|(i, item)| format!("{:>width$}) {}", i + 1, pretty(item)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+/// Read commands from the terminal and run them on one connection until `quit` or end of input.
+fn repl(cli: &Cli, mut client: KvsClient) -> Result<()> {
+    let mut editor = Editor::<Shell, DefaultHistory>::new().map_err(readline_error)?;
+    editor.set_helper(Some(Shell::new()));
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // there is no history before the first session
+        let _ = editor.load_history(path);
+    }
+    let prompt = match &cli.unix_socket {
+        Some(path) => format!("{}> ", path.display()),
+        None => format!("{}> ", address(cli)),
+    };
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(readline_error(err)),
+        };
+        let args = match split_args(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(err) => {
+                println!("(error) {err}");
+                continue;
+            }
+        };
+        let _ = editor.add_history_entry(line.trim());
+        if matches!(args[0].to_lowercase().as_str(), "quit" | "exit") {
+            break;
+        }
+        let command = match parse_command(args) {
+            Ok(command) => command,
+            Err(err) if matches!(err.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand) => {
+                print!("{err}");
+                continue;
+            }
+            Err(err) => {
+                println!("(error) {}", parse_error(&err));
+                continue;
+            }
+        };
+        if is_stream(&command) {
+            // the stream keeps the connection, later commands use a new one
+            let result = stream(mem::replace(&mut client, connect(cli)?), &command, Output::Text);
+            if let Err(err) = result {
+                println!("(error) {err}");
+            }
+            continue;
+        }
+        match execute(&mut client, &command) {
+            Ok(reply) => println!("{}", pretty(&reply)),
+            Err(err) => println!("(error) {err}"),
+        }
+    }
+    if let Some(path) = &history {
+        editor.save_history(path).map_err(readline_error)?;
+    }
     Ok(())
 }
+
+/// Parse a typed command, whose names are case insensitive like in `redis-cli`.
+fn parse_command(mut args: Vec<String>) -> std::result::Result<Commands, clap::Error> {
+    if let Some(name) = args.first_mut() {
+        *name = name.to_lowercase();
+    }
+    if matches!(args.first().map(String::as_str), Some("cluster" | "slowlog" | "acl")) {
+        if let Some(name) = args.get_mut(1) {
+            *name = name.to_lowercase();
+        }
+    }
+    Line::try_parse_from(&args).map(|line| line.command)
+}
+
+/// Only the first paragraph of a parse error, without the usage meant for the command line.
+fn parse_error(err: &clap::Error) -> String {
+    let message = err.to_string();
+    let message: Vec<&str> = message.lines().take_while(|line| !line.is_empty()).map(str::trim).collect();
+    message.join(" ").trim_start_matches("error: ").to_owned()
+}
+
+/// Run the commands of `input`, one per line, sending up to `PIPELINE_BATCH` at once or one at
+/// a time with `stop_on_error`. Failures are reported with their line number and a summary is
+/// printed, return whether all commands succeeded.
+fn run_batch(client: &mut KvsClient, input: impl BufRead, stop_on_error: bool) -> Result<bool> {
+    let batch_size = if stop_on_error { 1 } else { PIPELINE_BATCH };
+    let mut batch = Batch::default();
+    for (number, line) in input.lines().enumerate() {
+        let (number, line) = (number + 1, line?);
+        // blank lines and comments are skipped
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        match split_args(&line).and_then(batch_request) {
+            Ok(request) => batch.pending.push((number, request)),
+            Err(err) => {
+                batch.send(client);
+                batch.fail(number, &err);
+            }
+        }
+        if batch.pending.len() >= batch_size {
+            batch.send(client);
+        }
+        if (stop_on_error && batch.failed > 0) || batch.broken {
+            break;
+        }
+    }
+    batch.send(client);
+    println!("{} commands, {} succeeded, {} failed", batch.succeeded + batch.failed, batch.succeeded, batch.failed);
+    Ok(batch.failed == 0)
+}
+
+/// Commands of `--pipe` or `--file` waiting to be sent, and the outcome of those sent.
+#[derive(Default)]
+struct Batch {
+    /// Requests with their line numbers.
+    pending: Vec<(usize, Request)>,
+    succeeded: usize,
+    failed: usize,
+    /// Set when the connection failed, the outcome of the commands is unknown.
+    broken: bool
+}
+
+impl Batch {
+    fn send(&mut self, client: &mut KvsClient) {
+        if self.pending.is_empty() || self.broken {
+            return;
+        }
+        let (numbers, requests): (Vec<usize>, Vec<Request>) = self.pending.drain(..).unzip();
+        match client.pipeline(&requests) {
+            Ok(replies) => {
+                for (number, reply) in numbers.into_iter().zip(replies) {
+                    match reply {
+                        Ok(_) => self.succeeded += 1,
+                        Err(err) => self.fail(number, &err)
+                    }
+                }
+            }
+            Err(err) => {
+                match numbers.as_slice() {
+                    [number] => eprintln!("line {number}: {err}"),
+                    numbers => eprintln!("lines {}-{}: {err}", numbers[0], numbers[numbers.len() - 1])
+                }
+                self.failed += numbers.len();
+                self.broken = true;
+            }
+        }
+    }
+
+    fn fail(&mut self, number: usize, err: &KvError) {
+        eprintln!("line {number}: {err}");
+        self.failed += 1;
+    }
+}
+
+/// Parse a line of `--pipe` or `--file` into the request it sends.
+fn batch_request(args: Vec<String>) -> Result<Request> {
+    if args.is_empty() {
+        return Err(KvError::MissingArguments);
+    }
+    let command = parse_command(args).map_err(|err| KvError::Message(parse_error(&err)))?;
+    let request = match command {
+        Commands::Set { key, value } => Request::Set { key, value },
+        Commands::Get { key } => Request::Get { key },
+        Commands::Remove { key } => Request::Remove { key },
+        Commands::Mget { keys } => Request::MGet { keys },
+        Commands::Mset { pairs } => {
+            if pairs.len() % 2 != 0 {
+                return Err(KvError::MissingArguments);
+            }
+            Request::MSet { pairs: pairs.chunks(2).map(|kv| (kv[0].clone(), kv[1].clone())).collect() }
+        }
+        Commands::Mdel { keys } => Request::MDel { keys },
+        Commands::Scan { prefix } => Request::scan(&prefix),
+        Commands::Backup { dir } => Request::Backup { dir },
+        Commands::Info { section } => Request::Info { section },
+        Commands::Dbsize => Request::DbSize,
+        Commands::Role => Request::Role,
+        Commands::Cluster { command } => match command {
+            ClusterCommands::Slots => Request::ClusterSlots,
+            ClusterCommands::Nodes => Request::ClusterNodes,
+            ClusterCommands::Keyslot { key } => Request::ClusterKeySlot { key },
+            ClusterCommands::Countkeysinslot { slot } => Request::ClusterCountKeysInSlot { slot }
+        },
+        Commands::Slowlog { command } => match command {
+            SlowlogCommands::Get { count } => Request::SlowLogGet { count },
+            SlowlogCommands::Len => Request::SlowLogLen,
+            SlowlogCommands::Reset => Request::SlowLogReset
+        },
+        Commands::Acl { command } => match command {
+            AclCommands::Setuser { username, rules } => Request::AclSetUser { username, rules },
+            AclCommands::Deluser { usernames } => Request::AclDelUser { usernames },
+            AclCommands::List => Request::AclList,
+            AclCommands::Whoami => Request::AclWhoAmI
+        },
+        Commands::Publish { channel, message } => Request::Publish { channel, message },
+        Commands::Repl | Commands::Subscribe { .. } | Commands::Psubscribe { .. } | Commands::Watch { .. } | Commands::Changes { .. } => {
+            return Err(KvError::Message("interactive and streaming commands can't be run from --pipe or --file".to_owned()))
+        }
+    };
+    Ok(request)
+}
+
+fn readline_error(err: ReadlineError) -> KvError {
+    KvError::Message(format!("line editor failed: {err}"))
+}
+
+/// Completes command names in the interactive shell.
+struct Shell {
+    /// Names of commands, and of subcommands after their group like "slowlog get".
+    commands: Vec<String>
+}
+
+impl Shell {
+    fn new() -> Self {
+        let mut commands = vec!["help".to_owned(), "quit".to_owned(), "exit".to_owned()];
+        for command in Line::command().get_subcommands() {
+            commands.push(command.get_name().to_owned());
+            for subcommand in command.get_subcommands() {
+                commands.push(format!("{} {}", command.get_name(), subcommand.get_name()));
+            }
+        }
+        Shell { commands }
+    }
+}
+
+impl Completer for Shell {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = line[..pos].trim_start().to_lowercase();
+        let start = pos - typed.len();
+        let candidates = self.commands.iter()
+            .filter(|name| name.starts_with(&typed))
+            // complete the next word only
+            .filter(|name| !name[typed.len()..].trim_start().contains(' '))
+            .map(|name| name.clone() + " ")
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Shell {
+    type Hint = String;
+}
+
+impl Highlighter for Shell {}
+
+impl Validator for Shell {}
+
+impl Helper for Shell {}
+
+fn print_messages(subscription: Subscription, output: Output) -> Result<()> {
+    for message in subscription {
+        let message = message?;
+        match output {
+            Output::Json => println!("{}", json!({ "channel": message.channel(), "message": message.payload() })),
+            _ => println!("{} {}", message.channel(), message.payload())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> Result<KvsClient> {
+    KvsClient::connect_unix(path)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &Path) -> Result<KvsClient> {
+    Err(KvError::Message("unix sockets are not supported on this platform".to_owned()))
+}
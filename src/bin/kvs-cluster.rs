@@ -0,0 +1,47 @@
+use clap::{Parser, Subcommand};
+use kvs::cluster::{create, move_slots, parse_slots};
+use kvs::Result;
+
+#[derive(Parser)]
+#[command(author, version, about = "Assign and move the hash slots of kvs-servers running with --cluster", long_about = None)]
+struct Args {
+    #[arg(short = 'a', long, value_name = "PASSWORD", help = "Authenticate to servers started with --requirepass")]
+    password: Option<String>,
+    #[command(subcommand)]
+    command: Commands
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    #[command(about = "Split the slots evenly among empty nodes", long_about = None)]
+    Create {
+        #[arg(required = true, value_name = "ADDR")]
+        nodes: Vec<String>
+    },
+    #[command(about = "Move slots to a node while the cluster keeps serving them", long_about = None)]
+    Move {
+        #[arg(value_name = "SLOT|START-END", help = "Slots to move, e.g. 0-1000")]
+        slots: String,
+        #[arg(long, value_name = "ADDR", help = "Node to move the slots to")]
+        to: String,
+        #[arg(long, value_name = "ADDR", help = "Any node of the cluster, to read the slot map from")]
+        seed: String
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    match &args.command {
+        Commands::Create { nodes } => {
+            for range in create(nodes, args.password.as_deref())? {
+                println!("{}-{} {}", range.start, range.end, range.node);
+            }
+        }
+        Commands::Move { slots, to, seed } => {
+            let (start, end) = parse_slots(slots)?;
+            let moved = move_slots(seed, start, end, to, args.password.as_deref())?;
+            eprintln!("Moved {} keys", moved);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use clap::{Parser, ValueEnum};
+use kvs::dump::{dump, DumpFormat};
+use kvs::engine::{KvsEngine, Sled};
+use kvs::{KvStore, Result};
+
+#[derive(Parser)]
+#[command(author, version, about = "Export every live key-value pair of a store", long_about = None)]
+struct Args {
+    #[arg(short, long, value_enum, default_value_t = Engine::Kvs)]
+    engine: Engine,
+    #[arg(short, long, value_name = "DIR", default_value = ".", help = "Working directory of kvs-server")]
+    dir: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = Format::Jsonl)]
+    format: Format,
+    #[arg(short, long, value_name = "FILE", help = "Write to file instead of stdout")]
+    output: Option<PathBuf>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Jsonl,
+    Binary
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut engine: Box<dyn KvsEngine> = match args.engine {
+        Engine::Kvs => Box::new(KvStore::open(&args.dir)?),
+        Engine::Sled => Box::new(Sled::new(sled::open(args.dir.join("my_db"))?)?)
+    };
+    let format = match args.format {
+        Format::Jsonl => DumpFormat::JsonLines,
+        Format::Binary => DumpFormat::Binary
+    };
+    let writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock()))
+    };
+    let count = dump(engine.as_mut(), writer, format)?;
+    eprintln!("Dumped {} keys", count);
+    Ok(())
+}
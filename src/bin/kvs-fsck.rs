@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+use std::process::exit;
+use clap::Parser;
+use kvs::engine::kvstore::check::{check, repair, DamageKind};
+use kvs::Result;
+
+#[derive(Parser)]
+#[command(author, version, about = "Check the log files of a KvStore directory", long_about = None)]
+struct Args {
+    #[arg(value_name = "DIR", default_value = ".")]
+    dir: PathBuf,
+    #[arg(long, value_name = "DEST", help = "Salvage every readable record into a fresh directory")]
+    repair: Option<PathBuf>
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let report = check(&args.dir)?;
+    for file in &report.files {
+        println!(
+            "{}.log: {} bytes, {} records, {} live bytes, {} dead bytes",
+            file.file_stem, file.size, file.records, file.live_bytes, file.dead_bytes
+        );
+        for damage in &file.damages {
+            let kind = match damage.kind {
+                DamageKind::Truncated => "truncated",
+                DamageKind::Corrupted => "corrupted"
+            };
+            println!("  {} record at offset {} ({} bytes)", kind, damage.offset, damage.len);
+        }
+    }
+    for path in &report.orphaned {
+        println!("orphaned file: {}", path.display());
+    }
+    for path in &report.unexpected {
+        println!("unexpected file: {}", path.display());
+    }
+    if let Some(dest) = &args.repair {
+        let salvaged = repair(&args.dir, dest)?;
+        println!("salvaged {} records into {}", salvaged, dest.display());
+    } else if !report.is_healthy() {
+        println!("damaged records found, run with --repair <DEST> to salvage readable records");
+        exit(1);
+    }
+    Ok(())
+}
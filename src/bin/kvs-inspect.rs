@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use clap::{Parser, ValueEnum};
+use kvs::engine::kvstore::inspect::{Inspector, OpType, RecordInfo};
+use kvs::Result;
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+#[command(author, version, about = "Print the records of KvStore log files", long_about = None)]
+struct Args {
+    #[arg(value_name = "DIR", default_value = ".")]
+    dir: PathBuf,
+    #[arg(short, long, help = "Only print commands of this key")]
+    key: Option<String>,
+    #[arg(short, long, value_name = "FILE_STEM", help = "Only print commands of N.log")]
+    file: Option<u64>,
+    #[arg(short, long, value_enum)]
+    op: Option<Op>,
+    #[arg(short = 'F', long, help = "Keep printing commands appended to the active log")]
+    follow: bool
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Op {
+    Set,
+    Rm
+}
+
+impl Args {
+    fn matches(&self, record: &RecordInfo) -> bool {
+        let op_matches = match self.op {
+            Some(Op::Set) => record.op == OpType::Set,
+            Some(Op::Rm) => record.op == OpType::Remove,
+            None => true
+        };
+        op_matches
+            && self.key.as_ref().is_none_or(|key| *key == record.key)
+            && self.file.is_none_or(|file_stem| file_stem == record.file_stem)
+    }
+}
+
+fn print_record(record: &RecordInfo) {
+    let cmd = match &record.value {
+        Some(value) => format!("set {:?} {:?}", record.key, value),
+        None => format!("rm {:?}", record.key)
+    };
+    println!(
+        "{}.log offset={} len={} {}{} {}",
+        record.file_stem,
+        record.offset,
+        record.len,
+        if record.batch { "batch " } else { "" },
+        if record.live { "live" } else { "dead" },
+        cmd
+    );
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut inspector = Inspector::new(&args.dir);
+    loop {
+        for record in inspector.read_new()?.iter().filter(|record| args.matches(record)) {
+            print_record(record);
+        }
+        if !args.follow {
+            return Ok(());
+        }
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
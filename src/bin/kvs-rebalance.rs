@@ -0,0 +1,23 @@
+use clap::Parser;
+use kvs::sharding::{rebalance, DEFAULT_VNODES};
+use kvs::Result;
+
+#[derive(Parser)]
+#[command(author, version, about = "Move keys between kvs-servers sharded by ShardedKvsClient after nodes are added or removed", long_about = None)]
+struct Args {
+    #[arg(long, value_name = "ADDR,...", value_delimiter = ',', required = true, help = "Nodes of the cluster before the change")]
+    from: Vec<String>,
+    #[arg(long, value_name = "ADDR,...", value_delimiter = ',', required = true, help = "Nodes of the cluster after the change")]
+    to: Vec<String>,
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_VNODES, help = "Virtual nodes per node, as used by clients")]
+    vnodes: usize,
+    #[arg(short = 'a', long, value_name = "PASSWORD", help = "Authenticate to servers started with --requirepass")]
+    password: Option<String>
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let moved = rebalance(&args.from, &args.to, args.vnodes, args.password.as_deref())?;
+    eprintln!("Moved {} keys", moved);
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use clap::{Parser, ValueEnum};
+use kvs::dump::restore;
+use kvs::engine::{KvsEngine, Sled};
+use kvs::{KvStore, Result};
+
+#[derive(Parser)]
+#[command(author, version, about = "Load a dump written by kvs-dump into a store", long_about = None)]
+struct Args {
+    #[arg(short, long, value_enum, default_value_t = Engine::Kvs)]
+    engine: Engine,
+    #[arg(short, long, value_name = "DIR", default_value = ".", help = "Working directory of kvs-server")]
+    dir: PathBuf,
+    #[arg(short, long, value_name = "FILE", help = "Read from file instead of stdin")]
+    input: Option<PathBuf>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut engine: Box<dyn KvsEngine> = match args.engine {
+        Engine::Kvs => Box::new(KvStore::open(&args.dir)?),
+        Engine::Sled => Box::new(Sled::new(sled::open(args.dir.join("my_db"))?)?)
+    };
+    let reader: Box<dyn BufRead> = match &args.input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock())
+    };
+    let count = restore(engine.as_mut(), reader)?;
+    eprintln!("Restored {} keys", count);
+    Ok(())
+}
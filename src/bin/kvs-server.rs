@@ -1,20 +1,130 @@
-use std::env;
+use std::{env, fs};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use std::fmt::{Display, Formatter};
 use clap::{arg, Parser, ValueEnum};
-use kvs::{KvsServer, Result};
-use kvs::engine::Sled;
+use kvs::{KvError, KvsServer, Result};
+use kvs::engine::{KvsEngine, RaftEngine, Sled};
 use kvs::KvStore;
 
 const DEFAULT_PORT: u16 = 4000;
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 const DEFAULT_ENGINE: Engine = Engine::Kvs;
+/// Slot assignment of a cluster node, in the working directory like the data.
+const CLUSTER_CONFIG: &str = "nodes.conf";
+/// Sequence number of the latest mutation a follower applied, in the working directory like the data.
+const REPLICATION_STATE: &str = "replication.seq";
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..))]
+    #[arg(short, long, value_name = "PORT", help = "TCP port to listen to, 0 disables TCP [default: 4000]")]
     port: Option<u16>,
+    #[arg(long, value_name = "ADDR", help = "IP address to listen to, e.g. 0.0.0.0 for every interface [default: 127.0.0.1]")]
+    bind: Option<IpAddr>,
+    #[arg(long, value_name = "PATH", help = "Also listen to a unix socket")]
+    unix_socket: Option<PathBuf>,
+    #[arg(long, value_name = "MODE", value_parser = parse_mode, help = "Octal permissions of the unix socket file, e.g. 770")]
+    unix_socket_perm: Option<u32>,
     #[arg(short, long, value_enum)]
-    engine: Option<Engine>
+    engine: Option<Engine>,
+    #[arg(long, value_name = "ADDR", help = "Serve prometheus metrics at http://<ADDR>/metrics")]
+    metrics_addr: Option<String>,
+    #[arg(long, value_name = "ADDR", help = "Serve the HTTP/JSON gateway at http://<ADDR>, or https:// with TLS")]
+    http_addr: Option<String>,
+    #[arg(long, value_name = "MICROS", help = "Record commands slower than this into slowlog [default: 10000]")]
+    slowlog_threshold: Option<u64>,
+    #[arg(long, value_name = "LEN", help = "Keep at most this many slowlog entries [default: 128]")]
+    slowlog_max_len: Option<usize>,
+    #[arg(long, value_name = "PASSWORD", help = "Require clients to AUTH with this password")]
+    requirepass: Option<String>,
+    #[arg(long, value_name = "FILE", help = "Load users from an ACL file of `user <name> <rules...>` lines")]
+    aclfile: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "tls_key", help = "Accept only TLS connections with this PEM certificate chain")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "tls_cert", help = "PEM private key of the TLS certificate")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "tls_cert", help = "Require client certificates signed by the CAs of this PEM file")]
+    tls_client_ca: Option<PathBuf>,
+    #[arg(long, value_name = "HOST:PORT", help = "Follow the leader at this address, replacing local data and rejecting writes")]
+    replica_of: Option<String>,
+    #[arg(long, value_name = "PASSWORD", requires = "replica_of", help = "AUTH to the leader with this password")]
+    leader_password: Option<String>,
+    #[arg(long, value_name = "ID", requires = "raft_nodes", help = "Replicate the engine through raft as this node of --raft-nodes")]
+    raft_id: Option<u64>,
+    #[arg(long, value_name = "ID=ADDR,...", requires = "raft_id", help = "Raft addresses of every node of the cluster, e.g. 1=127.0.0.1:7001,2=127.0.0.1:7002")]
+    raft_nodes: Option<String>,
+    #[arg(long, value_name = "SECRET", requires = "raft_id", help = "Secret shared by every node of the raft cluster, required with --raft-id")]
+    raft_secret: Option<String>,
+    #[arg(long, help = "Run as a cluster node, serving only keys of the hash slots assigned to it in ./nodes.conf")]
+    cluster: bool,
+    #[arg(long, value_name = "HOST:PORT", requires = "cluster", help = "Address given to clients redirected to this cluster node [default: --bind address and --port, or 127.0.0.1 when bound to every interface]")]
+    announce_addr: Option<String>,
+    #[arg(short, long, value_name = "FILE", help = "Read options from a file of `<option> <value>` lines")]
+    config: Option<PathBuf>
+}
+
+impl Args {
+    /// Fill options not given on command line from config file.
+    /// Each line is an option name without leading `--` and its value, `#` starts a comment.
+    fn apply_config(&mut self, path: &Path) -> Result<()> {
+        let invalid = |line: &str| KvError::Message(format!("invalid config line: {}", line));
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).ok_or_else(|| invalid(line))?;
+            let value = value.trim();
+            match name {
+                "port" => { self.port.get_or_insert(value.parse().map_err(|_| invalid(line))?); }
+                "bind" => { self.bind.get_or_insert(value.parse().map_err(|_| invalid(line))?); }
+                "unix-socket" => { self.unix_socket.get_or_insert(PathBuf::from(value)); }
+                "unix-socket-perm" => { self.unix_socket_perm.get_or_insert(parse_mode(value).map_err(|_| invalid(line))?); }
+                "engine" => { self.engine.get_or_insert(Engine::from_str(value, true).map_err(|_| invalid(line))?); }
+                "metrics-addr" => { self.metrics_addr.get_or_insert(value.to_owned()); }
+                "http-addr" => { self.http_addr.get_or_insert(value.to_owned()); }
+                "slowlog-threshold" => { self.slowlog_threshold.get_or_insert(value.parse().map_err(|_| invalid(line))?); }
+                "slowlog-max-len" => { self.slowlog_max_len.get_or_insert(value.parse().map_err(|_| invalid(line))?); }
+                "requirepass" => { self.requirepass.get_or_insert(value.to_owned()); }
+                "aclfile" => { self.aclfile.get_or_insert(PathBuf::from(value)); }
+                "tls-cert" => { self.tls_cert.get_or_insert(PathBuf::from(value)); }
+                "tls-key" => { self.tls_key.get_or_insert(PathBuf::from(value)); }
+                "tls-client-ca" => { self.tls_client_ca.get_or_insert(PathBuf::from(value)); }
+                "replica-of" => { self.replica_of.get_or_insert(value.to_owned()); }
+                "leader-password" => { self.leader_password.get_or_insert(value.to_owned()); }
+                "raft-id" => { self.raft_id.get_or_insert(value.parse().map_err(|_| invalid(line))?); }
+                "raft-nodes" => { self.raft_nodes.get_or_insert(value.to_owned()); }
+                "raft-secret" => { self.raft_secret.get_or_insert(value.to_owned()); }
+                "cluster" => match value {
+                    "yes" => self.cluster = true,
+                    "no" => {}
+                    _ => return Err(invalid(line))
+                },
+                "announce-addr" => { self.announce_addr.get_or_insert(value.to_owned()); }
+                _ => return Err(invalid(line))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse `id=addr` pairs separated by commas.
+fn parse_raft_nodes(value: &str) -> Result<BTreeMap<u64, String>> {
+    value.split(',')
+        .map(|node| {
+            let invalid = || KvError::Message(format!("invalid raft node, expect <id>=<addr>: {}", node));
+            let (id, addr) = node.trim().split_once('=').ok_or_else(invalid)?;
+            Ok((id.parse().map_err(|_| invalid())?, addr.to_owned()))
+        })
+        .collect()
+}
+
+fn parse_mode(value: &str) -> std::result::Result<u32, String> {
+    u32::from_str_radix(value, 8).map_err(|_| format!("invalid octal mode: {}", value))
 }
 
 #[derive(Debug, Copy, Clone ,PartialOrd, PartialEq, Ord, Eq, ValueEnum)]
@@ -36,21 +146,111 @@ fn main() -> Result<()> {
     env::set_var("RUST_LOG", "debug");
     env_logger::init();
     log::info!("Running kvs server version {}", env!("CARGO_PKG_VERSION"));
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(config) = args.config.clone() {
+        args.apply_config(&config)?;
+    }
     let port = args.port.unwrap_or_else(|| DEFAULT_PORT);
-    let addr = format!("127.0.0.1:{}", port);
     let engine = args.engine.unwrap_or_else(|| DEFAULT_ENGINE);
     match engine {
-        Engine::Kvs =>  {
-            let mut server = KvsServer::new(KvStore::open(".")?)?;
-            log::info!("Listening to {}", addr);
-            server.run(&addr)?;
-        },
-        Engine::Sled => {
-            let mut server = KvsServer::new(Sled::new(sled::open("my_db")?))?;
+        Engine::Kvs => start(KvStore::open(".")?, port, &args),
+        Engine::Sled => start(Sled::new(sled::open("my_db")?)?, port, &args)
+    }
+}
+
+/// Serve `engine`, replicated through raft if the node is configured.
+fn start<E: KvsEngine + Send + 'static>(engine: E, port: u16, args: &Args) -> Result<()> {
+    match (args.raft_id, &args.raft_nodes, &args.raft_secret) {
+        (Some(id), Some(nodes), Some(secret)) => {
+            let nodes = parse_raft_nodes(nodes)?;
+            log::info!("Running as raft node {} of {} nodes", id, nodes.len());
+            run(KvsServer::new(RaftEngine::open(engine, "raft", id, nodes, secret)?)?, port, args)
+        }
+        (None, None, None) => run(KvsServer::new(engine)?, port, args),
+        // options from config file are not checked by clap
+        _ => Err(KvError::Message("raft-id, raft-nodes and raft-secret should be given together".to_owned()))
+    }
+}
+
+fn run<E: KvsEngine + Send + 'static>(mut server: KvsServer<E>, port: u16, args: &Args) -> Result<()> {
+    if let Some(threshold) = args.slowlog_threshold {
+        server.set_slowlog_threshold(Duration::from_micros(threshold));
+    }
+    if let Some(max_len) = args.slowlog_max_len {
+        server.set_slowlog_max_len(max_len);
+    }
+    if let Some(aclfile) = &args.aclfile {
+        server.load_acl_file(aclfile)?;
+        log::info!("Loaded users from {}", aclfile.display());
+    }
+    if let Some(password) = &args.requirepass {
+        server.set_requirepass(password);
+        log::info!("Password authentication is required");
+    }
+    match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            server.set_tls(cert, key, args.tls_client_ca.as_deref())?;
+            log::info!("TLS is enabled{}", if args.tls_client_ca.is_some() { " with client verification" } else { "" });
+        }
+        (None, None) => {}
+        // options from config file are not checked by clap
+        _ => return Err(KvError::Message("tls-cert and tls-key should be given together".to_owned()))
+    }
+    if args.cluster {
+        if port == 0 {
+            return Err(KvError::Message("cluster mode redirects clients to the TCP port, which should not be 0".to_owned()));
+        }
+        let myself = match &args.announce_addr {
+            Some(addr) => addr.clone(),
+            None => {
+                let ip = args.bind.filter(|ip| !ip.is_unspecified()).unwrap_or(DEFAULT_BIND);
+                SocketAddr::new(ip, port).to_string()
+            }
+        };
+        server.enable_cluster(&myself, Path::new(CLUSTER_CONFIG))?;
+        log::info!("Running as cluster node {}", myself);
+    }
+    if let Some(leader) = &args.replica_of {
+        server.replicate_from(leader, args.leader_password.as_deref(), Path::new(REPLICATION_STATE))?;
+        log::info!("Replicating from {}", leader);
+    }
+    if let Some(metrics_addr) = &args.metrics_addr {
+        server.serve_metrics(metrics_addr)?;
+        log::info!("Serving metrics at http://{}/metrics", metrics_addr);
+    }
+    if let Some(http_addr) = &args.http_addr {
+        server.serve_http(http_addr)?;
+        let scheme = if args.tls_cert.is_some() { "https" } else { "http" };
+        log::info!("Serving HTTP gateway at {}://{}", scheme, http_addr);
+    }
+    match (port, serve_unix(&server, args)?) {
+        (0, Some(unix)) => unix.join().expect("unix socket listener panicked"),
+        (0, None) => Err(KvError::Message("port 0 disables TCP, a unix socket is required".to_owned())),
+        _ => {
+            let addr = SocketAddr::new(args.bind.unwrap_or(DEFAULT_BIND), port);
             log::info!("Listening to {}", addr);
-            server.run(&addr)?;
+            server.run(addr)
         }
     }
-    Ok(())
+}
+
+/// Serve the unix socket, if any, in a background thread.
+#[cfg(unix)]
+fn serve_unix<E: KvsEngine + Send + 'static>(server: &KvsServer<E>, args: &Args) -> Result<Option<JoinHandle<Result<()>>>> {
+    let path = match &args.unix_socket {
+        Some(path) => path,
+        None => return Ok(None)
+    };
+    let listener = kvs::server::bind_unix(path, args.unix_socket_perm)?;
+    log::info!("Listening to unix socket {}", path.display());
+    let mut server = server.clone();
+    Ok(Some(std::thread::spawn(move || server.run_listener(listener))))
+}
+
+#[cfg(not(unix))]
+fn serve_unix<E: KvsEngine + Send + 'static>(_server: &KvsServer<E>, args: &Args) -> Result<Option<JoinHandle<Result<()>>>> {
+    match args.unix_socket {
+        Some(_) => Err(KvError::Message("unix sockets are not supported on this platform".to_owned())),
+        None => Ok(None)
+    }
 }
\ No newline at end of file
@@ -1,28 +1,387 @@
-use std::io::{Read, Write};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::mem;
 use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
 use serde_resp::{RESPType};
 use crate::{KvError, Request, Result};
+use crate::cluster::{key_slot, Route, SlotMap, SlotRange, SlotState};
+use crate::engine::{Change, Event};
+use crate::pubsub::Message;
+use crate::replication::{self, Role, SyncStart};
+use crate::slowlog::SlowLogEntry;
+
+/// A connection to the server, plain or encrypted.
+trait Stream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Stream for StreamOwned<ClientConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+/// Redirects followed at most per command.
+const MAX_REDIRECTS: usize = 5;
 
 pub struct KvsClient {
-    stream: TcpStream
+    stream: Box<dyn Stream>,
+    /// Set for TCP and TLS clients, which follow cluster redirects to other nodes.
+    cluster: Option<Box<Routing>>,
+    /// Set for unix socket clients, which connect to it again after a broken connection.
+    endpoint: Option<Endpoint>,
+    /// Authenticates new connections, to other nodes or after a broken connection.
+    auth: Option<Request>,
+    options: ClientOptions,
+    /// Set when the connection fails with `options.reconnect`, the next command connects again.
+    broken: bool,
+    /// Set when a request fails on the connection, see `failed`.
+    failed: bool
+}
+
+/// Timeouts, reconnection and retries of a client, none of them unless set by `ClientBuilder`.
+#[derive(Debug, Clone, Default)]
+struct ClientOptions {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    reconnect: bool,
+    retries: usize,
+    retry_writes: bool,
+    backoff: Duration,
+    max_backoff: Duration
+}
+
+/// A server connected to other than by TCP.
+enum Endpoint {
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+impl Endpoint {
+    /// Connect with the timeouts of `options`, see `open`.
+    fn open(&self, options: &ClientOptions) -> Result<Box<dyn Stream>> {
+        match self {
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                // connecting to a unix socket does not block, so there is no connect timeout
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(options.read_timeout)?;
+                stream.set_write_timeout(options.write_timeout)?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+/// TLS settings of a client. Every node of a cluster is checked against the same server name,
+/// so their certificates should all be valid for it.
+struct TlsSettings {
+    server_name: ServerName,
+    config: Arc<ClientConfig>
+}
+
+impl TlsSettings {
+    /// Complete the handshake over `stream`, so untrusted servers are rejected here.
+    fn handshake(&self, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        let conn = ClientConnection::new(self.config.clone(), self.server_name.clone())?;
+        let mut stream = StreamOwned::new(conn, stream);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).map_err(|err| io_error(err, "the TLS handshake"))?;
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+/// Options of a TCP, unix socket or TLS connection, see `KvsClient::builder`.
+///
+/// TCP and TLS clients follow `MOVED` and `ASK` redirects of a cluster, connecting to other
+/// nodes the same way. Unix socket clients can only reach the node they are connected to, so
+/// a redirect fails their command with `KvError::Redirect`.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    options: ClientOptions,
+    auth: Option<Request>
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        ClientBuilder {
+            options: ClientOptions {
+                reconnect: true,
+                retries: 3,
+                backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_secs(1),
+                ..ClientOptions::default()
+            },
+            auth: None
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Fail with `KvError::Timeout` if connecting to a node takes longer.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with `KvError::Timeout` if no part of a reply arrives for this long.
+    /// Subscriptions and feeds wait for their messages without a timeout.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with `KvError::Timeout` if a request cannot be sent for this long.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.options.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Close the connection after an I/O error, a timeout or an unreadable reply, and connect
+    /// again for the next command, authenticated as before. On by default.
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.options.reconnect = reconnect;
+        self
+    }
+
+    /// Retry an idempotent command, see `Request::is_idempotent`, up to `retries` times after
+    /// it fails on the connection, 3 by default. Needs `reconnect`. Writes are retried only
+    /// with `retry_writes`.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.options.retries = retries;
+        self
+    }
+
+    /// Also retry `SET` and `MSET`, off by default. A write whose reply is lost may have been
+    /// applied, so its retry overwrites any value another client wrote to the keys in between.
+    pub fn retry_writes(mut self, retry_writes: bool) -> Self {
+        self.options.retry_writes = retry_writes;
+        self
+    }
+
+    /// Wait `initial` before the first retry of a command, twice as long before each next one
+    /// up to `max`. 50ms up to 1s by default.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.options.backoff = initial;
+        self.options.max_backoff = max;
+        self
+    }
+
+    /// Authenticate as the default user.
+    pub fn auth(mut self, password: &str) -> Self {
+        self.auth = Some(Request::auth(None, password));
+        self
+    }
+
+    /// Log in as a user defined by the server ACL.
+    pub fn user(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some(Request::auth(Some(username), password));
+        self
+    }
+
+    /// Connect to a server, or a node of a cluster like `KvsClient::connect`.
+    pub fn connect<A: ToSocketAddrs>(self, addr: A) -> Result<KvsClient> {
+        let client = KvsClient::routed(addr, None, self.options)?;
+        Self::authenticate(client, self.auth)
+    }
+
+    /// Connect to a server listening on a unix socket at `path`, like `KvsClient::connect_unix`,
+    /// which fails commands redirected by a cluster node with `KvError::Redirect`.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(self, path: P) -> Result<KvsClient> {
+        let client = KvsClient::with_endpoint(Endpoint::Unix(path.as_ref().to_owned()), self.options)?;
+        Self::authenticate(client, self.auth)
+    }
+
+    /// Connect to a server started with TLS, or a node of a cluster like `KvsClient::connect_tls`.
+    pub fn connect_tls<A: ToSocketAddrs>(self, addr: A, server_name: &str, config: Arc<ClientConfig>) -> Result<KvsClient> {
+        let client = KvsClient::routed(addr, Some(tls_settings(server_name, config)?), self.options)?;
+        Self::authenticate(client, self.auth)
+    }
+
+    fn authenticate(mut client: KvsClient, auth: Option<Request>) -> Result<KvsClient> {
+        if let Some(auth) = auth {
+            client.send_auth(auth)?;
+        }
+        Ok(client)
+    }
+}
+
+/// Connections of a client to the nodes of a cluster.
+struct Routing {
+    /// The node connected first, commands without keys are sent to it.
+    home: String,
+    /// The node `stream` is connected to.
+    current: String,
+    /// Connections to nodes other than `current`.
+    idle: BTreeMap<String, Box<dyn Stream>>,
+    /// Owners of slots as last reported by the cluster, loaded on the first `MOVED`.
+    slots: Option<SlotMap>,
+    /// Set if nodes are connected with TLS.
+    tls: Option<TlsSettings>
+}
+
+impl Routing {
+    /// The owner of the slot of the request keys if known, otherwise the home node.
+    fn node_for(&self, request: &Request) -> String {
+        let slot = request.keys().first().map(|key| key_slot(key));
+        slot.and_then(|slot| self.slots.as_ref()?.owner(slot))
+            .unwrap_or(self.home.as_str())
+            .to_owned()
+    }
 }
 
 impl KvsClient {
+    /// Connect to a server, following `MOVED` and `ASK` redirects if it is a node of a cluster.
+    /// Commands wait for their replies without a timeout, see `builder` for other options.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
+        Self::routed(addr, None, ClientOptions::default())
+    }
+
+    /// Configure timeouts, reconnection and retries of a TCP, unix socket or TLS connection.
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use kvs::KvsClient;
+    /// let mut client = KvsClient::builder()
+    ///     .connect_timeout(Duration::from_secs(1))
+    ///     .read_timeout(Duration::from_secs(2))
+    ///     .retries(5)
+    ///     .connect("127.0.0.1:4000")?;
+    /// client.set("key", "value")?;
+    /// # Ok::<(), kvs::KvError>(())
+    /// ```
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Connect over TCP, with TLS if `tls` is set, and follow cluster redirects.
+    fn routed<A: ToSocketAddrs>(addr: A, tls: Option<TlsSettings>, options: ClientOptions) -> Result<Self> {
+        let stream = open(addr, &options)?;
+        let node = stream.peer_addr()?.to_string();
+        let stream = match &tls {
+            Some(tls) => tls.handshake(stream)?,
+            None => Box::new(stream)
+        };
+        Ok(Self {
+            stream,
+            cluster: Some(Box::new(Routing {
+                home: node.clone(),
+                current: node,
+                idle: BTreeMap::new(),
+                slots: None,
+                tls
+            })),
+            endpoint: None,
+            auth: None,
+            options,
+            broken: false,
+            failed: false
+        })
+    }
+
+    #[cfg(unix)]
+    fn with_endpoint(endpoint: Endpoint, options: ClientOptions) -> Result<Self> {
         Ok(Self {
-            stream
+            stream: endpoint.open(&options)?,
+            cluster: None,
+            endpoint: Some(endpoint),
+            auth: None,
+            options,
+            broken: false,
+            failed: false
         })
     }
 
+    /// Connect to a server listening on a unix socket at `path`. A command redirected by a
+    /// cluster node fails with `KvError::Redirect`, since other nodes are not reachable this way.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_endpoint(Endpoint::Unix(path.as_ref().to_owned()), ClientOptions::default())
+    }
+
+    /// Connect to a server started with TLS, see `tls::client_config` to build `config`.
+    /// `server_name` is checked against the server certificate.
+    /// The handshake is completed before return, so untrusted servers are rejected here.
+    /// Cluster redirects are followed like `connect` does, with TLS and the same `server_name`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, server_name: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        Self::routed(addr, Some(tls_settings(server_name, config)?), ClientOptions::default())
+    }
+
+    /// Connect to a server started with `--requirepass` and authenticate the connection.
+    pub fn connect_with_auth<A: ToSocketAddrs>(addr: A, password: &str) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.auth(password)?;
+        Ok(client)
+    }
+
+    /// Connect and log in as a user defined by the server ACL.
+    pub fn connect_with_user<A: ToSocketAddrs>(addr: A, username: &str, password: &str) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.auth_user(username, password)?;
+        Ok(client)
+    }
+
+    /// Authenticate the connection as the default user,
+    /// following commands are allowed only after success.
+    pub fn auth(&mut self, password: &str) -> Result<()> {
+        self.send_auth(Request::auth(None, password))
+    }
+
+    /// Authenticate the connection as `username`.
+    pub fn auth_user(&mut self, username: &str, password: &str) -> Result<()> {
+        self.send_auth(Request::auth(Some(username), password))
+    }
+
+    fn send_auth(&mut self, request: Request) -> Result<()> {
+        match self.request(request.clone())? {
+            RESPType::SimpleString(_) => {
+                if let Some(routing) = &mut self.cluster {
+                    // other nodes are connected again with the new credentials
+                    routing.idle.clear();
+                }
+                self.auth = Some(request);
+                Ok(())
+            }
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Check the connection, allowed before authentication.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.request(Request::Ping { message: None })? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
     pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let command: RESPType = Request::set(key, value).into();
-        let cmd_str = serde_resp::to_string(&command)?;
-        self.stream.write_all(cmd_str.as_bytes())?;
-        // let mut response = String::new();
-        // self.stream.read_to_string(&mut response).unwrap();
-        let response: RESPType = serde_resp::from_reader(&mut self.stream)?;
-        match response {
+        match self.request(Request::set(key, value))? {
             RESPType::SimpleString(_) => Ok(()),
             RESPType::Error(err) => Err(KvError::Message(err)),
             _ => Err(KvError::Message("Unknown Error".to_owned()))
@@ -30,15 +389,9 @@ impl KvsClient {
     }
 
     pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        let command: RESPType = Request::get(key).into();
-        let cmd_str = serde_resp::to_string(&command)?;
-        self.stream.write_all(cmd_str.as_bytes())?;
-        let mut response = String::new();
-        self.stream.read_to_string(&mut response).unwrap();
-        let resp: RESPType = serde_resp::from_str(&response).unwrap();
         // currently only bulk str
-        match resp {
-            RESPType::BulkString(buf) => Ok(Some(String::from_utf8(buf).unwrap())),
+        match self.request(Request::get(key))? {
+            RESPType::BulkString(buf) => Ok(Some(String::from_utf8(buf)?)),
             RESPType::Error(err) => Err(KvError::Message(err)),
             RESPType::SimpleString(msg) => Ok(Some(msg)),
             RESPType::None => Ok(None),
@@ -47,18 +400,728 @@ impl KvsClient {
         }
     }
 
+    /// Like `get` but return the value as stored, which may not be UTF-8.
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.request(Request::get(key))? {
+            RESPType::BulkString(buf) => Ok(Some(buf)),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            RESPType::None => Ok(None),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
     pub fn rm(&mut self, key: &str) -> Result<Option<String>> {
-        let command: RESPType = Request::remove(key).into();
-        let cmd_str = serde_resp::to_string(&command)?;
-        self.stream.write_all(cmd_str.as_bytes())?;
-        let mut response = String::new();
-        self.stream.read_to_string(&mut response).unwrap();
-        let response: RESPType = serde_resp::from_str(&response).unwrap();
-        match response {
+        match self.request(Request::remove(key))? {
             RESPType::SimpleString(msg) => Ok(Some(msg)),
             RESPType::Error(err) => Err(KvError::Message(err)),
             RESPType::None => Ok(None),
             _ => Err(KvError::Message("Unknown Error".to_owned()))
         }
     }
-}
\ No newline at end of file
+
+    pub fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<()> {
+        match self.request(Request::mset(pairs))? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get values of several keys in a single round trip.
+    /// The result has the same length as `keys`, with `None` for non-existent keys.
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        match self.request(Request::mget(keys))? {
+            RESPType::Array(arr) => arr.into_iter()
+                .map(|resp| match resp {
+                    RESPType::BulkString(buf) => Ok(Some(String::from_utf8(buf)?)),
+                    RESPType::None => Ok(None),
+                    _ => Err(KvError::Message("Unknown Error".to_owned()))
+                })
+                .collect(),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Remove several keys in a single round trip, return the number of removed keys.
+    pub fn mdel(&mut self, keys: &[&str]) -> Result<usize> {
+        match self.request(Request::mdel(keys))? {
+            RESPType::Integer(n) => Ok(n as usize),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get every pair whose key starts with `prefix`, sorted by key.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan_request(Request::scan(prefix))
+    }
+
+    /// Get the first `count` pairs whose key starts with `prefix` and is greater than `after`,
+    /// sorted by key. Pass the last key of a page as `after` to get the next one.
+    pub fn scan_page(&mut self, prefix: &str, after: Option<&str>, count: usize) -> Result<Vec<(String, String)>> {
+        self.scan_request(Request::Scan { prefix: prefix.to_owned(), after: after.map(str::to_owned), count: Some(count) })
+    }
+
+    fn scan_request(&mut self, request: Request) -> Result<Vec<(String, String)>> {
+        match self.request(request)? {
+            RESPType::Array(arr) if arr.len() % 2 == 0 => {
+                let mut fields = arr.into_iter().map(|resp| match resp {
+                    RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+                    _ => Err(KvError::Message("Unknown Error".to_owned()))
+                });
+                let mut pairs = vec![];
+                while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+                    pairs.push((key?, value?));
+                }
+                Ok(pairs)
+            }
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Ask the server to write a consistent copy of its store into `dir`.
+    /// `dir` is a path on the server host.
+    pub fn backup(&mut self, dir: &str) -> Result<()> {
+        match self.request(Request::backup(dir))? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get server information, all sections if `section` is `None`.
+    pub fn info(&mut self, section: Option<&str>) -> Result<String> {
+        match self.request(Request::info(section))? {
+            RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get the number of keys in the server store.
+    pub fn dbsize(&mut self) -> Result<u64> {
+        match self.request(Request::DbSize)? {
+            RESPType::Integer(n) => Ok(n as u64),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get the latest `count` slow commands, newest first, all of them if `count` is `None`.
+    pub fn slowlog_get(&mut self, count: Option<usize>) -> Result<Vec<SlowLogEntry>> {
+        match self.request(Request::SlowLogGet { count })? {
+            RESPType::Array(entries) => entries.into_iter().map(SlowLogEntry::try_from).collect(),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    pub fn slowlog_len(&mut self) -> Result<u64> {
+        match self.request(Request::SlowLogLen)? {
+            RESPType::Integer(n) => Ok(n as u64),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    pub fn slowlog_reset(&mut self) -> Result<()> {
+        match self.request(Request::SlowLogReset)? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Create a user or modify it with ACL rules, see `Acl` for the rules.
+    pub fn acl_setuser(&mut self, username: &str, rules: &[&str]) -> Result<()> {
+        let request = Request::AclSetUser {
+            username: username.to_owned(),
+            rules: rules.iter().map(|rule| rule.to_string()).collect()
+        };
+        match self.request(request)? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Remove users, return the number of removed users.
+    pub fn acl_deluser(&mut self, usernames: &[&str]) -> Result<usize> {
+        let request = Request::AclDelUser {
+            usernames: usernames.iter().map(|username| username.to_string()).collect()
+        };
+        match self.request(request)? {
+            RESPType::Integer(n) => Ok(n as usize),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Describe each user as a `user <name> <rules...>` line, passwords are not listed.
+    pub fn acl_list(&mut self) -> Result<Vec<String>> {
+        match self.request(Request::AclList)? {
+            RESPType::Array(arr) => arr.into_iter()
+                .map(|resp| match resp {
+                    RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+                    _ => Err(KvError::Message("Unknown Error".to_owned()))
+                })
+                .collect(),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get the user the connection is authenticated as.
+    pub fn acl_whoami(&mut self) -> Result<String> {
+        match self.request(Request::AclWhoAmI)? {
+            RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Publish a message to a channel, return the number of subscriptions it is sent to.
+    pub fn publish(&mut self, channel: &str, message: &str) -> Result<u64> {
+        let request = Request::Publish { channel: channel.to_owned(), message: message.to_owned() };
+        match self.request(request)? {
+            RESPType::Integer(n) => Ok(n as u64),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Subscribe to channels, the connection is then used only to receive messages.
+    /// Returns once the server confirms every subscription.
+    pub fn subscribe(self, channels: &[&str]) -> Result<Subscription> {
+        let channels = channels.iter().map(|channel| channel.to_string()).collect();
+        self.start_subscription(Request::Subscribe { channels })
+    }
+
+    /// Subscribe to every channel matching any of the glob `patterns`.
+    pub fn psubscribe(self, patterns: &[&str]) -> Result<Subscription> {
+        let patterns = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        self.start_subscription(Request::PSubscribe { patterns })
+    }
+
+    /// Watch changes of keys starting with any of `prefixes`, made by any client after the call.
+    /// The connection is then used only to receive events.
+    pub fn watch(mut self, prefixes: &[&str]) -> Result<KeyEvents> {
+        let prefixes = prefixes.iter().map(|prefix| prefix.to_string()).collect();
+        self.confirm_subscription(Request::Watch { prefixes })?;
+        self.stream.set_read_timeout(None)?;
+        Ok(KeyEvents { client: self, failed: false })
+    }
+
+    /// Follow every mutation with a sequence number greater than `since`, retained ones first.
+    /// The connection is then used only to receive changes. Resume after a disconnect with
+    /// the sequence number of the last change received.
+    pub fn changes(mut self, since: u64) -> Result<ChangeFeed> {
+        self.send(Request::Changes { since })?;
+        self.stream.set_read_timeout(None)?;
+        Ok(ChangeFeed { client: self, failed: false })
+    }
+
+    /// Sync as a follower: resume after the mutation `since` if the server still retains it,
+    /// otherwise receive a snapshot of every pair with `ChangeFeed::next_chunk`.
+    /// The feed follows mutations after the start.
+    pub fn sync(mut self, since: Option<u64>) -> Result<(SyncStart, ChangeFeed)> {
+        let start = match self.request(Request::Sync { since })? {
+            RESPType::Error(err) => return Err(KvError::Message(err)),
+            rsp => SyncStart::try_from(rsp)?
+        };
+        self.stream.set_read_timeout(None)?;
+        Ok((start, ChangeFeed { client: self, failed: false }))
+    }
+
+    /// Get the replication role of the server.
+    pub fn role(&mut self) -> Result<Role> {
+        match self.request(Request::Role)? {
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            rsp => Role::try_from(rsp)
+        }
+    }
+
+    /// Get the slots owned by each node of a cluster.
+    pub fn cluster_slots(&mut self) -> Result<Vec<SlotRange>> {
+        match self.request(Request::ClusterSlots)? {
+            RESPType::Array(ranges) => ranges.into_iter().map(SlotRange::try_from).collect(),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Describe each node of a cluster known to the server as a line, see `Cluster::nodes`.
+    pub fn cluster_nodes(&mut self) -> Result<String> {
+        match self.request(Request::ClusterNodes)? {
+            RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    pub fn cluster_keyslot(&mut self, key: &str) -> Result<u16> {
+        match self.request(Request::ClusterKeySlot { key: key.to_owned() })? {
+            RESPType::Integer(slot) => Ok(slot as u16),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Change slots `start..=end` on the server only, see `cluster::move_slots` to change a cluster.
+    pub fn cluster_setslot(&mut self, start: u16, end: u16, state: &SlotState) -> Result<()> {
+        match self.request(Request::ClusterSetSlot { start, end, state: state.clone() })? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Get the number of keys of `slot` stored on the server.
+    pub fn cluster_countkeysinslot(&mut self, slot: u16) -> Result<u64> {
+        match self.request(Request::ClusterCountKeysInSlot { slot })? {
+            RESPType::Integer(n) => Ok(n as u64),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Move up to `count` keys of a slot migrating from the server to its target, return the
+    /// number of moved keys. The server authenticates to the target with `password` if given.
+    pub fn cluster_migrate(&mut self, slot: u16, count: usize, password: Option<&str>) -> Result<usize> {
+        let password = password.map(str::to_owned);
+        match self.request(Request::ClusterMigrate { slot, count, password })? {
+            RESPType::Integer(n) => Ok(n as usize),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Let the next command be served by the server while it imports the slot of the command.
+    pub fn asking(&mut self) -> Result<()> {
+        match self.request(Request::Asking)? {
+            RESPType::SimpleString(_) => Ok(()),
+            RESPType::Error(err) => Err(KvError::Message(err)),
+            _ => Err(KvError::Message("Unknown Error".to_owned()))
+        }
+    }
+
+    /// Send several requests at once and then read their replies, saving a round trip per
+    /// request. Each reply is returned in order of the requests, with errors of the server as
+    /// `Err`. The requests are sent on the current connection, and replies redirecting to other
+    /// nodes of a cluster are followed one request at a time.
+    ///
+    /// The server holds replies until they are read, so very large batches should be split.
+    /// A failure of the connection fails the whole batch, as it is unknown which requests were
+    /// applied, and nothing is retried.
+    ///
+    /// ```no_run
+    /// use kvs::{KvsClient, Request};
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let requests: Vec<Request> = (0..100).map(|i| Request::set(&format!("key{i}"), "value")).collect();
+    /// for reply in client.pipeline(&requests)? {
+    ///     reply?;
+    /// }
+    /// # Ok::<(), kvs::KvError>(())
+    /// ```
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Result<RESPType>>> {
+        let result = self.pipelined(requests);
+        if let Err(err) = &result {
+            self.broken = self.options.reconnect && breaks_connection(err);
+            self.failed = breaks_connection(err);
+        }
+        let mut replies = vec![];
+        for (request, reply) in requests.iter().zip(result?) {
+            let redirected = match &reply {
+                RESPType::Error(err) if self.cluster.is_some() => Route::from_error(err).is_some(),
+                _ => false
+            };
+            let reply = if redirected {
+                self.redirected_request(request.clone()).inspect_err(|err| self.failed |= breaks_connection(err))?
+            } else {
+                reply
+            };
+            replies.push(match reply {
+                RESPType::Error(err) => Err(self.reply_error(err)),
+                reply => Ok(reply)
+            });
+        }
+        Ok(replies)
+    }
+
+    /// Stop following cluster redirects, they are returned as errors.
+    pub(crate) fn no_redirects(mut self) -> Self {
+        self.cluster = None;
+        self
+    }
+
+    fn start_subscription(mut self, request: Request) -> Result<Subscription> {
+        self.confirm_subscription(request)?;
+        self.stream.set_read_timeout(None)?;
+        Ok(Subscription { client: self, failed: false })
+    }
+
+    /// Whether a request failed on the connection since the client connected, by an I/O error,
+    /// a timeout or an invalid reply, but not by an error reply of the server. The connection may
+    /// still hold the reply of a failed request, so `KvsPool` closes it instead of lending it again.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Send a subscribing request and wait for a confirmation of each of its names.
+    fn confirm_subscription(&mut self, request: Request) -> Result<()> {
+        let count = match &request {
+            Request::Subscribe { channels } => channels.len(),
+            Request::PSubscribe { patterns } => patterns.len(),
+            Request::Watch { prefixes } => prefixes.len(),
+            _ => 0
+        };
+        self.send(request)?;
+        for _ in 0..count {
+            match self.receive()? {
+                RESPType::Array(_) => {}
+                RESPType::Error(err) => return Err(KvError::Message(err)),
+                _ => return Err(KvError::Message("Unknown Error".to_owned()))
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a request and receive its reply, retrying idempotent requests that fail on the
+    /// connection as configured by `ClientBuilder`.
+    fn request(&mut self, request: Request) -> Result<RESPType> {
+        let retried = request.is_idempotent() && (self.options.retry_writes || !request.is_write());
+        let mut retries = if retried { self.options.retries } else { 0 };
+        let mut backoff = self.options.backoff;
+        loop {
+            match self.redirected_request(request.clone()) {
+                Err(err) if self.options.reconnect && breaks_connection(&err) => {
+                    self.broken = true;
+                    if retries == 0 {
+                        self.failed = true;
+                        return Err(err);
+                    }
+                    log::debug!("Retry {} in {:?} after: {}", request.name(), backoff, err);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.options.max_backoff);
+                    retries -= 1;
+                }
+                result => {
+                    self.failed |= matches!(&result, Err(err) if breaks_connection(err));
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Send a request and receive its reply. On `MOVED` the slot map of the cluster is reloaded
+    /// and the request is sent to the new owner, on `ASK` it is sent once to the importing node.
+    fn redirected_request(&mut self, request: Request) -> Result<RESPType> {
+        self.send(request.clone())?;
+        let mut rsp = self.receive()?;
+        for _ in 0..MAX_REDIRECTS {
+            let route = match &rsp {
+                RESPType::Error(err) if self.cluster.is_some() => Route::from_error(err),
+                RESPType::Error(err) if self.endpoint.is_some() && Route::from_error(err).is_some() => {
+                    return Err(KvError::Redirect(err.clone()));
+                }
+                _ => None
+            };
+            match route {
+                Some(Route::Moved(_, node)) => {
+                    self.switch(&node)?;
+                    self.load_slots()?;
+                }
+                Some(Route::Ask(_, node)) => {
+                    self.switch(&node)?;
+                    write_request(&mut self.stream, Request::Asking)?;
+                    if let RESPType::Error(err) = self.receive()? {
+                        return Err(KvError::Message(err));
+                    }
+                }
+                _ => break
+            }
+            write_request(&mut self.stream, request.clone())?;
+            rsp = self.receive()?;
+        }
+        Ok(rsp)
+    }
+
+    /// The error of an error reply, see `KvError::Redirect` for redirects of endpoint clients.
+    fn reply_error(&self, err: String) -> KvError {
+        if self.endpoint.is_some() && Route::from_error(&err).is_some() {
+            KvError::Redirect(err)
+        } else {
+            KvError::Message(err)
+        }
+    }
+
+    /// Send a request on keys to the owner of their slot, other requests to the home node.
+    fn send(&mut self, request: Request) -> Result<()> {
+        if self.broken {
+            self.reconnect()?;
+        }
+        if let Some(node) = self.cluster.as_ref().map(|routing| routing.node_for(&request)) {
+            self.switch(&node)?;
+        }
+        write_request(&mut self.stream, request)
+    }
+
+    fn receive(&mut self) -> Result<RESPType> {
+        read_reply(&mut self.stream)
+    }
+
+    /// Write all requests in one go and read as many replies.
+    fn pipelined(&mut self, requests: &[Request]) -> Result<Vec<RESPType>> {
+        if self.broken {
+            self.reconnect()?;
+        }
+        let mut commands = String::new();
+        for request in requests {
+            let command: RESPType = request.clone().into();
+            commands += &serde_resp::to_string(&command)?;
+        }
+        self.stream.write_all(commands.as_bytes()).map_err(|err| io_error(err, "sending the requests"))?;
+        self.stream.flush().map_err(|err| io_error(err, "sending the requests"))?;
+        requests.iter().map(|_| self.receive()).collect()
+    }
+
+    /// Replace the broken connection to the current node or endpoint.
+    fn reconnect(&mut self) -> Result<()> {
+        self.stream = match (&self.cluster, &self.endpoint) {
+            (Some(routing), _) => open_node(&routing.current, routing.tls.as_ref(), self.auth.as_ref(), &self.options)?,
+            (None, Some(endpoint)) => login(endpoint.open(&self.options)?, self.auth.as_ref())?,
+            (None, None) => return Err(KvError::Message("the connection is broken".to_owned()))
+        };
+        self.broken = false;
+        Ok(())
+    }
+
+    /// Make `stream` the connection to `node`, connecting and authenticating it if needed.
+    fn switch(&mut self, node: &str) -> Result<()> {
+        let routing = match &mut self.cluster {
+            Some(routing) if routing.current != node => routing,
+            _ => return Ok(())
+        };
+        let stream = match routing.idle.remove(node) {
+            Some(stream) => stream,
+            None => open_node(node, routing.tls.as_ref(), self.auth.as_ref(), &self.options)?
+        };
+        let previous = mem::replace(&mut self.stream, stream);
+        routing.idle.insert(mem::replace(&mut routing.current, node.to_owned()), previous);
+        Ok(())
+    }
+
+    /// Reload the slot map from the current node.
+    fn load_slots(&mut self) -> Result<()> {
+        write_request(&mut self.stream, Request::ClusterSlots)?;
+        let ranges = match self.receive()? {
+            RESPType::Array(ranges) => ranges.into_iter().map(SlotRange::try_from).collect::<Result<Vec<_>>>()?,
+            RESPType::Error(err) => return Err(KvError::Message(err)),
+            _ => return Err(KvError::Message("Unknown Error".to_owned()))
+        };
+        if let Some(routing) = &mut self.cluster {
+            routing.slots = Some(SlotMap::from_ranges(&ranges));
+        }
+        Ok(())
+    }
+}
+
+/// Connect with the timeouts of `options`.
+fn open<A: ToSocketAddrs>(addr: A, options: &ClientOptions) -> Result<TcpStream> {
+    let stream = match options.connect_timeout {
+        Some(timeout) => {
+            let mut last_err = None;
+            let stream = addr.to_socket_addrs()?.find_map(|addr| {
+                TcpStream::connect_timeout(&addr, timeout).map_err(|err| last_err = Some(err)).ok()
+            });
+            match (stream, last_err) {
+                (Some(stream), _) => stream,
+                (None, Some(err)) => return Err(io_error(err, "connecting")),
+                (None, None) => return Err(KvError::Message("no address to connect to".to_owned()))
+            }
+        }
+        None => TcpStream::connect(addr)?
+    };
+    stream.set_read_timeout(options.read_timeout)?;
+    stream.set_write_timeout(options.write_timeout)?;
+    Ok(stream)
+}
+
+/// TLS settings checking server certificates against `server_name`.
+fn tls_settings(server_name: &str, config: Arc<ClientConfig>) -> Result<TlsSettings> {
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|_| KvError::Message(format!("invalid server name: {}", server_name)))?;
+    Ok(TlsSettings { server_name, config })
+}
+
+/// Connect to a node of a cluster, with TLS if `tls` is set, and authenticate the connection with `auth`.
+fn open_node(node: &str, tls: Option<&TlsSettings>, auth: Option<&Request>, options: &ClientOptions) -> Result<Box<dyn Stream>> {
+    let stream = open(node, options)?;
+    match tls {
+        Some(tls) => login(tls.handshake(stream)?, auth),
+        None => login(Box::new(stream), auth)
+    }
+}
+
+/// Authenticate a new connection with `auth`.
+fn login(mut stream: Box<dyn Stream>, auth: Option<&Request>) -> Result<Box<dyn Stream>> {
+    if let Some(auth) = auth {
+        write_request(&mut stream, auth.clone())?;
+        if let RESPType::Error(err) = read_reply(&mut stream)? {
+            return Err(KvError::Message(err));
+        }
+    }
+    Ok(stream)
+}
+
+fn write_request(stream: &mut Box<dyn Stream>, request: Request) -> Result<()> {
+    let command: RESPType = request.into();
+    let cmd_str = serde_resp::to_string(&command)?;
+    stream.write_all(cmd_str.as_bytes()).map_err(|err| io_error(err, "sending the request"))?;
+    stream.flush().map_err(|err| io_error(err, "sending the request"))?;
+    Ok(())
+}
+
+fn read_reply(stream: &mut Box<dyn Stream>) -> Result<RESPType> {
+    let mut reader = TimeoutReader { stream, timed_out: false };
+    match serde_resp::from_reader(&mut reader) {
+        Ok(reply) => Ok(reply),
+        Err(_) if reader.timed_out => Err(KvError::Timeout("waiting for the reply".to_owned())),
+        Err(err) => Err(err.into())
+    }
+}
+
+/// Remembers whether a read timed out, which the RESP decoder reports like any other error.
+struct TimeoutReader<'a> {
+    stream: &'a mut Box<dyn Stream>,
+    timed_out: bool
+}
+
+impl Read for TimeoutReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.stream.read(buf);
+        self.timed_out = result.as_ref().is_err_and(is_timeout);
+        result
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    // reads time out with `WouldBlock` on unix
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn io_error(err: io::Error, doing: &str) -> KvError {
+    if is_timeout(&err) {
+        KvError::Timeout(doing.to_owned())
+    } else {
+        KvError::IoError(err)
+    }
+}
+
+/// Errors after which the connection cannot be used, since a reply may be left unread.
+fn breaks_connection(err: &KvError) -> bool {
+    matches!(err, KvError::IoError(_) | KvError::RESPError(_) | KvError::Timeout(_))
+}
+
+/// Messages received by a subscribed connection, in the order they are published.
+/// The iteration blocks until the next message and ends after the first error.
+pub struct Subscription {
+    client: KvsClient,
+    failed: bool
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = loop {
+            match self.client.receive() {
+                Ok(RESPType::Array(fields)) => match fields.first() {
+                    Some(RESPType::BulkString(kind)) if kind == b"message" || kind == b"pmessage" => {
+                        break Message::try_from(RESPType::Array(fields));
+                    }
+                    // confirmations of (un)subscribing
+                    _ => continue
+                },
+                Ok(RESPType::Error(err)) => break Err(KvError::Message(err)),
+                Ok(_) => break Err(KvError::Message("Unknown Error".to_owned())),
+                Err(err) => break Err(err)
+            }
+        };
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// Changes of watched keys, in the order they are applied.
+/// The iteration blocks until the next event and ends after the first error.
+pub struct KeyEvents {
+    client: KvsClient,
+    failed: bool
+}
+
+impl Iterator for KeyEvents {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = loop {
+            match self.client.receive() {
+                Ok(RESPType::Array(fields)) => match fields.first() {
+                    Some(RESPType::BulkString(kind)) if kind == b"event" => break Event::try_from(RESPType::Array(fields)),
+                    // confirmations of (un)watching
+                    _ => continue
+                },
+                Ok(RESPType::Error(err)) => break Err(KvError::Message(err)),
+                Ok(_) => break Err(KvError::Message("Unknown Error".to_owned())),
+                Err(err) => break Err(err)
+            }
+        };
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// Mutations of the store in sequence order, see `KvsClient::changes`.
+/// The iteration blocks until the next change and ends after the first error,
+/// e.g. when the changes after the requested sequence number are compacted.
+pub struct ChangeFeed {
+    client: KvsClient,
+    failed: bool
+}
+
+impl ChangeFeed {
+    /// Receive the next chunk of pairs after `SyncStart::FullSync`, `None` after the last one.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<(String, String)>>> {
+        let pairs = match self.client.receive()? {
+            RESPType::Error(err) => return Err(KvError::Message(err)),
+            rsp => replication::chunk_from_resp(rsp)?
+        };
+        Ok(Some(pairs).filter(|pairs| !pairs.is_empty()))
+    }
+}
+
+impl Iterator for ChangeFeed {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = match self.client.receive() {
+            Ok(rsp @ RESPType::Array(_)) => Change::try_from(rsp),
+            Ok(RESPType::Error(err)) => Err(KvError::Message(err)),
+            Ok(_) => Err(KvError::Message("Unknown Error".to_owned())),
+            Err(err) => Err(err)
+        };
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
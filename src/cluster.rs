@@ -0,0 +1,670 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use serde_resp::{bulk, RESPType};
+use crate::engine::KvsEngine;
+use crate::{KvError, KvsClient, Result};
+
+/// Number of hash slots the key space is split into.
+pub const SLOTS: u16 = 16384;
+/// Keys moved per `CLUSTER MIGRATE` by `move_slots`.
+const MIGRATE_BATCH: usize = 100;
+
+/// The slot of a key: CRC16 of the key modulo `SLOTS`. If the key has a non-empty `{tag}`, only
+/// the first tag is hashed, so keys like `{user1}.name` and `{user1}.mail` share a slot and may
+/// be used by the same multi-key command.
+pub fn key_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let hashed = match bytes.iter().position(|byte| *byte == b'{') {
+        Some(open) => match bytes[open + 1..].iter().position(|byte| *byte == b'}') {
+            Some(len) if len > 0 => &bytes[open + 1..open + 1 + len],
+            _ => bytes
+        },
+        None => bytes
+    };
+    crc16(hashed) % SLOTS
+}
+
+/// CRC16-CCITT (XMODEM), as used by Redis cluster, so keys map to the same slots.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Parse a slot number, or a `<start>-<end>` range of slots.
+pub fn parse_slots(value: &str) -> Result<(u16, u16)> {
+    let invalid = || KvError::Message(format!("invalid slot or slot range: {}", value));
+    let parse = |slot: &str| slot.parse::<u16>().ok().filter(|slot| *slot < SLOTS).ok_or_else(invalid);
+    match value.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(invalid());
+            }
+            Ok((start, end))
+        }
+        None => parse(value).map(|slot| (slot, slot))
+    }
+}
+
+/// Slots `start..=end` owned by `node`, an entry of the reply of `CLUSTER SLOTS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    pub node: String,
+}
+
+/// Serialized as `[start, end, node]`
+impl From<SlotRange> for RESPType {
+    fn from(range: SlotRange) -> Self {
+        RESPType::Array(vec![
+            RESPType::Integer(range.start as i64),
+            RESPType::Integer(range.end as i64),
+            bulk!(range.node),
+        ])
+    }
+}
+
+impl TryFrom<RESPType> for SlotRange {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed slot range".to_owned());
+        let slot = |field: Option<RESPType>| match field {
+            Some(RESPType::Integer(slot)) if (0..SLOTS as i64).contains(&slot) => Ok(slot as u16),
+            _ => Err(malformed()),
+        };
+        let mut fields = match value {
+            RESPType::Array(fields) if fields.len() == 3 => fields.into_iter(),
+            _ => return Err(malformed()),
+        };
+        let (start, end) = (slot(fields.next())?, slot(fields.next())?);
+        match fields.next() {
+            Some(RESPType::BulkString(node)) => Ok(SlotRange { start, end, node: String::from_utf8(node)? }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// The owner of every slot, `None` for slots not assigned to any node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotMap {
+    owners: Vec<Option<String>>,
+}
+
+impl Default for SlotMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlotMap {
+    pub fn new() -> Self {
+        SlotMap { owners: vec![None; SLOTS as usize] }
+    }
+
+    pub fn from_ranges(ranges: &[SlotRange]) -> Self {
+        let mut map = Self::new();
+        for range in ranges {
+            map.assign(range.start, range.end, &range.node);
+        }
+        map
+    }
+
+    pub fn owner(&self, slot: u16) -> Option<&str> {
+        self.owners[slot as usize].as_deref()
+    }
+
+    /// Assign slots `start..=end` to `node`.
+    pub fn assign(&mut self, start: u16, end: u16, node: &str) {
+        for owner in &mut self.owners[start as usize..=end as usize] {
+            *owner = Some(node.to_owned());
+        }
+    }
+
+    /// Consecutive slots of the same owner, unassigned slots are left out.
+    pub fn ranges(&self) -> Vec<SlotRange> {
+        let mut ranges: Vec<SlotRange> = vec![];
+        for (slot, owner) in self.owners.iter().enumerate() {
+            let (slot, owner) = match owner {
+                Some(owner) => (slot as u16, owner),
+                None => continue
+            };
+            match ranges.last_mut() {
+                Some(last) if last.end + 1 == slot && last.node == *owner => last.end = slot,
+                _ => ranges.push(SlotRange { start: slot, end: slot, node: owner.clone() })
+            }
+        }
+        ranges
+    }
+
+    pub fn nodes(&self) -> BTreeSet<&str> {
+        self.owners.iter().flatten().map(String::as_str).collect()
+    }
+}
+
+/// How a node changes a slot, see `CLUSTER SETSLOT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlotState {
+    /// The slot is owned by the node, which ends its migration.
+    Node(String),
+    /// Keys of the slot are being moved from this node to the node.
+    Migrating(String),
+    /// Keys of the slot are being moved to this node from the node.
+    Importing(String),
+    /// Cancel a migration, the owner is unchanged.
+    Stable,
+}
+
+/// Where a command on keys is served, see `Cluster::route`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Here,
+    /// The slot is owned by another node, clients should update their slot map.
+    Moved(u16, String),
+    /// The slot is migrating and the keys are not here, clients should send this command only to
+    /// the node, preceded by `ASKING`.
+    Ask(u16, String),
+    Unassigned(u16),
+    CrossSlot,
+    /// Some keys of a migrating slot are moved and some are not.
+    TryAgain,
+}
+
+impl Route {
+    /// The error replied instead of serving the command, `None` if it is served here.
+    pub fn error(&self) -> Option<String> {
+        match self {
+            Route::Here => None,
+            Route::Moved(slot, node) => Some(format!("MOVED {} {}", slot, node)),
+            Route::Ask(slot, node) => Some(format!("ASK {} {}", slot, node)),
+            Route::Unassigned(slot) => Some(format!("CLUSTERDOWN Hash slot {} not served", slot)),
+            Route::CrossSlot => Some("CROSSSLOT Keys in request don't hash to the same slot".to_owned()),
+            Route::TryAgain => Some("TRYAGAIN Multiple keys request during rehashing of slot".to_owned()),
+        }
+    }
+
+    /// Parse a `MOVED` or `ASK` error, `None` for other errors.
+    pub fn from_error(err: &str) -> Option<Route> {
+        let mut words = err.split(' ');
+        let (kind, slot, node) = (words.next()?, words.next()?.parse().ok()?, words.next()?.to_owned());
+        match kind {
+            "MOVED" => Some(Route::Moved(slot, node)),
+            "ASK" => Some(Route::Ask(slot, node)),
+            _ => None,
+        }
+    }
+}
+
+/// Slot assignment of a server in cluster mode, saved to a file after each change.
+///
+/// Nodes do not talk to each other about slots: an admin, usually `move_slots`, sends every
+/// change to every node with `CLUSTER SETSLOT`. A slot is moved online: the target is marked as
+/// importing it and the source as migrating it, then `CLUSTER MIGRATE` moves its keys in batches.
+/// Meanwhile the source serves keys it still has and redirects others with `ASK`.
+pub struct Cluster {
+    myself: String,
+    path: PathBuf,
+    state: RwLock<ClusterState>,
+    /// One lock per slot, held for reading by commands on keys of the slot and for writing by
+    /// `migrate`, so keys do not move between the check of a command and its execution.
+    moving: Box<[RwLock<()>]>,
+    migration: Mutex<Migration>,
+}
+
+/// Keys left to move of migrating slots and connections to their targets, kept between batches.
+#[derive(Default)]
+struct Migration {
+    /// Found by a single scan for every slot migrating at the time, which can not get new keys:
+    /// commands on keys of a migrating slot not stored here are redirected to the target.
+    keys: BTreeMap<u16, Vec<String>>,
+    targets: BTreeMap<String, KvsClient>,
+}
+
+#[derive(Default)]
+struct ClusterState {
+    slots: SlotMap,
+    /// Slots moving from this node, with their target.
+    migrating: BTreeMap<u16, String>,
+    /// Slots moving to this node, with their source.
+    importing: BTreeMap<u16, String>,
+}
+
+impl Cluster {
+    /// Run as the node `myself`, the address clients are redirected to, loading the slot
+    /// assignment saved at `path` if any.
+    pub fn open(myself: &str, path: &Path) -> Result<Self> {
+        let mut state = ClusterState::default();
+        if path.exists() {
+            let invalid = |line: &str| KvError::Message(format!("invalid cluster config line: {}", line));
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let words: Vec<&str> = line.split_whitespace().collect();
+                match words.as_slice() {
+                    ["migrating", slot, node] => {
+                        state.migrating.insert(slot.parse().map_err(|_| invalid(line))?, node.to_string());
+                    }
+                    ["importing", slot, node] => {
+                        state.importing.insert(slot.parse().map_err(|_| invalid(line))?, node.to_string());
+                    }
+                    [slots, node] => {
+                        let (start, end) = parse_slots(slots).map_err(|_| invalid(line))?;
+                        state.slots.assign(start, end, node);
+                    }
+                    _ => return Err(invalid(line))
+                }
+            }
+        }
+        Ok(Cluster {
+            myself: myself.to_owned(),
+            path: path.to_owned(),
+            state: RwLock::new(state),
+            moving: (0..SLOTS).map(|_| RwLock::new(())).collect(),
+            migration: Mutex::default(),
+        })
+    }
+
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+
+    pub fn slots(&self) -> Vec<SlotRange> {
+        self.state().slots.ranges()
+    }
+
+    /// Describe each known node as `<addr> [myself] <slots...>`, with `[slot->-target]` for
+    /// migrating and `[slot-<-source]` for importing slots.
+    pub fn nodes(&self) -> String {
+        let state = self.state();
+        let mut nodes: BTreeSet<&str> = state.slots.nodes();
+        nodes.insert(&self.myself);
+        nodes.extend(state.migrating.values().chain(state.importing.values()).map(String::as_str));
+        let ranges = state.slots.ranges();
+        let mut described = String::new();
+        for node in nodes {
+            described += node;
+            if node == self.myself {
+                described += " myself";
+            }
+            for range in ranges.iter().filter(|range| range.node == node) {
+                if range.start == range.end {
+                    let _ = write!(described, " {}", range.start);
+                } else {
+                    let _ = write!(described, " {}-{}", range.start, range.end);
+                }
+            }
+            if node == self.myself {
+                for (slot, target) in &state.migrating {
+                    let _ = write!(described, " [{}->-{}]", slot, target);
+                }
+                for (slot, source) in &state.importing {
+                    let _ = write!(described, " [{}-<-{}]", slot, source);
+                }
+            }
+            described += "\n";
+        }
+        described
+    }
+
+    /// Change slots `start..=end` and save the assignment. Nothing is changed if any slot can not be.
+    pub fn set_slots(&self, start: u16, end: u16, slot_state: &SlotState) -> Result<()> {
+        let mut state = self.state_mut();
+        for slot in start..=end {
+            let owned = state.slots.owner(slot) == Some(self.myself.as_str());
+            match slot_state {
+                SlotState::Migrating(_) if !owned => {
+                    return Err(KvError::Message(format!("slot {} is not owned by this node", slot)));
+                }
+                SlotState::Migrating(target) if *target == self.myself => {
+                    return Err(KvError::Message("can not migrate a slot to this node itself".to_owned()));
+                }
+                SlotState::Importing(_) if owned => {
+                    return Err(KvError::Message(format!("slot {} is already owned by this node", slot)));
+                }
+                _ => {}
+            }
+        }
+        for slot in start..=end {
+            match slot_state {
+                SlotState::Node(_) | SlotState::Stable => {
+                    state.migrating.remove(&slot);
+                    state.importing.remove(&slot);
+                }
+                SlotState::Migrating(target) => { state.migrating.insert(slot, target.clone()); }
+                SlotState::Importing(source) => { state.importing.insert(slot, source.clone()); }
+            }
+        }
+        if let SlotState::Node(node) = slot_state {
+            state.slots.assign(start, end, node);
+        }
+        state.save(&self.path)?;
+        drop(state);
+        if !matches!(slot_state, SlotState::Importing(_)) {
+            // a later migration of these slots scans again for their keys
+            let mut migration = self.migration();
+            migration.keys.retain(|slot, _| !(start..=end).contains(slot));
+            if migration.keys.is_empty() {
+                migration.targets.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide whether a command on `keys` is served here. `exists` tells if a key is stored here,
+    /// it is only called for migrating slots. `asking` is set if the command follows `ASKING`.
+    pub fn route(&self, keys: &[&str], asking: bool, mut exists: impl FnMut(&str) -> Result<bool>) -> Result<Route> {
+        let mut slots = keys.iter().map(|key| key_slot(key));
+        let slot = match slots.next() {
+            Some(slot) => slot,
+            None => return Ok(Route::Here)
+        };
+        if slots.any(|other| other != slot) {
+            return Ok(Route::CrossSlot);
+        }
+        let state = self.state();
+        Ok(match state.slots.owner(slot) {
+            Some(owner) if owner == self.myself => match state.migrating.get(&slot) {
+                Some(target) => {
+                    let mut found = 0;
+                    for key in keys {
+                        if exists(key)? {
+                            found += 1;
+                        }
+                    }
+                    match found {
+                        0 => Route::Ask(slot, target.clone()),
+                        found if found == keys.len() => Route::Here,
+                        _ => Route::TryAgain
+                    }
+                }
+                None => Route::Here
+            },
+            _ if asking && state.importing.contains_key(&slot) => Route::Here,
+            Some(owner) => Route::Moved(slot, owner.to_owned()),
+            None => Route::Unassigned(slot)
+        })
+    }
+
+    /// Hold while routing and serving a command on `key` and keys of the same slot, see `route`.
+    pub fn serving(&self, key: &str) -> RwLockReadGuard<'_, ()> {
+        self.moving[key_slot(key) as usize].read().expect("moving lock is poisoned")
+    }
+
+    /// Count the keys of `slot` stored here, reading a snapshot of the engine after releasing it.
+    pub fn count_keys<E: KvsEngine>(&self, engine: &Mutex<E>, slot: u16) -> Result<u64> {
+        let snapshot = engine.lock().expect("engine lock is poisoned").snapshot()?;
+        let mut count = 0;
+        for pair in snapshot {
+            if key_slot(&pair?.0) == slot {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Move up to `count` keys of a migrating slot to its target, authenticating with `password`
+    /// if given, return the number of moved keys. Commands on keys of the slot wait until the
+    /// batch is moved, the engine is only locked to read and to remove the batch.
+    pub fn migrate<E: KvsEngine>(&self, engine: &Mutex<E>, slot: u16, count: usize, password: Option<&str>) -> Result<usize> {
+        let target = self.state().migrating.get(&slot).cloned()
+            .ok_or_else(|| KvError::Message(format!("slot {} is not migrating", slot)))?;
+        let mut migration = self.migration();
+        if !migration.keys.contains_key(&slot) {
+            self.find_migrating_keys(&mut migration, engine)?;
+        }
+        let _moving = self.moving[slot as usize].write().expect("moving lock is poisoned");
+        let keys = migration.keys.entry(slot).or_default();
+        let mut pairs = vec![];
+        while pairs.is_empty() && !keys.is_empty() {
+            let batch = keys.split_off(keys.len().saturating_sub(count));
+            let values = engine.lock().expect("engine lock is poisoned").mget(&batch)?;
+            // keys removed since the scan are skipped
+            pairs = batch.into_iter().zip(values)
+                .filter_map(|(key, value)| Some((key, value?)))
+                .collect();
+        }
+        if pairs.is_empty() {
+            migration.keys.remove(&slot);
+            if migration.keys.is_empty() {
+                migration.targets.clear();
+            }
+            return Ok(0);
+        }
+        let batch: Vec<(&str, &str)> = pairs.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+        let client = match migration.targets.entry(target.clone()) {
+            Entry::Occupied(client) => client.into_mut(),
+            // a redirect could only lead back here, which waits for this migration
+            Entry::Vacant(entry) => entry.insert(connect(&target, password)?.no_redirects())
+        };
+        if let Err(err) = client.asking().and_then(|_| client.mset(&batch)) {
+            // connect again for the next batch, and move the keys again with it
+            migration.targets.remove(&target);
+            migration.keys.entry(slot).or_default().extend(pairs.into_iter().map(|(key, _)| key));
+            return Err(err);
+        }
+        let keys: Vec<String> = pairs.into_iter().map(|(key, _)| key).collect();
+        engine.lock().expect("engine lock is poisoned").mdel(&keys)?;
+        log::info!("migrated {} keys of slot {} to {}", keys.len(), slot, target);
+        Ok(keys.len())
+    }
+
+    /// Scan the engine once for the keys of every migrating slot not scanned yet, holding their
+    /// locks so that no command adds a key to them meanwhile.
+    fn find_migrating_keys<E: KvsEngine>(&self, migration: &mut Migration, engine: &Mutex<E>) -> Result<()> {
+        let slots: Vec<u16> = self.state().migrating.keys()
+            .filter(|slot| !migration.keys.contains_key(slot))
+            .copied()
+            .collect();
+        // only `migrate` takes write locks, one call at a time, so the order does not matter
+        let _moving: Vec<_> = slots.iter()
+            .map(|slot| self.moving[*slot as usize].write().expect("moving lock is poisoned"))
+            .collect();
+        let mut keys: BTreeMap<u16, Vec<String>> = slots.iter().map(|slot| (*slot, vec![])).collect();
+        let snapshot = engine.lock().expect("engine lock is poisoned").snapshot()?;
+        for pair in snapshot {
+            let key = pair?.0;
+            if let Some(slot_keys) = keys.get_mut(&key_slot(&key)) {
+                slot_keys.push(key);
+            }
+        }
+        migration.keys.extend(keys);
+        Ok(())
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, ClusterState> {
+        self.state.read().expect("cluster lock is poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, ClusterState> {
+        self.state.write().expect("cluster lock is poisoned")
+    }
+
+    fn migration(&self) -> MutexGuard<'_, Migration> {
+        self.migration.lock().expect("migration lock is poisoned")
+    }
+}
+
+impl ClusterState {
+    /// Write to a temporary file first, so a crash leaves the old or the new assignment.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut config = "# slot assignment of kvs-server cluster mode, do not edit while it runs\n".to_owned();
+        for range in self.slots.ranges() {
+            let _ = writeln!(config, "{}-{} {}", range.start, range.end, range.node);
+        }
+        for (slot, target) in &self.migrating {
+            let _ = writeln!(config, "migrating {} {}", slot, target);
+        }
+        for (slot, source) in &self.importing {
+            let _ = writeln!(config, "importing {} {}", slot, source);
+        }
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, config)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+}
+
+fn connect(node: &str, password: Option<&str>) -> Result<KvsClient> {
+    match password {
+        Some(password) => KvsClient::connect_with_auth(node, password),
+        None => KvsClient::connect(node)
+    }
+}
+
+/// Split the slots evenly among `nodes`, in the given order, and send the assignment to every
+/// node. Nodes should be empty, keys already stored are not moved.
+pub fn create<S: AsRef<str>>(nodes: &[S], password: Option<&str>) -> Result<Vec<SlotRange>> {
+    if nodes.is_empty() {
+        return Err(KvError::Message("no node to assign slots to".to_owned()));
+    }
+    let ranges: Vec<SlotRange> = nodes.iter().enumerate()
+        .map(|(i, node)| SlotRange {
+            start: (SLOTS as usize * i / nodes.len()) as u16,
+            end: (SLOTS as usize * (i + 1) / nodes.len() - 1) as u16,
+            node: node.as_ref().to_owned(),
+        })
+        .collect();
+    for node in nodes {
+        let mut client = connect(node.as_ref(), password)?;
+        for range in &ranges {
+            client.cluster_setslot(range.start, range.end, &SlotState::Node(range.node.clone()))?;
+        }
+    }
+    Ok(ranges)
+}
+
+/// Move slots `start..=end` to `target` while the cluster keeps serving them, return the number
+/// of moved keys. The slot map is read from `seed`, and each change is sent to every node of it
+/// and to `target`, which learns the owners of the other slots first if it is a new node.
+/// An interrupted move can be run again.
+pub fn move_slots(seed: &str, start: u16, end: u16, target: &str, password: Option<&str>) -> Result<usize> {
+    let map = SlotMap::from_ranges(&connect(seed, password)?.cluster_slots()?);
+    let mut clients = BTreeMap::new();
+    for node in map.nodes().into_iter().chain([target]) {
+        if !clients.contains_key(node) {
+            clients.insert(node.to_owned(), connect(node, password)?);
+        }
+    }
+    let known = SlotMap::from_ranges(&client(&mut clients, target).cluster_slots()?);
+    for range in map.ranges() {
+        if known.owner(range.start).is_none() {
+            client(&mut clients, target).cluster_setslot(range.start, range.end, &SlotState::Node(range.node))?;
+        }
+    }
+    // mark every slot first, so that each source finds the keys of all of them in one scan
+    let mut sources = BTreeMap::new();
+    for slot in start..=end {
+        match map.owner(slot) {
+            // the target owns the slot already if a previous move is interrupted after the migration
+            Some(source) if source != target && known.owner(slot) != Some(target) => {
+                client(&mut clients, target).cluster_setslot(slot, slot, &SlotState::Importing(source.to_owned()))?;
+                client(&mut clients, source).cluster_setslot(slot, slot, &SlotState::Migrating(target.to_owned()))?;
+                sources.insert(slot, source);
+            }
+            _ => {}
+        }
+    }
+    let owned = SlotState::Node(target.to_owned());
+    let mut moved = 0;
+    for slot in start..=end {
+        if let Some(source) = sources.get(&slot) {
+            loop {
+                match client(&mut clients, source).cluster_migrate(slot, MIGRATE_BATCH, password)? {
+                    0 => break,
+                    n => moved += n
+                }
+            }
+        }
+        // the target first, so redirects of the other nodes never lead back to them
+        client(&mut clients, target).cluster_setslot(slot, slot, &owned)?;
+        for (node, other) in clients.iter_mut() {
+            if node != target {
+                other.cluster_setslot(slot, slot, &owned)?;
+            }
+        }
+    }
+    Ok(moved)
+}
+
+fn client<'a>(clients: &'a mut BTreeMap<String, KvsClient>, node: &str) -> &'a mut KvsClient {
+    clients.get_mut(node).expect("node is connected")
+}
+
+#[cfg(test)]
+mod cluster_tests {
+    use tempfile::tempdir;
+    use crate::Result;
+    use super::{key_slot, parse_slots, Cluster, Route, SlotMap, SlotRange, SlotState};
+
+    // Should hash like Redis cluster, honoring hash tags
+    #[test]
+    fn slots_of_keys() {
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("bar"), 5061);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("{user1000}.followers"));
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_ne!(key_slot("{}.foo"), key_slot("{}.bar"));
+        assert_eq!(parse_slots("5-10").unwrap(), (5, 10));
+        assert_eq!(parse_slots("7").unwrap(), (7, 7));
+        assert!(parse_slots("10-5").is_err());
+        assert!(parse_slots("16384").is_err());
+    }
+
+    // Should redirect keys of other nodes and of migrated keys, and reload the assignment
+    #[test]
+    fn route_and_reload() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("nodes.conf");
+        let cluster = Cluster::open("a:1", &path)?;
+        let foo = key_slot("foo");
+        assert_eq!(cluster.route(&["foo"], false, |_| Ok(true))?, Route::Unassigned(foo));
+        cluster.set_slots(0, 8191, &SlotState::Node("b:2".to_owned()))?;
+        cluster.set_slots(8192, 16383, &SlotState::Node("a:1".to_owned()))?;
+        assert_eq!(cluster.route(&["foo"], false, |_| Ok(true))?, Route::Here);
+        assert_eq!(cluster.route(&["bar"], false, |_| Ok(true))?, Route::Moved(key_slot("bar"), "b:2".to_owned()));
+        assert_eq!(cluster.route(&["foo", "bar"], false, |_| Ok(true))?, Route::CrossSlot);
+
+        cluster.set_slots(foo, foo, &SlotState::Migrating("b:2".to_owned()))?;
+        assert_eq!(cluster.route(&["foo"], false, |_| Ok(true))?, Route::Here);
+        assert_eq!(cluster.route(&["foo"], false, |_| Ok(false))?, Route::Ask(foo, "b:2".to_owned()));
+        let tagged = ["{foo}1", "{foo}2"];
+        assert_eq!(cluster.route(&tagged, false, |key| Ok(key.ends_with('1')))?, Route::TryAgain);
+        assert!(cluster.set_slots(0, 0, &SlotState::Migrating("b:2".to_owned())).is_err());
+        cluster.set_slots(5061, 5061, &SlotState::Importing("b:2".to_owned()))?;
+        assert_eq!(cluster.route(&["bar"], true, |_| Ok(false))?, Route::Here);
+
+        let reloaded = Cluster::open("a:1", &path)?;
+        assert_eq!(reloaded.slots(), cluster.slots());
+        assert_eq!(reloaded.nodes(), "a:1 myself 8192-16383 [12182->-b:2] [5061-<-b:2]\nb:2 0-8191\n");
+        assert_eq!(Route::from_error("MOVED 5061 b:2"), Some(Route::Moved(5061, "b:2".to_owned())));
+        assert_eq!(Route::from_error("ERR MOVED"), None);
+        Ok(())
+    }
+
+    // Should merge consecutive slots of an owner into ranges
+    #[test]
+    fn slot_ranges() {
+        let mut map = SlotMap::new();
+        map.assign(0, 99, "a:1");
+        map.assign(100, 199, "a:1");
+        map.assign(50, 50, "b:2");
+        map.assign(300, 300, "b:2");
+        let range = |start, end, node: &str| SlotRange { start, end, node: node.to_owned() };
+        let ranges = vec![range(0, 49, "a:1"), range(50, 50, "b:2"), range(51, 199, "a:1"), range(300, 300, "b:2")];
+        assert_eq!(map.ranges(), ranges);
+        assert_eq!(SlotMap::from_ranges(&ranges), map);
+    }
+}
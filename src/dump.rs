@@ -0,0 +1,290 @@
+use std::io::{BufRead, Read, Write};
+use serde::{Deserialize, Serialize};
+use crate::engine::KvsEngine;
+use crate::{KvError, Result};
+
+/// Current version of the dump format, written in the header of every dump.
+pub const DUMP_VERSION: u32 = 1;
+/// Magic bytes starting a binary dump.
+const BINARY_MAGIC: &[u8; 4] = b"KVSD";
+/// Pairs restored in a single `KvsEngine::mset` call.
+const RESTORE_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// A json header line followed by one json record per line.
+    JsonLines,
+    /// Magic bytes and version, followed by length-prefixed records.
+    Binary,
+}
+
+/// Header line of a json lines dump.
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonHeader {
+    format: String,
+    version: u32,
+}
+
+/// A live key-value pair in a dump.
+///
+/// `ttl` is the remaining time to live in milliseconds, `None` for keys that never expire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpRecord {
+    pub key: String,
+    pub value: String,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// Write records one by one in the given format.
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    format: DumpFormat,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Create a writer and write the header.
+    pub fn new(mut writer: W, format: DumpFormat) -> Result<Self> {
+        match format {
+            DumpFormat::JsonLines => {
+                let header = JsonHeader { format: "kvs-dump".to_owned(), version: DUMP_VERSION };
+                serde_json::to_writer(&mut writer, &header)?;
+                writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                writer.write_all(BINARY_MAGIC)?;
+                writer.write_all(&DUMP_VERSION.to_le_bytes())?;
+            }
+        }
+        Ok(Self { writer, format })
+    }
+
+    pub fn write(&mut self, record: &DumpRecord) -> Result<()> {
+        match self.format {
+            DumpFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                write_bytes(&mut self.writer, record.key.as_bytes())?;
+                write_bytes(&mut self.writer, record.value.as_bytes())?;
+                match record.ttl {
+                    Some(ttl) => {
+                        self.writer.write_all(&[1])?;
+                        self.writer.write_all(&ttl.to_le_bytes())?;
+                    }
+                    None => self.writer.write_all(&[0])?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Read records of a dump, the format is detected from the header.
+pub struct DumpReader<R: BufRead> {
+    reader: R,
+    format: DumpFormat,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    /// Read the header and check the version.
+    /// # Errors
+    /// * `KvError::Message` unknown format or unsupported version
+    pub fn new(mut reader: R) -> Result<Self> {
+        let format = if reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+            DumpFormat::Binary
+        } else {
+            DumpFormat::JsonLines
+        };
+        let version = match format {
+            DumpFormat::JsonLines => {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                let header: JsonHeader = serde_json::from_str(&line)
+                    .map_err(|_| KvError::Message("not a kvs dump".to_owned()))?;
+                if header.format != "kvs-dump" {
+                    return Err(KvError::Message("not a kvs dump".to_owned()));
+                }
+                header.version
+            }
+            DumpFormat::Binary => {
+                let mut buf = [0u8; 8];
+                reader.read_exact(&mut buf)?;
+                u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]])
+            }
+        };
+        if version > DUMP_VERSION {
+            return Err(KvError::Message(format!("unsupported dump version {}", version)));
+        }
+        Ok(Self { reader, format })
+    }
+
+    pub fn format(&self) -> DumpFormat {
+        self.format
+    }
+
+    fn read_record(&mut self) -> Result<Option<DumpRecord>> {
+        match self.format {
+            DumpFormat::JsonLines => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if self.reader.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if !line.trim().is_empty() {
+                        return Ok(Some(serde_json::from_str(&line)?));
+                    }
+                }
+            }
+            DumpFormat::Binary => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let key = String::from_utf8(read_bytes(&mut self.reader)?)?;
+                let value = String::from_utf8(read_bytes(&mut self.reader)?)?;
+                let mut flag = [0u8; 1];
+                self.reader.read_exact(&mut flag)?;
+                let ttl = if flag[0] == 1 {
+                    let mut buf = [0u8; 8];
+                    self.reader.read_exact(&mut buf)?;
+                    Some(u64::from_le_bytes(buf))
+                } else {
+                    None
+                };
+                Ok(Some(DumpRecord { key, value, ttl }))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<DumpRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Write every live pair of `engine` to `writer`, return the number of dumped pairs.
+/// Pairs are streamed, so memory usage does not grow with the store.
+/// # Examples
+/// ```rust
+/// use tempfile::TempDir;
+/// use kvs::engine::KvsEngine;
+/// use kvs::KvStore;
+/// use kvs::dump::{dump, restore, DumpFormat};
+/// let (src_dir, dest_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+/// let mut src = KvStore::open(src_dir.path()).unwrap();
+/// src.set("name", "Adam").unwrap();
+/// let mut buf = vec![];
+/// assert_eq!(dump(&mut src, &mut buf, DumpFormat::JsonLines).unwrap(), 1);
+/// let mut dest = KvStore::open(dest_dir.path()).unwrap();
+/// assert_eq!(restore(&mut dest, buf.as_slice()).unwrap(), 1);
+/// assert_eq!(dest.get("name").unwrap(), Some("Adam".to_owned()));
+/// ```
+pub fn dump<E: KvsEngine + ?Sized>(engine: &mut E, writer: impl Write, format: DumpFormat) -> Result<u64> {
+    let mut writer = DumpWriter::new(writer, format)?;
+    let mut count = 0u64;
+    for pair in engine.scan("")? {
+        let (key, value) = pair?;
+        writer.write(&DumpRecord { key, value, ttl: None })?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+/// Load every record of a dump into `engine`, return the number of restored pairs.
+/// Records are applied in batches with `KvsEngine::mset`.
+/// The engine has no expiration yet, so ttl of records is ignored.
+pub fn restore<E: KvsEngine + ?Sized>(engine: &mut E, reader: impl BufRead) -> Result<u64> {
+    let mut count = 0u64;
+    let mut batch = Vec::with_capacity(RESTORE_BATCH_SIZE);
+    for record in DumpReader::new(reader)? {
+        let record = record?;
+        batch.push((record.key, record.value));
+        if batch.len() == RESTORE_BATCH_SIZE {
+            engine.mset(&batch)?;
+            count += batch.len() as u64;
+            batch.clear();
+        }
+    }
+    engine.mset(&batch)?;
+    count += batch.len() as u64;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod dump_tests {
+    use tempfile::TempDir;
+    use crate::engine::{KvsEngine, Sled};
+    use crate::KvStore;
+    use crate::Result;
+    use super::{dump, restore, DumpFormat, DumpReader, DumpRecord, DumpWriter};
+
+    // Should read back the same records in both formats
+    #[test]
+    fn round_trip_records() -> Result<()> {
+        let records = vec![
+            DumpRecord { key: "key1".to_owned(), value: "".to_owned(), ttl: None },
+            DumpRecord { key: "key\n2".to_owned(), value: "value \"2\"".to_owned(), ttl: Some(1000) },
+        ];
+        for format in [DumpFormat::JsonLines, DumpFormat::Binary] {
+            let mut writer = DumpWriter::new(vec![], format)?;
+            for record in &records {
+                writer.write(record)?;
+            }
+            let buf = writer.finish()?;
+            let reader = DumpReader::new(buf.as_slice())?;
+            assert_eq!(reader.format(), format);
+            assert_eq!(reader.collect::<Result<Vec<_>>>()?, records);
+        }
+        Ok(())
+    }
+
+    // Should move data from kvs engine to sled engine
+    #[test]
+    fn dump_kvs_restore_sled() -> Result<()> {
+        let (src_dir, dest_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let mut src = KvStore::open(src_dir.path())?;
+        for i in 0..2500 {
+            src.set(&format!("key{}", i), &format!("value{}", i))?;
+        }
+        src.remove("key7")?;
+        let mut buf = vec![];
+        assert_eq!(dump(&mut src, &mut buf, DumpFormat::Binary)?, 2499);
+
+        let mut dest = Sled::new(sled::open(dest_dir.path())?)?;
+        assert_eq!(restore(&mut dest, buf.as_slice())?, 2499);
+        assert_eq!(dest.get("key2024")?, Some("value2024".to_owned()));
+        assert_eq!(dest.get("key7")?, None);
+        Ok(())
+    }
+
+    // Should refuse input without a dump header
+    #[test]
+    fn reject_unknown_format() {
+        assert!(DumpReader::new("{\"key\":\"k\"}\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use crate::error::{KvError, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::engine::KvsEngine;
+use crate::engine::kvstore::command::{Command, CommandPos};
+use crate::engine::kvstore::store::KvStore;
+use crate::engine::kvstore::tools;
+
+/// Every record starts with one of these, used to find the next readable record after damage.
+const RECORD_STARTS: [&[u8]; 4] = [b"{\"SetCommand\"", b"{\"RemoveCommand\"", b"{\"BatchCommand\"", b"{\"CompactCommand\""];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DamageKind {
+    /// The file ends in the middle of a record.
+    Truncated,
+    /// Bytes that can not be parsed as a record.
+    Corrupted,
+}
+
+/// A range of a log file that can not be read as records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    pub file_stem: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub kind: DamageKind,
+}
+
+/// Summary of a single `N.log` file.
+#[derive(Debug, Clone, Default)]
+pub struct FileReport {
+    pub file_stem: u64,
+    pub size: u64,
+    pub records: u64,
+    /// Bytes of records still referenced by the index after replaying every file.
+    pub live_bytes: u64,
+    /// Bytes of readable records that are overwritten or removed.
+    pub dead_bytes: u64,
+    pub damages: Vec<Damage>,
+}
+
+/// Result of checking a `KvStore` directory.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub files: Vec<FileReport>,
+    /// Log files without any live record, except the newest one which is the active file.
+    pub orphaned: Vec<PathBuf>,
+    /// Entries that are not `N.log` files.
+    pub unexpected: Vec<PathBuf>,
+}
+
+impl CheckReport {
+    /// Whether the directory can be opened without losing any record.
+    pub fn is_healthy(&self) -> bool {
+        self.files.iter().all(|file| file.damages.is_empty())
+    }
+}
+
+/// A readable record of a log file.
+struct Record {
+    offset: u64,
+    len: u64,
+    cmd: Command,
+}
+
+/// Read every record of the file, skipping damaged ranges instead of stopping at the first one.
+fn salvage_file(dir_path: &Path, file_stem: u64) -> Result<(Vec<Record>, Vec<Damage>, u64)> {
+    let buf = fs::read(dir_path.join(file_stem.to_string() + ".log"))?;
+    let mut records = vec![];
+    let mut damages = vec![];
+    let mut pos = 0usize;
+    'file: while pos < buf.len() {
+        for record in tools::read_records(&buf[pos..], pos as u64) {
+            match record {
+                Ok((offset, len, cmd)) => {
+                    pos = (offset + len) as usize;
+                    records.push(Record { offset, len, cmd });
+                }
+                Err(err) => {
+                    let next = next_record_start(&buf, pos + 1);
+                    let end = next.unwrap_or(buf.len());
+                    let kind = if next.is_none() && err.is_eof() {
+                        DamageKind::Truncated
+                    } else {
+                        DamageKind::Corrupted
+                    };
+                    damages.push(Damage { file_stem, offset: pos as u64, len: (end - pos) as u64, kind });
+                    pos = end;
+                    continue 'file;
+                }
+            }
+        }
+        break;
+    }
+    Ok((records, damages, buf.len() as u64))
+}
+
+fn next_record_start(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).find(|&i| RECORD_STARTS.iter().any(|start| buf[i..].starts_with(start)))
+}
+
+/// Split entries of `dir_path` into log file stems and unexpected paths.
+fn scan_dir(dir_path: &Path) -> Result<(Vec<u64>, Vec<PathBuf>)> {
+    let file_stems = tools::collect_file_stems(dir_path)?;
+    let mut unexpected: Vec<PathBuf> = fs::read_dir(dir_path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| {
+            let is_log = path.is_file()
+                && path.extension().eq(&Some("log".as_ref()))
+                && path.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .is_some_and(|stem| stem.parse::<u64>().is_ok());
+            !is_log
+        })
+        .collect();
+    unexpected.sort();
+    Ok((file_stems, unexpected))
+}
+
+/// Walk every log file of a `KvStore` directory without opening the store.
+/// # Errors
+/// * `KvError::IoError` fail due to I/O errors
+/// # Examples
+/// ```rust
+/// use tempfile::TempDir;
+/// use kvs::engine::KvsEngine;
+/// use kvs::KvStore;
+/// use kvs::engine::kvstore::check::check;
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("name", "Adam").unwrap();
+/// store.set("name", "Eve").unwrap();
+/// drop(store);
+/// let report = check(temp_dir.path()).unwrap();
+/// assert!(report.is_healthy());
+/// assert!(report.files[0].dead_bytes > 0);
+/// ```
+pub fn check(dir_path: impl AsRef<Path>) -> Result<CheckReport> {
+    let dir_path = dir_path.as_ref();
+    let (file_stems, unexpected) = scan_dir(dir_path)?;
+    let mut key_map: HashMap<String, CommandPos> = HashMap::new();
+    let mut files = vec![];
+    for file_stem in file_stems {
+        let (records, damages, size) = salvage_file(dir_path, file_stem)?;
+        for record in &records {
+            tools::apply_record(file_stem, record.offset, record.len, &record.cmd, &mut key_map)?;
+        }
+        files.push(FileReport {
+            file_stem,
+            size,
+            records: records.len() as u64,
+            dead_bytes: records.iter().map(|record| record.len).sum(),
+            damages,
+            ..Default::default()
+        });
+    }
+    for cmd_pos in key_map.values() {
+        if let Some(file) = files.iter_mut().find(|file| file.file_stem == cmd_pos.file_stem) {
+            file.live_bytes += cmd_pos.len;
+        }
+    }
+    for file in files.iter_mut() {
+        // inner commands of a batch are counted as live, the rest of the batch record is dead
+        file.dead_bytes = file.dead_bytes.saturating_sub(file.live_bytes);
+    }
+    let orphaned = match files.split_last() {
+        Some((_, old_files)) => old_files.iter()
+            .filter(|file| file.live_bytes == 0)
+            .map(|file| dir_path.join(file.file_stem.to_string() + ".log"))
+            .collect(),
+        None => vec![],
+    };
+    Ok(CheckReport { files, orphaned, unexpected })
+}
+
+/// Replay every readable record of `dir_path` into a fresh store at `dest_path`.
+/// Return the number of salvaged records.
+/// # Errors
+/// * `KvError::Message` `dest_path` already contains log files
+/// * `KvError::IoError` fail due to I/O errors
+pub fn repair(dir_path: impl AsRef<Path>, dest_path: impl AsRef<Path>) -> Result<u64> {
+    let (dir_path, dest_path) = (dir_path.as_ref(), dest_path.as_ref());
+    fs::create_dir_all(dest_path)?;
+    if !tools::collect_file_stems(dest_path)?.is_empty() {
+        return Err(KvError::Message(format!("{} already contains log files", dest_path.display())));
+    }
+    let mut store = KvStore::open(dest_path)?;
+    let mut salvaged = 0u64;
+    for file_stem in scan_dir(dir_path)?.0 {
+        let (records, _, _) = salvage_file(dir_path, file_stem)?;
+        for record in records {
+            match record.cmd {
+                Command::SetCommand { key, value, .. } => store.set(&key, &value)?,
+                Command::RemoveCommand { key, .. } => store.remove(&key).map(|_| ())?,
+                // sequence numbers are assigned again by the new store
+                Command::CompactCommand { .. } => continue,
+                Command::BatchCommand(commands) => {
+                    let pairs: Vec<(String, String)> = commands.into_iter()
+                        .filter_map(|cmd| match cmd {
+                            Command::SetCommand { key, value, .. } => Some((key, value)),
+                            _ => None,
+                        })
+                        .collect();
+                    store.mset(&pairs)?
+                }
+            }
+            salvaged += 1;
+        }
+    }
+    Ok(salvaged)
+}
+
+#[cfg(test)]
+mod check_tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use tempfile::TempDir;
+    use crate::engine::KvsEngine;
+    use crate::KvStore;
+    use crate::Result;
+    use super::{check, repair, DamageKind};
+
+    // Should locate corrupted and truncated records and salvage the records around them
+    #[test]
+    fn detect_and_repair_damage() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1", "value1")?;
+        drop(store);
+        let log_path = temp_dir.path().join("0.log");
+        let mut log = OpenOptions::new().append(true).open(&log_path)?;
+        log.write_all(b"garbage")?;
+        log.write_all(br#"{"SetCommand":{"key":"key2","value":"value2"}}"#)?;
+        log.write_all(br#"{"SetCommand":{"key":"key3","val"#)?;
+        drop(log);
+        fs::write(temp_dir.path().join("notes.txt"), "hello")?;
+
+        let report = check(temp_dir.path())?;
+        assert!(!report.is_healthy());
+        let damages = &report.files[0].damages;
+        assert_eq!(damages.len(), 2);
+        assert_eq!(damages[0].kind, DamageKind::Corrupted);
+        assert_eq!(damages[0].len, "garbage".len() as u64);
+        assert_eq!(damages[1].kind, DamageKind::Truncated);
+        assert_eq!(report.files[0].records, 2);
+        assert_eq!(report.unexpected, vec![temp_dir.path().join("notes.txt")]);
+
+        let repaired_dir = TempDir::new().unwrap();
+        assert_eq!(repair(temp_dir.path(), repaired_dir.path())?, 2);
+        let mut store = KvStore::open(repaired_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3")?, None);
+        Ok(())
+    }
+}
@@ -1,27 +1,50 @@
+use crate::engine::{Change, Event};
 use crate::error::Result;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
+/// A log record. `seq` is the sequence number of the mutation, records written before
+/// sequence numbers were introduced read as 0.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
-    SetCommand { key: String, value: String },
-    RemoveCommand { key: String }
+    SetCommand {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    RemoveCommand {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    /// Several commands written as a single log record, so that they are replayed all or none.
+    BatchCommand(Vec<Command>),
+    /// Written first by a compaction: mutations up to `seq` are dropped
+    /// except the latest set of each live key.
+    CompactCommand { seq: u64 },
 }
 
 impl Command {
-    pub fn set(key: &str, value: &str) -> Self {
+    pub fn set(key: &str, value: &str, seq: u64) -> Self {
         Command::SetCommand {
             key: key.to_string(),
             value: value.to_string(),
+            seq,
         }
     }
 
-    pub fn rm(key: &str) -> Self {
+    pub fn rm(key: &str, seq: u64) -> Self {
         Command::RemoveCommand {
             key: key.to_string(),
+            seq,
         }
     }
 
+    pub fn batch(commands: Vec<Command>) -> Self {
+        Command::BatchCommand(commands)
+    }
+
     pub fn as_json(&self) -> Result<String> {
         let str = serde_json::to_string(&self)?;
         Ok(str)
@@ -31,7 +54,53 @@ impl Command {
         match *self {
             Command::SetCommand { .. } => "SetCommand".to_string(),
             Command::RemoveCommand { .. } => "RemoveCommand".to_string(),
+            Command::BatchCommand(_) => "BatchCommand".to_string(),
+            Command::CompactCommand { .. } => "CompactCommand".to_string(),
+        }
+    }
+
+    /// The largest sequence number in this record.
+    pub fn seq(&self) -> u64 {
+        match self {
+            Command::SetCommand { seq, .. } | Command::RemoveCommand { seq, .. } | Command::CompactCommand { seq } => *seq,
+            Command::BatchCommand(commands) => commands.iter().map(Command::seq).max().unwrap_or(0),
+        }
+    }
+
+    /// Mutations of this record in order.
+    pub fn into_changes(self) -> Vec<Change> {
+        match self {
+            Command::SetCommand { key, value, seq } => vec![Change { seq, event: Event::Set { key, value }, remaining: 0 }],
+            Command::RemoveCommand { key, seq } => vec![Change { seq, event: Event::Remove { key }, remaining: 0 }],
+            Command::BatchCommand(commands) => {
+                let mut changes: Vec<Change> = commands.into_iter().flat_map(Command::into_changes).collect();
+                let len = changes.len() as u64;
+                for (i, change) in changes.iter_mut().enumerate() {
+                    change.remaining = len - 1 - i as u64;
+                }
+                changes
+            }
+            Command::CompactCommand { .. } => vec![],
+        }
+    }
+
+    /// Locate every command of a `BatchCommand` inside its json.
+    ///
+    /// Return `(offset, len, command)` for each inner command, where `offset` is relative to the
+    /// start of the batch record. Each located slice is a standalone json of `Command`, so it can
+    /// be read back just like a single record.
+    pub fn batch_entries(&self) -> Result<Vec<(u64, u64, &Command)>> {
+        let mut entries = vec![];
+        if let Command::BatchCommand(commands) = self {
+            // serialized as `{"BatchCommand":[cmd,cmd,...]}`
+            let mut offset = "{\"BatchCommand\":[".len() as u64;
+            for cmd in commands {
+                let len = cmd.as_json()?.len() as u64;
+                entries.push((offset, len, cmd));
+                offset += len + 1;
+            }
         }
+        Ok(entries)
     }
 }
 
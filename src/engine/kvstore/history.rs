@@ -0,0 +1,152 @@
+use crate::error::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::engine::kvstore::command::Command;
+use crate::engine::kvstore::tools;
+
+/// Number of latest mutations retained for `KvsEngine::changes` by default.
+pub const DEFAULT_CHANGES_RETAINED: u64 = 100_000;
+/// A history file is not appended any more once it reaches this size, unit: byte.
+const HISTORY_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Mutations dropped from the log by compactions, kept for `KvsEngine::changes`.
+///
+/// Records are appended to `<stem>.history` files, where `stem` is the sequence number right
+/// before the first record of the file. The oldest files are deleted once the latest `retained`
+/// mutations are all in newer ones, so at least `retained` mutations are kept.
+pub struct History {
+    dir_path: PathBuf,
+    /// Stems of history files in ascending order.
+    file_stems: Vec<u64>,
+    /// The sequence number of the latest mutation in history.
+    last_seq: u64,
+    retained: u64,
+}
+
+impl History {
+    /// Open history files in `dir_path`. A record of the latest file cut by a crash is truncated.
+    pub fn open(dir_path: &Path) -> Result<History> {
+        let file_stems = tools::collect_stems(dir_path, "history")?;
+        let mut last_seq = 0;
+        if let Some(&file_stem) = file_stems.last() {
+            let file = OpenOptions::new().read(true).write(true).open(history_path(dir_path, file_stem))?;
+            let mut end = 0;
+            last_seq = file_stem;
+            for record in tools::read_records(BufReader::new(&file), 0) {
+                match record {
+                    Ok((offset, len, cmd)) => {
+                        last_seq = cmd.seq();
+                        end = offset + len;
+                    }
+                    Err(_) => break,
+                }
+            }
+            file.set_len(end)?;
+        }
+        Ok(History {
+            dir_path: dir_path.to_owned(),
+            file_stems,
+            last_seq,
+            retained: DEFAULT_CHANGES_RETAINED,
+        })
+    }
+
+    pub fn set_retained(&mut self, retained: u64) {
+        self.retained = retained;
+    }
+
+    /// The sequence number right before the oldest mutation in history, `None` if it is empty.
+    pub fn first_seq(&self) -> Option<u64> {
+        self.file_stems.first().copied()
+    }
+
+    /// The sequence number of the latest mutation in history.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Append mutation records which follow `since`. The history is restarted if it does not
+    /// reach `since`, so that it never has a gap.
+    pub fn append(&mut self, since: u64, records: impl Iterator<Item = Result<Command>>) -> Result<()> {
+        let mut records = records.peekable();
+        if records.peek().is_none() {
+            return Ok(());
+        }
+        if since > self.last_seq {
+            self.clear()?;
+        }
+        let file_stem = match self.file_stems.last() {
+            Some(&file_stem) if fs::metadata(self.path(file_stem))?.len() < HISTORY_FILE_SIZE => file_stem,
+            _ => {
+                self.file_stems.push(since);
+                since
+            }
+        };
+        let file = OpenOptions::new().create(true).append(true).open(self.path(file_stem))?;
+        let mut writer = BufWriter::new(file);
+        for cmd in records {
+            let cmd = cmd?;
+            writer.write_all(cmd.as_json()?.as_bytes())?;
+            self.last_seq = cmd.seq();
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Delete the oldest files whose mutations are all older than the latest `retained` ones up to `seq`.
+    pub fn trim(&mut self, seq: u64) -> Result<()> {
+        let dropped_seq = seq.saturating_sub(self.retained);
+        while let Some(&file_stem) = self.file_stems.first() {
+            let end = self.file_stems.get(1).copied().unwrap_or(self.last_seq);
+            if end > dropped_seq {
+                break;
+            }
+            fs::remove_file(self.path(file_stem))?;
+            self.file_stems.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Open every history file in order, the files are read by `KvsEngine::changes`.
+    pub fn files(&self) -> Result<Vec<File>> {
+        self.file_stems.iter().map(|&file_stem| Ok(File::open(self.path(file_stem))?)).collect()
+    }
+
+    /// Copy history files into `dest_dir` as of now. The latest file, which is still appended, is
+    /// copied up to its current length, the others are hard linked or copied if links fail.
+    pub fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        for (i, &file_stem) in self.file_stems.iter().enumerate() {
+            let src = self.path(file_stem);
+            let dest = history_path(dest_dir, file_stem);
+            if i + 1 == self.file_stems.len() {
+                let len = fs::metadata(&src)?.len();
+                let mut copied = File::create(&dest)?;
+                io::copy(&mut File::open(&src)?.take(len), &mut copied)?;
+                copied.sync_all()?;
+            } else if fs::hard_link(&src, &dest).is_err() {
+                fs::copy(&src, &dest)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn disk_bytes(&self) -> Result<u64> {
+        self.file_stems.iter().map(|&file_stem| Ok(fs::metadata(self.path(file_stem))?.len())).sum()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        for file_stem in self.file_stems.drain(..) {
+            fs::remove_file(history_path(&self.dir_path, file_stem))?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, file_stem: u64) -> PathBuf {
+        history_path(&self.dir_path, file_stem)
+    }
+}
+
+fn history_path(dir_path: &Path, file_stem: u64) -> PathBuf {
+    dir_path.join(file_stem.to_string() + ".history")
+}
@@ -0,0 +1,150 @@
+use crate::error::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+use crate::engine::kvstore::command::{Command, CommandPos};
+use crate::engine::kvstore::tools;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpType {
+    Set,
+    Remove,
+}
+
+/// A command read from a log file. Commands of a batch are reported one by one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordInfo {
+    pub file_stem: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub op: OpType,
+    pub key: String,
+    /// `None` for remove commands.
+    pub value: Option<String>,
+    /// Whether the command is part of a batch written by `mset`.
+    pub batch: bool,
+    /// Whether the index still points at this command after reading all records so far.
+    pub live: bool,
+}
+
+/// Read the log files of a `KvStore` directory without opening the store, rebuilding the
+/// index the same way `KvStore::open` does.
+///
+/// Each call of `read_new` only returns records appended since the previous call, so it can
+/// be polled to tail the active log.
+pub struct Inspector {
+    dir_path: PathBuf,
+    key_map: HashMap<String, CommandPos>,
+    file_stem: u64,
+    offset: u64,
+}
+
+impl Inspector {
+    pub fn new(dir_path: impl Into<PathBuf>) -> Self {
+        Self {
+            dir_path: dir_path.into(),
+            key_map: HashMap::new(),
+            file_stem: 0,
+            offset: 0,
+        }
+    }
+
+    /// Read records appended since the last call, including records of new log files.
+    /// A record that is still being written is left for the next call.
+    /// # Examples
+    /// ```rust
+    /// use tempfile::TempDir;
+    /// use kvs::engine::KvsEngine;
+    /// use kvs::KvStore;
+    /// use kvs::engine::kvstore::inspect::Inspector;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("name", "Adam").unwrap();
+    /// let mut inspector = Inspector::new(temp_dir.path());
+    /// assert_eq!(inspector.read_new().unwrap().len(), 1);
+    /// store.set("name", "Eve").unwrap();
+    /// let records = inspector.read_new().unwrap();
+    /// assert_eq!(records[0].value, Some("Eve".to_owned()));
+    /// ```
+    pub fn read_new(&mut self) -> Result<Vec<RecordInfo>> {
+        let mut infos = vec![];
+        for file_stem in tools::collect_file_stems(&self.dir_path)? {
+            if file_stem < self.file_stem {
+                continue;
+            }
+            if file_stem > self.file_stem {
+                self.file_stem = file_stem;
+                self.offset = 0;
+            }
+            let mut file = File::open(self.dir_path.join(file_stem.to_string() + ".log"))?;
+            file.seek(SeekFrom::Start(self.offset))?;
+            for record in tools::read_records(BufReader::new(file), self.offset) {
+                let (offset, len, cmd) = match record {
+                    Ok(record) => record,
+                    Err(err) if err.is_eof() => break,
+                    Err(err) => return Err(err.into()),
+                };
+                tools::apply_record(file_stem, offset, len, &cmd, &mut self.key_map)?;
+                match cmd {
+                    Command::BatchCommand(_) => {
+                        for (inner_offset, len, inner_cmd) in cmd.batch_entries()? {
+                            infos.push(record_info(file_stem, offset + inner_offset, len, inner_cmd, true));
+                        }
+                    }
+                    // a compaction marker changes no key
+                    Command::CompactCommand { .. } => {}
+                    _ => infos.push(record_info(file_stem, offset, len, &cmd, false)),
+                }
+                self.offset = offset + len;
+            }
+        }
+        for info in infos.iter_mut() {
+            info.live = self.key_map
+                .get(&info.key)
+                .is_some_and(|pos| pos.file_stem == info.file_stem && pos.offset == info.offset);
+        }
+        Ok(infos)
+    }
+}
+
+fn record_info(file_stem: u64, offset: u64, len: u64, cmd: &Command, batch: bool) -> RecordInfo {
+    let (op, key, value) = match cmd {
+        Command::SetCommand { key, value, .. } => (OpType::Set, key.clone(), Some(value.clone())),
+        Command::RemoveCommand { key, .. } => (OpType::Remove, key.clone(), None),
+        // nested batches are never written, compaction markers are skipped by `read_new`
+        Command::BatchCommand(_) | Command::CompactCommand { .. } => (OpType::Set, String::new(), None),
+    };
+    RecordInfo { file_stem, offset, len, op, key, value, batch, live: false }
+}
+
+#[cfg(test)]
+mod inspect_tests {
+    use tempfile::TempDir;
+    use crate::engine::KvsEngine;
+    use crate::KvStore;
+    use crate::Result;
+    use super::{Inspector, OpType};
+
+    // Should mark overwritten and removed commands as not live
+    #[test]
+    fn live_records() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1", "value1")?;
+        store.mset(&[("key1".to_owned(), "value2".to_owned()), ("key2".to_owned(), "value3".to_owned())])?;
+        store.remove("key2")?;
+
+        let records = Inspector::new(temp_dir.path()).read_new()?;
+        let summary: Vec<_> = records.iter().map(|r| (r.op, r.key.as_str(), r.batch, r.live)).collect();
+        assert_eq!(summary, vec![
+            (OpType::Set, "key1", false, false),
+            (OpType::Set, "key1", true, true),
+            (OpType::Set, "key2", true, false),
+            (OpType::Remove, "key2", false, false),
+        ]);
+        assert_eq!(records[0].offset, 0);
+        assert_eq!(records[1].offset, records[0].len + "{\"BatchCommand\":[".len() as u64);
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
+pub mod check;
+pub mod inspect;
 mod command;
+mod history;
 mod io;
 pub mod store;
 pub mod tools;
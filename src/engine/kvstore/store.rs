@@ -1,12 +1,15 @@
 use crate::error::Result;
-use crate::error::KvError::UnexpectedCmdType;
-use std::collections::HashMap;
+use crate::error::KvError::{self, UnexpectedCmdType};
+use std::collections::{hash_map, HashMap};
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use crate::engine::KvsEngine;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::engine::{Change, Changes, EngineStats, Event, Events, KvPairs, KvsEngine, Snapshot};
+use crate::engine::watch::EventBus;
 use crate::engine::kvstore::command::{Command, CommandPos};
+use crate::engine::kvstore::history::History;
 use crate::engine::kvstore::io::{BufReaderWithOffset, BufWriterWithOffset};
 use crate::engine::kvstore::tools::{self, FileNameGenerator};
 
@@ -21,6 +24,15 @@ pub struct KvStore {
     uncompacted: u64,
     threshold: u64,
     dir_path: PathBuf,
+    compactions: u64,
+    compaction_time: Duration,
+    events: EventBus,
+    /// The sequence number of the latest mutation.
+    seq: u64,
+    /// Mutations up to this sequence number are dropped from the log by the latest compaction,
+    /// the latest ones of them are kept in `history`.
+    compacted_seq: u64,
+    history: History,
 }
 
 impl KvsEngine for KvStore {
@@ -41,7 +53,8 @@ impl KvsEngine for KvStore {
     /// assert!(kvs.set("name", "Adam").is_ok());
     /// ```
     fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let cmd = Command::set(key, value).as_json()?;
+        let seq = self.seq + 1;
+        let cmd = Command::set(key, value, seq).as_json()?;
         let offset = self.writer.offset;
         let len = self.writer.write(cmd.as_bytes())?;
         self.writer.flush()?;
@@ -49,6 +62,8 @@ impl KvsEngine for KvStore {
         if let Some(old_cmd_pos) = self.key_map.insert(key.to_string(), cmd_pos) {
             self.uncompacted += old_cmd_pos.len;
         }
+        self.seq = seq;
+        self.events.publish(Change { seq, event: Event::Set { key: key.to_owned(), value: value.to_owned() }, remaining: 0 });
         self.compact()?;
         Ok(())
     }
@@ -76,18 +91,7 @@ impl KvsEngine for KvStore {
     /// ```
     fn get(&mut self, key: &str) -> Result<Option<String>> {
         if let Some(cmd_pos) = self.key_map.get(key) {
-            let reader = self.reader_map.get_mut(&cmd_pos.file_stem).expect(&format!(
-                "log file: {}.log is not cached in memory",
-                cmd_pos.file_stem
-            ));
-            reader.seek(SeekFrom::Start(cmd_pos.offset))?;
-            let taker = reader.take(cmd_pos.len);
-            let command: Command = serde_json::from_reader(taker)?;
-            if let Command::SetCommand { value, .. } = command {
-                Ok(Some(value))
-            } else {
-                Err(UnexpectedCmdType(command.name()))
-            }
+            Ok(Some(read_value(&mut self.reader_map, cmd_pos)?))
         } else {
             Ok(None)
         }
@@ -118,16 +122,244 @@ impl KvsEngine for KvStore {
     fn remove(&mut self, key: &str) -> Result<Option<()>> {
         if let Some(old_cmd_pos) = self.key_map.remove(key) {
             self.uncompacted += old_cmd_pos.len;
-            let cmd = Command::rm(key).as_json()?;
+            let seq = self.seq + 1;
+            let cmd = Command::rm(key, seq).as_json()?;
             self.writer.write(cmd.as_bytes())?;
             self.writer.flush()?;
             self.uncompacted += cmd.len() as u64;
+            self.seq = seq;
+            self.events.publish(Change { seq, event: Event::Remove { key: key.to_owned() }, remaining: 0 });
             self.compact()?;
             Ok(Some(()))
         } else {
             Ok(None)
         }
     }
+
+    /// Set several key-value pairs within one log record.
+    /// A torn batch record fails to parse, so it never gets partially replayed.
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::KvsEngine;
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().expect("");
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// let pairs = vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())];
+    /// kvs.mset(&pairs).unwrap();
+    /// assert_eq!(kvs.get("b").unwrap(), Some("2".to_owned()));
+    /// ```
+    fn mset(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let first_seq = self.seq + 1;
+        let batch = Command::batch(
+            pairs.iter().zip(first_seq..).map(|((key, value), seq)| Command::set(key, value, seq)).collect()
+        );
+        let json = batch.as_json()?;
+        let offset = self.writer.offset;
+        self.writer.write_all(json.as_bytes())?;
+        self.writer.flush()?;
+        for (inner_offset, len, cmd) in batch.batch_entries()? {
+            if let Command::SetCommand { key, .. } = cmd {
+                let cmd_pos = CommandPos::new(self.generator.current, offset + inner_offset, len);
+                if let Some(old_cmd_pos) = self.key_map.insert(key.clone(), cmd_pos) {
+                    self.uncompacted += old_cmd_pos.len;
+                }
+            }
+        }
+        for change in batch.into_changes() {
+            self.seq = change.seq;
+            self.events.publish(change);
+        }
+        self.compact()?;
+        Ok(())
+    }
+
+    /// Copy the current state into `dest_dir`.
+    ///
+    /// Log files other than the active one are never written again, so they are hard-linked
+    /// (or copied if linking fails). The active file is copied up to the current writer offset.
+    /// History files are taken at the same point, so `changes` of the copy resumes where the store is.
+    /// # Errors
+    /// * `KvError::Message` `dest_dir` already contains log files
+    /// * `KvError::IoError` fail due to I/O errors
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::KvsEngine;
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let backup_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("name", "Adam").unwrap();
+    /// kvs.checkpoint(backup_dir.path()).unwrap();
+    /// let mut backup = KvStore::open(backup_dir.path()).unwrap();
+    /// assert_eq!(backup.get("name").unwrap(), Some("Adam".to_owned()));
+    /// ```
+    fn checkpoint(&mut self, dest_dir: &Path) -> Result<()> {
+        fs::create_dir_all(dest_dir)?;
+        if !tools::collect_file_stems(dest_dir)?.is_empty() {
+            return Err(KvError::Message(format!("{} already contains log files", dest_dir.display())));
+        }
+        self.writer.flush()?;
+        let active_len = self.writer.offset;
+        for file_stem in self.reader_map.keys() {
+            let file_name = file_stem.to_string() + ".log";
+            let src = self.dir_path.join(&file_name);
+            let dest = dest_dir.join(&file_name);
+            if *file_stem == self.generator.current {
+                let mut active = File::open(&src)?.take(active_len);
+                let mut copied = File::create(&dest)?;
+                io::copy(&mut active, &mut copied)?;
+                copied.sync_all()?;
+            } else if fs::hard_link(&src, &dest).is_err() {
+                fs::copy(&src, &dest)?;
+            }
+        }
+        // the checkpoint replays the same changes as the store at this point
+        self.history.checkpoint(dest_dir)
+    }
+
+    /// Iterate over live pairs in no particular order.
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::KvsEngine;
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("user1", "Adam").unwrap();
+    /// kvs.set("group1", "admin").unwrap();
+    /// let pairs: Vec<_> = kvs.scan("user").unwrap().collect::<kvs::Result<_>>().unwrap();
+    /// assert_eq!(pairs, vec![("user1".to_owned(), "Adam".to_owned())]);
+    /// ```
+    fn scan(&mut self, prefix: &str) -> Result<KvPairs<'_>> {
+        Ok(Box::new(KvStoreScan {
+            keys: self.key_map.iter(),
+            reader_map: &mut self.reader_map,
+            prefix: prefix.to_owned(),
+        }))
+    }
+
+    /// Report keys in index, size of log files, and compaction status.
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::KvsEngine;
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("name", "Adam").unwrap();
+    /// kvs.set("name", "Eve").unwrap();
+    /// let stats = kvs.stats().unwrap();
+    /// assert_eq!(stats.keys, 1);
+    /// assert!(stats.uncompacted_bytes.unwrap() > 0);
+    /// ```
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.writer.flush()?;
+        let mut disk_bytes = 0;
+        let file_stems = tools::collect_file_stems(&self.dir_path)?;
+        for file_stem in &file_stems {
+            disk_bytes += fs::metadata(self.dir_path.join(file_stem.to_string() + ".log"))?.len();
+        }
+        disk_bytes += self.history.disk_bytes()?;
+        Ok(EngineStats {
+            engine: "kvs".to_owned(),
+            keys: self.key_map.len() as u64,
+            disk_bytes,
+            seq: self.seq,
+            uncompacted_bytes: Some(self.uncompacted),
+            log_files: Some(file_stems.len() as u64),
+            compactions: Some(self.compactions),
+            compaction_time: Some(self.compaction_time),
+        })
+    }
+
+    fn seq(&mut self) -> Result<u64> {
+        Ok(self.seq)
+    }
+
+    /// Read the pairs of a copy of the index from log files opened before return, so neither
+    /// writes nor a compaction affect it. Only keys and positions are copied, values are read on demand.
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::KvsEngine;
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("name", "Adam").unwrap();
+    /// let snapshot = kvs.snapshot().unwrap();
+    /// kvs.set("name", "Eve").unwrap();
+    /// let pairs: Vec<_> = snapshot.collect::<kvs::Result<_>>().unwrap();
+    /// assert_eq!(pairs, vec![("name".to_owned(), "Adam".to_owned())]);
+    /// ```
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        self.writer.flush()?;
+        let mut reader_map = HashMap::new();
+        for file_stem in self.reader_map.keys() {
+            let file = File::open(self.dir_path.join(file_stem.to_string() + ".log"))?;
+            reader_map.insert(*file_stem, BufReaderWithOffset::new(file)?);
+        }
+        let keys: Vec<(String, CommandPos)> = self.key_map.iter()
+            .map(|(key, pos)| (key.clone(), CommandPos::new(pos.file_stem, pos.offset, pos.len)))
+            .collect();
+        let mut keys = keys.into_iter();
+        Ok(Box::new(std::iter::from_fn(move || {
+            let (key, cmd_pos) = keys.next()?;
+            Some(read_value(&mut reader_map, &cmd_pos).map(|value| (key, value)))
+        })))
+    }
+
+    /// Watch changes through an in-process event bus, events are sent after they are written to the log.
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::{Event, KvsEngine};
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// let mut events = kvs.watch_prefix("user").unwrap();
+    /// kvs.set("group1", "admin").unwrap();
+    /// kvs.set("user1", "Adam").unwrap();
+    /// assert_eq!(events.next(), Some(Event::Set { key: "user1".to_owned(), value: "Adam".to_owned() }));
+    /// ```
+    fn watch_prefix(&mut self, prefix: &str) -> Result<Events> {
+        Ok(self.events.watch(prefix))
+    }
+
+    /// Replay records of the history files and then the log files, which keep the latest mutations,
+    /// see `set_changes_retained`. Files are opened before return, so a compaction during the replay
+    /// does not affect it.
+    /// # Examples
+    /// ```
+    /// use tempfile::TempDir;
+    /// use kvs::engine::{Event, KvsEngine};
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("name", "Adam").unwrap();
+    /// kvs.remove("name").unwrap();
+    /// let change = kvs.changes(1).unwrap().next().unwrap().unwrap();
+    /// assert_eq!((change.seq, change.event), (2, Event::Remove { key: "name".to_owned() }));
+    /// ```
+    fn changes(&mut self, since: u64) -> Result<Changes> {
+        let compacted_seq = self.history.first_seq().unwrap_or(self.compacted_seq);
+        if since < compacted_seq {
+            return Err(KvError::Compacted(since, compacted_seq));
+        }
+        let live = self.events.changes();
+        let until = self.seq;
+        // sets rewritten by a compaction are in the log as well as in history
+        let logged_since = since.max(self.compacted_seq).max(self.history.last_seq());
+        let history = read_changes(self.history.files()?)
+            .filter(move |change| change.as_ref().map_or(true, |change| change.seq > since && change.seq <= until));
+        let logged = read_changes(self.log_files()?)
+            .filter(move |change| change.as_ref().map_or(true, |change| change.seq > logged_since && change.seq <= until));
+        Ok(Box::new(history.chain(logged).chain(live.into_iter().map(Ok))))
+    }
 }
 
 impl KvStore {
@@ -144,6 +376,8 @@ impl KvStore {
     /// ```
     pub fn open(dir_path: impl Into<PathBuf>) -> Result<KvStore> {
         let mut uncompacted = 0u64;
+        let mut seq = 0u64;
+        let mut compacted_seq = 0u64;
         let mut generator = FileNameGenerator::new("log");
         let mut key_map = HashMap::new();
         let mut reader_map = HashMap::new();
@@ -167,11 +401,15 @@ impl KvStore {
                 .write(true)
                 .open(file_path)?;
             let mut reader = BufReaderWithOffset::new(file)?;
-            uncompacted += tools::read_log(file_stem, &mut key_map, &mut reader)?;
+            let summary = tools::read_log(file_stem, &mut key_map, &mut reader)?;
+            uncompacted += summary.uncompacted;
+            seq = seq.max(summary.last_seq);
+            compacted_seq = compacted_seq.max(summary.compacted_seq);
             reader_map.insert(file_stem, reader);
         }
 
         let writer = tools::new_writer(&dir_path, generator.current)?;
+        let history = History::open(&dir_path)?;
         Ok(Self {
             key_map,
             reader_map,
@@ -180,19 +418,31 @@ impl KvStore {
             uncompacted,
             threshold: DEFAULT_COMPACTION_THRESHOLD,
             dir_path,
+            compactions: 0,
+            compaction_time: Duration::ZERO,
+            events: EventBus::default(),
+            seq,
+            compacted_seq,
+            history,
         })
     }
 
     /// After calling `compact` method, a new writer and log file will substitute the old one.
     ///
     /// All old file will be compacted into a new one, the only reader in memory correspond to
-    /// the writer.
+    /// the writer. Mutations since the previous compaction are appended to history first.
     pub fn compact(&mut self) -> Result<()> {
         if self.uncompacted < self.threshold {
             return Ok(());
         }
+        let started = Instant::now();
+        let since = self.compacted_seq.max(self.history.last_seq());
+        let records = read_records(self.log_files()?)
+            .filter(|record| record.as_ref().map_or(true, |cmd| cmd.seq() > since && !matches!(cmd, Command::CompactCommand { .. })));
+        self.history.append(since, records)?;
         self.generator.next();
         self.new_writer()?;
+        self.writer.write_all(Command::CompactCommand { seq: self.seq }.as_json()?.as_bytes())?;
         for (key, cmd_pos) in self.key_map.iter_mut() {
             let reader = self.reader_map.get_mut(&cmd_pos.file_stem).expect(&format!(
                 "log file: {}.log is not cached in memory",
@@ -201,8 +451,8 @@ impl KvStore {
             reader.seek(SeekFrom::Start(cmd_pos.offset))?;
             let taker = reader.take(cmd_pos.len);
             let command: Command = serde_json::from_reader(taker)?;
-            if let Command::SetCommand { value, .. } = command {
-                let new_cmd_json = Command::set(key, &value).as_json()?;
+            if let Command::SetCommand { value, seq, .. } = command {
+                let new_cmd_json = Command::set(key, &value, seq).as_json()?;
                 cmd_pos.file_stem = self.generator.current;
                 cmd_pos.offset = self.writer.offset;
                 cmd_pos.len = self.writer.write(new_cmd_json.as_bytes())? as u64;
@@ -210,8 +460,8 @@ impl KvStore {
                 return Err(UnexpectedCmdType(command.name()));
             }
         }
-        self.reader_map.clear();
-        for (file_stem, _) in self.reader_map.iter() {
+        self.writer.flush()?;
+        for (file_stem, _) in self.reader_map.drain() {
             let mut reader_path = self.dir_path.clone();
             reader_path.push(file_stem.to_string() + ".log");
             fs::remove_file(reader_path)?;
@@ -219,6 +469,10 @@ impl KvStore {
         let reader = tools::new_reader(&self.dir_path, self.generator.current)?;
         self.reader_map.insert(self.generator.current, reader);
         self.uncompacted = 0;
+        self.compacted_seq = self.seq;
+        self.history.trim(self.seq)?;
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
         Ok(())
     }
 
@@ -235,6 +489,35 @@ impl KvStore {
         self.threshold = threshold;
     }
 
+    /// Set the number of latest mutations retained for `KvsEngine::changes` across compactions,
+    /// default 100000. Older ones are dropped by the next compaction.
+    /// # Example
+    /// ```rust
+    /// use tempfile::TempDir;
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_changes_retained(1000);
+    /// ```
+    pub fn set_changes_retained(&mut self, retained: u64) {
+        self.history.set_retained(retained);
+    }
+
+    /// Open every log file in order, the active one up to the records written so far.
+    fn log_files(&mut self) -> Result<Vec<io::Take<File>>> {
+        self.writer.flush()?;
+        let mut file_stems: Vec<u64> = self.reader_map.keys().copied().collect();
+        file_stems.sort_unstable();
+        let mut files = vec![];
+        for file_stem in file_stems {
+            let file = File::open(self.dir_path.join(file_stem.to_string() + ".log"))?;
+            // the active file may get a partial record while it is read
+            let len = if file_stem == self.generator.current { self.writer.offset } else { file.metadata()?.len() };
+            files.push(file.take(len));
+        }
+        Ok(files)
+    }
+
     /// Warning: after calling this method, the old writer will be removed from memory,
     /// but the reader which reads the same log file as old writer stays in memory.
     ///
@@ -247,12 +530,69 @@ impl KvStore {
     }
 }
 
+/// Iterator returned by `KvStore::scan`.
+struct KvStoreScan<'a> {
+    keys: hash_map::Iter<'a, String, CommandPos>,
+    reader_map: &'a mut HashMap<u64, BufReaderWithOffset<File>>,
+    prefix: String,
+}
+
+impl<'a> Iterator for KvStoreScan<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, cmd_pos) = self.keys.find(|(key, _)| key.starts_with(&self.prefix))?;
+        Some(read_value(self.reader_map, cmd_pos).map(|value| (key.clone(), value)))
+    }
+}
+
+/// Read records of `files` in order, stop after the first error.
+fn read_records<R: Read + Send>(files: Vec<R>) -> impl Iterator<Item = Result<Command>> + Send {
+    let mut failed = false;
+    files.into_iter()
+        .flat_map(|file| tools::read_records(BufReader::new(file), 0))
+        .map_while(move |record| {
+            // a reader should not be used after it yields an error
+            let done = failed;
+            failed = record.is_err();
+            (!done).then_some(record)
+        })
+        .map(|record| Ok(record?.2))
+}
+
+/// Read mutations of the records of `files` in order, stop after the first error.
+fn read_changes<R: Read + Send>(files: Vec<R>) -> impl Iterator<Item = Result<Change>> + Send {
+    read_records(files).flat_map(|record| match record {
+        Ok(cmd) => cmd.into_changes().into_iter().map(Ok).collect::<Vec<Result<Change>>>(),
+        Err(err) => vec![Err(err)],
+    })
+}
+
+/// Read the value of the set command located at `cmd_pos`.
+fn read_value(reader_map: &mut HashMap<u64, BufReaderWithOffset<File>>, cmd_pos: &CommandPos) -> Result<String> {
+    let reader = reader_map.get_mut(&cmd_pos.file_stem).unwrap_or_else(|| {
+        panic!("log file: {}.log is not cached in memory", cmd_pos.file_stem)
+    });
+    reader.seek(SeekFrom::Start(cmd_pos.offset))?;
+    let taker = reader.take(cmd_pos.len);
+    let command: Command = serde_json::from_reader(taker)?;
+    if let Command::SetCommand { value, .. } = command {
+        Ok(value)
+    } else {
+        Err(UnexpectedCmdType(command.name()))
+    }
+}
 
 #[cfg(test)]
 mod store_tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
     use tempfile::TempDir;
     use walkdir::WalkDir;
-    use super::KvsEngine;
+    use super::{Change, Event, KvError, KvsEngine};
     use super::KvStore;
     use super::Result;
 
@@ -333,12 +673,225 @@ mod store_tests {
         Ok(())
     }
 
+    // Should replay every pair of a batch after reopen, and keep later overwrites
+    #[test]
+    fn mset_values() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        let pairs: Vec<(String, String)> = (0..10)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        store.mset(&pairs)?;
+        store.set("key3", "overwritten")?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        drop(store);
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        let keys: Vec<String> = (0..11).map(|i| format!("key{}", i)).collect();
+        let values = store.mget(&keys)?;
+        assert_eq!(values[0], Some("value0".to_owned()));
+        assert_eq!(values[3], Some("overwritten".to_owned()));
+        assert_eq!(values[9], Some("value9".to_owned()));
+        assert_eq!(values[10], None);
+        assert_eq!(store.mdel(&keys)?, 10);
+        assert_eq!(store.get("key5")?, None);
+        Ok(())
+    }
+
+    // Backup taken between the writes of another thread should open to a prefix of the writes
+    #[test]
+    fn checkpoint_between_writes() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let store = Arc::new(Mutex::new(KvStore::open(temp_dir.path())?));
+
+        let writer_store = store.clone();
+        let writer = thread::spawn(move || -> Result<()> {
+            for i in 0..2000 {
+                writer_store.lock().unwrap().set(&format!("key{}", i), &i.to_string())?;
+            }
+            Ok(())
+        });
+        thread::sleep(Duration::from_millis(20));
+        store.lock().unwrap().checkpoint(backup_dir.path())?;
+        writer.join().unwrap()?;
+
+        let mut backup = KvStore::open(backup_dir.path())?;
+        let mut written = 0;
+        while backup.get(&format!("key{}", written))?.is_some() {
+            written += 1;
+        }
+        for i in written..2000 {
+            assert_eq!(backup.get(&format!("key{}", i))?, None);
+        }
+        for i in 0..written {
+            assert_eq!(backup.get(&format!("key{}", i))?, Some(i.to_string()));
+        }
+        // the origin store is not affected by the backup
+        let mut store = store.lock().unwrap();
+        assert_eq!(store.get("key1999")?, Some("1999".to_owned()));
+        Ok(())
+    }
+
+    // Should resume changes from a checkpoint, including mutations moved to history by compactions
+    #[test]
+    fn changes_from_checkpoint() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_compact_threshold(200);
+        for i in 1..=300 {
+            store.set(&format!("key{}", i % 10), &i.to_string())?;
+        }
+        store.checkpoint(backup_dir.path())?;
+        for i in 301..=600 {
+            store.set(&format!("key{}", i % 10), &i.to_string())?;
+        }
+        assert!(store.stats()?.compactions.unwrap() > 1);
+        drop(store);
+
+        let mut backup = KvStore::open(backup_dir.path())?;
+        assert_eq!(backup.seq()?, 300);
+        let changes: Vec<Change> = backup.changes(0)?.take(300).collect::<Result<_>>()?;
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<_>>(), (1..=300).collect::<Vec<_>>());
+        assert_eq!(changes[299].event, Event::Set { key: "key0".to_owned(), value: "300".to_owned() });
+        backup.set("key1", "new")?;
+        let mut live = backup.changes(300)?;
+        assert_eq!(live.next().unwrap()?.seq, 301);
+        Ok(())
+    }
+
+    // Should read the pairs as of the snapshot after later writes and compactions
+    #[test]
+    fn snapshot_across_compactions() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_compact_threshold(200);
+        for i in 0..100 {
+            store.set(&format!("key{}", i), &i.to_string())?;
+        }
+        let snapshot = store.snapshot()?;
+        for i in 0..1000 {
+            store.set(&format!("key{}", i % 100), "new")?;
+        }
+        store.remove("key0")?;
+        assert!(store.stats()?.compactions.unwrap() > 1);
+        let mut pairs = snapshot.collect::<Result<Vec<_>>>()?;
+        pairs.sort_unstable();
+        let mut expected: Vec<_> = (0..100).map(|i| (format!("key{}", i), i.to_string())).collect();
+        expected.sort_unstable();
+        assert_eq!(pairs, expected);
+        assert_eq!(store.seq()?, 1101);
+        Ok(())
+    }
+
+    // Should send changes of watched keys in order and end after the store is dropped
+    #[test]
+    fn watch_events() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        let events = store.watch_prefix("user")?;
+        store.set("user1", "Adam")?;
+        store.set("group1", "admin")?;
+        store.mset(&[("user2".to_owned(), "Eve".to_owned())])?;
+        store.remove("user1")?;
+        assert_eq!(store.remove("user3")?, None);
+        drop(store);
+
+        let events: Vec<Event> = events.collect();
+        assert_eq!(events, vec![
+            Event::Set { key: "user1".to_owned(), value: "Adam".to_owned() },
+            Event::Set { key: "user2".to_owned(), value: "Eve".to_owned() },
+            Event::Remove { key: "user1".to_owned() },
+        ]);
+        Ok(())
+    }
+
+    // Should replay changes in sequence order across reopen, then follow live ones,
+    // and refuse changes dropped by a compaction
+    #[test]
+    fn changes_since() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1", "value1")?;
+        store.mset(&[("key2".to_owned(), "value2".to_owned()), ("key3".to_owned(), "value3".to_owned())])?;
+        store.remove("key1")?;
+        drop(store);
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key2", "value4")?;
+        let changes: Vec<Change> = store.changes(2)?.take(3).collect::<Result<_>>()?;
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(changes[1].event, Event::Remove { key: "key1".to_owned() });
+        let mut live = store.changes(5)?;
+        store.set("key3", "value5")?;
+        assert_eq!(live.next().unwrap()?.seq, 6);
+
+        store.set_compact_threshold(0);
+        store.set("key3", "value6")?;
+        drop(store);
+
+        // mutations dropped from the log by the compaction are kept in history
+        let mut store = KvStore::open(temp_dir.path())?;
+        let changes: Vec<Change> = store.changes(0)?.take(7).collect::<Result<_>>()?;
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<_>>(), (1..=7).collect::<Vec<_>>());
+        assert_eq!(changes[6].event, Event::Set { key: "key3".to_owned(), value: "value6".to_owned() });
+
+        store.set_compact_threshold(0);
+        store.set_changes_retained(0);
+        store.set("key3", "value7")?;
+        assert!(matches!(store.changes(7), Err(KvError::Compacted(7, 8))));
+        drop(store);
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.changes(7).is_err());
+        store.set("key1", "value8")?;
+        assert_eq!(store.changes(8)?.next().unwrap()?.seq, 9);
+        Ok(())
+    }
+
+    // Should keep every mutation across many compactions and reopen,
+    // dropping a history record cut by a crash
+    #[test]
+    fn changes_across_compactions() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_compact_threshold(200);
+        for i in 1..=300 {
+            store.set(&format!("key{}", i % 10), &i.to_string())?;
+        }
+        assert!(store.stats()?.compactions.unwrap() > 10);
+        let seqs: Vec<u64> = store.changes(0)?.take(300).map(|change| change.unwrap().seq).collect();
+        assert_eq!(seqs, (1..=300).collect::<Vec<_>>());
+        drop(store);
+
+        let history_path = WalkDir::new(temp_dir.path()).into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.path().extension().is_some_and(|ext| ext == "history"))
+            .unwrap()
+            .into_path();
+        let mut history = OpenOptions::new().append(true).open(history_path)?;
+        history.write_all(b"{\"SetCommand\":{\"key\":\"ke")?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_compact_threshold(200);
+        for i in 301..=400 {
+            store.set(&format!("key{}", i % 10), &i.to_string())?;
+        }
+        let changes: Vec<Change> = store.changes(250)?.take(150).collect::<Result<_>>()?;
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<_>>(), (251..=400).collect::<Vec<_>>());
+        assert_eq!(changes[149].event, Event::Set { key: "key0".to_owned(), value: "400".to_owned() });
+        Ok(())
+    }
+
     // Insert data until total size of the directory decreases.
     // Test data correctness after compaction.
     #[test]
     fn compaction() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let mut store = KvStore::open(temp_dir.path())?;
+        // history files would grow the directory until they are trimmed
+        store.set_changes_retained(0);
 
         let dir_size = || {
             let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -378,4 +931,30 @@ mod store_tests {
 
         panic!("No compaction detected");
     }
+
+    // Should delete the compacted log files and read values from the compacted log right away
+    #[test]
+    fn compaction_removes_old_logs() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_compact_threshold(200);
+        let log_files = || {
+            WalkDir::new(temp_dir.path()).into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+                .count()
+        };
+        for i in 0..100 {
+            store.set("key", &i.to_string())?;
+            store.set(&format!("other{}", i % 3), "value")?;
+            assert_eq!(store.get("key")?, Some(i.to_string()));
+        }
+        assert_eq!(log_files(), 1);
+
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key")?, Some("99".to_owned()));
+        assert_eq!(store.get("other2")?, Some("value".to_owned()));
+        Ok(())
+    }
 }
\ No newline at end of file
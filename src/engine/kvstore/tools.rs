@@ -3,7 +3,8 @@ use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom};
+use std::iter;
 use std::path::PathBuf;
 use crate::engine::kvstore::command::{Command, CommandPos};
 use crate::engine::kvstore::io::{BufReaderWithOffset, BufWriterWithOffset};
@@ -37,6 +38,17 @@ impl FileNameGenerator {
     }
 }
 
+/// What `read_log` finds in a log file besides the index.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LogSummary {
+    /// Bytes that become stale.
+    pub uncompacted: u64,
+    /// The largest sequence number.
+    pub last_seq: u64,
+    /// The sequence number of the compaction that wrote the file, 0 if none.
+    pub compacted_seq: u64,
+}
+
 /// Read commands from log file and store them to key_map
 /// In consideration of data consistency, the `reader` must be a `BufReaderWithOffset`.
 ///
@@ -47,30 +59,78 @@ pub fn read_log(
     file_stem: u64,
     key_map: &mut HashMap<String, CommandPos>,
     reader: &mut BufReaderWithOffset<File>,
+) -> Result<LogSummary> {
+    let start = reader.seek(SeekFrom::Start(0))?;
+    let mut summary = LogSummary::default();
+    for record in read_records(reader, start) {
+        let (offset, len, cmd) = record?;
+        summary.uncompacted += apply_record(file_stem, offset, len, &cmd, key_map)?;
+        summary.last_seq = summary.last_seq.max(cmd.seq());
+        if let Command::CompactCommand { seq } = cmd {
+            summary.compacted_seq = seq;
+        }
+    }
+    Ok(summary)
+}
+
+/// Read records one by one, yield `(offset, len, command)` of each record.
+/// `start` is the offset of `reader` in the log file.
+///
+/// The iterator should not be used after it yields an error.
+pub(crate) fn read_records<R: Read>(reader: R, start: u64) -> impl Iterator<Item = serde_json::Result<(u64, u64, Command)>> {
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut offset = start;
+    iter::from_fn(move || {
+        let cmd = stream.next()?;
+        let end = start + stream.byte_offset() as u64;
+        let record = cmd.map(|cmd| (offset, end - offset, cmd));
+        offset = end;
+        Some(record)
+    })
+}
+
+/// Update key_map with a record read from log file, inner commands of a batch are located
+/// separately. Return the bytes that become stale.
+pub(crate) fn apply_record(
+    file_stem: u64,
+    offset: u64,
+    len: u64,
+    cmd: &Command,
+    key_map: &mut HashMap<String, CommandPos>,
 ) -> Result<u64> {
-    let mut offset = reader.seek(SeekFrom::Start(0))?;
     let mut uncompacted = 0u64;
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-    while let Some(cmd) = stream.next() {
-        let current_offset = stream.byte_offset() as u64;
-        let cmd_pos = CommandPos::new(file_stem, offset, current_offset - offset);
-        match cmd? {
-            Command::SetCommand { key, .. } => {
-                if let Some(old_cmd) = key_map.insert(key, cmd_pos) {
-                    uncompacted += old_cmd.len;
-                }
+    if let Command::BatchCommand(_) = cmd {
+        for (inner_offset, len, inner_cmd) in cmd.batch_entries()? {
+            let cmd_pos = CommandPos::new(file_stem, offset + inner_offset, len);
+            uncompacted += apply_command(inner_cmd, cmd_pos, key_map);
+        }
+    } else {
+        uncompacted += apply_command(cmd, CommandPos::new(file_stem, offset, len), key_map);
+    }
+    Ok(uncompacted)
+}
+
+/// Update key_map with a single command located at `cmd_pos`.
+/// Return the bytes that become stale.
+fn apply_command(cmd: &Command, cmd_pos: CommandPos, key_map: &mut HashMap<String, CommandPos>) -> u64 {
+    let mut uncompacted = 0u64;
+    match cmd {
+        Command::SetCommand { key, .. } => {
+            if let Some(old_cmd) = key_map.insert(key.clone(), cmd_pos) {
+                uncompacted += old_cmd.len;
             }
-            Command::RemoveCommand { key } => {
-                // if already contains this
-                if let Some(old_cmd) = key_map.remove(&key) {
-                    uncompacted += old_cmd.len;
-                }
-                uncompacted += current_offset - offset;
+        }
+        Command::RemoveCommand { key, .. } => {
+            // if already contains this
+            if let Some(old_cmd) = key_map.remove(key) {
+                uncompacted += old_cmd.len;
             }
+            uncompacted += cmd_pos.len;
         }
-        offset = current_offset;
+        // nested batches are never written, compaction markers are not in the index
+        Command::BatchCommand(_) | Command::CompactCommand { .. } => {}
     }
-    Ok(uncompacted)
+    uncompacted
 }
 
 /// Collect log file names in given directory
@@ -85,10 +145,15 @@ pub fn read_log(
 /// assert_eq!(collect_file_stems(tempdir.path()).unwrap(), vec![0u64, 1u64]);
 /// ```
 pub fn collect_file_stems(path: impl Into<PathBuf>) -> Result<Vec<u64>> {
+    collect_stems(path, "log")
+}
+
+/// Collect sorted stems of file names with the given extension in given directory.
+pub(crate) fn collect_stems(path: impl Into<PathBuf>, extension: &str) -> Result<Vec<u64>> {
     let path = path.into();
     let mut file_stems: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension().eq(&Some("log".as_ref())))
+        .filter(|path| path.is_file() && path.extension().eq(&Some(extension.as_ref())))
         .flat_map(|path| {
             path.file_stem()
                 .and_then(OsStr::to_str)
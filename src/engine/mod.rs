@@ -1,13 +1,89 @@
 pub mod kvstore;
+pub mod raft;
 pub mod sled;
+pub mod watch;
 
 pub use self::kvstore::KvStore;
+pub use self::raft::RaftEngine;
 pub use self::sled::Sled;
+pub use self::watch::{Change, Changes, Event, Events};
 
+use std::path::Path;
+use std::time::Duration;
 use crate::Result;
 
+/// Statistics of an engine, fields that an engine does not track are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Name of the engine, `kvs` or `sled`, prefixed with `raft+` when replicated by `RaftEngine`.
+    pub engine: String,
+    /// Number of live keys.
+    pub keys: u64,
+    /// Bytes used on disk.
+    pub disk_bytes: u64,
+    /// Sequence number of the latest mutation, see `KvsEngine::changes`.
+    pub seq: u64,
+    /// Bytes of stale records waiting for compaction.
+    pub uncompacted_bytes: Option<u64>,
+    /// Number of log files.
+    pub log_files: Option<u64>,
+    /// Number of compactions since the store is opened.
+    pub compactions: Option<u64>,
+    /// Time spent on compactions since the store is opened.
+    pub compaction_time: Option<Duration>,
+}
+
+/// Lazily read key-value pairs, see `KvsEngine::scan`.
+pub type KvPairs<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// Key-value pairs that do not borrow the engine, see `KvsEngine::snapshot`.
+pub type Snapshot = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
 pub trait KvsEngine {
     fn set(&mut self, key: &str, value: &str) -> Result<()>;
     fn get(&mut self, key: &str) -> Result<Option<String>>;
     fn remove(&mut self, key: &str) -> Result<Option<()>>;
+    /// Set all key-value pairs atomically: after a crash either all or none of them are stored.
+    fn mset(&mut self, pairs: &[(String, String)]) -> Result<()>;
+    /// Write a consistent copy of the store into `dest_dir`, which can be opened by the same engine.
+    /// The store stays usable during and after the checkpoint. Fails if `dest_dir` already holds a store.
+    fn checkpoint(&mut self, dest_dir: &Path) -> Result<()>;
+    /// Iterate over every live pair whose key starts with `prefix`, values are read on demand.
+    /// The order of pairs depends on the engine.
+    fn scan(&mut self, prefix: &str) -> Result<KvPairs<'_>>;
+    /// Report statistics of the store.
+    fn stats(&mut self) -> Result<EngineStats>;
+    /// Sequence number of the latest mutation, see `changes`. Unlike `stats` it is always cheap.
+    fn seq(&mut self) -> Result<u64>;
+    /// Watch changes of every key starting with `prefix`, made through this engine after the call.
+    fn watch_prefix(&mut self, prefix: &str) -> Result<Events>;
+    /// Replay retained mutations with a sequence number greater than `since`, then follow new ones.
+    /// Sequence numbers start at 1, so `since` 0 replays everything still retained.
+    /// # Errors
+    /// * `KvError::Compacted` some mutations after `since` are no longer retained
+    fn changes(&mut self, since: u64) -> Result<Changes>;
+
+    /// Get values of several keys, `None` for each non-existent key.
+    fn mget(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Every live pair as of the call, read after the engine is released, so that a large scan does
+    /// not block writes. Pairs written later may be seen by engines without point-in-time reads,
+    /// they are listed with each engine. By default the pairs are collected in memory.
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        let pairs = self.scan("")?.collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+
+    /// Remove several keys, return the number of keys actually removed.
+    fn mdel(&mut self, keys: &[String]) -> Result<usize> {
+        let mut removed = 0;
+        for key in keys {
+            if self.remove(key)?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
 }
\ No newline at end of file
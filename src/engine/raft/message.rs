@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+/// A command replicated through the raft log and applied to the engine of every node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum RaftCommand {
+    /// Appended by a new leader to commit entries of earlier terms, and by reads to make sure
+    /// the node is still the leader when they run.
+    Noop,
+    Set { key: String, value: String },
+    Remove { key: String },
+    MSet { pairs: Vec<(String, String)> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: RaftCommand,
+}
+
+/// Messages between raft nodes, named after the RPCs of the raft paper.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Message {
+    RequestVote { last_log_index: u64, last_log_term: u64 },
+    RequestVoteReply { granted: bool },
+    AppendEntries { prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64 },
+    /// Also the reply of `InstallSnapshot`. On success `match_index` is the last index the
+    /// follower has in common with the leader, on failure it is a hint where to retry from.
+    AppendEntriesReply { success: bool, match_index: u64 },
+    /// A chunk of the live pairs of the leader's engine as of the entry at `last_index`, `offset`
+    /// pairs are sent before it. The follower installs the snapshot once it has the `done` chunk,
+    /// and replies with `AppendEntriesReply`.
+    InstallSnapshot { last_index: u64, last_term: u64, offset: u64, pairs: Vec<(String, String)>, done: bool },
+    /// The number of pairs of the snapshot at `last_index` the follower has received, the leader
+    /// sends the next chunk if it matches, or starts over.
+    InstallSnapshotReply { last_index: u64, received: u64 },
+}
+
+/// A message with its sender, receiver and the sender's term, written as a json line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub from: u64,
+    pub to: u64,
+    pub term: u64,
+    pub message: Message,
+}
+
+/// The first line of every connection between nodes. Envelopes are only accepted from a node of
+/// the cluster that knows the cluster secret, and only with the id it named here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Handshake {
+    pub from: u64,
+    pub secret: String,
+}
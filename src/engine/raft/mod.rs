@@ -0,0 +1,169 @@
+mod node;
+mod message;
+mod storage;
+mod transport;
+
+pub use self::node::{RaftRole, RaftStatus};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use crate::engine::{Changes, EngineStats, Events, KvPairs, KvsEngine, Snapshot};
+use crate::{KvError, Result};
+use self::node::{Core, Input};
+use self::message::RaftCommand;
+use self::storage::Storage;
+
+/// Fail a write or read that is not applied within this long, it may still be applied later.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// An engine replicated through a raft log among a fixed set of nodes, each of which runs a
+/// `RaftEngine` over its own local engine.
+///
+/// Writes are appended to the log by the leader and applied to the local engine of every node
+/// once a majority has them, so they survive the loss of a minority of nodes. Reads go through
+/// the log too, so every read and write is linearizable. Only the leader serves them, other
+/// nodes fail with `KvError::NotLeader` which names the leader if known.
+///
+/// The local engine doubles as the snapshot of the log: applied entries are dropped from the log
+/// and a node that is too far behind receives every pair of the leader's engine instead, in chunks
+/// that it stages on disk and installs after the last one.
+/// `watch_prefix` and `changes` report the mutations applied to the local engine.
+pub struct RaftEngine<E: KvsEngine> {
+    engine: Arc<Mutex<E>>,
+    inputs: Sender<Input>,
+}
+
+impl<E: KvsEngine + Send + 'static> RaftEngine<E> {
+    /// Start node `id` of the cluster whose raft addresses are `nodes`, this one included,
+    /// keeping its log in `dir`. The node listens to its own address for messages of the others,
+    /// which should be started with the same `secret`. The secret is sent in clear when nodes
+    /// connect, so raft addresses should be on a trusted network.
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::collections::BTreeMap;
+    /// use tempfile::TempDir;
+    /// use kvs::engine::{KvsEngine, RaftEngine};
+    /// use kvs::KvStore;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let nodes = BTreeMap::from([(1, "127.0.0.1:6399".to_owned())]);
+    /// let store = KvStore::open(temp_dir.path().join("data")).unwrap();
+    /// let mut engine = RaftEngine::open(store, temp_dir.path().join("raft"), 1, nodes, "secret").unwrap();
+    /// // a single node elects itself once its election timeout passes
+    /// std::thread::sleep(std::time::Duration::from_secs(1));
+    /// engine.set("key", "value").unwrap();
+    /// assert_eq!(engine.get("key").unwrap(), Some("value".to_owned()));
+    /// ```
+    pub fn open(engine: E, dir: impl Into<PathBuf>, id: u64, nodes: BTreeMap<u64, String>, secret: &str) -> Result<RaftEngine<E>> {
+        let addr = nodes.get(&id).ok_or_else(|| KvError::Message(format!("raft node {} is not in the cluster", id)))?;
+        let dir: PathBuf = dir.into();
+        let storage = Storage::open(&dir)?;
+        let engine = Arc::new(Mutex::new(engine));
+        let (inputs, receiver) = mpsc::channel();
+        let peers = nodes.keys().copied().filter(|peer| *peer != id).collect();
+        transport::listen(addr, peers, secret, inputs.clone())?;
+        let core = Core::new(id, nodes, secret, storage, engine.clone());
+        thread::spawn(move || core.run(receiver));
+        Ok(RaftEngine { engine, inputs })
+    }
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// Report the role, the term and the log position of this node.
+    pub fn status(&self) -> Result<RaftStatus> {
+        let (reply, receiver) = mpsc::channel();
+        self.inputs.send(Input::Status(reply)).map_err(|_| stopped())?;
+        receiver.recv().map_err(|_| stopped())
+    }
+
+    /// Replicate `command` and wait until it is applied to the local engine.
+    fn propose(&self, command: RaftCommand) -> Result<Option<()>> {
+        let (reply, receiver) = mpsc::channel();
+        self.inputs.send(Input::Propose(command, reply)).map_err(|_| stopped())?;
+        match receiver.recv_timeout(PROPOSAL_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(KvError::Message("raft proposal timed out, it may still be applied".to_owned())),
+            Err(RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+
+    /// Wait until every write committed before the call is applied locally, and this node is
+    /// still the leader, so a local read afterwards is linearizable.
+    fn read_barrier(&self) -> Result<MutexGuard<'_, E>> {
+        self.propose(RaftCommand::Noop)?;
+        Ok(self.engine.lock().expect("engine lock is poisoned"))
+    }
+}
+
+fn stopped() -> KvError {
+    KvError::Message("raft node is stopped".to_owned())
+}
+
+impl<E: KvsEngine> Drop for RaftEngine<E> {
+    fn drop(&mut self) {
+        let _ = self.inputs.send(Input::Stop);
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.propose(RaftCommand::Set { key: key.to_owned(), value: value.to_owned() })?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.read_barrier()?.get(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<()>> {
+        self.propose(RaftCommand::Remove { key: key.to_owned() })
+    }
+
+    fn mset(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        self.propose(RaftCommand::MSet { pairs: pairs.to_vec() })?;
+        Ok(())
+    }
+
+    /// Checkpoint the local engine, the copy is a plain store of that engine without the raft log.
+    fn checkpoint(&mut self, dest_dir: &Path) -> Result<()> {
+        self.read_barrier()?.checkpoint(dest_dir)
+    }
+
+    /// Pairs are read before the call returns, since the local engine is shared with the raft node.
+    fn scan(&mut self, prefix: &str) -> Result<KvPairs<'_>> {
+        let pairs = self.read_barrier()?.scan(prefix)?.collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+
+    /// Statistics of the local engine, named `raft+<engine>`.
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut stats = self.engine.lock().expect("engine lock is poisoned").stats()?;
+        stats.engine = format!("raft+{}", stats.engine);
+        Ok(stats)
+    }
+
+    /// Sequence number of the local engine.
+    fn seq(&mut self) -> Result<u64> {
+        self.engine.lock().expect("engine lock is poisoned").seq()
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        self.read_barrier()?.snapshot()
+    }
+
+    fn watch_prefix(&mut self, prefix: &str) -> Result<Events> {
+        self.engine.lock().expect("engine lock is poisoned").watch_prefix(prefix)
+    }
+
+    fn changes(&mut self, since: u64) -> Result<Changes> {
+        self.engine.lock().expect("engine lock is poisoned").changes(since)
+    }
+
+    fn mget(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.read_barrier()?.mget(keys)
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::engine::{KvsEngine, Snapshot};
+use crate::{replication, KvError, Result};
+use super::message::{Entry, Envelope, Message, RaftCommand};
+use super::storage::Storage;
+use super::transport::Transport;
+
+/// A leader sends entries or heartbeats to every follower this often.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// A follower that hears nothing from a leader for this long, plus a random part of the same
+/// length, starts an election. A leader that hears from no majority for this long steps down.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+/// Entries sent in a single `AppendEntries`.
+const MAX_APPEND_ENTRIES: usize = 256;
+/// Pairs sent in a single `InstallSnapshot`.
+const SNAPSHOT_CHUNK: usize = 1000;
+/// Applied entries kept in the log before it is compacted, the engine is the snapshot.
+const COMPACT_THRESHOLD: u64 = 10_000;
+
+/// The outcome of a proposed command once it is applied, `Some(())` unless a removed key did not exist.
+pub(crate) type Reply = Sender<Result<Option<()>>>;
+
+pub(crate) enum Input {
+    Message(Envelope),
+    Propose(RaftCommand, Reply),
+    Status(Sender<RaftStatus>),
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A view of a raft node, see `RaftEngine::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub id: u64,
+    pub role: RaftRole,
+    pub term: u64,
+    /// The leader of the current term if known.
+    pub leader: Option<u64>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+}
+
+/// A snapshot being sent to a peer, one chunk at a time once the previous one is acknowledged.
+struct OutgoingSnapshot {
+    last_index: u64,
+    last_term: u64,
+    pairs: Snapshot,
+    /// Pairs sent so far.
+    sent: u64,
+    /// When the latest chunk was sent, the transfer starts over if it is not acknowledged in time.
+    sent_at: Instant,
+}
+
+/// The raft state machine of a node, run by a single thread that owns it, see `Core::run`.
+pub(crate) struct Core<E: KvsEngine> {
+    id: u64,
+    /// Raft addresses of every node, this one included.
+    addrs: BTreeMap<u64, String>,
+    peers: Vec<u64>,
+    storage: Storage,
+    engine: Arc<Mutex<E>>,
+    transport: Transport,
+    role: RaftRole,
+    leader: Option<u64>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    votes: BTreeSet<u64>,
+    /// Leader only: the next entry to send to each peer.
+    next_index: BTreeMap<u64, u64>,
+    /// Leader only: the last entry known to be replicated on each peer.
+    match_index: BTreeMap<u64, u64>,
+    /// Leader only: when each peer last answered.
+    last_ack: BTreeMap<u64, Instant>,
+    /// Leader only: proposals waiting to be applied by index, with the term they were appended in.
+    pending: BTreeMap<u64, (u64, Reply)>,
+    /// Leader only: snapshots being sent to peers whose entries are compacted.
+    outgoing: BTreeMap<u64, OutgoingSnapshot>,
+    /// The snapshot being received as `(last_index, last_term, pairs received)`, staged in storage.
+    incoming: Option<(u64, u64, u64)>,
+}
+
+impl<E: KvsEngine> Core<E> {
+    pub(crate) fn new(id: u64, addrs: BTreeMap<u64, String>, secret: &str, storage: Storage, engine: Arc<Mutex<E>>) -> Core<E> {
+        let peers: Vec<u64> = addrs.keys().copied().filter(|peer| *peer != id).collect();
+        let peer_addrs: BTreeMap<u64, String> = peers.iter().map(|peer| (*peer, addrs[peer].clone())).collect();
+        let transport = Transport::new(id, &peer_addrs, secret);
+        // applied entries are in the engine, later ones are applied again once they are known to be
+        // committed, which leaves each key with the same value as before
+        let applied = storage.snapshot_index();
+        let now = Instant::now();
+        let mut core = Core {
+            id,
+            addrs,
+            peers,
+            storage,
+            engine,
+            transport,
+            role: RaftRole::Follower,
+            leader: None,
+            commit_index: applied,
+            last_applied: applied,
+            election_deadline: now,
+            heartbeat_due: now,
+            votes: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            last_ack: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            outgoing: BTreeMap::new(),
+            incoming: None,
+        };
+        core.reset_election_deadline();
+        core
+    }
+
+    /// Handle inputs and timers until the node is stopped or its storage fails.
+    pub(crate) fn run(mut self, inputs: Receiver<Input>) {
+        loop {
+            let deadline = match self.role {
+                RaftRole::Leader => self.heartbeat_due,
+                _ => self.election_deadline,
+            };
+            let result = match inputs.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Input::Message(envelope)) => self.step(envelope),
+                Ok(Input::Propose(command, reply)) => self.propose(command, reply),
+                Ok(Input::Status(reply)) => {
+                    let _ = reply.send(self.status());
+                    Ok(())
+                }
+                Ok(Input::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => Ok(()),
+            };
+            if let Err(err) = result.and_then(|()| self.tick()) {
+                log::error!("Raft node {} stopped: {}", self.id, err);
+                break;
+            }
+        }
+        self.fail_pending();
+    }
+
+    fn status(&self) -> RaftStatus {
+        RaftStatus {
+            id: self.id,
+            role: self.role,
+            term: self.storage.term(),
+            leader: self.leader,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+        }
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        match self.role {
+            RaftRole::Leader if now >= self.heartbeat_due => {
+                let reachable = self.peers.iter()
+                    .filter(|peer| self.last_ack.get(peer).is_some_and(|ack| now.duration_since(*ack) < ELECTION_TIMEOUT))
+                    .count();
+                if reachable + 1 < self.quorum() {
+                    // a partitioned leader can not commit anything, fail its proposals early
+                    log::warn!("Raft node {} lost the majority in term {}, stepping down", self.id, self.storage.term());
+                    return self.become_follower(self.storage.term(), None);
+                }
+                self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+                self.broadcast_append()
+            }
+            RaftRole::Follower | RaftRole::Candidate if now >= self.election_deadline => self.start_election(),
+            _ => Ok(()),
+        }
+    }
+
+    fn step(&mut self, envelope: Envelope) -> Result<()> {
+        let Envelope { from, term, message, .. } = envelope;
+        if term > self.storage.term() {
+            let leader = match message {
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(term, leader)?;
+        } else if term < self.storage.term() {
+            // the reply carries the newer term, which makes a stale leader or candidate step down
+            match message {
+                Message::RequestVote { .. } => self.send(from, Message::RequestVoteReply { granted: false }),
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => {
+                    self.send(from, Message::AppendEntriesReply { success: false, match_index: 0 })
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+        match message {
+            Message::RequestVote { last_log_index, last_log_term } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.storage.last_term(), self.storage.last_index());
+                let granted = up_to_date && self.storage.voted_for().is_none_or(|candidate| candidate == from);
+                if granted {
+                    self.storage.set_term(term, Some(from))?;
+                    self.reset_election_deadline();
+                }
+                self.send(from, Message::RequestVoteReply { granted });
+            }
+            Message::RequestVoteReply { granted } => {
+                if self.role == RaftRole::Candidate && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Message::AppendEntries { prev_log_index, prev_log_term, entries, leader_commit } => {
+                self.follow(from);
+                let reply = self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)?;
+                self.send(from, reply);
+            }
+            Message::AppendEntriesReply { success, match_index } => {
+                if self.role == RaftRole::Leader {
+                    self.on_append_reply(from, success, match_index)?;
+                }
+            }
+            Message::InstallSnapshot { last_index, last_term, offset, pairs, done } => {
+                self.follow(from);
+                let reply = self.receive_snapshot(last_index, last_term, offset, pairs, done)?;
+                self.send(from, reply);
+            }
+            Message::InstallSnapshotReply { last_index, received } => {
+                if self.role == RaftRole::Leader {
+                    self.on_snapshot_reply(from, last_index, received)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn propose(&mut self, command: RaftCommand, reply: Reply) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            let _ = reply.send(Err(self.not_leader()));
+            return Ok(());
+        }
+        let entry = Entry { term: self.storage.term(), index: self.storage.last_index() + 1, command };
+        self.pending.insert(entry.index, (entry.term, reply));
+        self.storage.append(&[entry])?;
+        self.advance_commit()?;
+        self.broadcast_append()
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        let term = self.storage.term() + 1;
+        self.storage.set_term(term, Some(self.id))?;
+        self.role = RaftRole::Candidate;
+        self.leader = None;
+        self.votes = BTreeSet::from([self.id]);
+        self.reset_election_deadline();
+        log::info!("Raft node {} starts an election in term {}", self.id, term);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let (last_log_index, last_log_term) = (self.storage.last_index(), self.storage.last_term());
+        for peer in self.peers.clone() {
+            self.send(peer, Message::RequestVote { last_log_index, last_log_term });
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        log::info!("Raft node {} is the leader of term {}", self.id, self.storage.term());
+        self.role = RaftRole::Leader;
+        self.leader = Some(self.id);
+        let now = Instant::now();
+        self.outgoing.clear();
+        for &peer in &self.peers {
+            self.next_index.insert(peer, self.storage.last_index() + 1);
+            self.match_index.insert(peer, 0);
+            self.last_ack.insert(peer, now);
+        }
+        self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+        // entries of earlier terms are committed only through an entry of the current term
+        let entry = Entry { term: self.storage.term(), index: self.storage.last_index() + 1, command: RaftCommand::Noop };
+        self.storage.append(&[entry])?;
+        self.advance_commit()?;
+        self.broadcast_append()
+    }
+
+    /// Move to `term` if it is newer, and follow `leader` if known.
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) -> Result<()> {
+        if term > self.storage.term() {
+            self.storage.set_term(term, None)?;
+        }
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+        self.reset_election_deadline();
+        self.fail_pending();
+        Ok(())
+    }
+
+    /// Accept `leader` of the current term.
+    fn follow(&mut self, leader: u64) {
+        if self.role != RaftRole::Follower {
+            self.role = RaftRole::Follower;
+            self.fail_pending();
+        }
+        self.leader = Some(leader);
+        self.reset_election_deadline();
+    }
+
+    fn append_entries(&mut self, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64) -> Result<Message> {
+        let snapshot_index = self.storage.snapshot_index();
+        let (prev_log_index, entries) = if prev_log_index < snapshot_index {
+            // entries up to the snapshot are committed, so they agree with the leader
+            (snapshot_index, entries.into_iter().filter(|entry| entry.index > snapshot_index).collect())
+        } else if self.storage.term_at(prev_log_index) != Some(prev_log_term) {
+            let hint = (prev_log_index - 1).min(self.storage.last_index());
+            return Ok(Message::AppendEntriesReply { success: false, match_index: hint });
+        } else {
+            (prev_log_index, entries)
+        };
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut missing = vec![];
+        for entry in entries {
+            match self.storage.term_at(entry.index) {
+                Some(term) if term == entry.term => {}
+                Some(_) => {
+                    self.storage.truncate(entry.index)?;
+                    missing.push(entry);
+                }
+                None => missing.push(entry),
+            }
+        }
+        self.storage.append(&missing)?;
+        self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+        self.apply()?;
+        Ok(Message::AppendEntriesReply { success: true, match_index: last_new })
+    }
+
+    fn on_append_reply(&mut self, from: u64, success: bool, match_index: u64) -> Result<()> {
+        self.last_ack.insert(from, Instant::now());
+        let next = self.next_index.get(&from).copied().unwrap_or(1);
+        if success {
+            if self.outgoing.get(&from).is_some_and(|snapshot| match_index >= snapshot.last_index) {
+                self.outgoing.remove(&from);
+            }
+            let matched = self.match_index.entry(from).or_insert(0);
+            *matched = (*matched).max(match_index);
+            self.next_index.insert(from, next.max(match_index + 1));
+            self.advance_commit()?;
+            if match_index < self.storage.last_index() {
+                self.send_append(from)?;
+            }
+        } else {
+            self.next_index.insert(from, (match_index + 1).min(next.saturating_sub(1)).max(1));
+            self.send_append(from)?;
+        }
+        Ok(())
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        for peer in self.peers.clone() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: u64) -> Result<()> {
+        let next = self.next_index.get(&peer).copied().unwrap_or(self.storage.last_index() + 1);
+        if next <= self.storage.snapshot_index() {
+            // the entries the peer needs are compacted, send the engine instead
+            if self.outgoing.get(&peer).is_some_and(|snapshot| snapshot.sent_at.elapsed() < ELECTION_TIMEOUT) {
+                return Ok(());
+            }
+            let last_index = self.last_applied;
+            let last_term = self.storage.term_at(last_index).expect("applied entries are in the log or the snapshot");
+            let pairs = lock(&self.engine).snapshot()?;
+            let snapshot = OutgoingSnapshot { last_index, last_term, pairs, sent: 0, sent_at: Instant::now() };
+            self.outgoing.insert(peer, snapshot);
+            self.send_snapshot_chunk(peer);
+            return Ok(());
+        }
+        let prev_log_index = next - 1;
+        let prev_log_term = self.storage.term_at(prev_log_index).expect("entries after the snapshot are in the log");
+        let entries = self.storage.entries_from(next, MAX_APPEND_ENTRIES).to_vec();
+        self.send(peer, Message::AppendEntries { prev_log_index, prev_log_term, entries, leader_commit: self.commit_index });
+        Ok(())
+    }
+
+    /// Send the next chunk of the snapshot being sent to `peer`, the pairs are read without the engine lock.
+    fn send_snapshot_chunk(&mut self, peer: u64) {
+        let snapshot = match self.outgoing.get_mut(&peer) {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let pairs = match snapshot.pairs.by_ref().take(SNAPSHOT_CHUNK).collect::<Result<Vec<_>>>() {
+            Ok(pairs) => pairs,
+            Err(err) => {
+                log::error!("Raft node {} failed to read the snapshot for node {}: {}", self.id, peer, err);
+                self.outgoing.remove(&peer);
+                return;
+            }
+        };
+        let (offset, done) = (snapshot.sent, pairs.len() < SNAPSHOT_CHUNK);
+        snapshot.sent += pairs.len() as u64;
+        snapshot.sent_at = Instant::now();
+        let (last_index, last_term) = (snapshot.last_index, snapshot.last_term);
+        self.send(peer, Message::InstallSnapshot { last_index, last_term, offset, pairs, done });
+    }
+
+    fn on_snapshot_reply(&mut self, from: u64, last_index: u64, received: u64) -> Result<()> {
+        self.last_ack.insert(from, Instant::now());
+        match self.outgoing.get(&from) {
+            Some(snapshot) if snapshot.last_index == last_index && snapshot.sent == received => {
+                self.send_snapshot_chunk(from);
+                Ok(())
+            }
+            // a chunk is lost or the follower started over, so does the leader
+            _ => {
+                self.outgoing.remove(&from);
+                self.send_append(from)
+            }
+        }
+    }
+
+    /// Commit the latest entry of the current term that a majority has.
+    fn advance_commit(&mut self) -> Result<()> {
+        let mut index = self.storage.last_index();
+        while index > self.commit_index && self.storage.term_at(index) == Some(self.storage.term()) {
+            let replicated = 1 + self.match_index.values().filter(|matched| **matched >= index).count();
+            if replicated >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+            index -= 1;
+        }
+        self.apply()
+    }
+
+    /// Stage a chunk of a snapshot, and install it into the engine and the log after the `done` chunk.
+    fn receive_snapshot(&mut self, last_index: u64, last_term: u64, offset: u64, pairs: Vec<(String, String)>, done: bool) -> Result<Message> {
+        if last_index <= self.commit_index {
+            self.incoming = None;
+            return Ok(Message::AppendEntriesReply { success: true, match_index: last_index });
+        }
+        if offset == 0 {
+            self.storage.begin_snapshot()?;
+            self.incoming = Some((last_index, last_term, 0));
+        }
+        let received = match &mut self.incoming {
+            Some((index, term, received)) if (*index, *term, *received) == (last_index, last_term, offset) => {
+                self.storage.stage_snapshot(&pairs)?;
+                *received += pairs.len() as u64;
+                *received
+            }
+            // a chunk in between is lost, the leader starts over
+            _ => return Ok(Message::InstallSnapshotReply { last_index, received: 0 }),
+        };
+        if !done {
+            return Ok(Message::InstallSnapshotReply { last_index, received });
+        }
+        self.incoming = None;
+        let loaded = replication::load_chunks(&self.engine, self.storage.staged_snapshot()?)?;
+        log::info!("Raft node {} installs a snapshot of {} keys up to {}", self.id, loaded, last_index);
+        self.storage.install_snapshot(last_index, last_term)?;
+        self.commit_index = last_index;
+        self.last_applied = last_index;
+        Ok(Message::AppendEntriesReply { success: true, match_index: last_index })
+    }
+
+    /// Apply committed entries to the engine and answer their proposals.
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self.storage.entry(index).cloned().expect("committed entries are in the log until applied");
+            let result = apply_command(&mut *lock(&self.engine), entry.command);
+            self.last_applied = index;
+            match self.pending.remove(&index) {
+                // another leader replaced the proposed entry
+                Some((term, reply)) if term != entry.term => { let _ = reply.send(Err(self.not_leader())); }
+                Some((_, reply)) => { let _ = reply.send(result); }
+                None => if let Err(err) = result {
+                    log::error!("Raft node {} failed to apply entry {}: {}", self.id, index, err);
+                }
+            }
+        }
+        if self.last_applied - self.storage.snapshot_index() >= COMPACT_THRESHOLD {
+            self.storage.compact(self.last_applied)?;
+        }
+        Ok(())
+    }
+
+    /// Answer every waiting proposal, they may or may not be applied later.
+    fn fail_pending(&mut self) {
+        for (_, (_, reply)) in mem::take(&mut self.pending) {
+            let _ = reply.send(Err(self.not_leader()));
+        }
+    }
+
+    fn not_leader(&self) -> KvError {
+        let leader = self.leader.filter(|leader| *leader != self.id).and_then(|leader| self.addrs.get(&leader));
+        KvError::NotLeader(leader.cloned().unwrap_or_else(|| "unknown".to_owned()))
+    }
+
+    fn quorum(&self) -> usize {
+        self.peers.len().div_ceil(2) + 1
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let random = RandomState::new().build_hasher().finish() % ELECTION_TIMEOUT.as_millis() as u64;
+        self.election_deadline = Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(random);
+    }
+
+    fn send(&self, to: u64, message: Message) {
+        self.transport.send(Envelope { from: self.id, to, term: self.storage.term(), message });
+    }
+}
+
+fn apply_command<E: KvsEngine>(engine: &mut E, command: RaftCommand) -> Result<Option<()>> {
+    match command {
+        RaftCommand::Noop => Ok(Some(())),
+        RaftCommand::Set { key, value } => engine.set(&key, &value).map(Some),
+        RaftCommand::Remove { key } => engine.remove(&key),
+        RaftCommand::MSet { pairs } => engine.mset(&pairs).map(Some),
+    }
+}
+
+fn lock<E>(engine: &Mutex<E>) -> MutexGuard<'_, E> {
+    engine.lock().expect("engine lock is poisoned")
+}
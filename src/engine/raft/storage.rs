@@ -0,0 +1,245 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::{KvError, Result};
+use super::message::Entry;
+
+const STATE_FILE: &str = "state.json";
+const LOG_FILE: &str = "log.jsonl";
+/// Chunks of a snapshot being received, one json array of pairs per line.
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+
+/// Persistent raft state that is rewritten as a whole, see `Storage`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<u64>,
+    /// Entries up to this index are applied to the engine and dropped from the log.
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+/// The term, the vote and the log entries of a raft node, kept in memory and written to `dir`.
+///
+/// `state.json` holds the term, the vote and the compacted prefix of the log, `log.jsonl` holds
+/// one entry per line. Every change is synced to disk before it returns, as raft requires before
+/// a node answers a message. Entries in `log.jsonl` that are covered by the snapshot are ignored,
+/// so a crash between rewriting the two files is harmless.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    state: HardState,
+    /// `entries[i]` has index `snapshot_index + 1 + i`.
+    entries: Vec<Entry>,
+    log: File,
+    /// Set while a snapshot is being received, see `begin_snapshot`.
+    staging: Option<BufWriter<File>>,
+}
+
+impl Storage {
+    pub(crate) fn open(dir: &Path) -> Result<Storage> {
+        fs::create_dir_all(dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let log = OpenOptions::new().create(true).read(true).append(true).open(dir.join(LOG_FILE))?;
+        let mut entries: Vec<Entry> = vec![];
+        for line in BufReader::new(&log).lines() {
+            let line = line?;
+            // a torn line at the end was never acknowledged
+            let entry: Entry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(_) => break,
+            };
+            if entry.index == state.snapshot_index + 1 + entries.len() as u64 {
+                entries.push(entry);
+            }
+        }
+        let mut storage = Storage { dir: dir.to_owned(), state, entries, log, staging: None };
+        // drop a torn tail and entries already covered by the snapshot
+        storage.rewrite_log()?;
+        Ok(storage)
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub(crate) fn voted_for(&self) -> Option<u64> {
+        self.state.voted_for
+    }
+
+    pub(crate) fn snapshot_index(&self) -> u64 {
+        self.state.snapshot_index
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.state.snapshot_index + self.entries.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.state.snapshot_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, `None` if it is compacted away or not written yet.
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            Some(self.state.snapshot_term)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    pub(crate) fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.state.snapshot_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+
+    /// At most `max` entries starting at `index`, which must be after the snapshot.
+    pub(crate) fn entries_from(&self, index: u64, max: usize) -> &[Entry] {
+        let start = ((index - self.state.snapshot_index - 1) as usize).min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    pub(crate) fn set_term(&mut self, term: u64, voted_for: Option<u64>) -> Result<()> {
+        self.state.term = term;
+        self.state.voted_for = voted_for;
+        self.save_state()
+    }
+
+    /// Append entries that directly follow the last one.
+    pub(crate) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut writer = BufWriter::new(&self.log);
+        for entry in entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.log.sync_data()?;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// Drop the entry at `index` and every one after it, they conflict with the leader's log.
+    pub(crate) fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate((index - self.state.snapshot_index - 1) as usize);
+        self.rewrite_log()
+    }
+
+    /// Drop entries up to `index`, which are applied to the engine.
+    pub(crate) fn compact(&mut self, index: u64) -> Result<()> {
+        let term = match self.term_at(index) {
+            Some(term) if index > self.state.snapshot_index => term,
+            _ => return Ok(()),
+        };
+        self.entries.drain(..(index - self.state.snapshot_index) as usize);
+        self.state.snapshot_index = index;
+        self.state.snapshot_term = term;
+        self.save_state()?;
+        self.rewrite_log()
+    }
+
+    /// Start staging the chunks of a snapshot sent by the leader, dropping the ones staged before.
+    pub(crate) fn begin_snapshot(&mut self) -> Result<()> {
+        self.staging = Some(BufWriter::new(File::create(self.dir.join(SNAPSHOT_FILE))?));
+        Ok(())
+    }
+
+    /// Stage a chunk of the snapshot started by `begin_snapshot`.
+    pub(crate) fn stage_snapshot(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        let writer = self.staging.as_mut().ok_or_else(|| KvError::Message("no snapshot is being received".to_owned()))?;
+        serde_json::to_writer(&mut *writer, pairs)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Read the staged chunks back, to be loaded into the engine before `install_snapshot`.
+    pub(crate) fn staged_snapshot(&mut self) -> Result<impl Iterator<Item = Result<Vec<(String, String)>>>> {
+        let mut writer = self.staging.take().ok_or_else(|| KvError::Message("no snapshot is being received".to_owned()))?;
+        writer.flush()?;
+        let file = File::open(self.dir.join(SNAPSHOT_FILE))?;
+        Ok(BufReader::new(file).lines().map(|line| Ok(serde_json::from_str(&line?)?)))
+    }
+
+    /// Replace the log with a snapshot of the leader up to `index`, keeping later entries
+    /// only if the log agrees with the snapshot.
+    pub(crate) fn install_snapshot(&mut self, index: u64, term: u64) -> Result<()> {
+        match fs::remove_file(self.dir.join(SNAPSHOT_FILE)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        if self.term_at(index) == Some(term) && index > self.state.snapshot_index {
+            self.entries.drain(..(index - self.state.snapshot_index) as usize);
+        } else {
+            self.entries.clear();
+        }
+        self.state.snapshot_index = index;
+        self.state.snapshot_term = term;
+        self.save_state()?;
+        self.rewrite_log()
+    }
+
+    /// Write the state to a temporary file and rename it over the old one.
+    fn save_state(&self) -> Result<()> {
+        let temp = self.dir.join(STATE_FILE.to_owned() + ".tmp");
+        let file = File::create(&temp)?;
+        serde_json::to_writer(&file, &self.state)?;
+        file.sync_all()?;
+        fs::rename(temp, self.dir.join(STATE_FILE))?;
+        Ok(())
+    }
+
+    /// Write the entries in memory to a new log file and swap it in.
+    fn rewrite_log(&mut self) -> Result<()> {
+        let temp = self.dir.join(LOG_FILE.to_owned() + ".tmp");
+        let file = File::create(&temp)?;
+        let mut writer = BufWriter::new(&file);
+        for entry in &self.entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        fs::rename(temp, self.dir.join(LOG_FILE))?;
+        self.log = OpenOptions::new().append(true).open(self.dir.join(LOG_FILE))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use tempfile::TempDir;
+    use crate::Result;
+    use super::super::message::{Entry, RaftCommand};
+    use super::Storage;
+
+    fn entry(term: u64, index: u64) -> Entry {
+        Entry { term, index, command: RaftCommand::Noop }
+    }
+
+    // Should keep the term, the vote and the entries across reopen, and drop truncated or compacted ones
+    #[test]
+    fn persist_log() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = Storage::open(temp_dir.path())?;
+        storage.set_term(2, Some(1))?;
+        storage.append(&[entry(1, 1), entry(1, 2), entry(2, 3)])?;
+        storage.truncate(3)?;
+        storage.append(&[entry(2, 3), entry(2, 4)])?;
+        storage.compact(2)?;
+        drop(storage);
+
+        let storage = Storage::open(temp_dir.path())?;
+        assert_eq!((storage.term(), storage.voted_for()), (2, Some(1)));
+        assert_eq!((storage.snapshot_index(), storage.last_index(), storage.last_term()), (2, 4, 2));
+        assert_eq!(storage.term_at(2), Some(1));
+        assert_eq!(storage.term_at(1), None);
+        assert_eq!(storage.entries_from(3, 10).len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use crate::{tools, KvError, Result};
+use super::node::Input;
+use super::message::{Envelope, Handshake};
+
+/// Give up connecting or writing to a peer after this long, the message is dropped.
+const PEER_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Messages to other nodes, sent as json lines over one TCP connection per peer.
+/// Each connection starts with a `Handshake` naming this node and carrying the cluster secret.
+///
+/// Each peer has its own sending thread, so a slow or unreachable peer never blocks the node.
+/// Messages that can not be delivered are dropped, raft retries what matters.
+pub(crate) struct Transport {
+    peers: BTreeMap<u64, Sender<Envelope>>,
+}
+
+impl Transport {
+    /// `addrs` are the raft addresses of the other nodes by id, `id` is the one of this node.
+    pub(crate) fn new(id: u64, addrs: &BTreeMap<u64, String>, secret: &str) -> Transport {
+        let handshake = Handshake { from: id, secret: secret.to_owned() };
+        let peers = addrs.iter()
+            .map(|(&id, addr)| {
+                let (sender, receiver) = mpsc::channel::<Envelope>();
+                let addr = addr.clone();
+                let handshake = handshake.clone();
+                thread::spawn(move || {
+                    let mut stream: Option<BufWriter<TcpStream>> = None;
+                    for envelope in receiver {
+                        if stream.is_none() {
+                            stream = connect(&addr, &handshake).ok();
+                        }
+                        if let Some(writer) = &mut stream {
+                            if let Err(err) = write_envelope(writer, &envelope) {
+                                log::debug!("Dropped raft message to {}: {}", addr, err);
+                                stream = None;
+                            }
+                        }
+                    }
+                });
+                (id, sender)
+            })
+            .collect();
+        Transport { peers }
+    }
+
+    pub(crate) fn send(&self, envelope: Envelope) {
+        if let Some(peer) = self.peers.get(&envelope.to) {
+            let _ = peer.send(envelope);
+        }
+    }
+}
+
+fn connect(addr: &str, handshake: &Handshake) -> Result<BufWriter<TcpStream>> {
+    let addr: SocketAddr = addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| KvError::Message(format!("invalid raft address: {}", addr)))?;
+    let stream = TcpStream::connect_timeout(&addr, PEER_TIMEOUT)?;
+    stream.set_write_timeout(Some(PEER_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut writer = BufWriter::new(stream);
+    write_line(&mut writer, handshake)?;
+    Ok(writer)
+}
+
+fn write_envelope(writer: &mut BufWriter<TcpStream>, envelope: &Envelope) -> Result<()> {
+    write_line(writer, envelope)
+}
+
+fn write_line<T: serde::Serialize>(writer: &mut BufWriter<TcpStream>, value: &T) -> Result<()> {
+    serde_json::to_writer(&mut *writer, value)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Parse the next json line, `None` once the connection is closed or the line is malformed.
+fn read_line<T: serde::de::DeserializeOwned>(lines: &mut Lines<BufReader<TcpStream>>) -> Option<T> {
+    match lines.next()?.map_err(KvError::from).and_then(|line| Ok(serde_json::from_str(&line)?)) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            log::debug!("Raft peer connection closed: {}", err);
+            None
+        }
+    }
+}
+
+/// Accept connections of peers in a new thread and forward their messages to the node.
+/// A connection is closed if its handshake does not come from one of `peers` with `secret`,
+/// or once it sends an envelope on behalf of another node.
+/// A connection is closed once the node stops, the listener itself lives as long as the process.
+pub(crate) fn listen(addr: &str, peers: BTreeSet<u64>, secret: &str, inputs: Sender<Input>) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let secret = secret.to_owned();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("Raft connection failed: {}", err);
+                    continue;
+                }
+            };
+            let inputs = inputs.clone();
+            let peers = peers.clone();
+            let secret = secret.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map_or_else(|_| "unknown".to_owned(), |addr| addr.to_string());
+                let mut lines = BufReader::new(stream).lines();
+                let from = match read_line::<Handshake>(&mut lines) {
+                    Some(handshake) if peers.contains(&handshake.from)
+                        && tools::constant_time_eq(handshake.secret.as_bytes(), secret.as_bytes()) => handshake.from,
+                    _ => {
+                        log::warn!("Rejected raft connection from {}: unknown node or wrong secret", peer);
+                        return;
+                    }
+                };
+                while let Some(envelope) = read_line::<Envelope>(&mut lines) {
+                    if envelope.from != from {
+                        log::warn!("Rejected raft message of node {} sent by node {}", envelope.from, from);
+                        break;
+                    }
+                    if inputs.send(Input::Message(envelope)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -1,24 +1,99 @@
-use crate::engine::KvsEngine;
-use crate::Result;
+use std::fs;
+use std::path::Path;
+use sled::transaction::{ConflictableTransactionResult, TransactionError, Transactional};
+use crate::engine::{Change, Changes, EngineStats, Event, Events, KvPairs, KvsEngine, Snapshot};
+use crate::engine::watch::EventBus;
+use crate::{KvError, Result};
 
-pub struct Sled(sled::Db);
+/// Name of the tree that keeps recent mutations by sequence number, for `KvsEngine::changes`.
+/// Values are the json of `Change`.
+const CHANGES_TREE: &str = "__kvs_changes__";
+/// Number of latest mutations kept in the changes tree by default.
+const DEFAULT_CHANGES_RETAINED: u64 = 100_000;
+
+pub struct Sled {
+    db: sled::Db,
+    /// Big-endian sequence number to the json of its `Change`.
+    changes: sled::Tree,
+    events: EventBus,
+    /// The sequence number of the latest mutation.
+    seq: u64,
+    /// Mutations up to this sequence number are dropped from the changes tree.
+    compacted_seq: u64,
+    changes_retained: u64,
+}
 
 impl Sled {
-    pub fn new(db: sled::Db) -> Sled {
-        Self(db)
+    pub fn new(db: sled::Db) -> Result<Sled> {
+        let changes = db.open_tree(CHANGES_TREE)?;
+        let seq = changes.last()?.map(|(key, _)| decode_seq(&key)).transpose()?.unwrap_or(0);
+        let compacted_seq = match changes.first()? {
+            Some((key, _)) => decode_seq(&key)? - 1,
+            None => seq,
+        };
+        Ok(Self {
+            db,
+            changes,
+            events: EventBus::default(),
+            seq,
+            compacted_seq,
+            changes_retained: DEFAULT_CHANGES_RETAINED,
+        })
+    }
+
+    /// Set the number of latest mutations retained for `KvsEngine::changes`, default 100000.
+    pub fn set_changes_retained(&mut self, retained: u64) {
+        self.changes_retained = retained;
     }
+
+    /// Apply mutations and record them in the changes tree within one transaction.
+    fn apply(&mut self, events: Vec<Event>) -> Result<()> {
+        let len = events.len() as u64;
+        let changes: Vec<Change> = events.into_iter().zip(self.seq + 1..)
+            .map(|(event, seq)| Change { seq, event, remaining: self.seq + len - seq })
+            .collect();
+        let encoded = changes.iter().map(serde_json::to_vec).collect::<serde_json::Result<Vec<_>>>()?;
+        let tree: &sled::Tree = &self.db;
+        (tree, &self.changes)
+            .transaction(|(tree, tx_changes)| -> ConflictableTransactionResult<()> {
+                for (change, json) in changes.iter().zip(&encoded) {
+                    match &change.event {
+                        Event::Set { key, value } => tree.insert(key.as_str(), value.as_str())?,
+                        Event::Remove { key } => tree.remove(key.as_str())?,
+                    };
+                    tx_changes.insert(&change.seq.to_be_bytes()[..], json.as_slice())?;
+                }
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(()) => KvError::Message("transaction aborted".to_owned()),
+                TransactionError::Storage(err) => err.into(),
+            })?;
+        self.db.flush()?;
+        for change in changes {
+            self.seq = change.seq;
+            self.events.publish(change);
+        }
+        while self.seq - self.compacted_seq > self.changes_retained {
+            self.changes.pop_min()?;
+            self.compacted_seq += 1;
+        }
+        Ok(())
+    }
+}
+
+fn decode_seq(key: &[u8]) -> Result<u64> {
+    let bytes = key.try_into().map_err(|_| KvError::Message("malformed sequence number in changes tree".to_owned()))?;
+    Ok(u64::from_be_bytes(bytes))
 }
 
 impl KvsEngine for Sled {
     fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let tree: &sled::Tree = &self.0;
-        tree.insert(key, value).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+        self.apply(vec![Event::Set { key: key.to_owned(), value: value.to_owned() }])
     }
 
     fn get(&mut self, key: &str) -> Result<Option<String>> {
-        let tree: &sled::Tree = &self.0;
+        let tree: &sled::Tree = &self.db;
         Ok(tree
             .get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
@@ -27,11 +102,137 @@ impl KvsEngine for Sled {
     }
 
     fn remove(&mut self, key: &str) -> Result<Option<()>> {
-        let tree: &sled::Tree = &self.0;
-        if let None = tree.remove(key)? {
+        let tree: &sled::Tree = &self.db;
+        // writes are serialized by `&mut self`, so the key can not appear meanwhile
+        if !tree.contains_key(key)? {
             return Ok(None);
         }
-        tree.flush()?;
+        self.apply(vec![Event::Remove { key: key.to_owned() }])?;
         Ok(Some(()))
     }
+
+    fn mset(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        self.apply(pairs.iter().map(|(key, value)| Event::Set { key: key.clone(), value: value.clone() }).collect())
+    }
+
+    /// Export every tree of the database and import them into a new database at `dest_dir`.
+    /// # Errors
+    /// * `KvError::Message` `dest_dir` is not empty
+    fn checkpoint(&mut self, dest_dir: &Path) -> Result<()> {
+        fs::create_dir_all(dest_dir)?;
+        if fs::read_dir(dest_dir)?.next().is_some() {
+            return Err(KvError::Message(format!("{} is not empty", dest_dir.display())));
+        }
+        let backup = sled::open(dest_dir)?;
+        backup.import(self.db.export());
+        backup.flush()?;
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<KvPairs<'_>> {
+        Ok(Box::new(self.db.scan_prefix(prefix).map(|res| -> Result<(String, String)> {
+            let (key, value) = res?;
+            Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+        })))
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: "sled".to_owned(),
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            seq: self.seq,
+            ..Default::default()
+        })
+    }
+
+    fn seq(&mut self) -> Result<u64> {
+        Ok(self.seq)
+    }
+
+    /// Iterate the tree without holding the engine, sled has no point-in-time reads so pairs
+    /// written meanwhile may be seen.
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        Ok(Box::new(self.db.iter().map(|res| -> Result<(String, String)> {
+            let (key, value) = res?;
+            Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+        })))
+    }
+
+    /// Events of `sled::Tree::watch_prefix`, keys or values that are not UTF-8 are skipped.
+    fn watch_prefix(&mut self, prefix: &str) -> Result<Events> {
+        Ok(Box::new(self.db.watch_prefix(prefix).filter_map(|event| match event {
+            sled::Event::Insert { key, value } => Some(Event::Set {
+                key: String::from_utf8(key.to_vec()).ok()?,
+                value: String::from_utf8(value.to_vec()).ok()?,
+            }),
+            sled::Event::Remove { key } => Some(Event::Remove { key: String::from_utf8(key.to_vec()).ok()? }),
+        })))
+    }
+
+    /// Replay the changes tree, which keeps the latest mutations, see `set_changes_retained`.
+    fn changes(&mut self, since: u64) -> Result<Changes> {
+        if since < self.compacted_seq {
+            return Err(KvError::Compacted(since, self.compacted_seq));
+        }
+        let live = self.events.changes();
+        let until = self.seq;
+        let retained = self.changes
+            .range(since.saturating_add(1).to_be_bytes()..)
+            .map(|res| -> Result<Change> { Ok(serde_json::from_slice(&res?.1)?) })
+            .take_while(move |change| change.as_ref().map_or(true, |change| change.seq <= until));
+        Ok(Box::new(retained.chain(live.into_iter().map(Ok))))
+    }
+}
+
+#[cfg(test)]
+mod sled_tests {
+    use tempfile::TempDir;
+    use crate::engine::{Change, Event, KvsEngine};
+    use crate::{KvError, Result};
+    use super::Sled;
+
+    // Should number mutations across reopen and drop the oldest ones beyond the retained count
+    #[test]
+    fn retained_changes() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = Sled::new(sled::open(temp_dir.path())?)?;
+        store.set("key1", "value1")?;
+        store.mset(&[("key2".to_owned(), "value2".to_owned()), ("key3".to_owned(), "value3".to_owned())])?;
+        assert_eq!(store.remove("key4")?, None);
+        drop(store);
+
+        let mut store = Sled::new(sled::open(temp_dir.path())?)?;
+        store.remove("key1")?;
+        let changes: Vec<Change> = store.changes(1)?.take(3).collect::<Result<_>>()?;
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(changes[2].event, Event::Remove { key: "key1".to_owned() });
+        assert_eq!(store.stats()?.keys, 2);
+
+        store.set_changes_retained(2);
+        store.set("key5", "value5")?;
+        assert!(matches!(store.changes(2), Err(KvError::Compacted(2, 3))));
+        let mut live = store.changes(3)?;
+        assert_eq!(live.next().unwrap()?.seq, 4);
+        assert_eq!(live.next().unwrap()?.seq, 5);
+        store.set("key6", "value6")?;
+        assert_eq!(live.next().unwrap()?.seq, 6);
+        Ok(())
+    }
+
+    // Should copy the store and refuse to write into a directory which is not empty
+    #[test]
+    fn checkpoint_into_non_empty_dir() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let mut store = Sled::new(sled::open(temp_dir.path())?)?;
+        store.set("key1", "value1")?;
+        store.checkpoint(backup_dir.path())?;
+        store.set("key1", "value2")?;
+        assert!(matches!(store.checkpoint(backup_dir.path()), Err(KvError::Message(_))));
+
+        let mut backup = Sled::new(sled::open(backup_dir.path())?)?;
+        assert_eq!(backup.get("key1")?, Some("value1".to_owned()));
+        Ok(())
+    }
 }
@@ -0,0 +1,68 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use crate::Result;
+
+/// A change of a key, see `KvsEngine::watch_prefix`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl Event {
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key } => key,
+        }
+    }
+}
+
+/// A mutation with its sequence number, see `KvsEngine::changes`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// Increases by one with every mutation of the store.
+    pub seq: u64,
+    pub event: Event,
+    /// Number of mutations after this one written atomically with it by an `mset`, 0 for the last
+    /// one or a single mutation.
+    #[serde(default)]
+    pub remaining: u64,
+}
+
+/// Events of watched keys in the order they are applied.
+/// `next` blocks until the next change, the iteration ends when the engine is dropped.
+pub type Events = Box<dyn Iterator<Item = Event> + Send>;
+
+/// Mutations in sequence order, retained ones first and then live ones as they are applied.
+/// `next` blocks until the next mutation, the iteration ends when the engine is dropped.
+pub type Changes = Box<dyn Iterator<Item = Result<Change>> + Send>;
+
+/// Deliver changes of an in-process engine to its watchers.
+#[derive(Default)]
+pub(crate) struct EventBus {
+    watchers: Vec<(String, Sender<Change>)>,
+}
+
+impl EventBus {
+    pub(crate) fn watch(&mut self, prefix: &str) -> Events {
+        Box::new(self.subscribe(prefix).into_iter().map(|change| change.event))
+    }
+
+    /// Receive every change applied from now on.
+    pub(crate) fn changes(&mut self) -> Receiver<Change> {
+        self.subscribe("")
+    }
+
+    fn subscribe(&mut self, prefix: &str) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push((prefix.to_owned(), sender));
+        receiver
+    }
+
+    /// Send `change` to watchers of matching prefixes, dropping watchers that are gone.
+    pub(crate) fn publish(&mut self, change: Change) {
+        self.watchers.retain(|(prefix, sender)| {
+            !change.event.key().starts_with(prefix.as_str()) || sender.send(change.clone()).is_ok()
+        });
+    }
+}
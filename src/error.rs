@@ -22,7 +22,24 @@ pub enum KvError {
     #[fail(display = "Sled error: {}", _0)]
     SledError(sled::Error),
     #[fail(display = "From utf8 error: {}", _0)]
-    FromUtf8Error(string::FromUtf8Error)
+    FromUtf8Error(string::FromUtf8Error),
+    #[fail(display = "TLS error: {}", _0)]
+    TlsError(rustls::Error),
+    /// Changes since `_0` are requested but mutations up to `_1` are compacted away.
+    #[fail(display = "Changes since {} are compacted, resume from {} or later", _0, _1)]
+    Compacted(u64, u64),
+    /// A raft write or read reached a node that is not the leader, `_0` is the raft address of
+    /// the leader, or `unknown` during an election. A write may still be applied if it failed
+    /// because the node lost its leadership after accepting it.
+    #[fail(display = "Not the raft leader, the leader is {}", _0)]
+    NotLeader(String),
+    /// A connection, request or reply did not complete in time, `_0` names what was waited for.
+    #[fail(display = "Timed out {}", _0)]
+    Timeout(String),
+    /// A cluster node answered `MOVED` or `ASK`, `_0`, to a unix socket client, which can only
+    /// reach the node it is connected to.
+    #[fail(display = "{}, unix socket clients do not follow cluster redirects", _0)]
+    Redirect(String)
 }
 
 impl KvError {
@@ -37,7 +54,12 @@ impl KvError {
             KvError::MissingArguments => KvErrorKind::MissingArguments,
             KvError::FromUtf8Error(_) => KvErrorKind::FromUtf8Error,
             KvError::Message(_) => KvErrorKind::Message,
-            KvError::SledError(_) => KvErrorKind::SledError
+            KvError::SledError(_) => KvErrorKind::SledError,
+            KvError::TlsError(_) => KvErrorKind::TlsError,
+            KvError::Compacted(..) => KvErrorKind::Compacted,
+            KvError::NotLeader(_) => KvErrorKind::NotLeader,
+            KvError::Timeout(_) => KvErrorKind::Timeout,
+            KvError::Redirect(_) => KvErrorKind::Redirect
         }
     }
 }
@@ -72,9 +94,15 @@ impl From<string::FromUtf8Error> for KvError {
     }
 }
 
+impl From<rustls::Error> for KvError {
+    fn from(value: rustls::Error) -> Self {
+        KvError::TlsError(value)
+    }
+}
+
 pub type Result<T> = result::Result<T, KvError>;
 
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum KvErrorKind {
     Message,
     IoError,
@@ -85,5 +113,10 @@ pub enum KvErrorKind {
     UnknownCommand,
     MissingArguments,
     FromUtf8Error,
-    SledError
+    SledError,
+    TlsError,
+    Compacted,
+    NotLeader,
+    Timeout,
+    Redirect
 }
@@ -0,0 +1,312 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use rustls::{ServerConfig, ServerConnection};
+use serde::Deserialize;
+use serde_json::json;
+use crate::acl::{Acl, DEFAULT_USER};
+use crate::cluster::{Cluster, Route};
+use crate::engine::KvsEngine;
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::replication::Replication;
+use crate::server;
+use crate::{tls, Request, Result};
+
+/// Pairs returned by `GET /keys` when `limit` is not given.
+const DEFAULT_LIST_LIMIT: usize = 1000;
+
+/// Body of `PUT /keys/{key}`.
+#[derive(Deserialize)]
+struct ValueBody {
+    value: String,
+}
+
+/// An operation of `POST /batch`.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get { key: String },
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+impl From<BatchOp> for Request {
+    fn from(op: BatchOp) -> Self {
+        match op {
+            BatchOp::Get { key } => Request::Get { key },
+            BatchOp::Set { key, value } => Request::Set { key, value },
+            BatchOp::Delete { key } => Request::Remove { key },
+        }
+    }
+}
+
+/// An HTTP request parsed into what it runs on the engine.
+enum Action {
+    /// `GET`, `PUT` or `DELETE /keys/{key}`.
+    Key(Request),
+    List { prefix: String, after: Option<String>, limit: usize },
+    Batch(Vec<Request>),
+}
+
+impl Action {
+    /// The commands checked against the user and routed to slots, `SCAN` has no keys
+    /// so listed keys are filtered instead.
+    fn commands(&self) -> Vec<Request> {
+        match self {
+            Action::Key(request) => vec![request.clone()],
+            Action::List { prefix, .. } => vec![Request::scan(prefix)],
+            Action::Batch(requests) => requests.clone(),
+        }
+    }
+}
+
+/// What the gateway shares with the RESP server, so that both enforce the same users,
+/// TLS, replication role and slots.
+pub(crate) struct Gateway<E> {
+    pub engine: Arc<Mutex<E>>,
+    pub acl: Arc<RwLock<Acl>>,
+    pub tls: Option<Arc<ServerConfig>>,
+    pub replication: Arc<Replication>,
+    pub cluster: Option<Arc<Cluster>>,
+}
+
+impl<E> Clone for Gateway<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            acl: self.acl.clone(),
+            tls: self.tls.clone(),
+            replication: self.replication.clone(),
+            cluster: self.cluster.clone(),
+        }
+    }
+}
+
+/// Serve the REST gateway in a new thread, each connection is served by its own thread:
+/// * `GET /keys/{key}` returns `{"key": key, "value": value}`, or 404 if the key does not exist
+/// * `PUT /keys/{key}` with body `{"value": value}` sets the key, returns 204
+/// * `DELETE /keys/{key}` removes the key, returns 204, or 404 if the key does not exist
+/// * `GET /keys?prefix=&after=&limit=` returns `[{"key": key, "value": value}...]` sorted by key,
+///   at most `limit` (default 1000) pairs with keys greater than `after` if given
+/// * `POST /batch` with body `[{"op": "get" | "set" | "delete", "key": key, "value": value}...]`
+///   runs the operations in order without interleaving other clients, returns one result per operation
+///
+/// Keys in paths are percent-decoded. Errors are returned as `{"error": message}`.
+///
+/// Requests log in with HTTP Basic credentials of an ACL user, or as the default user if it
+/// needs no password, otherwise they get 401. Each operation is checked like the command it
+/// runs (`get`, `set`, `del` and `scan`), denied ones get 403 and `GET /keys` only lists the keys
+/// the user may access. The gateway is served over HTTPS if the server has TLS enabled.
+///
+/// Writes are rejected with 403 while the server is a follower. In cluster mode, keys of slots
+/// served by other nodes get 421 with the `MOVED` or `ASK` error naming the node.
+pub(crate) fn serve_gateway<E, A>(addr: A, gateway: Gateway<E>) -> Result<JoinHandle<()>>
+where
+    E: KvsEngine + Send + 'static,
+    A: ToSocketAddrs,
+{
+    let listener = TcpListener::bind(addr)?;
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let gateway = gateway.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = gateway.serve_connection(stream) {
+                            log::error!("Error on serving http request: {}", err);
+                        }
+                    });
+                }
+                Err(err) => log::error!("Connection failed: {}", err),
+            }
+        }
+    }))
+}
+
+impl<E: KvsEngine> Gateway<E> {
+    fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+        match &self.tls {
+            Some(config) => {
+                let (reader, mut writer) = tls::split(ServerConnection::new(config.clone())?, stream.try_clone()?, stream);
+                self.serve_request(reader, &mut writer)?;
+                writer.close_notify()?;
+                Ok(())
+            }
+            None => self.serve_request(&stream, &stream),
+        }
+    }
+
+    fn serve_request<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()> {
+        let response = match http::read_request(&mut BufReader::new(reader))? {
+            Ok(request) => {
+                log::debug!("receive http request: {} {}", request.method, request.path);
+                self.handle(&request).unwrap_or_else(|err| {
+                    log::error!("Error on handling http request: {}", err);
+                    HttpResponse::error(500, &err.to_string())
+                })
+            }
+            Err(response) => response,
+        };
+        response.write_to(writer)?;
+        Ok(())
+    }
+
+    fn handle(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let user = match self.login(request) {
+            Ok(user) => user,
+            Err(message) => {
+                return Ok(HttpResponse::error(401, message).with_header("WWW-Authenticate", "Basic realm=\"kvs\""));
+            }
+        };
+        let action = match parse(request) {
+            Ok(action) => action,
+            Err(response) => return Ok(response),
+        };
+        let commands = action.commands();
+        for command in &commands {
+            if let Err(denied) = self.acl().check(&user, command) {
+                log::warn!("Denied {} from user {} over http", command.name(), user);
+                return Ok(HttpResponse::error(403, &denied.to_string()));
+            }
+        }
+        if self.replication.is_follower() && commands.iter().any(Request::is_write) {
+            return Ok(HttpResponse::error(403, "READONLY You can't write against a read only replica."));
+        }
+        // keys can not be migrated away until the request is served
+        let keys: Vec<&str> = commands.iter().flat_map(Request::keys).collect();
+        let _serving = match &self.cluster {
+            Some(cluster) if !keys.is_empty() => {
+                let serving = cluster.serving(keys[0]);
+                let route = cluster.route(&keys, false, |key| Ok(self.engine().get(key)?.is_some()))?;
+                if let Some(redirect) = route.error() {
+                    let status = match route {
+                        Route::Moved(..) | Route::Ask(..) => 421,
+                        Route::CrossSlot => 400,
+                        _ => 503,
+                    };
+                    return Ok(HttpResponse::error(status, &redirect));
+                }
+                Some(serving)
+            }
+            _ => None,
+        };
+        match action {
+            Action::Key(command) => self.run_key(command),
+            Action::List { prefix, after, limit } => self.list(&user, &prefix, after.as_deref(), limit),
+            Action::Batch(commands) => self.batch(commands),
+        }
+    }
+
+    /// The user logged in with Basic credentials, or the default user if it needs no password.
+    fn login(&self, request: &HttpRequest) -> std::result::Result<String, &'static str> {
+        let acl = self.acl();
+        match &request.credentials {
+            Some(Ok((user, password))) if acl.authenticate(user, password) => Ok(user.clone()),
+            Some(_) => Err("WRONGPASS invalid username-password pair or user is disabled"),
+            None if acl.default_user_open() => Ok(DEFAULT_USER.to_owned()),
+            None => Err("NOAUTH Authentication required."),
+        }
+    }
+
+    fn engine(&self) -> MutexGuard<'_, E> {
+        self.engine.lock().expect("engine lock is poisoned")
+    }
+
+    fn acl(&self) -> RwLockReadGuard<'_, Acl> {
+        self.acl.read().expect("acl lock is poisoned")
+    }
+
+    fn run_key(&self, command: Request) -> Result<HttpResponse> {
+        let mut engine = self.engine();
+        Ok(match command {
+            Request::Get { key } => match engine.get(&key)? {
+                Some(value) => HttpResponse::json(200, &json!({ "key": key, "value": value })),
+                None => HttpResponse::error(404, "key not found"),
+            },
+            Request::Set { key, value } => {
+                engine.set(&key, &value)?;
+                HttpResponse::empty(204)
+            }
+            Request::Remove { key } => match engine.remove(&key)? {
+                Some(()) => HttpResponse::empty(204),
+                None => HttpResponse::error(404, "key not found"),
+            },
+            _ => unreachable!("only single key commands are parsed from /keys/{{key}}"),
+        })
+    }
+
+    /// Only `limit` pairs are held at a time, so listing a large store does not load every pair.
+    fn list(&self, user: &str, prefix: &str, after: Option<&str>, limit: usize) -> Result<HttpResponse> {
+        let acl = self.acl();
+        let mut engine = self.engine();
+        let pairs = engine.scan(prefix)?.filter(|pair| pair.as_ref().map_or(true, |(key, _)| {
+            after.is_none_or(|after| key.as_str() > after) && acl.allows_key(user, key)
+        }));
+        // the scan order depends on the engine
+        let pairs = server::first_pairs(pairs, limit)?;
+        let pairs: Vec<_> = pairs.into_iter().map(|(key, value)| json!({ "key": key, "value": value })).collect();
+        Ok(HttpResponse::json(200, &json!(pairs)))
+    }
+
+    fn batch(&self, commands: Vec<Request>) -> Result<HttpResponse> {
+        let mut engine = self.engine();
+        let mut results = vec![];
+        for command in commands {
+            results.push(match command {
+                Request::Get { key } => {
+                    let value = engine.get(&key)?;
+                    json!({ "key": key, "value": value })
+                }
+                Request::Set { key, value } => {
+                    engine.set(&key, &value)?;
+                    json!({ "key": key, "ok": true })
+                }
+                Request::Remove { key } => {
+                    let deleted = engine.remove(&key)?.is_some();
+                    json!({ "key": key, "deleted": deleted })
+                }
+                _ => unreachable!("batch operations are get, set and delete"),
+            });
+        }
+        Ok(HttpResponse::json(200, &json!(results)))
+    }
+}
+
+/// Parse the route, a malformed request is returned as the response to reject it with.
+fn parse(request: &HttpRequest) -> std::result::Result<Action, HttpResponse> {
+    let method = request.method.as_str();
+    if let Some(key) = request.path.strip_prefix("/keys/") {
+        let key = match http::percent_decode(key, false) {
+            Some(key) if !key.is_empty() => key,
+            _ => return Err(HttpResponse::error(400, "invalid key")),
+        };
+        return match method {
+            "GET" => Ok(Action::Key(Request::Get { key })),
+            "PUT" => match serde_json::from_slice::<ValueBody>(&request.body) {
+                Ok(body) => Ok(Action::Key(Request::Set { key, value: body.value })),
+                Err(err) => Err(HttpResponse::error(400, &format!("invalid body: {}", err))),
+            },
+            "DELETE" => Ok(Action::Key(Request::Remove { key })),
+            _ => Err(HttpResponse::error(405, "method not allowed")),
+        };
+    }
+    match (method, request.path.as_str()) {
+        ("GET", "/keys") => {
+            let limit = match request.param("limit").map(str::parse::<usize>) {
+                None => DEFAULT_LIST_LIMIT,
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => return Err(HttpResponse::error(400, "limit should be a non-negative integer")),
+            };
+            let prefix = request.param("prefix").unwrap_or("").to_owned();
+            Ok(Action::List { prefix, after: request.param("after").map(str::to_owned), limit })
+        }
+        // reject the whole batch before running anything if it is malformed
+        ("POST", "/batch") => match serde_json::from_slice::<Vec<BatchOp>>(&request.body) {
+            Ok(ops) => Ok(Action::Batch(ops.into_iter().map(Request::from).collect())),
+            Err(err) => Err(HttpResponse::error(400, &format!("invalid body: {}", err))),
+        },
+        (_, "/keys" | "/batch") => Err(HttpResponse::error(405, "method not allowed")),
+        _ => Err(HttpResponse::error(404, "not found")),
+    }
+}
@@ -0,0 +1,229 @@
+use std::io::{self, BufRead, Write};
+use crate::Result;
+
+/// Largest request body accepted, bigger requests get `413 Payload Too Large`.
+const MAX_BODY_LEN: usize = 16 << 20;
+
+/// A minimal HTTP/1.1 request, connections are closed after the response.
+pub(crate) struct HttpRequest {
+    pub method: String,
+    /// Path without query, not percent-decoded.
+    pub path: String,
+    /// Percent-decoded query parameters in order.
+    pub query: Vec<(String, String)>,
+    /// Credentials of an `Authorization: Basic` header, `Err` if the header is malformed.
+    pub credentials: Option<std::result::Result<(String, String), ()>>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// The first value of a query parameter.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    /// Extra headers, such as `WWW-Authenticate`.
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self { status, content_type, headers: vec![], body: body.into() }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_owned()));
+        self
+    }
+
+    pub fn empty(status: u16) -> Self {
+        Self::new(status, "text/plain", vec![])
+    }
+
+    pub fn json(status: u16, value: &serde_json::Value) -> Self {
+        Self::new(status, "application/json", value.to_string())
+    }
+
+    /// A json body of `{"error": message}`.
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Read a request with its body. A malformed request is returned as the response to reject it with.
+pub(crate) fn read_request<R: BufRead>(reader: &mut R) -> Result<std::result::Result<HttpRequest, HttpResponse>> {
+    let bad_request = |message: &str| Ok(Err(HttpResponse::error(400, message)));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target),
+        _ => return bad_request("malformed request line"),
+    };
+    let mut content_len = 0;
+    let mut credentials = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_len = match value.trim().parse() {
+                    Ok(len) => len,
+                    Err(_) => return bad_request("invalid content-length"),
+                };
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                credentials = Some(basic_credentials(value.trim()).ok_or(()));
+            }
+        }
+        line.clear();
+    }
+    if content_len > MAX_BODY_LEN {
+        return Ok(Err(HttpResponse::error(413, "request body is too large")));
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut params = vec![];
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match (percent_decode(key, true), percent_decode(value, true)) {
+            (Some(key), Some(value)) => params.push((key, value)),
+            _ => return bad_request("malformed query"),
+        }
+    }
+    Ok(Ok(HttpRequest { method, path: path.to_owned(), query: params, credentials, body }))
+}
+
+/// Parse `Basic base64(user:password)`, `None` for other schemes or malformed credentials.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+/// Decode standard base64 with optional padding, `None` if it is malformed.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut len) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            bytes.push((bits >> len) as u8);
+        }
+    }
+    // a single trailing character can not hold a whole byte
+    (len < 6).then_some(bytes)
+}
+
+/// Decode `%XX` escapes, and `+` as space if `plus_as_space`.
+/// `None` if an escape is malformed or the result is not UTF-8.
+pub(crate) fn percent_decode(text: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod http_tests {
+    use std::io::Cursor;
+    use super::{base64_decode, percent_decode, read_request};
+
+    // Should parse the request line, query and body
+    #[test]
+    fn parse_request() {
+        let raw = "PUT /keys/a%2Fb?prefix=user%3A&limit=10 HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nbody";
+        let request = read_request(&mut Cursor::new(raw)).unwrap().ok().unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/keys/a%2Fb");
+        assert_eq!(request.param("prefix"), Some("user:"));
+        assert_eq!(request.param("limit"), Some("10"));
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn decode_percent() {
+        assert_eq!(percent_decode("a%20b+c", true), Some("a b c".to_owned()));
+        assert_eq!(percent_decode("a+b", false), Some("a+b".to_owned()));
+        assert_eq!(percent_decode("%E4%BD%A0", false), Some("你".to_owned()));
+        assert_eq!(percent_decode("%4", false), None);
+        assert_eq!(percent_decode("%zz", false), None);
+    }
+
+    #[test]
+    fn parse_basic_credentials() {
+        assert_eq!(base64_decode("dXNlcjpwYXNz"), Some(b"user:pass".to_vec()));
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode("YWI"), Some(b"ab".to_vec()));
+        assert_eq!(base64_decode("Y"), None);
+        assert_eq!(base64_decode("a*b"), None);
+        let raw = "GET /keys HTTP/1.1\r\nAuthorization: Basic YWRtaW46czpj\r\n\r\n";
+        let request = read_request(&mut Cursor::new(raw)).unwrap().ok().unwrap();
+        assert_eq!(request.credentials, Some(Ok(("admin".to_owned(), "s:c".to_owned()))));
+        let raw = "GET /keys HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n";
+        assert_eq!(read_request(&mut Cursor::new(raw)).unwrap().ok().unwrap().credentials, Some(Err(())));
+    }
+}
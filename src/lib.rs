@@ -4,9 +4,23 @@ pub mod client;
 pub mod server;
 pub mod message;
 pub mod tools;
+pub mod dump;
+pub mod metrics;
+pub mod slowlog;
+pub mod acl;
+pub mod tls;
+pub mod gateway;
+pub mod pubsub;
+pub mod replication;
+pub mod sharding;
+pub mod cluster;
+pub mod pool;
+mod http;
 
 pub use engine::{KvStore, };
 pub use error::*;
-pub use message::{Request, GetResponse, SetResponse, RemoveResponse};
-pub use client::KvsClient;
+pub use message::{Request, GetResponse, SetResponse, RemoveResponse, MGetResponse, MDelResponse, InfoResponse, DbSizeResponse, SlowLogResponse, AclListResponse, ScanResponse, ClusterSlotsResponse};
+pub use client::{ChangeFeed, ClientBuilder, KeyEvents, KvsClient, Subscription};
+pub use pool::{KvsPool, PooledClient};
+pub use sharding::ShardedKvsClient;
 pub use server::KvsServer;
\ No newline at end of file
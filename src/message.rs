@@ -1,10 +1,61 @@
 use serde_resp::{array, bulk, err, none, RESPType, simple};
+use crate::{KvError, Result};
+use crate::cluster::{self, SlotRange, SlotState};
+use crate::engine::{Change, Event};
+use crate::slowlog::SlowLogEntry;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Request {
     Set { key: String, value: String},
     Get { key: String },
-    Remove { key: String }
+    Remove { key: String },
+    MSet { pairs: Vec<(String, String)> },
+    MGet { keys: Vec<String> },
+    MDel { keys: Vec<String> },
+    Backup { dir: String },
+    Info { section: Option<String> },
+    DbSize,
+    SlowLogGet { count: Option<usize> },
+    SlowLogLen,
+    SlowLogReset,
+    /// `username` is `None` for the default user.
+    Auth { username: Option<String>, password: String },
+    Ping { message: Option<String> },
+    AclSetUser { username: String, rules: Vec<String> },
+    AclDelUser { usernames: Vec<String> },
+    AclList,
+    AclWhoAmI,
+    Subscribe { channels: Vec<String> },
+    /// Unsubscribe from all channels if `channels` is empty.
+    Unsubscribe { channels: Vec<String> },
+    PSubscribe { patterns: Vec<String> },
+    /// Unsubscribe from all patterns if `patterns` is empty.
+    PUnsubscribe { patterns: Vec<String> },
+    Publish { channel: String, message: String },
+    /// Push changes of keys starting with any of `prefixes`.
+    Watch { prefixes: Vec<String> },
+    /// Stop watching all prefixes if `prefixes` is empty.
+    Unwatch { prefixes: Vec<String> },
+    /// Stream mutations with a sequence number greater than `since`.
+    Changes { since: u64 },
+    /// Sent by a follower: resume after the mutation `since` if it is retained, otherwise sync fully.
+    Sync { since: Option<u64> },
+    Role,
+    /// Every pair whose key starts with `prefix`, sorted by key. Sent as
+    /// `SCAN [<prefix> [AFTER <key>] [COUNT <count>]]` to get only the first `count` pairs
+    /// whose key is greater than `after`.
+    Scan { prefix: String, after: Option<String>, count: Option<usize> },
+    ClusterSlots,
+    ClusterNodes,
+    ClusterKeySlot { key: String },
+    /// Change slots `start..=end`, sent as `CLUSTER SETSLOT <start>[-<end>] NODE|MIGRATING|IMPORTING <addr>`
+    /// or `... STABLE`.
+    ClusterSetSlot { start: u16, end: u16, state: SlotState },
+    ClusterCountKeysInSlot { slot: u16 },
+    /// Move up to `count` keys of a migrating slot to its target, which is authenticated with `password`.
+    ClusterMigrate { slot: u16, count: usize, password: Option<String> },
+    /// Serve the next command even if its slot is still being imported.
+    Asking
 }
 
 impl Request {
@@ -24,6 +75,104 @@ impl Request {
             key: key.to_owned()
         }
     }
+    pub fn mset(pairs: &[(&str, &str)]) -> Self {
+        Request::MSet {
+            pairs: pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        }
+    }
+    pub fn mget(keys: &[&str]) -> Self {
+        Request::MGet {
+            keys: keys.iter().map(|k| k.to_string()).collect()
+        }
+    }
+    pub fn mdel(keys: &[&str]) -> Self {
+        Request::MDel {
+            keys: keys.iter().map(|k| k.to_string()).collect()
+        }
+    }
+    pub fn backup(dir: &str) -> Self {
+        Request::Backup {
+            dir: dir.to_owned()
+        }
+    }
+    pub fn auth(username: Option<&str>, password: &str) -> Self {
+        Request::Auth {
+            username: username.map(str::to_owned),
+            password: password.to_owned()
+        }
+    }
+    pub fn info(section: Option<&str>) -> Self {
+        Request::Info {
+            section: section.map(str::to_owned)
+        }
+    }
+    pub fn scan(prefix: &str) -> Self {
+        Request::Scan {
+            prefix: prefix.to_owned(),
+            after: None,
+            count: None
+        }
+    }
+
+    /// The command name sent on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Remove { .. } => "rm",
+            Request::MSet { .. } => "mset",
+            Request::MGet { .. } => "mget",
+            Request::MDel { .. } => "mdel",
+            Request::Backup { .. } => "backup",
+            Request::Info { .. } => "info",
+            Request::DbSize => "dbsize",
+            Request::SlowLogGet { .. } | Request::SlowLogLen | Request::SlowLogReset => "slowlog",
+            Request::Auth { .. } => "auth",
+            Request::Ping { .. } => "ping",
+            Request::AclSetUser { .. } | Request::AclDelUser { .. } | Request::AclList | Request::AclWhoAmI => "acl",
+            Request::Subscribe { .. } => "subscribe",
+            Request::Unsubscribe { .. } => "unsubscribe",
+            Request::PSubscribe { .. } => "psubscribe",
+            Request::PUnsubscribe { .. } => "punsubscribe",
+            Request::Publish { .. } => "publish",
+            Request::Watch { .. } => "watch",
+            Request::Unwatch { .. } => "unwatch",
+            Request::Changes { .. } => "changes",
+            Request::Sync { .. } => "sync",
+            Request::Role => "role",
+            Request::Scan { .. } => "scan",
+            Request::ClusterSlots | Request::ClusterNodes | Request::ClusterKeySlot { .. } | Request::ClusterSetSlot { .. }
+                | Request::ClusterCountKeysInSlot { .. } | Request::ClusterMigrate { .. } => "cluster",
+            Request::Asking => "asking",
+        }
+    }
+
+    /// Requests that modify keys, rejected by followers.
+    pub fn is_write(&self) -> bool {
+        matches!(self, Request::Set { .. } | Request::Remove { .. } | Request::MSet { .. } | Request::MDel { .. })
+    }
+
+    /// Requests that leave the same state and get the same reply when sent again,
+    /// so clients may retry them after a broken connection. Writes among them are retried only
+    /// with `ClientBuilder::retry_writes`, as another client may write the keys in between.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, Request::Set { .. } | Request::Get { .. } | Request::MSet { .. } | Request::MGet { .. }
+            | Request::Info { .. } | Request::DbSize | Request::SlowLogGet { .. } | Request::SlowLogLen
+            | Request::Ping { .. } | Request::AclList | Request::AclWhoAmI | Request::Role | Request::Scan { .. }
+            | Request::ClusterSlots | Request::ClusterNodes | Request::ClusterKeySlot { .. }
+            | Request::ClusterCountKeysInSlot { .. })
+    }
+
+    /// Keys the request reads or writes.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Request::Set { key, .. } | Request::Get { key } | Request::Remove { key } => vec![key],
+            Request::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Request::MGet { keys } | Request::MDel { keys } => keys.iter().map(String::as_str).collect(),
+            // prefixes of `WATCH` and `SCAN` are not keys, the keys they find are checked one by one
+            _ => vec![]
+        }
+    }
 }
 
 impl Into<RESPType> for Request {
@@ -32,10 +181,240 @@ impl Into<RESPType> for Request {
             Request::Set { key, value } => array!(bulk!("set"), bulk!(key), bulk!(value)),
             Request::Get { key } => array!(bulk!("get"), bulk!(key)),
             Request::Remove { key } => array!(bulk!("rm"), bulk!(key)),
+            Request::MSet { pairs } => {
+                let mut arr = vec![bulk!("mset")];
+                for (key, value) in pairs {
+                    arr.push(bulk!(key));
+                    arr.push(bulk!(value));
+                }
+                RESPType::Array(arr)
+            }
+            Request::MGet { keys } => with_keys("mget", keys),
+            Request::MDel { keys } => with_keys("mdel", keys),
+            Request::Backup { dir } => array!(bulk!("backup"), bulk!(dir)),
+            Request::Info { section: Some(section) } => array!(bulk!("info"), bulk!(section)),
+            Request::Info { section: None } => array!(bulk!("info")),
+            Request::DbSize => array!(bulk!("dbsize")),
+            Request::SlowLogGet { count: Some(count) } => array!(bulk!("slowlog"), bulk!("get"), bulk!(count.to_string())),
+            Request::SlowLogGet { count: None } => array!(bulk!("slowlog"), bulk!("get")),
+            Request::SlowLogLen => array!(bulk!("slowlog"), bulk!("len")),
+            Request::SlowLogReset => array!(bulk!("slowlog"), bulk!("reset")),
+            Request::Auth { username: Some(username), password } => array!(bulk!("auth"), bulk!(username), bulk!(password)),
+            Request::Auth { username: None, password } => array!(bulk!("auth"), bulk!(password)),
+            Request::Ping { message: Some(message) } => array!(bulk!("ping"), bulk!(message)),
+            Request::Ping { message: None } => array!(bulk!("ping")),
+            Request::AclSetUser { username, rules } => {
+                let mut arr = vec![bulk!("acl"), bulk!("setuser"), bulk!(username)];
+                arr.extend(rules.into_iter().map(|rule| bulk!(rule)));
+                RESPType::Array(arr)
+            }
+            Request::AclDelUser { usernames } => {
+                let mut arr = vec![bulk!("acl"), bulk!("deluser")];
+                arr.extend(usernames.into_iter().map(|username| bulk!(username)));
+                RESPType::Array(arr)
+            }
+            Request::AclList => array!(bulk!("acl"), bulk!("list")),
+            Request::AclWhoAmI => array!(bulk!("acl"), bulk!("whoami")),
+            Request::Subscribe { channels } => with_keys("subscribe", channels),
+            Request::Unsubscribe { channels } => with_keys("unsubscribe", channels),
+            Request::PSubscribe { patterns } => with_keys("psubscribe", patterns),
+            Request::PUnsubscribe { patterns } => with_keys("punsubscribe", patterns),
+            Request::Publish { channel, message } => array!(bulk!("publish"), bulk!(channel), bulk!(message)),
+            Request::Watch { prefixes } => with_keys("watch", prefixes),
+            Request::Unwatch { prefixes } => with_keys("unwatch", prefixes),
+            Request::Changes { since } => array!(bulk!("changes"), bulk!("since"), bulk!(since.to_string())),
+            Request::Sync { since: Some(since) } => array!(bulk!("sync"), bulk!(since.to_string())),
+            Request::Sync { since: None } => array!(bulk!("sync")),
+            Request::Role => array!(bulk!("role")),
+            Request::Scan { prefix, after, count } => {
+                let mut arr = vec![bulk!("scan"), bulk!(prefix)];
+                if let Some(after) = after {
+                    arr.extend([bulk!("after"), bulk!(after)]);
+                }
+                if let Some(count) = count {
+                    arr.extend([bulk!("count"), bulk!(count.to_string())]);
+                }
+                RESPType::Array(arr)
+            }
+            Request::ClusterSlots => array!(bulk!("cluster"), bulk!("slots")),
+            Request::ClusterNodes => array!(bulk!("cluster"), bulk!("nodes")),
+            Request::ClusterKeySlot { key } => array!(bulk!("cluster"), bulk!("keyslot"), bulk!(key)),
+            Request::ClusterSetSlot { start, end, state } => {
+                let slots = if start == end { start.to_string() } else { format!("{}-{}", start, end) };
+                let mut arr = vec![bulk!("cluster"), bulk!("setslot"), bulk!(slots)];
+                match state {
+                    SlotState::Node(node) => arr.extend([bulk!("node"), bulk!(node)]),
+                    SlotState::Migrating(node) => arr.extend([bulk!("migrating"), bulk!(node)]),
+                    SlotState::Importing(node) => arr.extend([bulk!("importing"), bulk!(node)]),
+                    SlotState::Stable => arr.push(bulk!("stable")),
+                }
+                RESPType::Array(arr)
+            }
+            Request::ClusterCountKeysInSlot { slot } => array!(bulk!("cluster"), bulk!("countkeysinslot"), bulk!(slot.to_string())),
+            Request::ClusterMigrate { slot, count, password } => {
+                let mut arr = vec![bulk!("cluster"), bulk!("migrate"), bulk!(slot.to_string()), bulk!(count.to_string())];
+                if let Some(password) = password {
+                    arr.extend([bulk!("auth"), bulk!(password)]);
+                }
+                RESPType::Array(arr)
+            }
+            Request::Asking => array!(bulk!("asking")),
         }
     }
 }
 
+fn with_keys(cmd: &str, keys: Vec<String>) -> RESPType {
+    let mut arr = vec![bulk!(cmd)];
+    arr.extend(keys.into_iter().map(|key| bulk!(key)));
+    RESPType::Array(arr)
+}
+
+/// Parse a request sent by client, which should be a RESP array of bulk strings
+/// whose first element is the command name.
+/// # Errors
+/// * `KvError::UnknownCommand` the command name is not supported
+/// * `KvError::MissingArguments` the arguments do not match the command
+impl TryFrom<RESPType> for Request {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let arr = match value {
+            RESPType::Array(arr) => arr,
+            _ => return Err(KvError::Message("request should be a resp array".to_owned()))
+        };
+        let mut args = arr.iter().map(bulk_to_string).collect::<Result<Vec<_>>>()?;
+        if args.is_empty() {
+            return Err(KvError::MissingArguments);
+        }
+        let cmd = args.remove(0).to_lowercase();
+        match (cmd.as_str(), args.len()) {
+            ("get", 1) => Ok(Request::Get { key: args.remove(0) }),
+            ("set", 2) => Ok(Request::Set { key: args.remove(0), value: args.remove(0) }),
+            ("rm", 1) => Ok(Request::Remove { key: args.remove(0) }),
+            ("mset", n) if n > 0 && n % 2 == 0 => {
+                let pairs = args.chunks(2).map(|kv| (kv[0].clone(), kv[1].clone())).collect();
+                Ok(Request::MSet { pairs })
+            }
+            ("mget", n) if n > 0 => Ok(Request::MGet { keys: args }),
+            ("mdel", n) if n > 0 => Ok(Request::MDel { keys: args }),
+            ("backup", 1) => Ok(Request::Backup { dir: args.remove(0) }),
+            ("info", 0) => Ok(Request::Info { section: None }),
+            ("info", 1) => Ok(Request::Info { section: Some(args.remove(0)) }),
+            ("dbsize", 0) => Ok(Request::DbSize),
+            ("slowlog", 1 | 2) => {
+                let sub_cmd = args.remove(0).to_lowercase();
+                match (sub_cmd.as_str(), args.pop()) {
+                    ("get", None) => Ok(Request::SlowLogGet { count: None }),
+                    ("get", Some(count)) => {
+                        let count = count.parse().map_err(|_| KvError::Message("count should be an integer".to_owned()))?;
+                        Ok(Request::SlowLogGet { count: Some(count) })
+                    }
+                    ("len", None) => Ok(Request::SlowLogLen),
+                    ("reset", None) => Ok(Request::SlowLogReset),
+                    _ => Err(KvError::UnknownCommand)
+                }
+            }
+            ("auth", 1) => Ok(Request::Auth { username: None, password: args.remove(0) }),
+            ("auth", 2) => Ok(Request::Auth { username: Some(args.remove(0)), password: args.remove(0) }),
+            ("ping", 0) => Ok(Request::Ping { message: None }),
+            ("ping", 1) => Ok(Request::Ping { message: Some(args.remove(0)) }),
+            ("acl", n) if n > 0 => {
+                let sub_cmd = args.remove(0).to_lowercase();
+                match (sub_cmd.as_str(), args.len()) {
+                    ("setuser", n) if n > 0 => Ok(Request::AclSetUser { username: args.remove(0), rules: args }),
+                    ("deluser", n) if n > 0 => Ok(Request::AclDelUser { usernames: args }),
+                    ("list", 0) => Ok(Request::AclList),
+                    ("whoami", 0) => Ok(Request::AclWhoAmI),
+                    ("setuser" | "deluser" | "list" | "whoami", _) => Err(KvError::MissingArguments),
+                    _ => Err(KvError::UnknownCommand)
+                }
+            }
+            ("subscribe", n) if n > 0 => Ok(Request::Subscribe { channels: args }),
+            ("unsubscribe", _) => Ok(Request::Unsubscribe { channels: args }),
+            ("psubscribe", n) if n > 0 => Ok(Request::PSubscribe { patterns: args }),
+            ("punsubscribe", _) => Ok(Request::PUnsubscribe { patterns: args }),
+            ("publish", 2) => Ok(Request::Publish { channel: args.remove(0), message: args.remove(0) }),
+            ("watch", n) if n > 0 => Ok(Request::Watch { prefixes: args }),
+            ("unwatch", _) => Ok(Request::Unwatch { prefixes: args }),
+            ("changes", 2) if args[0].eq_ignore_ascii_case("since") => {
+                let since = args[1].parse().map_err(|_| KvError::Message("since should be a sequence number".to_owned()))?;
+                Ok(Request::Changes { since })
+            }
+            ("sync", 0) => Ok(Request::Sync { since: None }),
+            ("sync", 1) => {
+                let since = args[0].parse().map_err(|_| KvError::Message("since should be a sequence number".to_owned()))?;
+                Ok(Request::Sync { since: Some(since) })
+            }
+            ("role", 0) => Ok(Request::Role),
+            ("scan", 0) => Ok(Request::scan("")),
+            ("scan", n) if n % 2 == 1 => parse_scan(args),
+            ("cluster", n) if n > 0 => parse_cluster(args),
+            ("asking", 0) => Ok(Request::Asking),
+            ("get" | "set" | "rm" | "mset" | "mget" | "mdel" | "backup" | "info" | "dbsize" | "slowlog" | "auth" | "ping" | "acl"
+                | "subscribe" | "psubscribe" | "publish" | "watch" | "changes" | "sync" | "role" | "scan" | "cluster" | "asking", _) => {
+                Err(KvError::MissingArguments)
+            }
+            _ => Err(KvError::UnknownCommand)
+        }
+    }
+}
+
+/// Parse the prefix and options of `SCAN`.
+fn parse_scan(mut args: Vec<String>) -> Result<Request> {
+    let (mut after, mut count) = (None, None);
+    let prefix = args.remove(0);
+    let mut options = args.into_iter();
+    while let (Some(option), Some(value)) = (options.next(), options.next()) {
+        match option.to_lowercase().as_str() {
+            "after" => after = Some(value),
+            "count" => count = Some(value.parse().map_err(|_| KvError::Message("count should be an integer".to_owned()))?),
+            _ => return Err(KvError::Message(format!("unknown scan option: {}", option)))
+        }
+    }
+    Ok(Request::Scan { prefix, after, count })
+}
+
+/// Parse the subcommand and arguments of `CLUSTER`.
+fn parse_cluster(mut args: Vec<String>) -> Result<Request> {
+    let sub_cmd = args.remove(0).to_lowercase();
+    let slot = |arg: &str| match cluster::parse_slots(arg)? {
+        (slot, end) if slot == end => Ok(slot),
+        _ => Err(KvError::Message(format!("invalid slot: {}", arg)))
+    };
+    let count = |arg: &str| arg.parse::<usize>().map_err(|_| KvError::Message("count should be an integer".to_owned()));
+    match (sub_cmd.as_str(), args.len()) {
+        ("slots", 0) => Ok(Request::ClusterSlots),
+        ("nodes", 0) => Ok(Request::ClusterNodes),
+        ("keyslot", 1) => Ok(Request::ClusterKeySlot { key: args.remove(0) }),
+        ("setslot", 2 | 3) => {
+            let (start, end) = cluster::parse_slots(&args[0])?;
+            let state = match (args[1].to_lowercase().as_str(), args.get(2)) {
+                ("node", Some(node)) => SlotState::Node(node.clone()),
+                ("migrating", Some(node)) => SlotState::Migrating(node.clone()),
+                ("importing", Some(node)) => SlotState::Importing(node.clone()),
+                ("stable", None) => SlotState::Stable,
+                _ => return Err(KvError::MissingArguments)
+            };
+            Ok(Request::ClusterSetSlot { start, end, state })
+        }
+        ("countkeysinslot", 1) => Ok(Request::ClusterCountKeysInSlot { slot: slot(&args[0])? }),
+        ("migrate", 2) => Ok(Request::ClusterMigrate { slot: slot(&args[0])?, count: count(&args[1])?, password: None }),
+        ("migrate", 4) if args[2].eq_ignore_ascii_case("auth") => {
+            Ok(Request::ClusterMigrate { slot: slot(&args[0])?, count: count(&args[1])?, password: Some(args.remove(3)) })
+        }
+        ("slots" | "nodes" | "keyslot" | "setslot" | "countkeysinslot" | "migrate", _) => Err(KvError::MissingArguments),
+        _ => Err(KvError::UnknownCommand)
+    }
+}
+
+fn bulk_to_string(resp: &RESPType) -> Result<String> {
+    match resp {
+        RESPType::BulkString(buf) => Ok(String::from_utf8(buf.clone())?),
+        RESPType::SimpleString(str) => Ok(str.clone()),
+        _ => Err(KvError::Message("request argument should be a bulk string".to_owned()))
+    }
+}
+
 /// May deserialize as:
 /// `RESPType::BulkString(str)`
 /// `RESPType::None`
@@ -94,3 +473,195 @@ impl Into<RESPType> for RemoveResponse {
         }
     }
 }
+
+/// May deserialize as:
+/// `RESPType::Array(values)`, each value is `RESPType::BulkString(str)` or `RESPType::None`
+/// `RESPType::Error(err)`
+pub enum MGetResponse {
+    Ok(Vec<Option<String>>),
+    Err(String)
+}
+
+impl From<MGetResponse> for RESPType {
+    fn from(response: MGetResponse) -> Self {
+        match response {
+            MGetResponse::Ok(values) => RESPType::Array(
+                values.into_iter()
+                    .map(|opt_str| GetResponse::Ok(opt_str).into())
+                    .collect()
+            ),
+            MGetResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::Integer(n)`, `n` is the number of removed keys
+/// `RESPType::Error(err)`
+pub enum MDelResponse {
+    Ok(usize),
+    Err(String)
+}
+
+impl From<MDelResponse> for RESPType {
+    fn from(response: MDelResponse) -> Self {
+        match response {
+            MDelResponse::Ok(n) => RESPType::Integer(n as i64),
+            MDelResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::BulkString(info)`, lines of `field:value` grouped by `# Section` headers
+/// `RESPType::Error(err)`
+pub enum InfoResponse {
+    Ok(String),
+    Err(String)
+}
+
+impl From<InfoResponse> for RESPType {
+    fn from(response: InfoResponse) -> Self {
+        match response {
+            InfoResponse::Ok(info) => bulk!(info),
+            InfoResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::Integer(n)`, `n` is the number of keys
+/// `RESPType::Error(err)`
+pub enum DbSizeResponse {
+    Ok(u64),
+    Err(String)
+}
+
+impl From<DbSizeResponse> for RESPType {
+    fn from(response: DbSizeResponse) -> Self {
+        match response {
+            DbSizeResponse::Ok(n) => RESPType::Integer(n as i64),
+            DbSizeResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::Array(entries)`, see `SlowLogEntry` for the layout of an entry
+/// `RESPType::Error(err)`
+pub enum SlowLogResponse {
+    Ok(Vec<SlowLogEntry>),
+    Err(String)
+}
+
+impl From<SlowLogResponse> for RESPType {
+    fn from(response: SlowLogResponse) -> Self {
+        match response {
+            SlowLogResponse::Ok(entries) => RESPType::Array(entries.into_iter().map(Into::into).collect()),
+            SlowLogResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::Array(lines)`, each line is a `RESPType::BulkString` describing a user
+/// `RESPType::Error(err)`
+pub enum AclListResponse {
+    Ok(Vec<String>),
+    Err(String)
+}
+
+impl From<AclListResponse> for RESPType {
+    fn from(response: AclListResponse) -> Self {
+        match response {
+            AclListResponse::Ok(lines) => RESPType::Array(lines.into_iter().map(|line| bulk!(line)).collect()),
+            AclListResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::Array(fields)`, keys and values alternate: `[key1, value1, key2, value2, ...]`
+/// `RESPType::Error(err)`
+pub enum ScanResponse {
+    Ok(Vec<(String, String)>),
+    Err(String)
+}
+
+impl From<ScanResponse> for RESPType {
+    fn from(response: ScanResponse) -> Self {
+        match response {
+            ScanResponse::Ok(pairs) => RESPType::Array(
+                pairs.into_iter().flat_map(|(key, value)| [bulk!(key), bulk!(value)]).collect()
+            ),
+            ScanResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// May deserialize as:
+/// `RESPType::Array(ranges)`, see `SlotRange` for the layout of a range
+/// `RESPType::Error(err)`
+pub enum ClusterSlotsResponse {
+    Ok(Vec<SlotRange>),
+    Err(String)
+}
+
+impl From<ClusterSlotsResponse> for RESPType {
+    fn from(response: ClusterSlotsResponse) -> Self {
+        match response {
+            ClusterSlotsResponse::Ok(ranges) => RESPType::Array(ranges.into_iter().map(Into::into).collect()),
+            ClusterSlotsResponse::Err(err) => err!(err)
+        }
+    }
+}
+
+/// Serialized as `["change", seq, "set", key, value]` or `["change", seq, "remove", key]`,
+/// followed by `remaining` unless it is 0
+impl From<Change> for RESPType {
+    fn from(change: Change) -> Self {
+        let seq = RESPType::Integer(change.seq as i64);
+        let mut fields = match change.event {
+            Event::Set { key, value } => vec![bulk!("change"), seq, bulk!("set"), bulk!(key), bulk!(value)],
+            Event::Remove { key } => vec![bulk!("change"), seq, bulk!("remove"), bulk!(key)],
+        };
+        if change.remaining > 0 {
+            fields.push(RESPType::Integer(change.remaining as i64));
+        }
+        RESPType::Array(fields)
+    }
+}
+
+impl TryFrom<RESPType> for Change {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed change".to_owned());
+        let mut fields = match value {
+            RESPType::Array(fields) if fields.len() >= 4 => fields,
+            _ => return Err(malformed()),
+        };
+        let remaining = match fields.last() {
+            Some(RESPType::Integer(remaining)) if *remaining >= 0 => {
+                let remaining = *remaining as u64;
+                fields.pop();
+                remaining
+            }
+            _ => 0,
+        };
+        let mut fields = fields.into_iter();
+        let (kind, seq) = (fields.next(), fields.next());
+        let args = fields.map(|field| bulk_to_string(&field)).collect::<Result<Vec<_>>>()?;
+        let event = match (args.first().map(String::as_str), args.len()) {
+            (Some("set"), 3) => Event::Set { key: args[1].clone(), value: args[2].clone() },
+            (Some("remove"), 2) => Event::Remove { key: args[1].clone() },
+            _ => return Err(malformed()),
+        };
+        match (kind, seq) {
+            (Some(RESPType::BulkString(kind)), Some(RESPType::Integer(seq))) if kind == b"change" && seq >= 0 => {
+                Ok(Change { seq: seq as u64, event, remaining })
+            }
+            _ => Err(malformed()),
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::engine::{EngineStats, KvsEngine};
+use crate::http::{self, HttpResponse};
+use crate::{KvErrorKind, Result};
+
+/// Upper bounds in seconds of the command latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Counters of a single command.
+#[derive(Default)]
+struct CommandMetrics {
+    count: u64,
+    seconds: f64,
+    /// Observations per bucket, not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+/// Server level counters, shared by connections, `INFO` and the `/metrics` endpoint.
+pub struct Metrics {
+    started: Instant,
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    commands: Mutex<BTreeMap<&'static str, CommandMetrics>>,
+    errors: Mutex<BTreeMap<KvErrorKind, u64>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            active_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn connection_opened(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Record a processed command and how long it took.
+    pub fn record_command(&self, name: &'static str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut commands = self.commands.lock().unwrap();
+        let command = commands.entry(name).or_default();
+        command.count += 1;
+        command.seconds += seconds;
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            command.buckets[i] += 1;
+        }
+    }
+
+    pub fn record_error(&self, kind: KvErrorKind) {
+        *self.errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Number of processed commands by name.
+    pub fn command_counts(&self) -> BTreeMap<&'static str, u64> {
+        self.commands.lock().unwrap().iter().map(|(name, command)| (*name, command.count)).collect()
+    }
+
+    /// Render all metrics in prometheus text format.
+    pub fn render(&self, stats: &EngineStats) -> String {
+        let mut out = String::new();
+        let gauge = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+        };
+        let counter = |out: &mut String, name: &str, help: &str, value: String| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n");
+        };
+        gauge(&mut out, "kvs_uptime_seconds", "Seconds since the server started.", self.uptime().as_secs());
+        gauge(&mut out, "kvs_connections_active", "Connections being served.", self.active_connections());
+        counter(&mut out, "kvs_connections_total", "Accepted connections.", self.total_connections().to_string());
+        counter(&mut out, "kvs_bytes_in_total", "Bytes read from clients.", self.bytes_in.load(Ordering::Relaxed).to_string());
+        counter(&mut out, "kvs_bytes_out_total", "Bytes written to clients.", self.bytes_out.load(Ordering::Relaxed).to_string());
+
+        out += "# HELP kvs_commands_total Processed commands.\n# TYPE kvs_commands_total counter\n";
+        let commands = self.commands.lock().unwrap();
+        for (name, command) in commands.iter() {
+            let _ = writeln!(out, "kvs_commands_total{{command=\"{}\"}} {}", name, command.count);
+        }
+        out += "# HELP kvs_command_duration_seconds Latency of processed commands.\n";
+        out += "# TYPE kvs_command_duration_seconds histogram\n";
+        for (name, command) in commands.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(command.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(out, "kvs_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}", name, bound, cumulative);
+            }
+            let _ = writeln!(out, "kvs_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", name, command.count);
+            let _ = writeln!(out, "kvs_command_duration_seconds_sum{{command=\"{}\"}} {}", name, command.seconds);
+            let _ = writeln!(out, "kvs_command_duration_seconds_count{{command=\"{}\"}} {}", name, command.count);
+        }
+        drop(commands);
+
+        out += "# HELP kvs_errors_total Errors by kind.\n# TYPE kvs_errors_total counter\n";
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "kvs_errors_total{{kind=\"{:?}\"}} {}", kind, count);
+        }
+
+        gauge(&mut out, "kvs_engine_keys", "Live keys in the engine.", stats.keys);
+        gauge(&mut out, "kvs_engine_disk_bytes", "Bytes used on disk by the engine.", stats.disk_bytes);
+        if let Some(uncompacted) = stats.uncompacted_bytes {
+            gauge(&mut out, "kvs_engine_uncompacted_bytes", "Bytes of stale records.", uncompacted);
+        }
+        if let Some(log_files) = stats.log_files {
+            gauge(&mut out, "kvs_engine_log_files", "Log files of the engine.", log_files);
+        }
+        if let Some(compactions) = stats.compactions {
+            counter(&mut out, "kvs_engine_compactions_total", "Compactions since the store is opened.", compactions.to_string());
+        }
+        if let Some(compaction_time) = stats.compaction_time {
+            counter(&mut out, "kvs_engine_compaction_seconds_total", "Time spent on compactions.", compaction_time.as_secs_f64().to_string());
+        }
+        out
+    }
+}
+
+/// Wrap a client stream to count bytes read and written.
+pub struct Counted<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.metrics.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.metrics.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Serve `GET /metrics` over HTTP in a new thread.
+/// Engine statistics are read at scrape time.
+pub fn serve_metrics<E, A>(addr: A, metrics: Arc<Metrics>, engine: Arc<Mutex<E>>) -> Result<JoinHandle<()>>
+where
+    E: KvsEngine + Send + 'static,
+    A: ToSocketAddrs,
+{
+    let listener = TcpListener::bind(addr)?;
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(Into::into).and_then(|stream| scrape(stream, &metrics, &engine));
+            if let Err(err) = result {
+                log::error!("Error on serving metrics: {}", err);
+            }
+        }
+    }))
+}
+
+fn scrape<E: KvsEngine>(stream: TcpStream, metrics: &Metrics, engine: &Mutex<E>) -> Result<()> {
+    let response = match http::read_request(&mut BufReader::new(&stream))? {
+        Ok(request) if request.path == "/metrics" => {
+            let stats = engine.lock().unwrap().stats()?;
+            HttpResponse::new(200, "text/plain; version=0.0.4", metrics.render(&stats))
+        }
+        Ok(_) => HttpResponse::empty(404),
+        Err(response) => response
+    };
+    response.write_to(&stream)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::time::Duration;
+    use crate::engine::EngineStats;
+    use crate::KvErrorKind;
+    use super::Metrics;
+
+    // Should render cumulative buckets and errors by kind
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_command("get", Duration::from_micros(50));
+        metrics.record_command("get", Duration::from_millis(3));
+        metrics.record_error(KvErrorKind::UnknownCommand);
+        let stats = EngineStats { engine: "kvs".to_owned(), keys: 7, compactions: Some(2), ..Default::default() };
+        let text = metrics.render(&stats);
+        assert!(text.contains("kvs_commands_total{command=\"get\"} 2"));
+        assert!(text.contains("kvs_command_duration_seconds_bucket{command=\"get\",le=\"0.0001\"} 1"));
+        assert!(text.contains("kvs_command_duration_seconds_bucket{command=\"get\",le=\"0.005\"} 2"));
+        assert!(text.contains("kvs_errors_total{kind=\"UnknownCommand\"} 1"));
+        assert!(text.contains("kvs_engine_keys 7"));
+        assert!(text.contains("kvs_engine_compactions_total 2"));
+    }
+}
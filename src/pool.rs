@@ -0,0 +1,260 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{KvError, KvsClient, Result};
+
+/// Settings of a `KvsPool`.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Connections opened by `KvsPool::connect` and kept open however long they are idle.
+    /// Closed connections are replaced to keep this many idle while fewer than `max_size` are open.
+    pub min_idle: usize,
+    /// Connections open at most, in use or idle.
+    pub max_size: usize,
+    /// Idle connections beyond `min_idle` are closed once unused for this long.
+    pub idle_timeout: Duration,
+    /// How long `get` waits for a connection to be returned when `max_size` are in use.
+    pub wait_timeout: Duration,
+    /// Authenticates every connection as the default user.
+    pub password: Option<String>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_idle: 1,
+            max_size: 10,
+            idle_timeout: Duration::from_secs(300),
+            wait_timeout: Duration::from_secs(30),
+            password: None,
+        }
+    }
+}
+
+/// Connections to a server shared by threads, cloning the pool shares the same connections.
+///
+/// `get` lends a connection that answered `PING`, so connections broken while idle, e.g. by a
+/// restart of the server, are closed and replaced instead of failing the next command.
+/// Connections are returned when the `PooledClient` is dropped, unless a request on them failed,
+/// see `KvsClient::failed`, and expired idle connections are closed on the next `get` or return.
+#[derive(Clone)]
+pub struct KvsPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addrs: Vec<SocketAddr>,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    /// Notified when a connection is returned or closed.
+    released: Condvar,
+}
+
+struct PoolState {
+    /// Idle connections with the time they were returned, the most recently returned last.
+    idle: Vec<(KvsClient, Instant)>,
+    /// Connections idle, in use or being opened.
+    open: usize,
+}
+
+impl KvsPool {
+    /// Resolve `addr` and open `min_idle` connections to it.
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: PoolConfig) -> Result<Self> {
+        if config.max_size == 0 || config.min_idle > config.max_size {
+            return Err(KvError::Message(format!(
+                "invalid pool size: min_idle {} and max_size {}", config.min_idle, config.max_size
+            )));
+        }
+        let addrs = addr.to_socket_addrs()?.collect();
+        let pool = KvsPool {
+            shared: Arc::new(Shared {
+                addrs,
+                config,
+                state: Mutex::new(PoolState { idle: vec![], open: 0 }),
+                released: Condvar::new(),
+            }),
+        };
+        for _ in 0..pool.shared.config.min_idle {
+            let client = pool.shared.open()?;
+            let mut state = pool.shared.state();
+            state.open += 1;
+            state.idle.push((client, Instant::now()));
+        }
+        Ok(pool)
+    }
+
+    /// Lend the most recently returned idle connection which answers `PING`, or open a new one
+    /// if fewer than `max_size` are open, or else wait up to `wait_timeout` for one.
+    pub fn get(&self) -> Result<PooledClient> {
+        let shared = &self.shared;
+        let deadline = Instant::now() + shared.config.wait_timeout;
+        let mut state = shared.state();
+        loop {
+            shared.close_expired(&mut state);
+            if let Some((mut client, _)) = state.idle.pop() {
+                drop(state);
+                if client.ping().is_ok() {
+                    return Ok(self.lend(client));
+                }
+                drop(client);
+                shared.evict();
+                state = shared.state();
+                continue;
+            }
+            if state.open < shared.config.max_size {
+                state.open += 1;
+                drop(state);
+                return match shared.open() {
+                    Ok(client) => Ok(self.lend(client)),
+                    Err(err) => {
+                        shared.release();
+                        Err(err)
+                    }
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvError::Timeout(format!(
+                    "waiting {:?} for a pooled connection", shared.config.wait_timeout
+                )));
+            }
+            state = shared.released.wait_timeout(state, deadline - now).expect("pool lock is poisoned").0;
+        }
+    }
+
+    /// Connections waiting in the pool.
+    pub fn idle(&self) -> usize {
+        self.shared.state().idle.len()
+    }
+
+    /// Connections open, idle or in use.
+    pub fn size(&self) -> usize {
+        self.shared.state().open
+    }
+
+    fn lend(&self, client: KvsClient) -> PooledClient {
+        PooledClient { client: Some(client), pool: self.shared.clone() }
+    }
+}
+
+impl Shared {
+    fn open(&self) -> Result<KvsClient> {
+        let mut client = KvsClient::connect(&self.addrs[..])?;
+        if let Some(password) = &self.config.password {
+            client.auth(password)?;
+        }
+        Ok(client)
+    }
+
+    /// Close idle connections unused for `idle_timeout`, keeping `min_idle` of them.
+    fn close_expired(&self, state: &mut PoolState) {
+        let expired = state.idle.iter()
+            .take(state.idle.len().saturating_sub(self.config.min_idle))
+            .take_while(|(_, returned)| returned.elapsed() >= self.config.idle_timeout)
+            .count();
+        if expired > 0 {
+            state.idle.drain(..expired);
+            state.open -= expired;
+            self.released.notify_all();
+        }
+    }
+
+    fn put(&self, client: KvsClient) {
+        let mut state = self.state();
+        state.idle.push((client, Instant::now()));
+        self.close_expired(&mut state);
+        self.released.notify_one();
+    }
+
+    /// Forget a connection that is closed.
+    fn release(&self) {
+        self.state().open -= 1;
+        self.released.notify_one();
+    }
+
+    /// Forget a connection that is closed while open, and replace it if fewer than `min_idle` are idle.
+    fn evict(&self) {
+        self.release();
+        self.fill();
+    }
+
+    /// Forget a connection closed by a `PooledClient`, and replace it in a background thread if
+    /// fewer than `min_idle` are idle, so that dropping the client does not wait for a connect.
+    fn replace(self: &Arc<Self>) {
+        self.release();
+        let state = self.state();
+        if state.idle.len() < self.config.min_idle && state.open < self.config.max_size {
+            let shared = self.clone();
+            thread::spawn(move || shared.fill());
+        }
+    }
+
+    /// Open connections until `min_idle` are idle or `max_size` are open. A connection that
+    /// fails to open is left to the next `get`.
+    fn fill(&self) {
+        loop {
+            let mut state = self.state();
+            if state.idle.len() >= self.config.min_idle || state.open >= self.config.max_size {
+                return;
+            }
+            state.open += 1;
+            drop(state);
+            match self.open() {
+                Ok(client) => self.put(client),
+                Err(err) => {
+                    log::warn!("Failed to replace a closed pooled connection: {}", err);
+                    self.release();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().expect("pool lock is poisoned")
+    }
+}
+
+/// A connection lent by a `KvsPool`, returned to it on drop unless a request on it failed.
+pub struct PooledClient {
+    /// Taken on drop or `discard`.
+    client: Option<KvsClient>,
+    pool: Arc<Shared>,
+}
+
+impl PooledClient {
+    /// Close the connection instead of returning it, e.g. after `AUTH` as another user.
+    pub fn discard(mut self) {
+        self.client.take();
+        self.pool.replace();
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("pooled client is taken")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("pooled client is taken")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(client) if client.failed() => {
+                drop(client);
+                self.pool.replace();
+            }
+            Some(client) => self.pool.put(client),
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use serde_resp::{bulk, RESPType};
+use crate::engine::Event;
+use crate::{tools, KvError, Result};
+
+/// A message pushed to a subscribed connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Published to a subscribed channel.
+    Message { channel: String, payload: String },
+    /// Published to a channel matching a subscribed pattern.
+    PMessage { pattern: String, channel: String, payload: String },
+}
+
+impl Message {
+    pub fn channel(&self) -> &str {
+        match self {
+            Message::Message { channel, .. } | Message::PMessage { channel, .. } => channel,
+        }
+    }
+
+    pub fn payload(&self) -> &str {
+        match self {
+            Message::Message { payload, .. } | Message::PMessage { payload, .. } => payload,
+        }
+    }
+}
+
+/// Serialized as `["message", channel, payload]` or `["pmessage", pattern, channel, payload]`
+impl From<Message> for RESPType {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Message { channel, payload } => RESPType::Array(vec![bulk!("message"), bulk!(channel), bulk!(payload)]),
+            Message::PMessage { pattern, channel, payload } => {
+                RESPType::Array(vec![bulk!("pmessage"), bulk!(pattern), bulk!(channel), bulk!(payload)])
+            }
+        }
+    }
+}
+
+impl TryFrom<RESPType> for Message {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed pubsub message".to_owned());
+        let fields = match value {
+            RESPType::Array(fields) => fields,
+            _ => return Err(malformed()),
+        };
+        let mut fields = fields.into_iter().map(|field| match field {
+            RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+            _ => Err(malformed()),
+        });
+        let mut next = || fields.next().unwrap_or_else(|| Err(malformed()));
+        match next()?.as_str() {
+            "message" => Ok(Message::Message { channel: next()?, payload: next()? }),
+            "pmessage" => Ok(Message::PMessage { pattern: next()?, channel: next()?, payload: next()? }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// Serialized as `["event", "set", key, value]` or `["event", "remove", key]`
+impl From<Event> for RESPType {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::Set { key, value } => RESPType::Array(vec![bulk!("event"), bulk!("set"), bulk!(key), bulk!(value)]),
+            Event::Remove { key } => RESPType::Array(vec![bulk!("event"), bulk!("remove"), bulk!(key)]),
+        }
+    }
+}
+
+impl TryFrom<RESPType> for Event {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed key event".to_owned());
+        let fields = match value {
+            RESPType::Array(fields) => fields,
+            _ => return Err(malformed()),
+        };
+        let fields = fields.into_iter().map(|field| match field {
+            RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+            _ => Err(malformed()),
+        }).collect::<Result<Vec<_>>>()?;
+        match fields.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["event", "set", key, value] => Ok(Event::Set { key: key.to_string(), value: value.to_string() }),
+            ["event", "remove", key] => Ok(Event::Remove { key: key.to_string() }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// Decides which keys of a watched prefix a watcher may receive changes of.
+pub type KeyFilter = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Subscribers of each channel, pattern and watched key prefix, messages are sent to their outbox.
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<String, HashMap<u64, Sender<RESPType>>>,
+    patterns: HashMap<String, HashMap<u64, Sender<RESPType>>>,
+    prefixes: HashMap<String, HashMap<u64, (Sender<RESPType>, KeyFilter)>>,
+}
+
+/// Channels shared by all connections of a server.
+///
+/// Each subscribed connection has an outbox, messages published to its channels are queued
+/// there in order, so replies queued to the same outbox are never reordered with messages.
+#[derive(Default)]
+pub struct PubSub {
+    next_id: AtomicU64,
+    registry: Mutex<Registry>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new id to subscribe with, unique within this `PubSub`.
+    pub fn new_subscriber_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Subscribe to a channel. `confirmation` is queued to the outbox while no message can be published,
+    /// so it is ahead of messages and the subscription is in effect once the client receives it.
+    pub fn subscribe(&self, id: u64, outbox: &Sender<RESPType>, channel: &str, confirmation: RESPType) {
+        let mut registry = self.registry();
+        let _ = outbox.send(confirmation);
+        registry.channels.entry(channel.to_owned()).or_default().insert(id, outbox.clone());
+    }
+
+    pub fn unsubscribe(&self, id: u64, channel: &str) {
+        remove_subscriber(&mut self.registry().channels, id, channel);
+    }
+
+    /// Subscribe to every channel matching a glob pattern, see `subscribe` for `confirmation`.
+    pub fn psubscribe(&self, id: u64, outbox: &Sender<RESPType>, pattern: &str, confirmation: RESPType) {
+        let mut registry = self.registry();
+        let _ = outbox.send(confirmation);
+        registry.patterns.entry(pattern.to_owned()).or_default().insert(id, outbox.clone());
+    }
+
+    pub fn punsubscribe(&self, id: u64, pattern: &str) {
+        remove_subscriber(&mut self.registry().patterns, id, pattern);
+    }
+
+    /// Receive changes of keys starting with `prefix` and accepted by `filter`, see `notify`,
+    /// and `subscribe` for `confirmation`.
+    pub fn watch(&self, id: u64, outbox: &Sender<RESPType>, prefix: &str, filter: KeyFilter, confirmation: RESPType) {
+        let mut registry = self.registry();
+        let _ = outbox.send(confirmation);
+        registry.prefixes.entry(prefix.to_owned()).or_default().insert(id, (outbox.clone(), filter));
+    }
+
+    pub fn unwatch(&self, id: u64, prefix: &str) {
+        remove_subscriber(&mut self.registry().prefixes, id, prefix);
+    }
+
+    /// Send a change of the store to watchers of matching prefixes,
+    /// return the number of watches it is sent to.
+    pub fn notify(&self, event: &Event) -> usize {
+        let registry = self.registry();
+        let mut receivers = 0;
+        for (_, watchers) in registry.prefixes.iter().filter(|(prefix, _)| event.key().starts_with(prefix.as_str())) {
+            let message: RESPType = event.clone().into();
+            receivers += watchers.values()
+                .filter(|(outbox, filter)| filter(event.key()) && outbox.send(message.clone()).is_ok())
+                .count();
+        }
+        receivers
+    }
+
+    /// Send `payload` to subscribers of `channel` and of matching patterns,
+    /// return the number of subscriptions it is sent to.
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        let registry = self.registry();
+        let mut receivers = 0;
+        if let Some(subscribers) = registry.channels.get(channel) {
+            let message: RESPType = Message::Message { channel: channel.to_owned(), payload: payload.to_owned() }.into();
+            // the outbox of a closed connection is removed when it unsubscribes
+            receivers += subscribers.values().filter(|outbox| outbox.send(message.clone()).is_ok()).count();
+        }
+        for (pattern, subscribers) in registry.patterns.iter().filter(|(pattern, _)| tools::glob_match(pattern, channel)) {
+            let message: RESPType = Message::PMessage {
+                pattern: pattern.clone(),
+                channel: channel.to_owned(),
+                payload: payload.to_owned(),
+            }.into();
+            receivers += subscribers.values().filter(|outbox| outbox.send(message.clone()).is_ok()).count();
+        }
+        receivers
+    }
+
+    /// Number of channels and patterns with at least one subscriber.
+    pub fn counts(&self) -> (usize, usize) {
+        let registry = self.registry();
+        (registry.channels.len(), registry.patterns.len())
+    }
+
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().expect("pubsub lock is poisoned")
+    }
+}
+
+fn remove_subscriber<T>(subscriptions: &mut HashMap<String, HashMap<u64, T>>, id: u64, name: &str) {
+    if let Some(subscribers) = subscriptions.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            subscriptions.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod pubsub_tests {
+    use std::sync::{mpsc, Arc};
+    use serde_resp::RESPType;
+    use crate::engine::Event;
+    use super::{Message, PubSub};
+
+    // Should deliver to channel and pattern subscribers until they unsubscribe
+    #[test]
+    fn publish_messages() {
+        let pubsub = PubSub::new();
+        let (outbox, inbox) = mpsc::channel();
+        let id = pubsub.new_subscriber_id();
+        pubsub.subscribe(id, &outbox, "news.tech", RESPType::Integer(1));
+        pubsub.psubscribe(id, &outbox, "news.*", RESPType::Integer(2));
+        assert_eq!(inbox.try_iter().collect::<Vec<_>>(), vec![RESPType::Integer(1), RESPType::Integer(2)]);
+        assert_eq!(pubsub.publish("news.tech", "rust"), 2);
+        assert_eq!(pubsub.publish("news.art", "paint"), 1);
+        assert_eq!(pubsub.publish("weather", "rain"), 0);
+
+        let messages: Vec<Message> = inbox.try_iter().map(|resp| Message::try_from(resp).unwrap()).collect();
+        assert_eq!(messages[0], Message::Message { channel: "news.tech".to_owned(), payload: "rust".to_owned() });
+        assert_eq!(messages[1].channel(), "news.tech");
+        assert_eq!(messages[2], Message::PMessage {
+            pattern: "news.*".to_owned(),
+            channel: "news.art".to_owned(),
+            payload: "paint".to_owned(),
+        });
+
+        pubsub.unsubscribe(id, "news.tech");
+        pubsub.punsubscribe(id, "news.*");
+        assert_eq!(pubsub.counts(), (0, 0));
+        assert_eq!(pubsub.publish("news.tech", "rust"), 0);
+    }
+
+    // Should send key events to watchers of matching prefixes, for keys accepted by their filter only
+    #[test]
+    fn notify_watchers() {
+        let pubsub = PubSub::new();
+        let (outbox, inbox) = mpsc::channel();
+        let id = pubsub.new_subscriber_id();
+        pubsub.watch(id, &outbox, "user:", Arc::new(|key| !key.ends_with(":secret")), RESPType::Integer(1));
+        assert_eq!(inbox.try_recv().unwrap(), RESPType::Integer(1));
+        let event = Event::Set { key: "user:1".to_owned(), value: "Adam".to_owned() };
+        assert_eq!(pubsub.notify(&event), 1);
+        assert_eq!(pubsub.notify(&Event::Remove { key: "group:1".to_owned() }), 0);
+        assert_eq!(pubsub.notify(&Event::Remove { key: "user:1:secret".to_owned() }), 0);
+        assert_eq!(Event::try_from(inbox.try_recv().unwrap()).unwrap(), event);
+        pubsub.unwatch(id, "user:");
+        assert_eq!(pubsub.notify(&event), 0);
+    }
+
+    #[test]
+    fn malformed_message() {
+        assert!(Message::try_from(RESPType::Array(vec![])).is_err());
+        assert!(Message::try_from(RESPType::Integer(1)).is_err());
+    }
+}
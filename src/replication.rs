@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde_resp::{bulk, RESPType};
+use crate::engine::{Change, Event, KvsEngine};
+use crate::{KvError, KvsClient, Result};
+
+/// Delay before a follower reconnects to its leader after the link breaks.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Pairs of a full sync written per `mset`, the engine is unlocked between batches so reads go on.
+const LOAD_BATCH: usize = 1000;
+/// Pairs of a full sync sent per reply.
+pub(crate) const SYNC_CHUNK: usize = 1000;
+
+/// State of the link from a follower to its leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    /// Waiting to connect to the leader.
+    Connecting,
+    /// Connected, loading the snapshot of a full sync.
+    Syncing,
+    /// Applying mutations of the leader as they come.
+    Connected,
+}
+
+impl LinkStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkStatus::Connecting => "connect",
+            LinkStatus::Syncing => "sync",
+            LinkStatus::Connected => "connected",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "connect" => Some(LinkStatus::Connecting),
+            "sync" => Some(LinkStatus::Syncing),
+            "connected" => Some(LinkStatus::Connected),
+            _ => None,
+        }
+    }
+}
+
+/// Replication role of a server, the reply of `ROLE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// `seq` is the sequence number of the latest mutation, `followers` are the address of each
+    /// syncing follower with the sequence number of the latest mutation sent to it.
+    Leader { seq: u64, followers: Vec<(String, u64)> },
+    /// `seq` is the sequence number of the latest mutation of the leader applied, 0 before the first sync.
+    Follower { leader: String, status: LinkStatus, seq: u64 },
+}
+
+/// Serialized as `["leader", seq, [[addr, seq]...]]` or `["follower", leader, status, seq]`
+impl From<Role> for RESPType {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Leader { seq, followers } => {
+                let followers = followers.into_iter()
+                    .map(|(addr, seq)| RESPType::Array(vec![bulk!(addr), RESPType::Integer(seq as i64)]))
+                    .collect();
+                RESPType::Array(vec![bulk!("leader"), RESPType::Integer(seq as i64), RESPType::Array(followers)])
+            }
+            Role::Follower { leader, status, seq } => RESPType::Array(vec![
+                bulk!("follower"),
+                bulk!(leader),
+                bulk!(status.as_str()),
+                RESPType::Integer(seq as i64),
+            ]),
+        }
+    }
+}
+
+impl TryFrom<RESPType> for Role {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed role".to_owned());
+        let string = |field: RESPType| match field {
+            RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+            _ => Err(malformed()),
+        };
+        let integer = |field: RESPType| match field {
+            RESPType::Integer(n) if n >= 0 => Ok(n as u64),
+            _ => Err(malformed()),
+        };
+        let fields = match value {
+            RESPType::Array(fields) => fields,
+            _ => return Err(malformed()),
+        };
+        let mut fields = fields.into_iter();
+        match (fields.next().map(string).transpose()?.as_deref(), fields.len()) {
+            (Some("leader"), 2) => {
+                let seq = integer(fields.next().ok_or_else(malformed)?)?;
+                let followers = match fields.next() {
+                    Some(RESPType::Array(followers)) => followers.into_iter()
+                        .map(|follower| match follower {
+                            RESPType::Array(pair) if pair.len() == 2 => {
+                                let mut pair = pair.into_iter();
+                                Ok((string(pair.next().ok_or_else(malformed)?)?, integer(pair.next().ok_or_else(malformed)?)?))
+                            }
+                            _ => Err(malformed()),
+                        })
+                        .collect::<Result<Vec<_>>>()?,
+                    _ => return Err(malformed()),
+                };
+                Ok(Role::Leader { seq, followers })
+            }
+            (Some("follower"), 3) => {
+                let leader = string(fields.next().ok_or_else(malformed)?)?;
+                let status = LinkStatus::parse(&string(fields.next().ok_or_else(malformed)?)?).ok_or_else(malformed)?;
+                let seq = integer(fields.next().ok_or_else(malformed)?)?;
+                Ok(Role::Follower { leader, status, seq })
+            }
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// The first reply of `SYNC`, followed by the changes after `seq` or `since` in the format of `CHANGES`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStart {
+    /// Every live pair as of the mutation `seq` follows in chunks, see `chunk_to_resp`.
+    /// The follower replaces its data with them.
+    FullSync { seq: u64 },
+    /// The leader still retains the mutations after `since`, the follower keeps its data.
+    Continue { since: u64 },
+}
+
+/// Serialized as `["fullsync", seq]` or `["continue", since]`
+impl From<SyncStart> for RESPType {
+    fn from(start: SyncStart) -> Self {
+        let (kind, seq) = match start {
+            SyncStart::FullSync { seq } => ("fullsync", seq),
+            SyncStart::Continue { since } => ("continue", since),
+        };
+        RESPType::Array(vec![bulk!(kind), RESPType::Integer(seq as i64)])
+    }
+}
+
+impl TryFrom<RESPType> for SyncStart {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed sync reply".to_owned());
+        let fields = match value {
+            RESPType::Array(fields) => fields,
+            _ => return Err(malformed()),
+        };
+        let mut fields = fields.into_iter();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(RESPType::BulkString(kind)), Some(RESPType::Integer(seq)), None) if seq >= 0 => match kind.as_slice() {
+                b"fullsync" => Ok(SyncStart::FullSync { seq: seq as u64 }),
+                b"continue" => Ok(SyncStart::Continue { since: seq as u64 }),
+                _ => Err(malformed()),
+            },
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// Serialize a chunk of the pairs of a full sync as `[key, value, key, value...]`,
+/// an empty chunk ends the pairs.
+pub(crate) fn chunk_to_resp(pairs: Vec<(String, String)>) -> RESPType {
+    RESPType::Array(pairs.into_iter().flat_map(|(key, value)| [bulk!(key), bulk!(value)]).collect())
+}
+
+pub(crate) fn chunk_from_resp(value: RESPType) -> Result<Vec<(String, String)>> {
+    let malformed = || KvError::Message("malformed sync chunk".to_owned());
+    let items = match value {
+        RESPType::Array(items) if items.len() % 2 == 0 => items,
+        _ => return Err(malformed()),
+    };
+    let mut items = items.into_iter().map(|item| match item {
+        RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+        _ => Err(malformed()),
+    });
+    let mut pairs = vec![];
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        pairs.push((key?, value?));
+    }
+    Ok(pairs)
+}
+
+/// The leader a follower replicates from.
+struct LeaderLink {
+    addr: String,
+    status: LinkStatus,
+    /// `None` until the first full sync is loaded, restored from the saved one after a restart.
+    seq: Option<u64>,
+}
+
+/// Replication state of a server, shared by its connections.
+#[derive(Default)]
+pub struct Replication {
+    /// Set on a follower.
+    leader: Mutex<Option<LeaderLink>>,
+    /// Followers syncing from this server by id, with their address and the latest sequence number sent.
+    followers: Mutex<BTreeMap<u64, (String, u64)>>,
+    next_follower_id: AtomicU64,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Followers reject writes of clients.
+    pub fn is_follower(&self) -> bool {
+        self.leader().is_some()
+    }
+
+    /// `seq` is the latest sequence number of the local engine, reported when this server is a leader.
+    pub fn role(&self, seq: u64) -> Role {
+        match &*self.leader() {
+            Some(link) => Role::Follower { leader: link.addr.clone(), status: link.status, seq: link.seq.unwrap_or(0) },
+            None => Role::Leader { seq, followers: self.followers().values().cloned().collect() },
+        }
+    }
+
+    pub(crate) fn add_follower(&self, addr: &str, seq: u64) -> u64 {
+        let id = self.next_follower_id.fetch_add(1, Ordering::Relaxed);
+        self.followers().insert(id, (addr.to_owned(), seq));
+        id
+    }
+
+    pub(crate) fn update_follower(&self, id: u64, seq: u64) {
+        if let Some((_, sent)) = self.followers().get_mut(&id) {
+            *sent = seq;
+        }
+    }
+
+    pub(crate) fn remove_follower(&self, id: u64) {
+        self.followers().remove(&id);
+    }
+
+    /// Replicate `engine` from the leader at `addr` in a new thread until the process exits.
+    /// The follower starts with a full sync and resumes from the latest applied mutation after
+    /// the link breaks, or syncs fully again if the leader no longer retains it.
+    ///
+    /// The sequence number of the latest applied mutation is saved to `path`, so that a restarted
+    /// follower resumes as well. It is removed while a full sync replaces the data.
+    pub(crate) fn follow<E>(self: &Arc<Self>, engine: Arc<Mutex<E>>, addr: &str, password: Option<&str>, path: &Path) -> Result<JoinHandle<()>>
+    where
+        E: KvsEngine + Send + 'static,
+    {
+        let seq = match fs::read_to_string(path) {
+            Ok(seq) => Some(seq.trim().parse().map_err(|_| KvError::Message(format!("invalid sequence number in {}", path.display())))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        *self.leader() = Some(LeaderLink { addr: addr.to_owned(), status: LinkStatus::Connecting, seq });
+        let replication = self.clone();
+        let (addr, password, path) = (addr.to_owned(), password.map(str::to_owned), path.to_owned());
+        Ok(thread::spawn(move || loop {
+            if let Err(err) = replication.sync(&engine, &addr, password.as_deref(), &path) {
+                log::error!("Replication from {} is broken: {}", addr, err);
+            }
+            replication.set_link(LinkStatus::Connecting, None);
+            thread::sleep(RECONNECT_DELAY);
+        }))
+    }
+
+    /// Sync from the leader and apply its mutations until the link breaks.
+    fn sync<E: KvsEngine>(&self, engine: &Mutex<E>, addr: &str, password: Option<&str>, path: &Path) -> Result<()> {
+        let client = match password {
+            Some(password) => KvsClient::connect_with_auth(addr, password)?,
+            None => KvsClient::connect(addr)?,
+        };
+        let since = self.leader().as_ref().and_then(|link| link.seq);
+        self.set_link(LinkStatus::Syncing, None);
+        let (start, mut changes) = client.sync(since)?;
+        match start {
+            SyncStart::FullSync { seq } => {
+                // a full sync broken halfway has to start over
+                if let Some(link) = &mut *self.leader() {
+                    link.seq = None;
+                }
+                save_seq(path, None)?;
+                let chunks = iter::from_fn(|| changes.next_chunk().transpose());
+                let loaded = load_chunks(engine, chunks)?;
+                log::info!("Full sync of {} keys from {} as of {}", loaded, addr, seq);
+                self.applied(LinkStatus::Connected, seq, path)?;
+            }
+            SyncStart::Continue { since } => {
+                log::info!("Resume replication from {} after {}", addr, since);
+                self.set_link(LinkStatus::Connected, Some(since));
+            }
+        }
+        // mutations of an `mset` are applied together, like the leader wrote them
+        let mut entry: Vec<Change> = vec![];
+        for change in changes {
+            let change = change?;
+            let done = change.remaining == 0;
+            entry.push(change);
+            if done {
+                let seq = apply(&mut *lock(engine), mem::take(&mut entry))?;
+                self.applied(LinkStatus::Connected, seq, path)?;
+            }
+        }
+        Err(KvError::Message("connection to leader is closed".to_owned()))
+    }
+
+    /// Record that mutations up to `seq` are applied, in memory and in `path`.
+    fn applied(&self, status: LinkStatus, seq: u64, path: &Path) -> Result<()> {
+        save_seq(path, Some(seq))?;
+        self.set_link(status, Some(seq));
+        Ok(())
+    }
+
+    /// Update the link of a follower, keeping the sequence number if `seq` is `None`.
+    fn set_link(&self, status: LinkStatus, seq: Option<u64>) {
+        if let Some(link) = &mut *self.leader() {
+            link.status = status;
+            link.seq = seq.or(link.seq);
+        }
+    }
+
+    fn leader(&self) -> MutexGuard<'_, Option<LeaderLink>> {
+        self.leader.lock().expect("replication lock is poisoned")
+    }
+
+    fn followers(&self) -> MutexGuard<'_, BTreeMap<u64, (String, u64)>> {
+        self.followers.lock().expect("replication lock is poisoned")
+    }
+}
+
+/// Replace the data of `engine` with the pairs of `chunks`, return the number of pairs loaded.
+/// Only the keys of `engine` are kept in memory, to remove the ones which are not loaded.
+pub(crate) fn load_chunks<E, I>(engine: &Mutex<E>, chunks: I) -> Result<usize>
+where
+    E: KvsEngine,
+    I: Iterator<Item = Result<Vec<(String, String)>>>,
+{
+    let mut stale = lock(engine).scan("")?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<HashSet<String>>>()?;
+    let mut loaded = 0;
+    for chunk in chunks {
+        let chunk = chunk?;
+        for (key, _) in &chunk {
+            stale.remove(key);
+        }
+        for batch in chunk.chunks(LOAD_BATCH) {
+            lock(engine).mset(batch)?;
+        }
+        loaded += chunk.len();
+    }
+    let stale: Vec<String> = stale.into_iter().collect();
+    for batch in stale.chunks(LOAD_BATCH) {
+        lock(engine).mdel(batch)?;
+    }
+    Ok(loaded)
+}
+
+/// Apply the mutations of one atomic write of the leader, return the sequence number of the last one.
+fn apply<E: KvsEngine>(engine: &mut E, entry: Vec<Change>) -> Result<u64> {
+    let seq = entry.last().map_or(0, |change| change.seq);
+    let mut pairs = vec![];
+    for change in entry {
+        match change.event {
+            Event::Set { key, value } => pairs.push((key, value)),
+            // only `mset` writes several mutations atomically, removals come alone
+            Event::Remove { key } => {
+                engine.remove(&key)?;
+            }
+        }
+    }
+    match pairs.as_slice() {
+        [] => {}
+        [(key, value)] => engine.set(key, value)?,
+        pairs => engine.mset(pairs)?,
+    }
+    Ok(seq)
+}
+
+/// Save `seq` to `path`, or remove the file if it is `None`.
+/// The file is replaced by a rename, so it is never read half written.
+fn save_seq(path: &Path, seq: Option<u64>) -> Result<()> {
+    match seq {
+        Some(seq) => {
+            let mut temp_path = PathBuf::from(path);
+            temp_path.set_extension("tmp");
+            fs::write(&temp_path, seq.to_string())?;
+            fs::rename(&temp_path, path)?;
+        }
+        None => match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+fn lock<E>(engine: &Mutex<E>) -> MutexGuard<'_, E> {
+    engine.lock().expect("engine lock is poisoned")
+}
+
+#[cfg(test)]
+mod replication_tests {
+    use std::sync::{Arc, Mutex};
+    use serde_resp::RESPType;
+    use tempfile::TempDir;
+    use crate::engine::{Change, Event, KvsEngine};
+    use crate::{KvStore, Result};
+    use super::{apply, chunk_from_resp, chunk_to_resp, load_chunks, LinkStatus, Role, SyncStart};
+
+    // Should convert roles and sync replies to RESP and back
+    #[test]
+    fn replies_roundtrip() -> Result<()> {
+        let leader = Role::Leader { seq: 7, followers: vec![("127.0.0.1:5000".to_owned(), 6)] };
+        let follower = Role::Follower { leader: "127.0.0.1:4000".to_owned(), status: LinkStatus::Syncing, seq: 0 };
+        for role in [leader, follower] {
+            let resp: RESPType = role.clone().into();
+            assert_eq!(Role::try_from(resp)?, role);
+        }
+        for start in [SyncStart::FullSync { seq: 3 }, SyncStart::Continue { since: 3 }] {
+            let resp: RESPType = start.clone().into();
+            assert_eq!(SyncStart::try_from(resp)?, start);
+        }
+        let chunk = vec![("key".to_owned(), "value".to_owned())];
+        assert_eq!(chunk_from_resp(chunk_to_resp(chunk.clone()))?, chunk);
+        let change = Change { seq: 5, event: Event::Remove { key: "key".to_owned() }, remaining: 2 };
+        let resp: RESPType = change.clone().into();
+        assert_eq!(Change::try_from(resp)?, change);
+        assert!(Role::try_from(RESPType::Array(vec![])).is_err());
+        Ok(())
+    }
+
+    // Should replace the data with the chunks of a snapshot, removing keys they do not have
+    #[test]
+    fn load_snapshot() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = Arc::new(Mutex::new(KvStore::open(temp_dir.path())?));
+        engine.lock().unwrap().mset(&[("stale".to_owned(), "1".to_owned()), ("kept".to_owned(), "2".to_owned())])?;
+        let chunks = vec![Ok(vec![("kept".to_owned(), "3".to_owned())]), Ok(vec![("new".to_owned(), "4".to_owned())])];
+        assert_eq!(load_chunks(&engine, chunks.into_iter())?, 2);
+        let mut engine = engine.lock().unwrap();
+        assert_eq!(engine.get("stale")?, None);
+        assert_eq!(engine.get("kept")?, Some("3".to_owned()));
+        assert_eq!(engine.get("new")?, Some("4".to_owned()));
+        Ok(())
+    }
+
+    // Should apply the mutations of an entry with one write and return the last sequence number
+    #[test]
+    fn apply_entry() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let mut engine = KvStore::open(temp_dir.path())?;
+        let set = |seq, key: &str, remaining| Change {
+            seq,
+            event: Event::Set { key: key.to_owned(), value: seq.to_string() },
+            remaining,
+        };
+        assert_eq!(apply(&mut engine, vec![set(7, "key1", 1), set(8, "key2", 0)])?, 8);
+        assert_eq!(apply(&mut engine, vec![Change { seq: 9, event: Event::Remove { key: "key1".to_owned() }, remaining: 0 }])?, 9);
+        let changes: Vec<Change> = engine.changes(0)?.take(3).collect::<Result<_>>()?;
+        assert_eq!(changes.iter().map(|change| change.remaining).collect::<Vec<_>>(), vec![1, 0, 0]);
+        assert_eq!(engine.get("key2")?, Some("8".to_owned()));
+        assert_eq!(engine.get("key1")?, None);
+        Ok(())
+    }
+}
@@ -1,68 +1,825 @@
-use std::io::Write;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use serde_resp::RESPType;
-use crate::{GetResponse, KvError, RemoveResponse, SetResponse, tools};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use rustls::{ServerConfig, ServerConnection};
+use serde_resp::{bulk, err, none, simple, RESPType};
+use crate::{AclListResponse, ClusterSlotsResponse, DbSizeResponse, GetResponse, InfoResponse, MDelResponse, MGetResponse, RemoveResponse, Request, ScanResponse, SetResponse, SlowLogResponse};
+use crate::acl::{Acl, DEFAULT_USER};
+use crate::cluster::{self, Cluster};
 use crate::engine::KvsEngine;
-use crate::Result;
+use crate::metrics::{self, Counted, Metrics};
+use crate::pubsub::PubSub;
+use crate::replication::{self, Replication, Role, SyncStart, SYNC_CHUNK};
+use crate::slowlog::SlowLog;
+use crate::gateway::{self, Gateway};
+use crate::{tls, KvError, Result};
+
+/// A client connection that can be read and written from different threads,
+/// so messages can be pushed to subscribers while their requests are read.
+pub trait SplitStream: Send + 'static {
+    type Reader: Read + Send + 'static;
+    type Writer: Write + Send + 'static;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+impl SplitStream for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+
+    fn split(self) -> io::Result<(TcpStream, TcpStream)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
+#[cfg(unix)]
+impl SplitStream for UnixStream {
+    type Reader = UnixStream;
+    type Writer = UnixStream;
+
+    fn split(self) -> io::Result<(UnixStream, UnixStream)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
+/// A listener the server accepts client connections from.
+pub trait Listener {
+    type Stream: SplitStream;
+
+    /// Wait for a new connection, return it with a description of the client for logs.
+    fn accept_client(&self) -> io::Result<(Self::Stream, String)>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_client(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, addr) = self.accept()?;
+        Ok((stream, addr.to_string()))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    /// Clients of a unix socket have no address, they are described by the socket path.
+    fn accept_client(&self) -> io::Result<(UnixStream, String)> {
+        let (stream, _) = self.accept()?;
+        let client = self.local_addr()?
+            .as_pathname()
+            .map_or_else(|| "unix".to_owned(), |path| format!("unix:{}", path.display()));
+        Ok((stream, client))
+    }
+}
+
+/// Bind a unix socket at `path`, replacing a stale socket file left by a previous run.
+/// `mode` sets the permission bits of the socket file, e.g. `0o770`.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
 
 pub struct KvsServer<E: KvsEngine> {
-    engine: E
+    engine: Arc<Mutex<E>>,
+    metrics: Arc<Metrics>,
+    slowlog: Arc<Mutex<SlowLog>>,
+    acl: Arc<RwLock<Acl>>,
+    tls: Option<Arc<ServerConfig>>,
+    pubsub: Arc<PubSub>,
+    replication: Arc<Replication>,
+    cluster: Option<Arc<Cluster>>
+}
+
+/// State of a single client connection.
+struct Connection {
+    client: String,
+    /// The authenticated user, `None` before a successful `AUTH`.
+    user: Option<String>,
+    writer: Arc<Mutex<dyn Write + Send>>,
+    /// Set while the connection has subscriptions.
+    push: Option<PushState>,
+    /// Set by `ASKING` for the next command.
+    asking: bool
+}
+
+/// Subscriptions of a connection in push mode. Replies and messages are queued to the outbox
+/// and written in order by a forwarding thread.
+struct PushState {
+    id: u64,
+    outbox: Sender<RESPType>,
+    forwarder: JoinHandle<()>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    prefixes: BTreeSet<String>
+}
+
+impl PushState {
+    /// `[kind, name, number of subscriptions]`, `name` is nil when there was nothing to unsubscribe.
+    fn confirmation(&self, kind: &str, name: Option<&str>) -> RESPType {
+        let count = (self.channels.len() + self.patterns.len() + self.prefixes.len()) as i64;
+        let name = name.map_or_else(|| none!(), |name| bulk!(name));
+        RESPType::Array(vec![bulk!(kind), name, RESPType::Integer(count)])
+    }
+
+    fn send(&self, rsp: RESPType) -> Result<()> {
+        self.outbox.send(rsp).map_err(|_| KvError::Message("connection is closed".to_owned()))
+    }
+}
+
+impl Connection {
+    fn reply(&self, rsp: RESPType) -> Result<()> {
+        match &self.push {
+            Some(push) => push.send(rsp),
+            None => write_resp(&self.writer, &rsp)
+        }
+    }
+}
+
+fn write_resp(writer: &Mutex<dyn Write + Send>, rsp: &RESPType) -> Result<()> {
+    let mut guard = writer.lock().expect("writer lock is poisoned");
+    let mut writer: &mut (dyn Write + Send) = &mut *guard;
+    serde_resp::to_writer(rsp, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+impl<E: KvsEngine> Clone for KvsServer<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            metrics: self.metrics.clone(),
+            slowlog: self.slowlog.clone(),
+            acl: self.acl.clone(),
+            tls: self.tls.clone(),
+            pubsub: self.pubsub.clone(),
+            replication: self.replication.clone(),
+            cluster: self.cluster.clone()
+        }
+    }
 }
 
 impl<E: KvsEngine> KvsServer<E> {
-    pub fn new(engine: E) -> Result<Self> {
+    pub fn new(mut engine: E) -> Result<Self> {
+        let pubsub = Arc::new(PubSub::new());
+        // changes of all keys are routed to the connections that watch them
+        let events = engine.watch_prefix("")?;
+        let notifier = pubsub.clone();
+        thread::spawn(move || {
+            for event in events {
+                notifier.notify(&event);
+            }
+        });
         Ok(Self {
-            engine
+            engine: Arc::new(Mutex::new(engine)),
+            metrics: Arc::new(Metrics::new()),
+            slowlog: Arc::new(Mutex::new(SlowLog::default())),
+            acl: Arc::new(RwLock::new(Acl::new())),
+            tls: None,
+            pubsub,
+            replication: Arc::new(Replication::new()),
+            cluster: None
         })
     }
 
-    pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(&addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    if let Err(err) = self.serve(&mut stream) {
-                        log::error!("Error on serving client: {}", err);
-                        self.handle_err(err, &mut stream);
-                    }
+    /// Accept TCP connections and serve each of them in a new thread.
+    pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()>
+    where
+        E: Send + 'static
+    {
+        self.run_listener(TcpListener::bind(&addr)?)
+    }
+
+    /// Accept connections on a unix socket and serve each of them in a new thread,
+    /// see `bind_unix` for `mode`.
+    #[cfg(unix)]
+    pub fn run_unix(&mut self, path: &Path, mode: Option<u32>) -> Result<()>
+    where
+        E: Send + 'static
+    {
+        self.run_listener(bind_unix(path, mode)?)
+    }
+
+    /// Accept connections of any listener and serve each of them in a new thread.
+    pub fn run_listener<L: Listener>(&mut self, listener: L) -> Result<()>
+    where
+        E: Send + 'static
+    {
+        loop {
+            match listener.accept_client() {
+                Ok((stream, client)) => {
+                    let server = self.clone();
+                    thread::spawn(move || server.serve(stream, client));
                 }
                 Err(err) => log::error!("Connection failed: {}", err)
             }
         }
+    }
+
+    /// Serve requests of a connection one by one until the client closes it.
+    /// The TLS handshake, if enabled, happens on the first read.
+    pub fn serve<S: SplitStream>(&self, stream: S, client: String) {
+        self.metrics.connection_opened();
+        let result = stream.split().map_err(Into::into).and_then(|(reader, writer)| match &self.tls {
+            Some(config) => {
+                let (reader, writer) = tls::split(ServerConnection::new(config.clone())?, reader, writer);
+                self.serve_connection(reader, writer, &client)
+            }
+            None => self.serve_connection(reader, writer, &client)
+        });
+        if let Err(err) = result {
+            log::error!("Error on serving client {}: {}", client, err);
+            self.metrics.record_error(err.kind());
+        }
+        self.metrics.connection_closed();
+    }
+
+    fn serve_connection<R: Read, W: Write + Send + 'static>(&self, reader: R, writer: W, client: &str) -> Result<()> {
+        let mut conn = Connection {
+            client: client.to_owned(),
+            user: self.acl().default_user_open().then(|| DEFAULT_USER.to_owned()),
+            writer: Arc::new(Mutex::new(Counted::new(writer, self.metrics.clone()))),
+            push: None,
+            asking: false
+        };
+        let result = self.serve_requests(reader, &mut conn);
+        self.leave_push(&mut conn);
+        result
+    }
+
+    fn serve_requests<R: Read>(&self, reader: R, conn: &mut Connection) -> Result<()> {
+        let mut stream = BufReader::new(Counted::new(reader, self.metrics.clone()));
+        loop {
+            match stream.fill_buf() {
+                Ok([]) => break,
+                Ok(_) => {}
+                // TLS clients may close the socket without sending close_notify
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into())
+            }
+            let command: RESPType = serde_resp::from_reader(&mut stream)?;
+            let rsp = match self.handle(command, conn) {
+                Ok(rsp) => rsp,
+                Err(err) => {
+                    log::error!("Error on serving client {}: {}", conn.client, err);
+                    self.metrics.record_error(err.kind());
+                    Some(err!(format!("ERR {}", err)))
+                }
+            };
+            if let Some(rsp) = rsp {
+                conn.reply(rsp)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the reply of a request, `None` if pub/sub commands already queued their replies.
+    fn handle(&self, command: RESPType, conn: &mut Connection) -> Result<Option<RESPType>> {
+        let request = Request::try_from(command)?;
+        let asking = std::mem::take(&mut conn.asking);
+        if let Request::Auth { .. } | Request::AclSetUser { .. } | Request::ClusterMigrate { password: Some(_), .. } = request {
+            log::debug!("receive command: {} from {}", request.name(), conn.client);
+        } else {
+            log::debug!("receive command: {:?}", request);
+        }
+        if !matches!(request, Request::Auth { .. } | Request::Ping { .. }) {
+            let user = match &conn.user {
+                Some(user) => user,
+                None => return Ok(Some(err!("NOAUTH Authentication required.")))
+            };
+            if let Err(denied) = self.acl().check(user, &request) {
+                log::warn!("Denied {} from user {} at {}", request.name(), user, conn.client);
+                return Ok(Some(err!(denied.to_string())));
+            }
+        }
+        if request.is_write() && self.replication.is_follower() {
+            return Ok(Some(err!("READONLY You can't write against a read only replica.")));
+        }
+        // keys can not be migrated away until the command is served
+        let _serving = match &self.cluster {
+            Some(cluster) if !request.keys().is_empty() => {
+                let serving = cluster.serving(request.keys()[0]);
+                let route = cluster.route(&request.keys(), asking, |key| Ok(self.engine().get(key)?.is_some()))?;
+                if let Some(redirect) = route.error() {
+                    return Ok(Some(err!(redirect)));
+                }
+                Some(serving)
+            }
+            _ => None
+        };
+        let subscription = matches!(
+            request,
+            Request::Subscribe { .. } | Request::Unsubscribe { .. } | Request::PSubscribe { .. } | Request::PUnsubscribe { .. }
+                | Request::Watch { .. } | Request::Unwatch { .. }
+        );
+        if conn.push.is_some() && !subscription && !matches!(request, Request::Ping { .. }) {
+            return Ok(Some(err!("ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / (UN)WATCH / PING are allowed in push mode")));
+        }
+        if let Request::Changes { since } = request {
+            self.stream_changes(since, conn)?;
+            return Ok(None);
+        }
+        if let Request::Sync { since } = request {
+            self.sync_follower(since, conn)?;
+            return Ok(None);
+        }
+        let started = Instant::now();
+        let rsp = if subscription {
+            self.update_subscriptions(&request, conn)?;
+            None
+        } else {
+            Some(self.dispatch(&request, conn)?)
+        };
+        let elapsed = started.elapsed();
+        let name = request.name();
+        self.metrics.record_command(name, elapsed);
+        self.slowlog().record(elapsed, || request_args(request), &conn.client);
+        Ok(rsp)
+    }
+
+    fn dispatch(&self, request: &Request, conn: &mut Connection) -> Result<RESPType> {
+        Ok(match request {
+            Request::Get { key } => GetResponse::Ok(self.engine().get(key)?).into(),
+            Request::Set { key, value } => SetResponse::Ok(self.engine().set(key, value)?).into(),
+            Request::Remove { key } => RemoveResponse::Ok(self.engine().remove(key)?).into(),
+            Request::MGet { keys } => MGetResponse::Ok(self.engine().mget(keys)?).into(),
+            Request::MSet { pairs } => SetResponse::Ok(self.engine().mset(pairs)?).into(),
+            Request::MDel { keys } => MDelResponse::Ok(self.engine().mdel(keys)?).into(),
+            Request::Backup { dir } => {
+                log::info!("backup to {}", dir);
+                SetResponse::Ok(self.engine().checkpoint(Path::new(dir))?).into()
+            }
+            Request::Info { section } => InfoResponse::Ok(self.info(section.as_deref())?).into(),
+            Request::DbSize => DbSizeResponse::Ok(self.engine().stats()?.keys).into(),
+            Request::SlowLogGet { count } => SlowLogResponse::Ok(self.slowlog().get(*count)).into(),
+            Request::SlowLogLen => DbSizeResponse::Ok(self.slowlog().len() as u64).into(),
+            Request::SlowLogReset => {
+                self.slowlog().reset();
+                SetResponse::Ok(()).into()
+            }
+            Request::Auth { username, password } => self.auth(username.as_deref(), password, conn),
+            Request::Ping { message: Some(message) } => bulk!(message.as_str()),
+            Request::Ping { message: None } => simple!("PONG"),
+            Request::AclSetUser { username, rules } => SetResponse::Ok(self.acl_mut().set_user(username, rules.as_slice())?).into(),
+            Request::AclDelUser { usernames } => MDelResponse::Ok(self.acl_mut().del_user(usernames.as_slice())?).into(),
+            Request::AclList => AclListResponse::Ok(self.acl().list()).into(),
+            Request::AclWhoAmI => GetResponse::Ok(conn.user.clone()).into(),
+            Request::Publish { channel, message } => DbSizeResponse::Ok(self.pubsub.publish(channel, message) as u64).into(),
+            Request::Subscribe { .. } | Request::Unsubscribe { .. } | Request::PSubscribe { .. } | Request::PUnsubscribe { .. }
+                | Request::Watch { .. } | Request::Unwatch { .. } => {
+                unreachable!("subscriptions are updated by update_subscriptions")
+            }
+            Request::Scan { prefix, after, count } => {
+                let user = conn.user.as_deref().unwrap_or_default();
+                let acl = self.acl();
+                let mut engine = self.engine();
+                let pairs = engine.scan(prefix)?.filter(|pair| pair.as_ref().map_or(true, |(key, _)| {
+                    after.as_ref().is_none_or(|after| key > after) && acl.allows_key(user, key)
+                }));
+                // the scan order depends on the engine
+                let pairs = match count {
+                    Some(count) => first_pairs(pairs, *count)?,
+                    None => {
+                        let mut pairs = pairs.collect::<Result<Vec<_>>>()?;
+                        pairs.sort();
+                        pairs
+                    }
+                };
+                ScanResponse::Ok(pairs).into()
+            }
+            Request::Role => {
+                let seq = self.engine().seq()?;
+                self.replication.role(seq).into()
+            }
+            Request::ClusterSlots => ClusterSlotsResponse::Ok(self.cluster()?.slots()).into(),
+            Request::ClusterNodes => InfoResponse::Ok(self.cluster()?.nodes()).into(),
+            Request::ClusterKeySlot { key } => DbSizeResponse::Ok(cluster::key_slot(key) as u64).into(),
+            Request::ClusterSetSlot { start, end, state } => {
+                log::info!("set slots {}-{} to {:?}", start, end, state);
+                SetResponse::Ok(self.cluster()?.set_slots(*start, *end, state)?).into()
+            }
+            Request::ClusterCountKeysInSlot { slot } => DbSizeResponse::Ok(self.cluster()?.count_keys(&self.engine, *slot)?).into(),
+            Request::ClusterMigrate { slot, count, password } => {
+                let moved = self.cluster()?.migrate(&self.engine, *slot, *count, password.as_deref())?;
+                DbSizeResponse::Ok(moved as u64).into()
+            }
+            Request::Asking => {
+                conn.asking = true;
+                simple!("OK")
+            }
+            Request::Changes { .. } => unreachable!("changes are streamed by stream_changes"),
+            Request::Sync { .. } => unreachable!("followers are synced by sync_follower"),
+        })
+    }
+
+    /// Reply every change after `since` and keep following new ones,
+    /// until the client disconnects or the engine fails to read a change.
+    fn stream_changes(&self, since: u64, conn: &mut Connection) -> Result<()> {
+        log::info!("stream changes since {} to {}", since, conn.client);
+        let changes = self.engine().changes(since)?;
+        for change in changes {
+            conn.reply(change?.into())?;
+        }
         Ok(())
     }
 
-    pub fn serve(&mut self, mut stream: &mut TcpStream) -> Result<()> {
-        let input = tools::read_to_end(&mut stream);
-        let command: RESPType = serde_resp::from_str(&input)?;
-        let arr = if let RESPType::Array(arr) = command { arr } else { panic!("not a resp array") };
-        let cmd = tools::unwrap_bulk_str(&arr[0]);
-        match cmd.as_str() {
-            "get" => {
-                let key = tools::unwrap_bulk_str(&arr[1]);
-                log::debug!("receive command: get {}", key);
-                let rsp: RESPType = GetResponse::Ok(self.engine.get(&key)?).into();
-                serde_resp::to_writer(&rsp, &mut stream)?;
-            },
-            "set" => {
-                let key = tools::unwrap_bulk_str(&arr[1]);
-                let value = tools::unwrap_bulk_str(&arr[2]);
-                log::debug!("receive command: set {} {}", key, value);
-                let rsp: RESPType = SetResponse::Ok(self.engine.set(&key, &value)?).into();
-                serde_resp::to_writer(&rsp, &mut stream)?;
-            },
-            "rm" => {
-                let key = tools::unwrap_bulk_str(&arr[1]);
-                log::debug!("receive command: rm {}", key);
-                let rsp: RESPType = RemoveResponse::Ok(self.engine.remove(&key)?).into();
-                serde_resp::to_writer(&rsp, &mut stream)?;
+    /// Start a follower with a full sync, or resume it after `since` if the mutations after it are
+    /// retained, then stream new mutations like `stream_changes`.
+    ///
+    /// The pairs of a full sync are read from an engine snapshot taken together with the sequence
+    /// number, and sent in chunks after the engine is released, so writes do not wait for them.
+    fn sync_follower(&self, since: Option<u64>, conn: &mut Connection) -> Result<()> {
+        let (seq, changes, snapshot) = {
+            let mut engine = self.engine();
+            let seq = engine.seq()?;
+            // a follower ahead of the leader has data the leader lost, it has to sync fully
+            match since.filter(|since| *since <= seq).map(|since| (since, engine.changes(since))) {
+                Some((since, Ok(changes))) => (since, changes, None),
+                _ => (seq, engine.changes(seq)?, Some(engine.snapshot()?))
+            }
+        };
+        match snapshot {
+            None => {
+                log::info!("resume follower {} after {}", conn.client, seq);
+                conn.reply(SyncStart::Continue { since: seq }.into())?;
+            }
+            Some(mut pairs) => {
+                conn.reply(SyncStart::FullSync { seq }.into())?;
+                let mut sent = 0;
+                loop {
+                    let chunk = pairs.by_ref().take(SYNC_CHUNK).collect::<Result<Vec<_>>>()?;
+                    let done = chunk.is_empty();
+                    sent += chunk.len();
+                    conn.reply(replication::chunk_to_resp(chunk))?;
+                    if done {
+                        break;
+                    }
+                }
+                log::info!("full sync of {} keys to follower {}", sent, conn.client);
+            }
+        }
+        let id = self.replication.add_follower(&conn.client, seq);
+        let result = changes.into_iter().try_for_each(|change| {
+            let change = change?;
+            let seq = change.seq;
+            conn.reply(change.into())?;
+            self.replication.update_follower(id, seq);
+            Ok(())
+        });
+        self.replication.remove_follower(id);
+        result
+    }
+
+    /// Subscribe or unsubscribe channels, patterns or watched prefixes,
+    /// the connection is in push mode while it has subscriptions.
+    fn update_subscriptions(&self, request: &Request, conn: &mut Connection) -> Result<()> {
+        let push = conn.push.get_or_insert_with(|| self.enter_push(&conn.writer));
+        match request {
+            Request::Subscribe { channels } => for channel in channels {
+                push.channels.insert(channel.clone());
+                self.pubsub.subscribe(push.id, &push.outbox, channel, push.confirmation("subscribe", Some(channel.as_str())));
+            }
+            Request::PSubscribe { patterns } => for pattern in patterns {
+                push.patterns.insert(pattern.clone());
+                self.pubsub.psubscribe(push.id, &push.outbox, pattern, push.confirmation("psubscribe", Some(pattern.as_str())));
+            }
+            Request::Unsubscribe { channels } => {
+                let channels = if channels.is_empty() { push.channels.iter().cloned().collect() } else { channels.clone() };
+                if channels.is_empty() {
+                    push.send(push.confirmation("unsubscribe", None))?;
+                }
+                for channel in channels {
+                    self.pubsub.unsubscribe(push.id, &channel);
+                    push.channels.remove(&channel);
+                    push.send(push.confirmation("unsubscribe", Some(channel.as_str())))?;
+                }
+            }
+            Request::PUnsubscribe { patterns } => {
+                let patterns = if patterns.is_empty() { push.patterns.iter().cloned().collect() } else { patterns.clone() };
+                if patterns.is_empty() {
+                    push.send(push.confirmation("punsubscribe", None))?;
+                }
+                for pattern in patterns {
+                    self.pubsub.punsubscribe(push.id, &pattern);
+                    push.patterns.remove(&pattern);
+                    push.send(push.confirmation("punsubscribe", Some(pattern.as_str())))?;
+                }
+            }
+            Request::Watch { prefixes } => for prefix in prefixes {
+                push.prefixes.insert(prefix.clone());
+                // the ACL is checked for each changed key, a prefix may cover keys the user can not read
+                let (acl, user) = (self.acl.clone(), conn.user.clone().unwrap_or_default());
+                let filter = Arc::new(move |key: &str| acl.read().expect("acl lock is poisoned").allows_key(&user, key));
+                self.pubsub.watch(push.id, &push.outbox, prefix, filter, push.confirmation("watch", Some(prefix.as_str())));
+            }
+            Request::Unwatch { prefixes } => {
+                let prefixes = if prefixes.is_empty() { push.prefixes.iter().cloned().collect() } else { prefixes.clone() };
+                if prefixes.is_empty() {
+                    push.send(push.confirmation("unwatch", None))?;
+                }
+                for prefix in prefixes {
+                    self.pubsub.unwatch(push.id, &prefix);
+                    push.prefixes.remove(&prefix);
+                    push.send(push.confirmation("unwatch", Some(prefix.as_str())))?;
+                }
             }
             _ => {}
         }
+        if push.channels.is_empty() && push.patterns.is_empty() && push.prefixes.is_empty() {
+            self.leave_push(conn);
+        }
+        Ok(())
+    }
+
+    /// Start the thread writing queued replies and messages to the client.
+    fn enter_push(&self, writer: &Arc<Mutex<dyn Write + Send>>) -> PushState {
+        let (outbox, inbox) = mpsc::channel::<RESPType>();
+        let writer = writer.clone();
+        let forwarder = thread::spawn(move || {
+            for rsp in inbox {
+                if let Err(err) = write_resp(&writer, &rsp) {
+                    log::error!("Error on pushing to client: {}", err);
+                    break;
+                }
+            }
+        });
+        PushState {
+            id: self.pubsub.new_subscriber_id(),
+            outbox,
+            forwarder,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            prefixes: BTreeSet::new()
+        }
+    }
+
+    /// Drop all subscriptions and wait until queued replies are written,
+    /// so following replies are not written ahead of them.
+    fn leave_push(&self, conn: &mut Connection) {
+        if let Some(push) = conn.push.take() {
+            for channel in &push.channels {
+                self.pubsub.unsubscribe(push.id, channel);
+            }
+            for pattern in &push.patterns {
+                self.pubsub.punsubscribe(push.id, pattern);
+            }
+            for prefix in &push.prefixes {
+                self.pubsub.unwatch(push.id, prefix);
+            }
+            drop(push.outbox);
+            let _ = push.forwarder.join();
+        }
+    }
+
+    /// Log in as `username`, or the default user if it is `None`.
+    /// Failed attempts are logged with client address.
+    fn auth(&self, username: Option<&str>, password: &str, conn: &mut Connection) -> RESPType {
+        let acl = self.acl();
+        if username.is_none() && acl.default_user_open() {
+            return err!("ERR AUTH called without any password configured");
+        }
+        let username = username.unwrap_or(DEFAULT_USER);
+        if acl.authenticate(username, password) {
+            conn.user = Some(username.to_owned());
+            simple!("OK")
+        } else {
+            log::warn!("Failed authentication of user {} from {}", username, conn.client);
+            err!("WRONGPASS invalid username-password pair or user is disabled")
+        }
+    }
+
+    /// Require clients to send `AUTH <password>` before any command other than `AUTH` and `PING`.
+    /// This sets the password of the default user.
+    pub fn set_requirepass(&mut self, password: &str) {
+        self.acl_mut()
+            .set_user(DEFAULT_USER, &["resetpass", format!(">{}", password).as_str()])
+            .expect("password rules are valid");
+    }
+
+    /// Replace users by those of an ACL file, see `Acl::load` for its format.
+    /// Call it before `set_requirepass`, which only changes the default user.
+    pub fn load_acl_file(&mut self, path: &Path) -> Result<()> {
+        *self.acl_mut() = Acl::load(path)?;
+        Ok(())
+    }
+
+    /// Accept only TLS connections, using PEM files of the certificate chain and its private key.
+    /// If `client_ca` is given, clients must present a certificate signed by one of its CAs.
+    pub fn set_tls(&mut self, cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<()> {
+        self.tls = Some(tls::server_config(cert, key, client_ca)?);
+        Ok(())
+    }
+
+    /// Record commands that take at least `threshold` into slowlog, default 10ms.
+    pub fn set_slowlog_threshold(&mut self, threshold: Duration) {
+        self.slowlog().set_threshold(threshold);
+    }
+
+    /// Keep at most `max_len` latest entries in slowlog, default 128.
+    pub fn set_slowlog_max_len(&mut self, max_len: usize) {
+        self.slowlog().set_max_len(max_len);
+    }
+
+    /// Expose prometheus metrics at `http://<addr>/metrics`, served by a background thread.
+    pub fn serve_metrics<A: ToSocketAddrs>(&self, addr: A) -> Result<JoinHandle<()>>
+    where
+        E: Send + 'static
+    {
+        metrics::serve_metrics(addr, self.metrics.clone(), self.engine.clone())
+    }
+
+    /// Serve the HTTP/JSON gateway at `http://<addr>`, or `https://` with TLS, backed by the same
+    /// engine, users and slots, see `gateway::serve_gateway` for its routes.
+    /// Call it after `set_tls` and `enable_cluster`, they are not applied to a running gateway.
+    pub fn serve_http<A: ToSocketAddrs>(&self, addr: A) -> Result<JoinHandle<()>>
+    where
+        E: Send + 'static
+    {
+        gateway::serve_gateway(addr, Gateway {
+            engine: self.engine.clone(),
+            acl: self.acl.clone(),
+            tls: self.tls.clone(),
+            replication: self.replication.clone(),
+            cluster: self.cluster.clone()
+        })
+    }
+
+    /// Replicate from the leader at `addr` in a background thread, authenticating with `password`
+    /// if given. Writes of clients are rejected with `READONLY` from now on.
+    /// The sequence number of the latest mutation applied is saved to `path`, next to the data,
+    /// so that the follower resumes after a restart instead of syncing fully.
+    pub fn replicate_from(&self, addr: &str, password: Option<&str>, path: &Path) -> Result<JoinHandle<()>>
+    where
+        E: Send + 'static
+    {
+        self.replication.follow(self.engine.clone(), addr, password, path)
+    }
+
+    /// Serve only keys of the slots assigned to this node and redirect others, announcing
+    /// `myself` as the address of this node. The slot assignment is loaded from and saved to `path`.
+    pub fn enable_cluster(&mut self, myself: &str, path: &Path) -> Result<()> {
+        self.cluster = Some(Arc::new(Cluster::open(myself, path)?));
         Ok(())
     }
 
-    pub fn handle_err(&mut self, err: KvError, stream: &mut TcpStream) {
-        stream.write_all(format!("{}", err).as_bytes()).unwrap();
+    fn cluster(&self) -> Result<&Cluster> {
+        self.cluster.as_deref().ok_or_else(|| KvError::Message("cluster support is disabled".to_owned()))
+    }
+
+    fn engine(&self) -> MutexGuard<'_, E> {
+        self.engine.lock().expect("engine lock is poisoned")
+    }
+
+    fn slowlog(&self) -> MutexGuard<'_, SlowLog> {
+        self.slowlog.lock().expect("slowlog lock is poisoned")
+    }
+
+    fn acl(&self) -> RwLockReadGuard<'_, Acl> {
+        self.acl.read().expect("acl lock is poisoned")
+    }
+
+    fn acl_mut(&self) -> RwLockWriteGuard<'_, Acl> {
+        self.acl.write().expect("acl lock is poisoned")
+    }
+
+    /// Render `INFO` sections: `server`, `clients`, `stats`, `engine`, `replication` and `cluster`.
+    /// Render all sections if `section` is `None`, nothing if it is unknown.
+    pub fn info(&self, section: Option<&str>) -> Result<String> {
+        let section = section.map(str::to_lowercase);
+        let wanted = |name: &str| section.as_deref().is_none_or(|section| section == name);
+        let mut info = String::new();
+        if wanted("server") {
+            info += "# Server\r\n";
+            let _ = write!(info, "kvs_version:{}\r\n", env!("CARGO_PKG_VERSION"));
+            let _ = write!(info, "uptime_in_seconds:{}\r\n", self.metrics.uptime().as_secs());
+        }
+        if wanted("clients") {
+            info += "# Clients\r\n";
+            let _ = write!(info, "connected_clients:{}\r\n", self.metrics.active_connections());
+            let _ = write!(info, "total_connections_received:{}\r\n", self.metrics.total_connections());
+        }
+        if wanted("stats") {
+            info += "# Stats\r\n";
+            let commands = self.metrics.command_counts();
+            let total: u64 = commands.values().sum();
+            let _ = write!(info, "total_commands_processed:{}\r\n", total);
+            let (channels, patterns) = self.pubsub.counts();
+            let _ = write!(info, "pubsub_channels:{}\r\n", channels);
+            let _ = write!(info, "pubsub_patterns:{}\r\n", patterns);
+            for (cmd, count) in &commands {
+                let _ = write!(info, "cmd_{}:{}\r\n", cmd, count);
+            }
+        }
+        if wanted("engine") {
+            let stats = self.engine().stats()?;
+            info += "# Engine\r\n";
+            let _ = write!(info, "engine:{}\r\n", stats.engine);
+            let _ = write!(info, "keys:{}\r\n", stats.keys);
+            let _ = write!(info, "disk_bytes:{}\r\n", stats.disk_bytes);
+            if let Some(uncompacted) = stats.uncompacted_bytes {
+                let _ = write!(info, "uncompacted_bytes:{}\r\n", uncompacted);
+            }
+            if let Some(log_files) = stats.log_files {
+                let _ = write!(info, "log_files:{}\r\n", log_files);
+            }
+            if let Some(compactions) = stats.compactions {
+                let _ = write!(info, "compactions:{}\r\n", compactions);
+            }
+            if let Some(compaction_time) = stats.compaction_time {
+                let _ = write!(info, "compaction_millis:{}\r\n", compaction_time.as_millis());
+            }
+        }
+        if wanted("replication") {
+            info += "# Replication\r\n";
+            match self.replication.role(self.engine().seq()?) {
+                Role::Leader { seq, followers } => {
+                    info += "role:leader\r\n";
+                    let _ = write!(info, "seq:{}\r\n", seq);
+                    let _ = write!(info, "connected_followers:{}\r\n", followers.len());
+                    for (i, (addr, seq)) in followers.iter().enumerate() {
+                        let _ = write!(info, "follower{}:addr={},seq={}\r\n", i, addr, seq);
+                    }
+                }
+                Role::Follower { leader, status, seq } => {
+                    info += "role:follower\r\n";
+                    let _ = write!(info, "leader_addr:{}\r\n", leader);
+                    let _ = write!(info, "leader_link_status:{}\r\n", status.as_str());
+                    let _ = write!(info, "leader_seq:{}\r\n", seq);
+                }
+            }
+        }
+        if wanted("cluster") {
+            info += "# Cluster\r\n";
+            let _ = write!(info, "cluster_enabled:{}\r\n", self.cluster.is_some() as u8);
+            if let Some(cluster) = &self.cluster {
+                let _ = write!(info, "cluster_myself:{}\r\n", cluster.myself());
+                let owned: u32 = cluster.slots().iter()
+                    .filter(|range| range.node == cluster.myself())
+                    .map(|range| (range.end - range.start + 1) as u32)
+                    .sum();
+                let _ = write!(info, "cluster_slots_owned:{}\r\n", owned);
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The `count` smallest pairs by key, sorted. Only `count` pairs are held at a time,
+/// so a page of a large scan does not load every pair.
+pub(crate) fn first_pairs(pairs: impl Iterator<Item = Result<(String, String)>>, count: usize) -> Result<Vec<(String, String)>> {
+    let mut first = BinaryHeap::with_capacity(count + 1);
+    for pair in pairs {
+        first.push(pair?);
+        if first.len() > count {
+            first.pop();
+        }
     }
-}
\ No newline at end of file
+    Ok(first.into_sorted_vec())
+}
+
+/// Arguments of a request as sent by client, with passwords redacted.
+fn request_args(request: Request) -> Vec<String> {
+    if let Request::Auth { .. } = request {
+        return vec!["auth".to_owned(), "(redacted)".to_owned()];
+    }
+    if let Request::AclSetUser { username, rules } = request {
+        let rules = rules.into_iter().map(|rule| match rule.chars().next() {
+            Some('>' | '<') => "(redacted)".to_owned(),
+            _ => rule
+        });
+        return ["acl".to_owned(), "setuser".to_owned(), username].into_iter().chain(rules).collect();
+    }
+    let request = match request {
+        Request::ClusterMigrate { slot, count, password: Some(_) } => {
+            Request::ClusterMigrate { slot, count, password: Some("(redacted)".to_owned()) }
+        }
+        request => request
+    };
+    let command: RESPType = request.into();
+    match command {
+        RESPType::Array(arr) => arr.iter()
+            .map(|arg| match arg {
+                RESPType::BulkString(buf) => String::from_utf8_lossy(buf).into_owned(),
+                other => format!("{:?}", other)
+            })
+            .collect(),
+        _ => vec![]
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{KvError, KvsClient, Result};
+
+/// Virtual nodes per server, enough to spread keys within a few percent of even.
+pub const DEFAULT_VNODES: usize = 160;
+/// Pairs written per `mset` and removed per `mdel` while moving keys.
+const MOVE_BATCH: usize = 1000;
+
+/// Consistent hashing of keys to nodes: each node owns `vnodes` points of a 64-bit ring, and a
+/// key belongs to the node of the first point at or after its hash, wrapping around.
+/// Adding or removing a node only moves the keys of the points it gains or loses.
+///
+/// The owner of a key depends only on the set of nodes and `vnodes`, not on the order they are
+/// added, so every client given the same nodes routes the same way.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    points: BTreeSet<(u64, String)>,
+}
+
+impl HashRing {
+    pub fn new(vnodes: usize) -> Self {
+        HashRing { vnodes, points: BTreeSet::new() }
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.points.insert((hash(format!("{}#{}", node, i).as_bytes()), node.to_owned()));
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|(_, owner)| owner != node);
+    }
+
+    /// The node owning `key`, `None` if the ring is empty.
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let point = hash(key.as_bytes());
+        self.points.range((point, String::new())..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> BTreeSet<&str> {
+        self.points.iter().map(|(_, node)| node.as_str()).collect()
+    }
+}
+
+/// FNV-1a with a final mix, stable across platforms and releases unlike `DefaultHasher`,
+/// since clients and the rebalancing tool have to agree on it.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    // FNV alone clusters similar keys like `node#1`, `node#2`
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+/// A client of several independent servers, each storing the keys a `HashRing` of their
+/// addresses assigns to it.
+///
+/// Single-key commands go to the owner of the key. Multi-key commands are split by owner and
+/// sent to each node in turn, so an `mset` is atomic on each node but not across nodes.
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    clients: BTreeMap<String, KvsClient>,
+    /// Authenticates nodes added later too.
+    password: Option<String>,
+}
+
+impl ShardedKvsClient {
+    /// Connect to every node, `nodes` are addresses like `127.0.0.1:4000`.
+    pub fn connect<S: AsRef<str>>(nodes: &[S]) -> Result<Self> {
+        Self::connect_with_vnodes(nodes, DEFAULT_VNODES)
+    }
+
+    /// Connect with `vnodes` points per node, every client of the nodes should use the same.
+    pub fn connect_with_vnodes<S: AsRef<str>>(nodes: &[S], vnodes: usize) -> Result<Self> {
+        let mut client = ShardedKvsClient { ring: HashRing::new(vnodes), clients: BTreeMap::new(), password: None };
+        for node in nodes {
+            client.connect_node(node.as_ref())?;
+            client.ring.add(node.as_ref());
+        }
+        Ok(client)
+    }
+
+    /// Authenticate the connection to every node as the default user.
+    pub fn auth(&mut self, password: &str) -> Result<()> {
+        self.password = Some(password.to_owned());
+        self.clients.values_mut().try_for_each(|client| client.auth(password))
+    }
+
+    pub fn nodes(&self) -> Vec<&str> {
+        self.clients.keys().map(String::as_str).collect()
+    }
+
+    /// The node storing `key`.
+    pub fn node_for(&self, key: &str) -> Result<&str> {
+        self.ring.node(key).ok_or_else(|| KvError::Message("no node in the cluster".to_owned()))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.client_for(key)?.set(key, value)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.client_for(key)?.get(key)
+    }
+
+    pub fn rm(&mut self, key: &str) -> Result<Option<String>> {
+        self.client_for(key)?.rm(key)
+    }
+
+    /// Set pairs with one `mset` per node.
+    pub fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<()> {
+        for (node, pairs) in self.group(pairs, |pair| pair.0)? {
+            self.clients.get_mut(&node).expect("node of the ring is connected").mset(&pairs)?;
+        }
+        Ok(())
+    }
+
+    /// Get values with one `mget` per node, in the order of `keys`.
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let indexed: Vec<(usize, &str)> = keys.iter().copied().enumerate().collect();
+        let mut values = vec![None; keys.len()];
+        for (node, indexed) in self.group(&indexed, |pair| pair.1)? {
+            let keys: Vec<&str> = indexed.iter().map(|(_, key)| *key).collect();
+            let client = self.clients.get_mut(&node).expect("node of the ring is connected");
+            for ((i, _), value) in indexed.iter().zip(client.mget(&keys)?) {
+                values[*i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Remove keys with one `mdel` per node, return the number of removed keys.
+    pub fn mdel(&mut self, keys: &[&str]) -> Result<usize> {
+        let mut removed = 0;
+        for (node, keys) in self.group(keys, |key| *key)? {
+            removed += self.clients.get_mut(&node).expect("node of the ring is connected").mdel(&keys)?;
+        }
+        Ok(removed)
+    }
+
+    /// Get every pair whose key starts with `prefix` from all nodes, sorted by key.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = vec![];
+        for client in self.clients.values_mut() {
+            pairs.extend(client.scan(prefix)?);
+        }
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    /// Get the number of keys of all nodes.
+    pub fn dbsize(&mut self) -> Result<u64> {
+        self.clients.values_mut().map(|client| client.dbsize()).sum()
+    }
+
+    /// Connect to a new node and move the keys it now owns to it, return the number of moved keys.
+    ///
+    /// Writes are not forwarded during the move. This client waits for it to finish, but other
+    /// clients keep writing to the old owners: a key written through them while or after it is
+    /// moved stays on its old node, where this client no longer reads it. Pause other writers or
+    /// run `rebalance` again once they all know the new node, see `rebalance`.
+    pub fn add_node(&mut self, node: &str) -> Result<usize> {
+        if self.clients.contains_key(node) {
+            return Ok(0);
+        }
+        self.connect_node(node)?;
+        self.ring.add(node);
+        move_keys(&mut self.clients, &self.ring)
+    }
+
+    /// Move the keys of a node to the remaining nodes and disconnect from it,
+    /// return the number of moved keys. The node is left empty unless other clients write to it,
+    /// see `add_node` about writes during the move.
+    pub fn remove_node(&mut self, node: &str) -> Result<usize> {
+        if !self.clients.contains_key(node) {
+            return Ok(0);
+        }
+        self.ring.remove(node);
+        if self.ring.nodes().is_empty() {
+            self.ring.add(node);
+            return Err(KvError::Message("can not remove the last node".to_owned()));
+        }
+        let moved = move_keys(&mut self.clients, &self.ring)?;
+        self.clients.remove(node);
+        Ok(moved)
+    }
+
+    fn connect_node(&mut self, node: &str) -> Result<()> {
+        let client = match &self.password {
+            Some(password) => KvsClient::connect_with_auth(node, password)?,
+            None => KvsClient::connect(node)?
+        };
+        self.clients.insert(node.to_owned(), client);
+        Ok(())
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut KvsClient> {
+        let node = self.node_for(key)?.to_owned();
+        Ok(self.clients.get_mut(&node).expect("node of the ring is connected"))
+    }
+
+    /// Split `items` by the node owning the key of each.
+    fn group<'a, T: Copy>(&self, items: &[T], key: impl Fn(&T) -> &'a str) -> Result<BTreeMap<String, Vec<T>>> {
+        let mut groups: BTreeMap<String, Vec<T>> = BTreeMap::new();
+        for item in items {
+            groups.entry(self.node_for(key(item))?.to_owned()).or_default().push(*item);
+        }
+        Ok(groups)
+    }
+}
+
+/// Move keys from a cluster of `from` nodes to a cluster of `to` nodes, e.g. after adding or
+/// removing a node, so every key is stored on the node a `ShardedKvsClient` of `to` looks it up.
+/// Every node is authenticated with `password` if given. Return the number of moved keys.
+///
+/// Each node is scanned a page at a time and each key is written to its new node before it is
+/// removed from the old one, so a failed move can be run again. Writes are not blocked nor
+/// forwarded, so writers should be paused during the move: a key written through the old cluster
+/// after it is copied stays on the old node, where clients of `to` do not read it until the move
+/// is run again, and a key written through the new cluster before it is copied is overwritten
+/// by the old value.
+pub fn rebalance<S: AsRef<str>>(from: &[S], to: &[S], vnodes: usize, password: Option<&str>) -> Result<usize> {
+    let mut ring = HashRing::new(vnodes);
+    to.iter().for_each(|node| ring.add(node.as_ref()));
+    if ring.nodes().is_empty() {
+        return Err(KvError::Message("no node to move keys to".to_owned()));
+    }
+    let mut clients = BTreeMap::new();
+    for node in from.iter().chain(to) {
+        if !clients.contains_key(node.as_ref()) {
+            let client = match password {
+                Some(password) => KvsClient::connect_with_auth(node.as_ref(), password)?,
+                None => KvsClient::connect(node.as_ref())?
+            };
+            clients.insert(node.as_ref().to_owned(), client);
+        }
+    }
+    move_keys(&mut clients, &ring)
+}
+
+/// Move every key not stored on its owner in `ring` to the owner, scanning each node
+/// `MOVE_BATCH` keys at a time.
+fn move_keys(clients: &mut BTreeMap<String, KvsClient>, ring: &HashRing) -> Result<usize> {
+    let nodes: Vec<String> = clients.keys().cloned().collect();
+    let mut moved = 0;
+    for node in nodes {
+        let mut node_moved = 0;
+        let mut after: Option<String> = None;
+        loop {
+            let pairs = clients.get_mut(&node).expect("node is connected").scan_page("", after.as_deref(), MOVE_BATCH)?;
+            match pairs.last() {
+                // keys removed from the node are before the next page, so it is not shifted
+                Some((key, _)) => after = Some(key.clone()),
+                None => break,
+            }
+            let mut misplaced: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+            for (key, value) in &pairs {
+                let owner = ring.node(key).expect("ring is not empty");
+                if owner != node {
+                    misplaced.entry(owner).or_default().push((key.as_str(), value.as_str()));
+                }
+            }
+            for (owner, pairs) in misplaced {
+                clients.get_mut(owner).expect("owner is connected").mset(&pairs)?;
+                let keys: Vec<&str> = pairs.iter().map(|(key, _)| *key).collect();
+                clients.get_mut(&node).expect("node is connected").mdel(&keys)?;
+                node_moved += pairs.len();
+            }
+        }
+        log::info!("moved {} keys from {}", node_moved, node);
+        moved += node_moved;
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod sharding_tests {
+    use std::collections::BTreeMap;
+    use super::{HashRing, DEFAULT_VNODES};
+
+    fn owners(ring: &HashRing) -> Vec<String> {
+        (0..10_000).map(|i| ring.node(&format!("key{}", i)).unwrap().to_owned()).collect()
+    }
+
+    // Should spread keys about evenly and route the same regardless of insertion order
+    #[test]
+    fn spread_keys() {
+        let mut ring = HashRing::new(DEFAULT_VNODES);
+        assert_eq!(ring.node("key"), None);
+        for node in ["a:1", "b:2", "c:3"] {
+            ring.add(node);
+        }
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for owner in owners(&ring) {
+            *counts.entry(owner).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 3);
+        assert!(counts.values().all(|count| (2_500..4_200).contains(count)), "{:?}", counts);
+
+        let mut reversed = HashRing::new(DEFAULT_VNODES);
+        for node in ["c:3", "b:2", "a:1"] {
+            reversed.add(node);
+        }
+        assert_eq!(owners(&ring), owners(&reversed));
+    }
+
+    // Should only move keys to a new node, and only keys of a removed node
+    #[test]
+    fn minimal_movement() {
+        let mut ring = HashRing::new(DEFAULT_VNODES);
+        for node in ["a:1", "b:2", "c:3"] {
+            ring.add(node);
+        }
+        let before = owners(&ring);
+        ring.add("d:4");
+        let after = owners(&ring);
+        let moved = before.iter().zip(&after).filter(|(old, new)| old != new).count();
+        assert!(before.iter().zip(&after).all(|(old, new)| old == new || new == "d:4"));
+        assert!((1_500..3_500).contains(&moved), "{} keys moved", moved);
+
+        ring.remove("d:4");
+        assert_eq!(owners(&ring), before);
+    }
+}
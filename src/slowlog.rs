@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_resp::{bulk, RESPType};
+use crate::{KvError, Result};
+
+const DEFAULT_SLOWLOG_THRESHOLD: Duration = Duration::from_millis(10);
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+/// Arguments kept for a single entry, the rest are replaced by a placeholder.
+const MAX_LOGGED_ARGS: usize = 32;
+
+/// A command that took longer than the slowlog threshold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    /// Unix timestamp in seconds when the command finished.
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub args: Vec<String>,
+    pub client: String,
+}
+
+/// Bounded ring of the latest slow commands, the oldest entry is dropped when full.
+pub struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    next_id: u64,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_SLOWLOG_THRESHOLD, DEFAULT_SLOWLOG_MAX_LEN)
+    }
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, max_len: usize) -> Self {
+        Self {
+            threshold,
+            max_len,
+            next_id: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: Duration) {
+        self.threshold = threshold;
+    }
+
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+        self.entries.truncate(max_len);
+    }
+
+    /// Whether a command that took `duration` should be recorded.
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        duration >= self.threshold
+    }
+
+    /// Record a slow command. `args` is only built when needed, since most commands are fast.
+    pub fn record(&mut self, duration: Duration, args: impl FnOnce() -> Vec<String>, client: &str) {
+        if !self.is_slow(duration) || self.max_len == 0 {
+            return;
+        }
+        let mut args = args();
+        if args.len() > MAX_LOGGED_ARGS {
+            let more = args.len() - MAX_LOGGED_ARGS + 1;
+            args.truncate(MAX_LOGGED_ARGS - 1);
+            args.push(format!("... ({} more arguments)", more));
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            timestamp,
+            duration,
+            args,
+            client: client.to_owned(),
+        });
+        self.next_id += 1;
+        self.entries.truncate(self.max_len);
+    }
+
+    /// The latest `count` entries, newest first. All entries if `count` is `None`.
+    pub fn get(&self, count: Option<usize>) -> Vec<SlowLogEntry> {
+        let count = count.unwrap_or(self.entries.len());
+        self.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Serialized as `[id, timestamp, duration in microseconds, [args...], client]`
+impl From<SlowLogEntry> for RESPType {
+    fn from(entry: SlowLogEntry) -> Self {
+        RESPType::Array(vec![
+            RESPType::Integer(entry.id as i64),
+            RESPType::Integer(entry.timestamp as i64),
+            RESPType::Integer(entry.duration.as_micros() as i64),
+            RESPType::Array(entry.args.into_iter().map(|arg| bulk!(arg)).collect()),
+            bulk!(entry.client),
+        ])
+    }
+}
+
+impl TryFrom<RESPType> for SlowLogEntry {
+    type Error = KvError;
+
+    fn try_from(value: RESPType) -> Result<Self> {
+        let malformed = || KvError::Message("malformed slowlog entry".to_owned());
+        let fields = match value {
+            RESPType::Array(fields) if fields.len() == 5 => fields,
+            _ => return Err(malformed()),
+        };
+        let mut fields = fields.into_iter();
+        let mut integer = || match fields.next() {
+            Some(RESPType::Integer(n)) => Ok(n as u64),
+            _ => Err(malformed()),
+        };
+        let (id, timestamp, micros) = (integer()?, integer()?, integer()?);
+        let args = match fields.next() {
+            Some(RESPType::Array(args)) => args.into_iter()
+                .map(|arg| match arg {
+                    RESPType::BulkString(buf) => Ok(String::from_utf8(buf)?),
+                    _ => Err(malformed()),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            _ => return Err(malformed()),
+        };
+        let client = match fields.next() {
+            Some(RESPType::BulkString(buf)) => String::from_utf8(buf)?,
+            _ => return Err(malformed()),
+        };
+        Ok(SlowLogEntry { id, timestamp, duration: Duration::from_micros(micros), args, client })
+    }
+}
+
+#[cfg(test)]
+mod slowlog_tests {
+    use std::time::Duration;
+    use serde_resp::RESPType;
+    use super::{SlowLog, SlowLogEntry};
+
+    // Should keep only the latest entries above threshold, newest first
+    #[test]
+    fn bounded_ring() {
+        let mut slowlog = SlowLog::new(Duration::from_millis(5), 2);
+        slowlog.record(Duration::from_millis(1), || vec!["get".to_owned()], "client");
+        for i in 0..3 {
+            slowlog.record(Duration::from_millis(10), || vec!["set".to_owned(), i.to_string()], "client");
+        }
+        assert_eq!(slowlog.len(), 2);
+        let entries = slowlog.get(None);
+        assert_eq!(entries[0].args, vec!["set", "2"]);
+        assert_eq!(entries[1].id, 1);
+        assert_eq!(slowlog.get(Some(1)).len(), 1);
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+    }
+
+    // Should deserialize the same entry from resp
+    #[test]
+    fn entry_resp_round_trip() {
+        let mut slowlog = SlowLog::new(Duration::ZERO, 10);
+        slowlog.record(Duration::from_micros(1500), || vec!["mget".to_owned(), "k".to_owned()], "127.0.0.1:5000");
+        let entry = slowlog.get(None).remove(0);
+        let resp: RESPType = entry.clone().into();
+        assert_eq!(SlowLogEntry::try_from(resp).unwrap(), entry);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+use crate::{KvError, Result};
+
+/// Bytes read from the socket at once, the size of the largest TLS record.
+const TLS_READ_LEN: usize = 16 * 1024 + 256;
+
+/// Build a server config from PEM files of the certificate chain and its private key.
+/// If `client_ca` is given, clients must present a certificate signed by one of its CAs.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(client_ca)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(builder.with_single_cert(load_certs(cert)?, load_key(key)?)?))
+}
+
+/// Build a client config trusting the CAs of the `ca` PEM file.
+/// `identity` is the certificate chain and private key to present to servers verifying clients.
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(KvError::Message(format!("no certificate found in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)
+            .map_err(|err| KvError::Message(format!("invalid CA certificate in {}: {}", path.display(), err)))?;
+    }
+    Ok(roots)
+}
+
+/// Read the first PKCS#8, PKCS#1 or SEC1 private key of a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(KvError::Message(format!("no private key found in {}", path.display())))
+}
+
+/// Split a server side TLS connection into halves usable from different threads.
+/// Both halves share the rustls state, only the reader blocks on the socket without holding it.
+pub(crate) fn split<R: Read, W: Write>(conn: ServerConnection, reader: R, writer: W) -> (TlsReader<R, W>, TlsWriter<W>) {
+    let shared = Arc::new(Mutex::new(Shared { conn, sock: writer }));
+    let reader = TlsReader {
+        shared: shared.clone(),
+        sock: reader,
+        pending: Vec::new(),
+        pending_start: 0,
+    };
+    (reader, TlsWriter { shared })
+}
+
+struct Shared<W> {
+    conn: ServerConnection,
+    sock: W,
+}
+
+impl<W: Write> Shared<W> {
+    /// Send handshake messages, alerts and encrypted data waiting in rustls.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        self.sock.flush()
+    }
+}
+
+pub(crate) struct TlsReader<R, W> {
+    shared: Arc<Mutex<Shared<W>>>,
+    sock: R,
+    /// Bytes read from the socket but not yet passed to rustls.
+    pending: Vec<u8>,
+    pending_start: usize,
+}
+
+impl<R: Read, W: Write> Read for TlsReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut shared = self.shared.lock().expect("tls lock is poisoned");
+                match shared.conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                if self.pending_start < self.pending.len() {
+                    let mut data = &self.pending[self.pending_start..];
+                    self.pending_start += shared.conn.read_tls(&mut data)?;
+                    if let Err(err) = shared.conn.process_new_packets() {
+                        // tell the peer why the connection is closed
+                        let _ = shared.write_tls();
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                    shared.write_tls()?;
+                    continue;
+                }
+            }
+            self.pending.resize(TLS_READ_LEN, 0);
+            let len = self.sock.read(&mut self.pending)?;
+            self.pending.truncate(len);
+            self.pending_start = 0;
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+pub(crate) struct TlsWriter<W> {
+    shared: Arc<Mutex<Shared<W>>>,
+}
+
+impl<W: Write> TlsWriter<W> {
+    /// Tell the peer that nothing more is sent, for protocols that end a response by closing.
+    pub fn close_notify(&mut self) -> io::Result<()> {
+        let mut shared = self.shared.lock().expect("tls lock is poisoned");
+        shared.conn.send_close_notify();
+        shared.write_tls()
+    }
+}
+
+impl<W: Write> Write for TlsWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.shared.lock().expect("tls lock is poisoned");
+        let len = shared.conn.writer().write(buf)?;
+        shared.write_tls()?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut shared = self.shared.lock().expect("tls lock is poisoned");
+        shared.conn.writer().flush()?;
+        shared.write_tls()
+    }
+}
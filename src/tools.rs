@@ -1,6 +1,7 @@
 use std::io::Read;
 use std::net::TcpStream;
 use serde_resp::RESPType;
+use crate::{KvError, Result};
 
 pub fn unwrap_bulk_str(resp: &RESPType) -> String {
     if let RESPType::BulkString(bulk_str) = resp {
@@ -21,4 +22,171 @@ pub fn read_to_end(stream: &mut TcpStream) -> String {
         }
     }
     String::from_utf8(received).unwrap()
-}
\ No newline at end of file
+}
+
+/// Compare a secret given by client with the expected one.
+/// The time taken depends only on the length of `given`, so it leaks nothing about `expected`.
+pub fn constant_time_eq(given: &[u8], expected: &[u8]) -> bool {
+    let mut diff = given.len() ^ expected.len();
+    for (i, byte) in given.iter().enumerate() {
+        let other = if expected.is_empty() { 0 } else { expected[i % expected.len()] };
+        diff |= (byte ^ other) as usize;
+    }
+    diff == 0
+}
+
+/// Match `text` against a glob `pattern`: `*` matches any sequence, `?` matches a single
+/// character and `\` escapes the next character.
+/// # Examples
+/// ```rust
+/// use kvs::tools::glob_match;
+/// assert!(glob_match("user:*", "user:1"));
+/// assert!(glob_match("news.?", "news.a"));
+/// assert!(!glob_match("news.?", "news.ab"));
+/// ```
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    // `None` stands for `*`, `Some(None)` for `?`
+    let mut tokens: Vec<Option<Option<char>>> = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' => None,
+            '?' => Some(None),
+            '\\' => Some(Some(chars.next().unwrap_or('\\'))),
+            c => Some(Some(c)),
+        });
+    }
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of last `*` in tokens and the text position it is retried from
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(None) => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(Some(c)) if c.is_none_or(|c| c == text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[p..].iter().all(Option::is_none)
+}
+
+/// Split a command line into arguments like `redis-cli` does. Arguments are separated by
+/// whitespace, `"..."` may contain whitespace and the escapes `\n`, `\r`, `\t`, `\"`, `\\`
+/// and `\xHH`, and `'...'` is taken literally except for `\'`.
+/// # Examples
+/// ```rust
+/// use kvs::tools::split_args;
+/// let args = split_args(r#"set greeting "hello\tworld" 'it''s'"#).unwrap();
+/// assert_eq!(args, vec!["set", "greeting", "hello\tworld", "its"]);
+/// ```
+pub fn split_args(line: &str) -> Result<Vec<String>> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(args);
+        }
+        // bytes, since `\xHH` escapes may encode a character in several
+        let mut arg: Vec<u8> = vec![];
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push(b'\n'),
+                            Some('r') => arg.push(b'\r'),
+                            Some('t') => arg.push(b'\t'),
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16)
+                                    .map_err(|_| KvError::Message(format!("invalid escape \\x{}", hex)))?;
+                                arg.push(byte);
+                            }
+                            Some(c) => push_char(&mut arg, c),
+                            None => return Err(unbalanced_quotes()),
+                        },
+                        Some(c) => push_char(&mut arg, c),
+                        None => return Err(unbalanced_quotes()),
+                    }
+                },
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') if chars.peek() == Some(&'\'') => push_char(&mut arg, chars.next().unwrap()),
+                        Some(c) => push_char(&mut arg, c),
+                        None => return Err(unbalanced_quotes()),
+                    }
+                },
+                c => push_char(&mut arg, c),
+            }
+        }
+        args.push(String::from_utf8(arg)?);
+    }
+}
+
+fn push_char(arg: &mut Vec<u8>, c: char) {
+    arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+fn unbalanced_quotes() -> KvError {
+    KvError::Message("unbalanced quotes".to_owned())
+}
+
+#[cfg(test)]
+mod tools_tests {
+    use super::{constant_time_eq, glob_match, split_args};
+
+    #[test]
+    fn compare_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secreT", b"secret"));
+        assert!(!constant_time_eq(b"secretsecret", b"secret"));
+        assert!(!constant_time_eq(b"sec", b"secret"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn match_globs() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn split_command_lines() {
+        assert_eq!(split_args("  get   key ").unwrap(), vec!["get", "key"]);
+        assert_eq!(split_args("").unwrap(), Vec::<String>::new());
+        assert_eq!(split_args(r#"set k "a b\n\"c\"""#).unwrap(), vec!["set", "k", "a b\n\"c\""]);
+        assert_eq!(split_args(r#"set k "\xe4\xbd\xa0" '\n\''"#).unwrap(), vec!["set", "k", "你", "\\n'"]);
+        assert_eq!(split_args(r#"set k "" ''"#).unwrap(), vec!["set", "k", "", ""]);
+        assert_eq!(split_args(r#"set pre"fix "'s'"#).unwrap(), vec!["set", "prefix s"]);
+        assert!(split_args(r#"set k "value"#).is_err());
+        assert!(split_args("set k 'value").is_err());
+        assert!(split_args(r#"set k "\xZZ""#).is_err());
+        assert!(split_args(r#"set k "\xff""#).is_err());
+    }
+}
@@ -9,6 +9,7 @@ mod cli_tests {
     use tempfile::{tempdir, tempfile};
     use kvs::Result;
     use predicates::str;
+    use predicates::prelude::*;
 
     // Should exit with nonzero
     #[test]
@@ -268,6 +269,23 @@ mod cli_tests {
             .assert()
             .stdout(str::contains("value3"));
 
+        // Test dbsize and info, key1 and key2 exist
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", port, "dbsize"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("2\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", port, "info", "engine"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(str::contains(format!("engine:{}", engine)).and(str::contains("keys:2")));
+
         Command::cargo_bin("kvs-client")
             .unwrap()
             .args(&["--port", port, "rm", "key1"])
@@ -308,4 +326,245 @@ mod cli_tests {
     fn cli_access_server_sled_engine() -> Result<()>{
         cli_access_server("sled", "6006")
     }
-}
\ No newline at end of file
+
+    // Should run typed commands on one connection and print replies like redis-cli
+    #[test]
+    fn cli_repl() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        server
+            .args(&["--port", "6007"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(3));
+        let server_handle = thread::spawn(move || {
+            let _assert = server.assert();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6007"])
+            .env("HOME", temp_dir.path())
+            .current_dir(&temp_dir)
+            .write_stdin("set greeting \"hello world\"\nGET greeting\nget missing\n\nmget greeting missing\n\
+                mdel greeting\nscan\nset 'unbalanced\nbogus\nget\nquit\ndbsize\n")
+            .assert()
+            .success()
+            .stdout(concat!(
+                "OK\n",
+                "\"hello world\"\n",
+                "(nil)\n",
+                "1) \"hello world\"\n2) (nil)\n",
+                "(integer) 1\n",
+                "(empty array)\n",
+                "(error) unbalanced quotes\n",
+                "(error) unrecognized subcommand 'bogus'\n",
+                "(error) the following required arguments were not provided: <KEY>\n",
+            ));
+        let history = fs::read_to_string(temp_dir.path().join(".kvscli_history"))?;
+        assert!(history.contains("set greeting \"hello world\"") && history.contains("quit"));
+
+        // `repl` subcommand starts the same shell
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6007", "repl"])
+            .env("HOME", temp_dir.path())
+            .current_dir(&temp_dir)
+            .write_stdin("dbsize\n")
+            .assert()
+            .success()
+            .stdout("(integer) 0\n");
+        server_handle.join().unwrap();
+        Ok(())
+    }
+
+    // Should pipeline commands of stdin or a file and report failed lines
+    #[test]
+    fn cli_pipe() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        server
+            .args(&["--port", "6008"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(4));
+        let server_handle = thread::spawn(move || {
+            let _assert = server.assert();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let mut commands: String = (0..2000).map(|i| format!("set key{i} \"value {i}\"\n")).collect();
+        commands += "# comment\n\nget\nbogus\nSET last 'one more'\n";
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6008", "--pipe"])
+            .current_dir(&temp_dir)
+            .write_stdin(commands)
+            .assert()
+            .failure()
+            .stdout("2003 commands, 2001 succeeded, 2 failed\n")
+            .stderr(str::contains("line 2003: ").and(str::contains("line 2004: unrecognized subcommand 'bogus'")));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6008", "get", "last"])
+            .current_dir(&temp_dir)
+            .assert()
+            .stdout("one more\n");
+
+        let mut file = File::create(temp_dir.path().join("commands.txt"))?;
+        file.write_all(b"rm key0\nmset a\nrm key1\n")?;
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6008", "--file", "commands.txt", "--stop-on-error"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout("2 commands, 1 succeeded, 1 failed\n")
+            .stderr("line 2: Missing arguments\n");
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6008", "dbsize"])
+            .current_dir(&temp_dir)
+            .assert()
+            .stdout("2000\n");
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6008", "--file", "commands.txt", "dbsize"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+        server_handle.join().unwrap();
+        Ok(())
+    }
+
+    // Should tell empty values from missing keys by JSON output and exit codes
+    #[test]
+    fn cli_output_formats() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        server
+            .args(&["--port", "6009"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(3));
+        let server_handle = thread::spawn(move || {
+            let _assert = server.assert();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            let mut command = Command::cargo_bin("kvs-client").unwrap();
+            command.args(&["--port", "6009"]).args(args).current_dir(&temp_dir);
+            command
+        };
+        client(&["set", "empty", ""]).assert().success();
+        client(&["set", "key", "line\n"]).assert().success();
+        client(&["--output", "json", "get", "empty"])
+            .assert()
+            .success()
+            .stdout("{\"key\":\"empty\",\"found\":true,\"value\":\"\"}\n");
+        client(&["--output", "json", "get", "missing"])
+            .assert()
+            .code(1)
+            .stdout("{\"key\":\"missing\",\"found\":false}\n");
+        client(&["-o", "json", "mget", "empty", "missing"])
+            .assert()
+            .success()
+            .stdout("[{\"key\":\"empty\",\"found\":true,\"value\":\"\"},{\"key\":\"missing\",\"found\":false}]\n");
+        client(&["-o", "json", "dbsize"]).assert().success().stdout("{\"keys\":2}\n");
+        client(&["-o", "json", "acl", "deluser", "default"])
+            .assert()
+            .code(2)
+            .stdout(str::starts_with("{\"error\":"));
+        client(&["-o", "raw", "get", "key"]).assert().success().stdout("line\n");
+        client(&["-o", "raw", "get", "empty"]).assert().success().stdout("");
+        client(&["-o", "raw", "get", "missing"]).assert().code(1).stdout("");
+        client(&["-o", "raw", "rm", "missing"]).assert().code(1);
+        client(&["-o", "json", "rm", "empty"])
+            .assert()
+            .success()
+            .stdout("{\"key\":\"empty\",\"found\":true}\n");
+        // text output keeps its exit codes
+        client(&["get", "missing"]).assert().success().stdout("\n");
+        client(&["-o", "json", "--pipe"]).assert().code(2);
+        server_handle.join().unwrap();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--port", "6009", "-o", "json", "dbsize"])
+            .current_dir(&temp_dir)
+            .assert()
+            .code(2)
+            .stdout(str::starts_with("{\"error\":"));
+        Ok(())
+    }
+
+    // Should exit with 1 for a missing key of get or rm with json and raw, 0 with text,
+    // and with 2 on errors with json and raw, 1 with text
+    #[test]
+    fn cli_exit_codes() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        server
+            .args(["--port", "6010"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(5));
+        let server_handle = thread::spawn(move || {
+            let _assert = server.assert();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |output: &str, args: &[&str]| {
+            let mut command = Command::cargo_bin("kvs-client").unwrap();
+            command.args(["--port", "6010", "--output", output]).args(args).current_dir(&temp_dir);
+            command
+        };
+        for (output, not_found, error) in [("text", 0, 1), ("json", 1, 2), ("raw", 1, 2)] {
+            client(output, &["set", "key", "value"]).assert().code(0);
+            client(output, &["get", "key"]).assert().code(0);
+            client(output, &["get", "missing"]).assert().code(not_found);
+            client(output, &["rm", "key"]).assert().code(0);
+            client(output, &["rm", "key"]).assert().code(not_found);
+            client(output, &["acl", "deluser", "default"]).assert().code(error);
+        }
+        server_handle.join().unwrap();
+        Ok(())
+    }
+
+    // Should listen to the address given by --bind, from the command line or a config file
+    #[test]
+    fn server_bind() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let output = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--bind", "0.0.0.0", "--port", "6011"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(1))
+            .output()?;
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Listening to 0.0.0.0:6011"));
+
+        fs::write(temp_dir.path().join("kvs.conf"), "bind ::1\nport 6011\n")?;
+        let output = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--config", "kvs.conf"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(1))
+            .output()?;
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Listening to [::1]:6011"));
+        Command::cargo_bin("kvs-server").unwrap().args(["--bind", "localhost"]).assert().failure();
+
+        let output = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--bind", "0.0.0.0", "--port", "6011", "--cluster"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(1))
+            .output()?;
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Running as cluster node 127.0.0.1:6011"));
+        let output = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--bind", "0.0.0.0", "--port", "6011", "--cluster", "--announce-addr", "kvs1.example:6011"])
+            .current_dir(&temp_dir)
+            .timeout(Duration::from_secs(1))
+            .output()?;
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Running as cluster node kvs1.example:6011"));
+        Ok(())
+    }
+}
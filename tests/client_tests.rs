@@ -0,0 +1,162 @@
+mod client_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use serde_resp::RESPType;
+    use tempfile::tempdir;
+    use kvs::{KvErrorKind, KvStore, KvsClient, KvsServer, Request, Result};
+
+    /// Run a server which never replies on its first `stalled` connections and closes the next
+    /// `closed` ones after reading a request. Later connections answer `OK` to writes and
+    /// `value` to every other request. Return the number of accepted connections.
+    fn start_flaky_server(port: u16, stalled: usize, closed: usize) -> Result<Arc<AtomicUsize>> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    while let Ok(len @ 1..) = stream.read(&mut buf) {
+                        if n < stalled {
+                            continue;
+                        }
+                        if n < stalled + closed {
+                            return;
+                        }
+                        let write = buf[..len].windows(3).any(|word| word == b"set");
+                        stream.write_all(if write { b"+OK\r\n" } else { b"$5\r\nvalue\r\n" }).unwrap();
+                    }
+                });
+            }
+        });
+        Ok(accepted)
+    }
+
+    // Should fail with a timeout error and connect again for the next command
+    #[test]
+    fn timeout_and_reconnect() -> Result<()> {
+        let accepted = start_flaky_server(6801, 1, 0)?;
+        let mut client = KvsClient::builder()
+            .read_timeout(Duration::from_millis(200))
+            .retries(3)
+            .connect("127.0.0.1:6801")?;
+        let started = Instant::now();
+        // not idempotent, so not retried
+        assert_eq!(client.rm("key").unwrap_err().kind(), KvErrorKind::Timeout);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let accepted = start_flaky_server(6802, 1, 0)?;
+        let mut client = KvsClient::builder()
+            .read_timeout(Duration::from_millis(200))
+            .retries(0)
+            .connect("127.0.0.1:6802")?;
+        assert_eq!(client.get("key").unwrap_err().kind(), KvErrorKind::Timeout);
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    // Should retry idempotent commands with backoff until a connection answers
+    #[test]
+    fn retry_idempotent() -> Result<()> {
+        let accepted = start_flaky_server(6803, 1, 2)?;
+        let mut client = KvsClient::builder()
+            .read_timeout(Duration::from_millis(200))
+            .backoff(Duration::from_millis(10), Duration::from_millis(20))
+            .retries(3)
+            .connect("127.0.0.1:6803")?;
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 4);
+
+        let accepted = start_flaky_server(6804, 0, 3)?;
+        let mut client = KvsClient::builder().retries(2).connect("127.0.0.1:6804")?;
+        assert!(client.get("key").is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        // writes are retried only if enabled
+        let accepted = start_flaky_server(6807, 0, 1)?;
+        let mut client = KvsClient::builder().retries(2).connect("127.0.0.1:6807")?;
+        assert!(client.set("key", "value").is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        client.set("key", "value")?;
+        let accepted = start_flaky_server(6808, 0, 1)?;
+        let mut client = KvsClient::builder().retries(2).retry_writes(true).connect("127.0.0.1:6808")?;
+        client.set("key", "value")?;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // plain clients neither retry nor reconnect
+        let accepted = start_flaky_server(6805, 0, 1)?;
+        let mut client = KvsClient::connect("127.0.0.1:6805")?;
+        assert!(client.get("key").is_err());
+        assert!(client.get("key").is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    // Should apply timeouts and reconnection to unix socket connections too
+    #[cfg(unix)]
+    #[test]
+    fn unix_timeout_and_reconnect() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kvs.sock");
+        let listener = UnixListener::bind(&path)?;
+        // the first connection never replies
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    while stream.read(&mut buf).is_ok_and(|len| len > 0) {
+                        if n > 0 {
+                            stream.write_all(b"$5\r\nvalue\r\n").unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        let mut client = KvsClient::builder()
+            .read_timeout(Duration::from_millis(200))
+            .retries(0)
+            .connect_unix(&path)?;
+        assert_eq!(client.get("key").unwrap_err().kind(), KvErrorKind::Timeout);
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+        Ok(())
+    }
+
+    // Should send requests at once and return their replies in order
+    #[test]
+    fn pipeline() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        thread::spawn(move || server.run(("127.0.0.1", 6806)));
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect("127.0.0.1:6806")?;
+        let mut requests: Vec<Request> = (0..500).map(|i| Request::set(&format!("key{i}"), &i.to_string())).collect();
+        requests.push(Request::get("key42"));
+        requests.push(Request::get("missing"));
+        requests.push(Request::AclDelUser { usernames: vec!["default".to_owned()] });
+        requests.push(Request::DbSize);
+        let replies = client.pipeline(&requests)?;
+        assert_eq!(replies.len(), 504);
+        assert!(replies[..500].iter().all(|reply| matches!(reply, Ok(RESPType::SimpleString(_)))));
+        assert!(matches!(&replies[500], Ok(RESPType::BulkString(value)) if value == b"42"));
+        assert!(matches!(replies[501], Ok(RESPType::None)));
+        assert!(replies[502].is_err());
+        assert!(matches!(replies[503], Ok(RESPType::Integer(500))));
+        // the connection is left ready for the next command
+        assert_eq!(client.get("key499")?, Some("499".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+mod cluster_tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{tempdir, TempDir};
+    use kvs::cluster::{create, key_slot, move_slots};
+    use kvs::{KvStore, KvsClient, KvsServer, Result};
+
+    /// Run a cluster node with kvs engine in background, return the temp dir to keep it alive.
+    fn start_node(port: u16) -> Result<TempDir> {
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        server.enable_cluster(&format!("127.0.0.1:{}", port), &temp_dir.path().join("nodes.conf"))?;
+        thread::spawn(move || server.run(("127.0.0.1", port)));
+        thread::sleep(Duration::from_millis(500));
+        Ok(temp_dir)
+    }
+
+    // Should store keys on the owners of their slots, with clients following redirects
+    #[test]
+    fn route_by_slots() -> Result<()> {
+        let _dirs = [6601, 6602, 6603].map(|port| start_node(port).unwrap());
+        let nodes = ["127.0.0.1:6601", "127.0.0.1:6602", "127.0.0.1:6603"];
+        let mut client = KvsClient::connect(nodes[0])?;
+        assert!(client.set("key", "value").unwrap_err().to_string().contains("CLUSTERDOWN"));
+        let ranges = create(&nodes, None)?;
+        assert_eq!(client.cluster_slots()?, ranges);
+        assert_eq!((ranges[0].start, ranges[2].end), (0, 16383));
+
+        for i in 0..300 {
+            client.set(&format!("key{}", i), &i.to_string())?;
+        }
+        for (node, range) in nodes.iter().zip(&ranges) {
+            let stored = KvsClient::connect(node)?.scan("")?;
+            assert!(stored.len() > 50, "{} stores {} keys", node, stored.len());
+            assert!(stored.iter().all(|(key, _)| (range.start..=range.end).contains(&key_slot(key))));
+        }
+        let mut other = KvsClient::connect(nodes[2])?;
+        for i in 0..300 {
+            assert_eq!(other.get(&format!("key{}", i))?, Some(i.to_string()));
+        }
+        assert!(other.mget(&["key1", "key2"]).unwrap_err().to_string().contains("CROSSSLOT"));
+        other.mset(&[("{user}.name", "alice"), ("{user}.mail", "a@example.com")])?;
+        assert_eq!(client.mget(&["{user}.name", "{user}.mail"])?.len(), 2);
+        assert_eq!(other.cluster_keyslot("{user}.name")?, key_slot("user"));
+        assert!(other.cluster_nodes()?.contains("127.0.0.1:6603 myself 10922-16383"));
+        Ok(())
+    }
+
+    // Should move slots to a new node without losing writes made during the move
+    #[test]
+    fn move_slots_online() -> Result<()> {
+        let _dirs = [6611, 6612, 6613].map(|port| start_node(port).unwrap());
+        let nodes = ["127.0.0.1:6611", "127.0.0.1:6612"];
+        create(&nodes, None)?;
+        let mut client = KvsClient::connect(nodes[0])?;
+        for i in 0..300 {
+            client.set(&format!("key{}", i), &i.to_string())?;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            thread::spawn(move || -> Result<usize> {
+                let mut client = KvsClient::connect("127.0.0.1:6612")?;
+                let mut written = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    client.set(&format!("key{}", written % 300), &format!("new{}", written))?;
+                    written += 1;
+                }
+                Ok(written)
+            })
+        };
+        let moved = move_slots(nodes[1], 0, 1000, "127.0.0.1:6613", None)?;
+        stop.store(true, Ordering::SeqCst);
+        let written = writer.join().unwrap()?;
+        assert!(moved > 0);
+
+        let mut new_node = KvsClient::connect("127.0.0.1:6613")?;
+        let stored = new_node.scan("")?;
+        assert!(stored.iter().all(|(key, _)| key_slot(key) <= 1000));
+        assert!(new_node.cluster_slots()?.iter().any(|range| (range.start, range.end) == (0, 1000)));
+        for slot in [0, 500, 1000] {
+            assert_eq!(KvsClient::connect(nodes[0])?.cluster_countkeysinslot(slot)?, 0);
+        }
+        // each key holds its latest value, wherever it is stored
+        for i in 0..300 {
+            let latest = (0..written).rev().find(|n| n % 300 == i).map_or(i.to_string(), |n| format!("new{}", n));
+            assert_eq!(client.get(&format!("key{}", i))?, Some(latest));
+        }
+        Ok(())
+    }
+
+    // Should fail commands redirected to another node on a unix socket, which can not reach it
+    #[cfg(unix)]
+    #[test]
+    fn unix_redirect() -> Result<()> {
+        use kvs::{KvErrorKind, Request};
+        let temp_dir = tempdir()?;
+        let config = temp_dir.path().join("nodes.conf");
+        std::fs::write(&config, "0-16383 127.0.0.1:6621\n")?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        server.enable_cluster("127.0.0.1:6620", &config)?;
+        let path = temp_dir.path().join("kvs.sock");
+        let listener = kvs::server::bind_unix(&path, None)?;
+        thread::spawn(move || server.run_listener(listener));
+
+        let mut client = KvsClient::connect_unix(&path)?;
+        let err = client.set("key", "value").unwrap_err();
+        assert_eq!(err.kind(), KvErrorKind::Redirect);
+        assert!(err.to_string().starts_with("MOVED"));
+        let replies = client.pipeline(&[Request::set("key", "value")])?;
+        assert_eq!(replies[0].as_ref().unwrap_err().kind(), KvErrorKind::Redirect);
+        // commands without keys are served
+        client.ping()?;
+        Ok(())
+    }
+}
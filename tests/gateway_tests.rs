@@ -0,0 +1,139 @@
+mod gateway_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tempfile::{tempdir, TempDir};
+    use kvs::cluster::{create, key_slot};
+    use kvs::{KvStore, KvsClient, KvsServer, Result};
+
+    /// Serve the gateway with kvs engine, return the temp dir to keep it alive.
+    fn start_gateway(port: u16) -> Result<TempDir> {
+        let temp_dir = tempdir()?;
+        let server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        server.serve_http(("127.0.0.1", port))?;
+        Ok(temp_dir)
+    }
+
+    /// Send a request, return the status and the body parsed as json, `Null` if empty.
+    fn request(port: u16, method: &str, target: &str, body: &str) -> (u16, Value) {
+        request_as(port, None, method, target, body)
+    }
+
+    /// Send a request with base64 encoded Basic `credentials` if given.
+    fn request_as(port: u16, credentials: Option<&str>, method: &str, target: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let auth = credentials.map_or(String::new(), |credentials| format!("Authorization: Basic {}\r\n", credentials));
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}", method, target, auth, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, if body.is_empty() { Value::Null } else { serde_json::from_str(body).unwrap() })
+    }
+
+    // Should get, put and delete single keys with proper status codes
+    #[test]
+    fn single_keys() -> Result<()> {
+        let _dir = start_gateway(6301)?;
+        assert_eq!(request(6301, "GET", "/keys/name", "").0, 404);
+        assert_eq!(request(6301, "PUT", "/keys/name", r#"{"value":"Adam"}"#).0, 204);
+        assert_eq!(request(6301, "GET", "/keys/name", ""), (200, json!({ "key": "name", "value": "Adam" })));
+        assert_eq!(request(6301, "PUT", "/keys/a%2Fb", r#"{"value":"slash"}"#).0, 204);
+        assert_eq!(request(6301, "GET", "/keys/a%2Fb", "").1["key"], "a/b");
+        assert_eq!(request(6301, "PUT", "/keys/name", "Eve").0, 400);
+        assert_eq!(request(6301, "DELETE", "/keys/name", "").0, 204);
+        assert_eq!(request(6301, "DELETE", "/keys/name", "").0, 404);
+        assert_eq!(request(6301, "POST", "/keys/name", "").0, 405);
+        assert_eq!(request(6301, "GET", "/nothing", "").0, 404);
+        Ok(())
+    }
+
+    // Should list pairs by prefix in key order and run batches in order
+    #[test]
+    fn list_and_batch() -> Result<()> {
+        let _dir = start_gateway(6302)?;
+        let batch = json!([
+            { "op": "set", "key": "user:2", "value": "Eve" },
+            { "op": "set", "key": "user:1", "value": "Adam" },
+            { "op": "set", "key": "other", "value": "x" },
+            { "op": "delete", "key": "other" },
+            { "op": "get", "key": "user:1" },
+            { "op": "get", "key": "other" },
+        ]);
+        let (status, results) = request(6302, "POST", "/batch", &batch.to_string());
+        assert_eq!(status, 200);
+        assert_eq!(results[3], json!({ "key": "other", "deleted": true }));
+        assert_eq!(results[4], json!({ "key": "user:1", "value": "Adam" }));
+        assert_eq!(results[5], json!({ "key": "other", "value": null }));
+
+        let (status, pairs) = request(6302, "GET", "/keys?prefix=user%3A", "");
+        assert_eq!(status, 200);
+        assert_eq!(pairs, json!([{ "key": "user:1", "value": "Adam" }, { "key": "user:2", "value": "Eve" }]));
+        assert_eq!(request(6302, "GET", "/keys?prefix=user:&limit=1", "").1, json!([{ "key": "user:1", "value": "Adam" }]));
+        assert_eq!(request(6302, "GET", "/keys?prefix=user:&after=user:1", "").1, json!([{ "key": "user:2", "value": "Eve" }]));
+        assert_eq!(request(6302, "GET", "/keys?limit=many", "").0, 400);
+
+        // nothing runs if any operation is malformed
+        let (status, _) = request(6302, "POST", "/batch", r#"[{"op":"set","key":"k","value":"v"},{"op":"flush"}]"#);
+        assert_eq!(status, 400);
+        assert_eq!(request(6302, "GET", "/keys/k", "").0, 404);
+        Ok(())
+    }
+
+    // Should require credentials and enforce commands and key patterns of ACL users
+    #[test]
+    fn acl_users() -> Result<()> {
+        // base64 of `default:admin` and `reader:secret`
+        let (admin, reader) = (Some("ZGVmYXVsdDphZG1pbg=="), Some("cmVhZGVyOnNlY3JldA=="));
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        server.set_requirepass("admin");
+        server.serve_http(("127.0.0.1", 6303))?;
+        thread::spawn(move || server.run(("127.0.0.1", 6304)));
+        thread::sleep(Duration::from_millis(500));
+        KvsClient::connect_with_auth("127.0.0.1:6304", "admin")?.acl_setuser("reader", &["on", ">secret", "+@read", "~app:*"])?;
+
+        assert_eq!(request(6303, "GET", "/keys/app:1", "").0, 401);
+        // `reader:wrong`
+        assert_eq!(request_as(6303, Some("cmVhZGVyOndyb25n"), "GET", "/keys/app:1", "").0, 401);
+        assert_eq!(request_as(6303, admin, "PUT", "/keys/app:1", r#"{"value":"one"}"#).0, 204);
+        assert_eq!(request_as(6303, admin, "PUT", "/keys/other", r#"{"value":"two"}"#).0, 204);
+        assert_eq!(request_as(6303, reader, "GET", "/keys/app:1", "").0, 200);
+        let (status, body) = request_as(6303, reader, "GET", "/keys/other", "");
+        assert_eq!(status, 403);
+        assert!(body["error"].as_str().unwrap().starts_with("NOPERM"));
+        assert_eq!(request_as(6303, reader, "PUT", "/keys/app:1", r#"{"value":"x"}"#).0, 403);
+        assert_eq!(request_as(6303, reader, "GET", "/keys", "").1, json!([{ "key": "app:1", "value": "one" }]));
+        let batch = json!([{ "op": "get", "key": "app:1" }, { "op": "get", "key": "other" }]);
+        assert_eq!(request_as(6303, reader, "POST", "/batch", &batch.to_string()).0, 403);
+        Ok(())
+    }
+
+    // Should refuse keys of slots served by other cluster nodes
+    #[test]
+    fn cluster_slots() -> Result<()> {
+        let mut dirs = vec![];
+        for port in [6305, 6306] {
+            let temp_dir = tempdir()?;
+            let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+            server.enable_cluster(&format!("127.0.0.1:{}", port), &temp_dir.path().join("nodes.conf"))?;
+            if port == 6305 {
+                server.serve_http(("127.0.0.1", 6307))?;
+            }
+            thread::spawn(move || server.run(("127.0.0.1", port)));
+            dirs.push(temp_dir);
+        }
+        thread::sleep(Duration::from_millis(500));
+        let ranges = create(&["127.0.0.1:6305", "127.0.0.1:6306"], None)?;
+        let mut keys = (0..).map(|i| format!("key{}", i));
+        let here = keys.find(|key| key_slot(key) <= ranges[0].end).unwrap();
+        let there = keys.find(|key| key_slot(key) > ranges[0].end).unwrap();
+        assert_eq!(request(6307, "PUT", &format!("/keys/{}", here), r#"{"value":"x"}"#).0, 204);
+        let (status, body) = request(6307, "PUT", &format!("/keys/{}", there), r#"{"value":"x"}"#);
+        assert_eq!(status, 421);
+        assert!(body["error"].as_str().unwrap().starts_with("MOVED"));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+mod pool_tests {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{tempdir, TempDir};
+    use kvs::pool::PoolConfig;
+    use kvs::{KvErrorKind, KvStore, KvsPool, KvsServer, Result};
+
+    /// Run a server with kvs engine in background, return the temp dir to keep it alive.
+    fn start_server(port: u16) -> Result<TempDir> {
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        thread::spawn(move || server.run(("127.0.0.1", port)));
+        thread::sleep(Duration::from_millis(500));
+        Ok(temp_dir)
+    }
+
+    // Should share at most `max_size` connections among threads and wait for returned ones
+    #[test]
+    fn shared_by_threads() -> Result<()> {
+        let _dir = start_server(6701)?;
+        let config = PoolConfig { min_idle: 2, max_size: 4, ..PoolConfig::default() };
+        let pool = KvsPool::connect("127.0.0.1:6701", config)?;
+        assert_eq!((pool.idle(), pool.size()), (2, 2));
+
+        let handles: Vec<_> = (0..8).map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    pool.get()?.set(&format!("key{}-{}", t, i), &i.to_string())?;
+                }
+                Ok(())
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert!(pool.size() <= 4);
+        assert_eq!(pool.idle(), pool.size());
+        assert_eq!(pool.get()?.dbsize()?, 400);
+
+        let config = PoolConfig {
+            min_idle: 0,
+            max_size: 1,
+            idle_timeout: Duration::from_millis(100),
+            wait_timeout: Duration::from_millis(200),
+            password: None,
+        };
+        let pool = KvsPool::connect("127.0.0.1:6701", config)?;
+        let client = pool.get()?;
+        assert_eq!(pool.get().err().map(|err| err.kind()), Some(KvErrorKind::Timeout));
+        drop(client);
+        assert_eq!(pool.get()?.get("key7-7")?, Some("7".to_owned()));
+        // the idle connection expires
+        thread::sleep(Duration::from_millis(200));
+        pool.get()?.discard();
+        assert_eq!((pool.idle(), pool.size()), (0, 0));
+        Ok(())
+    }
+
+    // Should close connections that fail the health check and open new ones instead
+    #[test]
+    fn replace_broken() -> Result<()> {
+        // a server answering PONG to everything, which can break its connections
+        let accepted = Arc::new(Mutex::new(Vec::<TcpStream>::new()));
+        let listener = TcpListener::bind("127.0.0.1:6702")?;
+        let streams = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                streams.lock().unwrap().push(stream.try_clone().unwrap());
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    while stream.read(&mut buf).is_ok_and(|n| n > 0) {
+                        stream.write_all(b"+PONG\r\n").unwrap();
+                    }
+                });
+            }
+        });
+
+        let config = PoolConfig { min_idle: 2, ..PoolConfig::default() };
+        let pool = KvsPool::connect("127.0.0.1:6702", config)?;
+        thread::sleep(Duration::from_millis(100));
+        for stream in accepted.lock().unwrap().iter() {
+            stream.shutdown(Shutdown::Both)?;
+        }
+        let mut client = pool.get()?;
+        client.ping()?;
+        // the broken connection is replaced to keep `min_idle` idle, and the replacement is lent
+        assert_eq!(accepted.lock().unwrap().len(), 3);
+        assert_eq!((pool.idle(), pool.size()), (1, 2));
+        drop(client);
+        assert_eq!((pool.idle(), pool.size()), (2, 2));
+
+        // a connection failing a request is closed and replaced in the background
+        let mut client = pool.get()?;
+        for stream in accepted.lock().unwrap().iter() {
+            // the first ones are shut down already
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        assert!(client.set("key", "value").is_err());
+        assert!(client.failed());
+        drop(client);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(accepted.lock().unwrap().len(), 4);
+        assert_eq!((pool.idle(), pool.size()), (2, 2));
+        Ok(())
+    }
+
+    // Should lend a connection again after an error reply, but not after `discard`
+    #[test]
+    fn return_after_error_reply() -> Result<()> {
+        let _dir = start_server(6703)?;
+        let config = PoolConfig { min_idle: 1, ..PoolConfig::default() };
+        let pool = KvsPool::connect("127.0.0.1:6703", config)?;
+        let mut client = pool.get()?;
+        client.set("key", "value")?;
+        assert!(!client.failed());
+        assert!(client.acl_setuser("bob", &["bogus"]).is_err());
+        assert!(!client.failed());
+        drop(client);
+        assert_eq!((pool.idle(), pool.size()), (1, 1));
+
+        let mut client = pool.get()?;
+        assert!(client.info(Some("clients"))?.contains("total_connections_received:1\r\n"));
+        client.discard();
+        thread::sleep(Duration::from_millis(100));
+        let mut client = pool.get()?;
+        assert!(client.info(Some("clients"))?.contains("total_connections_received:2\r\n"));
+        assert_eq!((pool.idle(), pool.size()), (0, 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+mod raft_tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::io;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use rand::Rng;
+    use tempfile::{tempdir, TempDir};
+    use kvs::engine::raft::{RaftRole, RaftStatus};
+    use kvs::engine::{KvsEngine, RaftEngine};
+    use kvs::{KvError, KvStore, Result};
+
+    type Node = Arc<Mutex<RaftEngine<KvStore>>>;
+
+    /// Forward connections to a node until cut, which drops open connections and refuses new ones.
+    struct Proxy {
+        cut: Arc<AtomicBool>,
+        connections: Arc<Mutex<Vec<TcpStream>>>,
+    }
+
+    impl Proxy {
+        fn start(port: u16, target: u16) -> Result<Proxy> {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            let cut = Arc::new(AtomicBool::new(false));
+            let connections = Arc::new(Mutex::new(vec![]));
+            let proxy = Proxy { cut: cut.clone(), connections: connections.clone() };
+            thread::spawn(move || {
+                for inbound in listener.incoming().flatten() {
+                    if cut.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let outbound = match TcpStream::connect(("127.0.0.1", target)) {
+                        Ok(outbound) => outbound,
+                        Err(_) => continue,
+                    };
+                    let pipe = |mut from: TcpStream, mut to: TcpStream| thread::spawn(move || {
+                        let _ = io::copy(&mut from, &mut to);
+                        let _ = to.shutdown(Shutdown::Both);
+                    });
+                    let mut connections = connections.lock().unwrap();
+                    connections.extend([inbound.try_clone().unwrap(), outbound.try_clone().unwrap()]);
+                    pipe(inbound.try_clone().unwrap(), outbound.try_clone().unwrap());
+                    pipe(outbound, inbound);
+                }
+            });
+            Ok(proxy)
+        }
+
+        fn set_cut(&self, cut: bool) {
+            self.cut.store(cut, Ordering::SeqCst);
+            if cut {
+                for connection in self.connections.lock().unwrap().drain(..) {
+                    let _ = connection.shutdown(Shutdown::Both);
+                }
+            }
+        }
+    }
+
+    /// Three raft nodes in this process. Node `i` listens to port `base + i`, and reaches node `j`
+    /// through its own proxy at port `base + 10 + 3 * (i - 1) + (j - 1)`, so links can be cut.
+    struct Cluster {
+        nodes: BTreeMap<u64, Node>,
+        proxies: BTreeMap<(u64, u64), Proxy>,
+        _dirs: Vec<TempDir>,
+    }
+
+    impl Cluster {
+        fn start(base: u16) -> Result<Cluster> {
+            let ids = [1u64, 2, 3];
+            let proxy_port = |from: u64, to: u64| base + 10 + 3 * (from as u16 - 1) + (to as u16 - 1);
+            let mut proxies = BTreeMap::new();
+            for from in ids {
+                for to in ids.iter().copied().filter(|to| *to != from) {
+                    proxies.insert((from, to), Proxy::start(proxy_port(from, to), base + to as u16)?);
+                }
+            }
+            let mut nodes = BTreeMap::new();
+            let mut dirs = vec![];
+            for id in ids {
+                let addrs = ids.iter()
+                    .map(|&peer| {
+                        let port = if peer == id { base + id as u16 } else { proxy_port(id, peer) };
+                        (peer, format!("127.0.0.1:{}", port))
+                    })
+                    .collect();
+                let dir = tempdir()?;
+                let store = KvStore::open(dir.path().join("data"))?;
+                let engine = RaftEngine::open(store, dir.path().join("raft"), id, addrs, "secret")?;
+                nodes.insert(id, Arc::new(Mutex::new(engine)));
+                dirs.push(dir);
+            }
+            Ok(Cluster { nodes, proxies, _dirs: dirs })
+        }
+
+        /// Cut or heal every link from and to node `id`.
+        fn isolate(&self, id: u64, isolated: bool) {
+            for ((from, to), proxy) in &self.proxies {
+                if *from == id || *to == id {
+                    proxy.set_cut(isolated);
+                }
+            }
+        }
+
+        fn status(&self, id: u64) -> Result<RaftStatus> {
+            self.nodes[&id].lock().unwrap().status()
+        }
+
+        /// Wait until a node other than `except` leads the highest term, return it.
+        fn wait_leader(&self, except: Option<u64>) -> Result<u64> {
+            let started = Instant::now();
+            loop {
+                let statuses = self.nodes.keys().map(|id| self.status(*id)).collect::<Result<Vec<_>>>()?;
+                let term = statuses.iter().map(|status| status.term).max().unwrap();
+                let leader = statuses.iter()
+                    .find(|status| status.role == RaftRole::Leader && status.term == term && Some(status.id) != except);
+                if let Some(leader) = leader {
+                    return Ok(leader.id);
+                }
+                assert!(started.elapsed() < Duration::from_secs(10), "no leader is elected");
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    // Should serve only on the leader, fail over when it is partitioned, and catch it up after healing
+    #[test]
+    fn partition_and_heal() -> Result<()> {
+        let cluster = Cluster::start(6300)?;
+        let leader = cluster.wait_leader(None)?;
+        cluster.nodes[&leader].lock().unwrap().set("key", "value1")?;
+        let follower = cluster.nodes.keys().copied().find(|id| *id != leader).unwrap();
+        assert!(matches!(cluster.nodes[&follower].lock().unwrap().get("key"), Err(KvError::NotLeader(_))));
+
+        cluster.isolate(leader, true);
+        let new_leader = cluster.wait_leader(Some(leader))?;
+        cluster.nodes[&new_leader].lock().unwrap().set("key", "value2")?;
+        // the old leader steps down and can not commit anything
+        assert!(cluster.nodes[&leader].lock().unwrap().set("key", "lost").is_err());
+
+        cluster.isolate(leader, false);
+        let leader = cluster.wait_leader(None)?;
+        let mut engine = cluster.nodes[&leader].lock().unwrap();
+        assert_eq!(engine.get("key")?, Some("value2".to_owned()));
+        drop(engine);
+        let commit_index = cluster.status(leader)?.commit_index;
+        let started = Instant::now();
+        while cluster.nodes.keys().any(|id| cluster.status(*id).unwrap().last_applied < commit_index) {
+            assert!(started.elapsed() < Duration::from_secs(10), "nodes do not catch up");
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    // Should catch up a node whose entries are compacted with a snapshot sent in chunks
+    #[test]
+    fn install_snapshot() -> Result<()> {
+        let cluster = Cluster::start(6700)?;
+        let leader = cluster.wait_leader(None)?;
+        let lagging = cluster.nodes.keys().copied().find(|id| *id != leader).unwrap();
+        cluster.isolate(lagging, true);
+        // more entries than a log keeps before compaction, with more keys than a chunk
+        for i in 0..10_100 {
+            cluster.nodes[&leader].lock().unwrap().set(&format!("key{}", i), &i.to_string())?;
+        }
+        cluster.isolate(lagging, false);
+        let commit_index = cluster.status(leader)?.commit_index;
+        let started = Instant::now();
+        while cluster.status(lagging)?.last_applied < commit_index {
+            assert!(started.elapsed() < Duration::from_secs(10), "node {} does not catch up", lagging);
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(cluster.nodes[&lagging].lock().unwrap().stats()?.keys, 10_100);
+        Ok(())
+    }
+
+    /// An operation on a register: a read with the value it returned, or a write of a value.
+    #[derive(Debug, Clone)]
+    enum OpKind {
+        Read(Option<String>),
+        Write(Option<String>),
+    }
+
+    /// `complete` is `None` when a write failed, it may or may not have taken effect.
+    #[derive(Debug, Clone)]
+    struct Op {
+        invoke: u128,
+        complete: Option<u128>,
+        kind: OpKind,
+    }
+
+    /// Check that a history of one register, initially empty, is linearizable, by searching for an
+    /// order that respects real time and register semantics.
+    fn linearizable(ops: &[Op]) -> bool {
+        fn search(ops: &[Op], done: u128, state: Option<String>, visited: &mut HashSet<(u128, Option<String>)>) -> bool {
+            if ops.iter().enumerate().all(|(i, op)| done & 1 << i != 0 || op.complete.is_none()) {
+                return true;
+            }
+            if !visited.insert((done, state.clone())) {
+                return false;
+            }
+            // an operation can go next only if it started before every remaining one completed
+            let horizon = ops.iter().enumerate()
+                .filter(|(i, _)| done & 1 << i == 0)
+                .filter_map(|(_, op)| op.complete)
+                .min()
+                .unwrap_or(u128::MAX);
+            ops.iter().enumerate()
+                .filter(|(i, op)| done & 1 << i == 0 && op.invoke <= horizon)
+                .any(|(i, op)| match &op.kind {
+                    OpKind::Read(value) => *value == state && search(ops, done | 1 << i, state.clone(), visited),
+                    OpKind::Write(value) => search(ops, done | 1 << i, value.clone(), visited),
+                })
+        }
+        assert!(ops.len() <= 128, "history is too long to check");
+        search(ops, 0, None, &mut HashSet::new())
+    }
+
+    // Should keep the histories of concurrent clients linearizable while nodes are partitioned and healed
+    #[test]
+    fn linearizable_histories() -> Result<()> {
+        let cluster = Arc::new(Cluster::start(6400)?);
+        cluster.wait_leader(None)?;
+        let started = Instant::now();
+        let keys = ["a", "b"];
+        let history: Arc<Mutex<BTreeMap<&str, Vec<Op>>>> = Arc::new(Mutex::new(BTreeMap::new()));
+
+        let nemesis = {
+            let cluster = cluster.clone();
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..4 {
+                    let id = rng.gen_range(1..=3);
+                    cluster.isolate(id, true);
+                    thread::sleep(Duration::from_millis(700));
+                    cluster.isolate(id, false);
+                    thread::sleep(Duration::from_millis(500));
+                }
+            })
+        };
+        let clients: Vec<_> = (0..3)
+            .map(|client| {
+                let (cluster, history) = (cluster.clone(), history.clone());
+                thread::spawn(move || {
+                    let mut rng = rand::thread_rng();
+                    let mut node = 1u64;
+                    for i in 0..40 {
+                        let key = keys[rng.gen_range(0..keys.len())];
+                        let write = rng.gen_bool(0.5).then(|| format!("{}-{}", client, i));
+                        let invoke = started.elapsed().as_nanos();
+                        let mut engine = cluster.nodes[&node].lock().unwrap();
+                        let result = match &write {
+                            Some(value) => engine.set(key, value).map(|()| None),
+                            None => engine.get(key),
+                        };
+                        drop(engine);
+                        let complete = started.elapsed().as_nanos();
+                        let op = match (write, result) {
+                            (Some(value), Ok(_)) => Some(Op { invoke, complete: Some(complete), kind: OpKind::Write(Some(value)) }),
+                            (Some(value), Err(_)) => Some(Op { invoke, complete: None, kind: OpKind::Write(Some(value)) }),
+                            (None, Ok(value)) => Some(Op { invoke, complete: Some(complete), kind: OpKind::Read(value) }),
+                            (None, Err(_)) => None,
+                        };
+                        if op.as_ref().is_none_or(|op| op.complete.is_none()) {
+                            // try another node, the leader moved or is unreachable
+                            node = node % 3 + 1;
+                        }
+                        if let Some(op) = op {
+                            history.lock().unwrap().entry(key).or_default().push(op);
+                        }
+                        thread::sleep(Duration::from_millis(30));
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        nemesis.join().unwrap();
+
+        let history = history.lock().unwrap();
+        let completed = history.values().flatten().filter(|op| op.complete.is_some()).count();
+        assert!(completed >= 20, "only {} operations completed", completed);
+        for (key, ops) in history.iter() {
+            // a failed write that nobody read can be taken as never applied
+            let read: HashSet<&String> = ops.iter()
+                .filter_map(|op| match &op.kind {
+                    OpKind::Read(Some(value)) => Some(value),
+                    _ => None,
+                })
+                .collect();
+            let ops: Vec<Op> = ops.iter()
+                .filter(|op| op.complete.is_some() || matches!(&op.kind, OpKind::Write(Some(value)) if read.contains(value)))
+                .cloned()
+                .collect();
+            assert!(linearizable(&ops), "history of {} is not linearizable: {:?}", key, ops);
+        }
+        Ok(())
+    }
+
+    // Should ignore nodes that do not know the cluster secret, so no leader is elected without them
+    #[test]
+    fn reject_wrong_secret() -> Result<()> {
+        let addrs = BTreeMap::from([(1, "127.0.0.1:6391".to_owned()), (2, "127.0.0.1:6392".to_owned())]);
+        let dirs = [tempdir()?, tempdir()?];
+        let open = |id: u64, secret: &str| {
+            let dir = dirs[id as usize - 1].path();
+            RaftEngine::open(KvStore::open(dir.join("data"))?, dir.join("raft"), id, addrs.clone(), secret)
+        };
+        let nodes = [open(1, "secret")?, open(2, "guess")?];
+        thread::sleep(Duration::from_secs(2));
+        for node in &nodes {
+            let status = node.status()?;
+            assert!(status.role != RaftRole::Leader && status.leader.is_none(), "{:?}", status);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+mod replication_tests {
+    use std::fs;
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+    use kvs::engine::{Change, Event};
+    use kvs::replication::{LinkStatus, Role};
+    use kvs::{KvStore, KvsClient, KvsServer, Result};
+
+    /// A `kvs-server` process, killed when dropped.
+    struct ServerProcess(Child);
+
+    impl ServerProcess {
+        fn start(dir: &Path, port: u16, leader: &str) -> Result<Self> {
+            let child = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+                .args(["--port", &port.to_string(), "--replica-of", leader])
+                .current_dir(dir)
+                .stderr(Stdio::null())
+                .spawn()?;
+            thread::sleep(Duration::from_millis(500));
+            Ok(Self(child))
+        }
+
+        fn kill(mut self) -> Result<()> {
+            self.0.kill()?;
+            self.0.wait()?;
+            Ok(())
+        }
+    }
+
+    impl Drop for ServerProcess {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+        }
+    }
+
+    /// Poll `get` of `key` until it returns `expected`, fail after a few seconds.
+    fn wait_for(client: &mut KvsClient, key: &str, expected: Option<&str>) -> Result<()> {
+        let started = Instant::now();
+        while client.get(key)?.as_deref() != expected {
+            assert!(started.elapsed() < Duration::from_secs(5), "{} is not replicated", key);
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    // Should sync a follower fully, stream later writes, and resume after the follower restarts
+    #[test]
+    fn follower_catches_up() -> Result<()> {
+        let leader_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(leader_dir.path())?)?;
+        thread::spawn(move || server.run(("127.0.0.1", 6201)));
+        thread::sleep(Duration::from_millis(500));
+        let mut leader = KvsClient::connect("127.0.0.1:6201")?;
+        leader.mset(&[("key1", "value1"), ("key2", "value2")])?;
+
+        let follower_dir = tempdir()?;
+        let follower_process = ServerProcess::start(follower_dir.path(), 6202, "127.0.0.1:6201")?;
+        let mut follower = KvsClient::connect("127.0.0.1:6202")?;
+        wait_for(&mut follower, "key1", Some("value1"))?;
+        leader.set("key3", "value3")?;
+        wait_for(&mut follower, "key3", Some("value3"))?;
+        assert!(follower.set("key4", "value4").unwrap_err().to_string().starts_with("READONLY"));
+        assert_eq!(
+            follower.role()?,
+            Role::Follower { leader: "127.0.0.1:6201".to_owned(), status: LinkStatus::Connected, seq: 3 }
+        );
+        match leader.role()? {
+            Role::Leader { seq, followers } => assert_eq!((seq, followers.len()), (3, 1)),
+            role => panic!("unexpected role {:?}", role),
+        }
+        assert!(leader.info(Some("replication"))?.contains("connected_followers:1\r\n"));
+
+        // writes while the follower is down are caught up, including removals
+        follower_process.kill()?;
+        assert_eq!(fs::read_to_string(follower_dir.path().join("replication.seq"))?, "3");
+        leader.rm("key1")?;
+        leader.set("key2", "changed")?;
+        let _follower_process = ServerProcess::start(follower_dir.path(), 6202, "127.0.0.1:6201")?;
+        let mut follower = KvsClient::connect("127.0.0.1:6202")?;
+        wait_for(&mut follower, "key2", Some("changed"))?;
+        assert_eq!(follower.get("key1")?, None);
+        assert_eq!(follower.dbsize()?, 2);
+        // the follower resumed instead of loading a snapshot, so its own store only got the two mutations
+        let changes: Vec<Change> = KvsClient::connect("127.0.0.1:6202")?.changes(3)?.take(2).collect::<Result<_>>()?;
+        assert_eq!(changes[0].event, Event::Remove { key: "key1".to_owned() });
+        assert_eq!(changes[1].event, Event::Set { key: "key2".to_owned(), value: "changed".to_owned() });
+
+        // an mset is applied as one write
+        leader.mset(&[("key5", "value5"), ("key6", "value6"), ("key7", "value7")])?;
+        wait_for(&mut follower, "key7", Some("value7"))?;
+        let changes = KvsClient::connect("127.0.0.1:6202")?.changes(5)?.take(3);
+        let remaining: Vec<u64> = changes.map(|change| change.map(|change| change.remaining)).collect::<Result<_>>()?;
+        assert_eq!(remaining, vec![2, 1, 0]);
+        Ok(())
+    }
+
+    // Should load a snapshot larger than a sync chunk
+    #[test]
+    fn full_sync_in_chunks() -> Result<()> {
+        let leader_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(leader_dir.path())?)?;
+        thread::spawn(move || server.run(("127.0.0.1", 6203)));
+        thread::sleep(Duration::from_millis(500));
+        let mut leader = KvsClient::connect("127.0.0.1:6203")?;
+        let pairs: Vec<(String, String)> = (0..2500).map(|i| (format!("key{}", i), i.to_string())).collect();
+        let pairs: Vec<(&str, &str)> = pairs.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+        leader.mset(&pairs)?;
+
+        let follower_dir = tempdir()?;
+        let _follower_process = ServerProcess::start(follower_dir.path(), 6204, "127.0.0.1:6203")?;
+        let mut follower = KvsClient::connect("127.0.0.1:6204")?;
+        wait_for(&mut follower, "key2499", Some("2499"))?;
+        assert_eq!(follower.dbsize()?, 2500);
+        assert_eq!(follower.get("key1000")?, Some("1000".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+mod server_tests {
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{tempdir, TempDir};
+    use kvs::engine::Event;
+    use kvs::engine::KvsEngine;
+    use kvs::{KvStore, KvsClient, KvsServer, Result};
+
+    /// Run a server with kvs engine in background, return the temp dir to keep it alive.
+    fn start_server(port: u16, password: Option<&str>) -> Result<TempDir> {
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        if let Some(password) = password {
+            server.set_requirepass(password);
+        }
+        thread::spawn(move || server.run(("127.0.0.1", port)));
+        thread::sleep(Duration::from_millis(500));
+        Ok(temp_dir)
+    }
+
+    // Should serve many commands over a single connection
+    #[test]
+    fn persistent_connection() -> Result<()> {
+        let _dir = start_server(6101, None)?;
+        let mut client = KvsClient::connect("127.0.0.1:6101")?;
+        for i in 0..100 {
+            client.set(&format!("key{}", i), &i.to_string())?;
+        }
+        assert_eq!(client.get("key42")?, Some("42".to_owned()));
+        assert_eq!(client.rm("key42")?, Some("OK".to_owned()));
+        assert_eq!(client.get("key42")?, None);
+        assert_eq!(client.dbsize()?, 99);
+        Ok(())
+    }
+
+    // Should reject commands other than AUTH and PING before authentication
+    #[test]
+    fn require_password() -> Result<()> {
+        let _dir = start_server(6102, Some("secret"))?;
+        let mut client = KvsClient::connect("127.0.0.1:6102")?;
+        client.ping()?;
+        let err = client.set("key", "value").unwrap_err();
+        assert!(err.to_string().starts_with("NOAUTH"));
+        let err = client.auth("wrong").unwrap_err();
+        assert!(err.to_string().starts_with("WRONGPASS"));
+        assert!(client.get("key").is_err());
+        client.auth("secret")?;
+        client.set("key", "value")?;
+
+        let mut client = KvsClient::connect_with_auth("127.0.0.1:6102", "secret")?;
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+        assert!(KvsClient::connect_with_auth("127.0.0.1:6102", "wrong").is_err());
+        Ok(())
+    }
+
+    // Should enforce commands and key patterns of ACL users
+    #[test]
+    fn acl_users() -> Result<()> {
+        let _dir = start_server(6103, Some("admin"))?;
+        let mut admin = KvsClient::connect_with_auth("127.0.0.1:6103", "admin")?;
+        assert_eq!(admin.acl_whoami()?, "default");
+        admin.acl_setuser("reader", &["on", ">secret", "+@read", "~app:*"])?;
+        admin.mset(&[("app:1", "one"), ("other", "two")])?;
+
+        let mut reader = KvsClient::connect_with_user("127.0.0.1:6103", "reader", "secret")?;
+        assert_eq!(reader.acl_whoami()?, "reader");
+        assert_eq!(reader.get("app:1")?, Some("one".to_owned()));
+        let err = reader.get("other").unwrap_err();
+        assert!(err.to_string().starts_with("NOPERM"));
+        let err = reader.set("app:1", "value").unwrap_err();
+        assert!(err.to_string().starts_with("NOPERM"));
+        assert!(reader.acl_list().unwrap_err().to_string().starts_with("NOPERM"));
+
+        assert!(admin.acl_list()?.contains(&"user reader on -@all +dbsize +get +mget +scan +unwatch +watch ~app:*".to_owned()));
+        assert_eq!(admin.acl_deluser(&["reader"])?, 1);
+        assert!(reader.get("app:1").unwrap_err().to_string().starts_with("NOPERM"));
+        assert!(KvsClient::connect_with_user("127.0.0.1:6103", "reader", "secret").is_err());
+        Ok(())
+    }
+
+    // Should only scan and watch keys matching the key patterns, whatever the prefix
+    #[test]
+    fn acl_scan_watch() -> Result<()> {
+        let _dir = start_server(6109, Some("admin"))?;
+        let mut admin = KvsClient::connect_with_auth("127.0.0.1:6109", "admin")?;
+        admin.acl_setuser("reader", &["on", ">secret", "+@read", "~*:public"])?;
+        admin.mset(&[("x:public", "one"), ("x:public_secret", "two")])?;
+
+        let mut reader = KvsClient::connect_with_user("127.0.0.1:6109", "reader", "secret")?;
+        assert_eq!(reader.scan("x:public")?, vec![("x:public".to_owned(), "one".to_owned())]);
+        assert_eq!(reader.scan("")?, vec![("x:public".to_owned(), "one".to_owned())]);
+        assert_eq!(reader.scan_page("x:", None, 1)?, vec![("x:public".to_owned(), "one".to_owned())]);
+        assert_eq!(reader.scan_page("x:", Some("x:public"), 1)?, vec![]);
+        let mut events = KvsClient::connect_with_user("127.0.0.1:6109", "reader", "secret")?.watch(&["x:public"])?;
+        admin.set("x:public_secret", "three")?;
+        admin.set("x:public", "four")?;
+        assert_eq!(events.next().unwrap()?, Event::Set { key: "x:public".to_owned(), value: "four".to_owned() });
+        Ok(())
+    }
+
+    // Should push published messages to channel and pattern subscribers in order
+    #[test]
+    fn publish_subscribe() -> Result<()> {
+        let _dir = start_server(6104, None)?;
+        let mut messages = KvsClient::connect("127.0.0.1:6104")?.subscribe(&["news"])?;
+        let mut pmessages = KvsClient::connect("127.0.0.1:6104")?.psubscribe(&["news*"])?;
+        let mut publisher = KvsClient::connect("127.0.0.1:6104")?;
+        assert_eq!(publisher.publish("news", "first")?, 2);
+        assert_eq!(publisher.publish("newsletter", "second")?, 1);
+        assert_eq!(publisher.publish("weather", "rain")?, 0);
+        assert!(publisher.info(Some("stats"))?.contains("pubsub_channels:1\r\n"));
+
+        let message = messages.next().unwrap()?;
+        assert_eq!((message.channel(), message.payload()), ("news", "first"));
+        let payloads: Vec<String> = pmessages.by_ref().take(2).map(|m| m.unwrap().payload().to_owned()).collect();
+        assert_eq!(payloads, vec!["first", "second"]);
+
+        // subscribers are removed when their connections close
+        drop(messages);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(publisher.publish("news", "third")?, 1);
+        Ok(())
+    }
+
+    // Should push changes of watched prefixes made by other clients
+    #[test]
+    fn watch_prefix() -> Result<()> {
+        let _dir = start_server(6105, None)?;
+        let mut events = KvsClient::connect("127.0.0.1:6105")?.watch(&["user:"])?;
+        let mut client = KvsClient::connect("127.0.0.1:6105")?;
+        client.set("user:1", "Adam")?;
+        client.set("group:1", "admin")?;
+        client.mset(&[("user:2", "Eve")])?;
+        client.rm("user:1")?;
+
+        assert_eq!(events.next().unwrap()?, Event::Set { key: "user:1".to_owned(), value: "Adam".to_owned() });
+        assert_eq!(events.next().unwrap()?, Event::Set { key: "user:2".to_owned(), value: "Eve".to_owned() });
+        assert_eq!(events.next().unwrap()?, Event::Remove { key: "user:1".to_owned() });
+        Ok(())
+    }
+
+    // Should replay mutations after a sequence number, follow new ones and reject compacted ones
+    #[test]
+    fn change_feed() -> Result<()> {
+        let _dir = start_server(6106, None)?;
+        let mut client = KvsClient::connect("127.0.0.1:6106")?;
+        client.set("key1", "value1")?;
+        client.mset(&[("key2", "value2"), ("key3", "value3")])?;
+        client.rm("key1")?;
+
+        let mut changes = KvsClient::connect("127.0.0.1:6106")?.changes(1)?;
+        let seqs: Vec<u64> = changes.by_ref().take(3).map(|change| change.unwrap().seq).collect();
+        assert_eq!(seqs, vec![2, 3, 4]);
+        client.set("key4", "value4")?;
+        let change = changes.next().unwrap()?;
+        assert_eq!((change.seq, change.event), (5, Event::Set { key: "key4".to_owned(), value: "value4".to_owned() }));
+
+        let temp_dir = tempdir()?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_compact_threshold(0);
+        store.set_changes_retained(0);
+        store.set("key", "value1")?;
+        store.set("key", "value2")?;
+        let mut server = KvsServer::new(store)?;
+        thread::spawn(move || server.run(("127.0.0.1", 6107)));
+        thread::sleep(Duration::from_millis(500));
+        let mut compacted = KvsClient::connect("127.0.0.1:6107")?.changes(0)?;
+        assert!(compacted.next().unwrap().unwrap_err().to_string().contains("compacted"));
+        assert!(compacted.next().is_none());
+        Ok(())
+    }
+
+    // Should write a backup once and refuse to overwrite it
+    #[test]
+    fn backup_twice() -> Result<()> {
+        let _dir = start_server(6108, None)?;
+        let backup_dir = tempdir()?;
+        let dir = backup_dir.path().to_str().unwrap();
+        let mut client = KvsClient::connect("127.0.0.1:6108")?;
+        client.set("key", "value1")?;
+        client.backup(dir)?;
+        client.set("key", "value2")?;
+        let err = client.backup(dir).unwrap_err();
+        assert!(err.to_string().contains("already contains log files"));
+        assert_eq!(client.get("key")?, Some("value2".to_owned()));
+
+        let mut backup = KvStore::open(backup_dir.path())?;
+        assert_eq!(backup.get("key")?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    // Should serve clients on a unix socket with the given permissions
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() -> Result<()> {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("kvs.sock");
+        // a stale socket file of a previous run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        let listener = kvs::server::bind_unix(&path, Some(0o700))?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o700);
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        thread::spawn(move || server.run_listener(listener));
+
+        let mut client = KvsClient::connect_unix(&path)?;
+        client.set("key", "value")?;
+        assert_eq!(KvsClient::connect_unix(&path)?.get("key")?, Some("value".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+mod sharding_tests {
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{tempdir, TempDir};
+    use kvs::sharding::{rebalance, DEFAULT_VNODES};
+    use kvs::{KvStore, KvsClient, KvsServer, Result, ShardedKvsClient};
+
+    /// Run a server with kvs engine in background, return the temp dir to keep it alive.
+    fn start_server(port: u16) -> Result<TempDir> {
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        thread::spawn(move || server.run(("127.0.0.1", port)));
+        thread::sleep(Duration::from_millis(500));
+        Ok(temp_dir)
+    }
+
+    // Should route keys to their owners, fan out multi-key commands and move keys as nodes change
+    #[test]
+    fn route_and_rebalance() -> Result<()> {
+        let _dirs = [6501, 6502, 6503, 6504].map(|port| start_server(port).unwrap());
+        let nodes = ["127.0.0.1:6501", "127.0.0.1:6502", "127.0.0.1:6503"];
+        let mut client = ShardedKvsClient::connect(&nodes)?;
+        for i in 0..300 {
+            client.set(&format!("key{}", i), &i.to_string())?;
+        }
+        for node in nodes {
+            let stored = KvsClient::connect(node)?.scan("")?;
+            assert!(stored.len() > 50, "{} stores {} keys", node, stored.len());
+            assert!(stored.iter().all(|(key, _)| client.node_for(key).unwrap() == node));
+        }
+        assert_eq!(client.get("key7")?, Some("7".to_owned()));
+        assert_eq!(client.mget(&["key1", "missing", "key2"])?, vec![Some("1".to_owned()), None, Some("2".to_owned())]);
+        client.mset(&[("a", "1"), ("b", "2"), ("c", "3")])?;
+        assert_eq!(client.mdel(&["a", "b", "missing"])?, 2);
+        assert_eq!(client.rm("c")?, Some("OK".to_owned()));
+        assert_eq!(client.dbsize()?, 300);
+        assert_eq!(client.scan("key29")?.len(), 11);
+
+        let moved = client.add_node("127.0.0.1:6504")?;
+        assert!(moved > 0 && moved < 150, "{} keys moved", moved);
+        assert_eq!(KvsClient::connect("127.0.0.1:6504")?.dbsize()?, moved as u64);
+        let moved = client.remove_node("127.0.0.1:6501")?;
+        assert!(moved > 0);
+        assert_eq!(KvsClient::connect("127.0.0.1:6501")?.dbsize()?, 0);
+        for i in 0..300 {
+            assert_eq!(client.get(&format!("key{}", i))?, Some(i.to_string()));
+        }
+
+        // the standalone tool moves keys back for clients of the original nodes
+        let current = ["127.0.0.1:6502", "127.0.0.1:6503", "127.0.0.1:6504"];
+        rebalance(&current, &nodes, DEFAULT_VNODES, None)?;
+        assert_eq!(KvsClient::connect("127.0.0.1:6504")?.dbsize()?, 0);
+        let mut client = ShardedKvsClient::connect(&nodes)?;
+        assert_eq!(client.dbsize()?, 300);
+        assert_eq!(client.get("key299")?, Some("299".to_owned()));
+        Ok(())
+    }
+
+    // Should leave keys written through a client of the old nodes behind until rebalanced again
+    #[test]
+    fn writes_during_move() -> Result<()> {
+        let _dirs = [6505, 6506, 6507].map(|port| start_server(port).unwrap());
+        let nodes = ["127.0.0.1:6505", "127.0.0.1:6506"];
+        let mut stale = ShardedKvsClient::connect(&nodes)?;
+        let mut client = ShardedKvsClient::connect(&nodes)?;
+        for i in 0..2_500 {
+            client.set(&format!("key{}", i), &i.to_string())?;
+        }
+        // nodes store more keys than a scan page, so the move takes several pages per node
+        for node in nodes {
+            assert!(KvsClient::connect(node)?.dbsize()? > 1_000);
+        }
+        let moved = client.add_node("127.0.0.1:6507")?;
+        assert!(moved > 500, "{} keys moved", moved);
+        assert_eq!(KvsClient::connect("127.0.0.1:6507")?.dbsize()?, moved as u64);
+        assert_eq!(client.dbsize()?, 2_500);
+
+        let key = (0..).map(|i| format!("late{}", i)).find(|key| client.node_for(key).unwrap() == "127.0.0.1:6507").unwrap();
+        stale.set(&key, "value")?;
+        assert_eq!(client.get(&key)?, None);
+        let all = ["127.0.0.1:6505", "127.0.0.1:6506", "127.0.0.1:6507"];
+        assert_eq!(rebalance(&all, &all, DEFAULT_VNODES, None)?, 1);
+        assert_eq!(client.get(&key)?, Some("value".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+mod tls_tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use tempfile::{tempdir, TempDir};
+    use kvs::{tls, KvStore, KvsClient, KvsServer, Result};
+
+    /// Generate a self-signed CA.
+    fn new_ca(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        Certificate::from_params(params).unwrap()
+    }
+
+    /// Write the PEM files of a certificate signed by `ca` into `dir`, return the certificate and key paths.
+    fn issue(dir: &Path, name: &str, ca: &Certificate) -> (PathBuf, PathBuf) {
+        let cert = Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()])).unwrap();
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.serialize_pem_with_signer(ca).unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn write_ca(dir: &Path, name: &str, ca: &Certificate) -> PathBuf {
+        let path = dir.join(format!("{}.pem", name));
+        fs::write(&path, ca.serialize_pem().unwrap()).unwrap();
+        path
+    }
+
+    /// Run a TLS server with kvs engine in background, return the temp dir to keep it alive.
+    fn start_server(port: u16, cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TempDir> {
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        server.set_tls(cert, key, client_ca)?;
+        thread::spawn(move || server.run(("127.0.0.1", port)));
+        thread::sleep(Duration::from_millis(500));
+        Ok(temp_dir)
+    }
+
+    // Should serve clients trusting the server CA and fail the handshake of others
+    #[test]
+    fn server_certificate() -> Result<()> {
+        let pki = tempdir()?;
+        let ca = new_ca("kvs test ca");
+        let ca_path = write_ca(pki.path(), "ca", &ca);
+        let other_ca_path = write_ca(pki.path(), "other-ca", &new_ca("other ca"));
+        let (cert, key) = issue(pki.path(), "server", &ca);
+        let _dir = start_server(6201, &cert, &key, None)?;
+
+        let mut client = KvsClient::connect_tls("127.0.0.1:6201", "localhost", tls::client_config(&ca_path, None)?)?;
+        client.set("key", "value")?;
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+        let mut client = KvsClient::builder()
+            .connect_timeout(Duration::from_secs(1))
+            .read_timeout(Duration::from_secs(1))
+            .connect_tls("127.0.0.1:6201", "localhost", tls::client_config(&ca_path, None)?)?;
+        assert_eq!(client.get("key")?, Some("value".to_owned()));
+
+        let untrusted = tls::client_config(&other_ca_path, None)?;
+        assert!(KvsClient::connect_tls("127.0.0.1:6201", "localhost", untrusted).is_err());
+        let wrong_name = tls::client_config(&ca_path, None)?;
+        assert!(KvsClient::connect_tls("127.0.0.1:6201", "kvs.example.com", wrong_name).is_err());
+        // plain TCP clients can not talk to a TLS server
+        assert!(KvsClient::connect("127.0.0.1:6201")?.ping().is_err());
+        Ok(())
+    }
+
+    // Should only accept clients presenting a certificate signed by the client CA
+    #[test]
+    fn client_verification() -> Result<()> {
+        let pki = tempdir()?;
+        let ca = new_ca("kvs test ca");
+        let ca_path = write_ca(pki.path(), "ca", &ca);
+        let (cert, key) = issue(pki.path(), "server", &ca);
+        let (client_cert, client_key) = issue(pki.path(), "client", &ca);
+        let other_ca = new_ca("other ca");
+        let (other_cert, other_key) = issue(pki.path(), "other-client", &other_ca);
+        let _dir = start_server(6202, &cert, &key, Some(&ca_path))?;
+
+        let config = tls::client_config(&ca_path, Some((&client_cert, &client_key)))?;
+        let mut client = KvsClient::connect_tls("127.0.0.1:6202", "localhost", config)?;
+        client.ping()?;
+
+        // with TLS 1.3 the server rejects the client certificate after the client finishes the handshake
+        let rejected = |config| KvsClient::connect_tls("127.0.0.1:6202", "localhost", config).and_then(|mut client| client.ping());
+        assert!(rejected(tls::client_config(&ca_path, None)?).is_err());
+        assert!(rejected(tls::client_config(&ca_path, Some((&other_cert, &other_key)))?).is_err());
+        Ok(())
+    }
+
+    // Should serve the HTTP gateway over TLS when TLS is enabled
+    #[test]
+    fn https_gateway() -> Result<()> {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use rustls::{ClientConnection, StreamOwned};
+        let pki = tempdir()?;
+        let ca = new_ca("kvs test ca");
+        let ca_path = write_ca(pki.path(), "ca", &ca);
+        let (cert, key) = issue(pki.path(), "server", &ca);
+        let temp_dir = tempdir()?;
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?)?;
+        server.set_tls(&cert, &key, None)?;
+        server.serve_http(("127.0.0.1", 6205))?;
+
+        let conn = ClientConnection::new(tls::client_config(&ca_path, None)?, "localhost".try_into().unwrap()).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect("127.0.0.1:6205")?);
+        write!(stream, "GET /keys/missing HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // plain HTTP clients can not talk to an HTTPS gateway
+        let mut plain = TcpStream::connect("127.0.0.1:6205")?;
+        write!(plain, "GET /keys/missing HTTP/1.1\r\n\r\n")?;
+        let mut response = vec![];
+        let _ = plain.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/1.1"));
+        Ok(())
+    }
+
+    // Should follow cluster redirects over TLS, checking every node against the same server name
+    #[test]
+    fn cluster_redirects() -> Result<()> {
+        let pki = tempdir()?;
+        let ca = new_ca("kvs test ca");
+        let ca_path = write_ca(pki.path(), "ca", &ca);
+        let (cert, key) = issue(pki.path(), "server", &ca);
+        let nodes = ["127.0.0.1:6206", "127.0.0.1:6207"];
+        let _dirs = [6206, 6207].map(|port| {
+            let temp_dir = tempdir().unwrap();
+            let config = temp_dir.path().join("nodes.conf");
+            fs::write(&config, format!("0-8191 {}\n8192-16383 {}\n", nodes[0], nodes[1])).unwrap();
+            let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap()).unwrap();
+            server.set_tls(&cert, &key, None).unwrap();
+            server.enable_cluster(&format!("127.0.0.1:{}", port), &config).unwrap();
+            thread::spawn(move || server.run(("127.0.0.1", port)));
+            temp_dir
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let config = tls::client_config(&ca_path, None)?;
+        let mut client = KvsClient::connect_tls(nodes[0], "localhost", config.clone())?;
+        for i in 0..100 {
+            client.set(&format!("key{}", i), &i.to_string())?;
+        }
+        for node in nodes {
+            let stored = KvsClient::connect_tls(node, "localhost", config.clone())?.scan("")?;
+            assert!(!stored.is_empty() && stored.len() < 100, "{} stores {} keys", node, stored.len());
+        }
+        let mut other = KvsClient::builder().connect_tls(nodes[1], "localhost", config)?;
+        for i in 0..100 {
+            assert_eq!(other.get(&format!("key{}", i))?, Some(i.to_string()));
+        }
+        Ok(())
+    }
+}